@@ -0,0 +1,64 @@
+//! Bearer-token check for the LAN REST API (`crate::rest_api`).
+//!
+//! The token is set over the BLE config characteristic (`api_token`) and sent by clients as
+//! `Authorization: Bearer <token>`. An empty token leaves the API disabled: nothing is
+//! authorized.
+
+pub const MIN_TOKEN_LEN: usize = 16;
+pub const MAX_TOKEN_LEN: usize = 64;
+
+/// 16 to 64 printable ASCII characters without spaces, so it fits a header unquoted
+pub fn valid_token(token: &str) -> bool {
+    (MIN_TOKEN_LEN..=MAX_TOKEN_LEN).contains(&token.len())
+        && token.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Whether an `Authorization` header value carries `token`
+pub fn authorized(header: Option<&str>, token: &str) -> bool {
+    if token.is_empty() {
+        return false;
+    }
+    let Some((scheme, presented)) = header.and_then(|h| h.trim().split_once(' ')) else {
+        return false;
+    };
+    scheme.eq_ignore_ascii_case("bearer") && constant_time_eq(presented.trim(), token)
+}
+
+/// Compares every byte whatever the first mismatch, so response timing does not leak
+/// how much of a guess was right
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.bytes()
+        .zip(b.bytes())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    #[test]
+    fn token_rules() {
+        assert!(valid_token(TOKEN));
+        assert!(!valid_token("short"));
+        assert!(!valid_token(&"x".repeat(MAX_TOKEN_LEN + 1)));
+        assert!(!valid_token("0123456789 abcdef"));
+    }
+
+    #[test]
+    fn bearer_header() {
+        assert!(authorized(Some("Bearer 0123456789abcdef"), TOKEN));
+        assert!(authorized(Some("bearer  0123456789abcdef "), TOKEN));
+        assert!(!authorized(Some("Bearer 0123456789abcdeF"), TOKEN));
+        assert!(!authorized(Some("Basic 0123456789abcdef"), TOKEN));
+        assert!(!authorized(Some("0123456789abcdef"), TOKEN));
+        assert!(!authorized(None, TOKEN));
+        // no token configured: the API is off
+        assert!(!authorized(Some("Bearer "), ""));
+    }
+}
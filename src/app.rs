@@ -4,7 +4,9 @@ use std::sync::Arc;
 use embedded_graphics::prelude::{Dimensions, WebColors};
 
 use crate::{
-    bt_keyboard_mode::{self, KeymapConfig},
+    bt_keyboard_mode::{self, KeymapConfig, KeysPin},
+    input::InputEvent,
+    key_template,
     lcd::ColorFormat,
     protocol::{self},
 };
@@ -23,6 +25,8 @@ pub enum Event {
     Custom,
     SwitchMode,
     NEXT,
+    /// 两键和弦(KeysPin 下标,小的在前),见 crate::chord
+    Chord(u8, u8),
 }
 
 impl std::fmt::Debug for Event {
@@ -39,6 +43,71 @@ impl std::fmt::Debug for Event {
             Event::Custom => write!(f, "Custom"),
             Event::SwitchMode => write!(f, "SwtchMode"),
             Event::NEXT => write!(f, "Next"),
+            Event::Chord(a, b) => write!(f, "Chord({a}, {b})"),
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum MicMode {
+    PushToTalk,
+    Toggle,
+}
+
+impl Default for MicMode {
+    fn default() -> Self {
+        Self::Toggle
+    }
+}
+
+impl From<u8> for MicMode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::PushToTalk,
+            1 => Self::Toggle,
+            _ => {
+                log::warn!(
+                    "Invalid mic mode value: {}, defaulting to PushToTalk",
+                    value
+                );
+                Self::PushToTalk
+            }
+        }
+    }
+}
+
+/// 统一输入事件 → 远程模式的按键事件。松开、长按、MIC 等远程模式不直接处理的返回 None;
+/// 自动连发(keymap 的 repeat 设置)与按下一样处理,即重发一次 pty_in。旋钮按下固定是
+/// 打开会话选择器,不连发。
+fn remote_event(evt: InputEvent) -> Option<Event> {
+    let pin = match evt {
+        InputEvent::Press(pin) => pin,
+        InputEvent::Repeat(KeysPin::ROTATE_BUTTON) => return None,
+        InputEvent::Repeat(pin) => pin,
+        InputEvent::RotateUp => return Some(Event::RotateUp),
+        InputEvent::RotateDown => return Some(Event::RotateDown),
+        InputEvent::Chord(a, b) => return Some(Event::Chord(a, b)),
+        _ => return None,
+    };
+    let event = match pin {
+        KeysPin::CUSTOM => Event::Custom,
+        KeysPin::NEXT => Event::NEXT,
+        KeysPin::SWITCH => Event::SwitchMode,
+        KeysPin::ESC => Event::Esc,
+        KeysPin::ACCEPT => Event::Accept,
+        KeysPin::ROTATE_BUTTON => Event::RotatePush,
+        KeysPin::BACKSPACE => Event::Backspace,
+        _ => return None,
+    };
+    Some(event)
+}
+
+/// 下一个远程模式关心的按键事件;输入服务已退出时返回 None。
+async fn next_key(input: &mut crate::input::Input) -> Option<Event> {
+    loop {
+        if let Some(e) = remote_event(input.next().await?) {
+            return Some(e);
         }
     }
 }
@@ -46,23 +115,47 @@ impl std::fmt::Debug for Event {
 enum SelectResult {
     Event(Event),
     Mqtt(crate::mqtt::MqttEvent),
-    /// MIC 按键按下(falling edge)。
+    /// MIC 按键按下。
     MicPressed,
+    /// 文本动作里 `{DELAY:n}` 之后、已到点的模板片段。
+    Template(Vec<key_template::TemplateStep>),
 }
 
 /// 同时等待三类事件:`select!` 只负责 select,真正会借用 `server` 的处理放在外层
 /// `match` 里 —— 这样各 future 返回后即被释放,避免 `server.recv()` future 与
 /// `server.send()` 在同一 `select!` 内的借用冲突。
+/// 混合模式下 `ble` 为 Some:按 keymap 的 route 分给 BLE 主机的按键直接转发出去。
 async fn select_event(
     server: &mut crate::mqtt::MqttServer,
-    rx: &mut tokio::sync::mpsc::Receiver<Event>,
-    mic_btn: &mut crate::AnyBtn,
+    input: &mut crate::input::Input,
+    playback: &mut key_template::Playback,
+    ble: Option<(
+        &KeymapConfig,
+        &tokio::sync::mpsc::UnboundedSender<InputEvent>,
+    )>,
 ) -> Option<SelectResult> {
-    tokio::select! {
-        Some(evt) = rx.recv() => Some(SelectResult::Event(evt)),
-        Some(msg) = server.recv() => Some(SelectResult::Mqtt(msg)),
-        _ = mic_btn.wait_for_low() => Some(SelectResult::MicPressed),
-        else => None,
+    loop {
+        tokio::select! {
+            evt = input.next() => {
+                let evt = evt?;
+                if let Some((keymaps, tx)) = ble {
+                    if crate::mode::route(keymaps, &evt) == crate::mode::KeyRoute::Ble {
+                        let _ = tx.send(evt);
+                        continue;
+                    }
+                }
+                match evt {
+                    InputEvent::Press(KeysPin::MIC) => return Some(SelectResult::MicPressed),
+                    evt => {
+                        if let Some(e) = remote_event(evt) {
+                            return Some(SelectResult::Event(e));
+                        }
+                    }
+                }
+            }
+            Some(msg) = server.recv() => return Some(SelectResult::Mqtt(msg)),
+            steps = playback.next() => return Some(SelectResult::Template(steps)),
+        }
     }
 }
 
@@ -80,17 +173,25 @@ async fn send_active_sync(server: &mut crate::mqtt::MqttServer, close: bool) ->
     server.send(msg).await
 }
 
+/// 远程模式主循环。`ble_tx` 为 Some 即混合模式:路由到 BLE 的按键事件发给它,由
+/// main.rs 的 `hybrid_ble_main` 打到主机上;channel 随本函数返回而关闭。
+/// 按下 `mode` 动作时返回下一个模式。
 pub async fn run(
     uri: String,
     client_id: &str,
     ui: &mut crate::lcd::UI,
-    mut rx: crate::audio::EventRx,
+    input: &mut crate::input::Input,
     keymaps: &KeymapConfig,
     asr_tx: std::sync::mpsc::Sender<crate::audio::AsrRequest>,
     asr_config: Option<&crate::audio::AsrConfig>,
-    mic_mode: key_task::MicMode,
-    mic_btn: &mut crate::AnyBtn,
-) -> anyhow::Result<()> {
+    mic_mode: MicMode,
+    ble_tx: Option<tokio::sync::mpsc::UnboundedSender<InputEvent>>,
+) -> anyhow::Result<Option<crate::mode::RunMode>> {
+    let current = match ble_tx {
+        Some(_) => crate::mode::RunMode::Hybrid,
+        None => crate::mode::RunMode::Remote,
+    };
+    let hybrid = ble_tx.as_ref().map(|tx| (keymaps, tx));
     log::info!("Connecting to MQTT broker at {uri} with client_id {client_id}");
     let server = crate::mqtt::MqttServer::new(&uri, client_id).await;
     if let Err(e) = &server {
@@ -110,7 +211,7 @@ pub async fn run(
 
     // 进入 remote 模式直接弹会话列表让用户挑,而不是先自动看活跃会话的屏。
     // 冷启动时 retained presence 还没经 recv() 落进 sessions 表,picker 入口会先等它们到达。
-    let _ = open_session_picker(&mut server, ui, &mut rx, &mut popup, false).await;
+    let _ = open_session_picker(&mut server, ui, input, &mut popup, false).await;
 
     // ASR 文本编辑器:Some = 正在编辑(屏幕显示编辑器,不刷会话屏);None = 空闲。
     // 用 ui::AsrEditor(ui.rs 弹窗风格),不用 lcd::UI 那套(麦克风状态条,风格不一致)。
@@ -149,6 +250,8 @@ pub async fn run(
     let view_windows_height = crate::lcd::DISPLAY_HEIGHT as usize;
     /// 滚轮每格本地平移的像素步长(可调)。
     const SCROLL_STEP_PX: usize = 20;
+    // 文本动作的后台模板回放(`{DELAY:n}` 不卡事件循环);离开本函数时随之取消。
+    let mut playback = key_template::Playback::new();
 
     loop {
         // 把 desired_prefix 落实为 subscribe(必须在 select 之外,不可被取消)。
@@ -158,39 +261,48 @@ pub async fn run(
 
         // 事件获取带轮询超时:无事件时每 POLL_INTERVAL 醒来一次,检查 pending 是否超时
         // (vibetty 到顶/到底不发图 → 翻页请求永远等不到响应,超时清掉才能恢复滚动)。
-        let evt =
-            match tokio::time::timeout(POLL_INTERVAL, select_event(&mut server, &mut rx, mic_btn))
-                .await
-            {
-                Ok(inner) => inner,
-                Err(_) => {
-                    // 超时阈值 = 平均 RTT × 倍数(下限 RTT_TIMEOUT_FLOOR);无样本用默认。
-                    let timeout = rtt_avg
-                        .map(|a| a * RTT_TIMEOUT_MULT)
-                        .unwrap_or(RTT_TIMEOUT_DEFAULT)
-                        .max(RTT_TIMEOUT_FLOOR);
-                    if pending_scroll.is_some()
-                        && pending_since.map_or(false, |t| t.elapsed() >= timeout)
-                    {
-                        log::info!(
-                            "Pending scroll timed out (>{timeout:?}, avg RTT={rtt_avg:?}), clearing"
-                        );
-                        pending_scroll = None;
-                        pending_since = None;
-                        let _ = popup.hide(ui.display_mut());
-                    }
-                    continue;
+        let next_evt = select_event(&mut server, input, &mut playback, hybrid);
+        let evt = match tokio::time::timeout(POLL_INTERVAL, next_evt).await {
+            Ok(inner) => inner,
+            Err(_) => {
+                // 超时阈值 = 平均 RTT × 倍数(下限 RTT_TIMEOUT_FLOOR);无样本用默认。
+                let timeout = rtt_avg
+                    .map(|a| a * RTT_TIMEOUT_MULT)
+                    .unwrap_or(RTT_TIMEOUT_DEFAULT)
+                    .max(RTT_TIMEOUT_FLOOR);
+                if pending_scroll.is_some()
+                    && pending_since.map_or(false, |t| t.elapsed() >= timeout)
+                {
+                    log::info!(
+                        "Pending scroll timed out (>{timeout:?}, avg RTT={rtt_avg:?}), clearing"
+                    );
+                    pending_scroll = None;
+                    pending_since = None;
+                    let _ = popup.hide(ui.display_mut());
                 }
-            };
+                continue;
+            }
+        };
         let Some(evt) = evt else {
             log::warn!("All event sources closed, exiting run loop");
             break;
         };
 
+        // 切换模式的按键/和弦:断线时也要能离开 remote,故放在断线过滤之前。
+        if let SelectResult::Event(e) = &evt {
+            if let Some(bt_keyboard_mode::KeyAction::Mode { mode, .. }) = bound_action(keymaps, e) {
+                if let Some(next) = current.switch_target(mode) {
+                    log::info!("Leaving {:?} mode for {:?}", current, next);
+                    return Ok(Some(next));
+                }
+                continue;
+            }
+        }
+
         // 弹窗收敛:在线则关闭上一轮瞬态弹窗;断线则(重新)显示「下线」弹窗,
         // 让它在无事件期间也持续保持(断线时不会有 MQTT 事件来触发重绘)。
         if disconnected {
-            let _ = popup.show(ui.display_mut(), "MQTT disconnected");
+            let _ = popup.show(ui.display_mut(), offline_text());
         } else {
             let _ = popup.hide(ui.display_mut());
         }
@@ -339,19 +451,22 @@ pub async fn run(
                     if asr_editor.is_some() {
                         continue; // 编辑 ASR 文本时忽略这些键
                     }
-                    if let Some(bytes) = keymaps
-                        .keys
-                        .get(KeymapConfig::KEY_NEXT)
-                        .and_then(|action| key_action_to_ansi(action))
-                    {
-                        server
-                            .send(protocol::ClientMessage::PtyInput(bytes))
-                            .await?;
-                    } else {
+                    let sent = match keymaps.keys.get(KeymapConfig::KEY_NEXT) {
+                        Some(action) => {
+                            send_key_action(
+                                &mut server,
+                                &mut playback,
+                                action,
+                                ui.application_cursor(),
+                            )
+                            .await?
+                        }
+                        None => false,
+                    };
+                    if !sent {
                         // Fallback to default DOWN arrow
-                        server
-                            .send(protocol::ClientMessage::PtyInput(b"\x1b[B".to_vec()))
-                            .await?;
+                        let down = xterm_cursor_key(b'B', ui.application_cursor(), 1);
+                        server.send(protocol::ClientMessage::PtyInput(down)).await?;
                     }
                 }
                 Event::Backspace => {
@@ -370,16 +485,20 @@ pub async fn run(
                     if asr_editor.is_some() {
                         continue; // 编辑 ASR 文本时忽略这些键
                     }
-                    if let Some(bytes) = keymaps
-                        .keys
-                        .get(KeymapConfig::KEY_SWITCH)
-                        .and_then(|action| key_action_to_ansi(action))
-                    {
-                        server
-                            .send(protocol::ClientMessage::PtyInput(bytes))
-                            .await?;
-                    } else {
-                        // Fallback to default DOWN arrow
+                    let sent = match keymaps.keys.get(KeymapConfig::KEY_SWITCH) {
+                        Some(action) => {
+                            send_key_action(
+                                &mut server,
+                                &mut playback,
+                                action,
+                                ui.application_cursor(),
+                            )
+                            .await?
+                        }
+                        None => false,
+                    };
+                    if !sent {
+                        // Fallback to default Shift+Tab
                         server
                             .send(protocol::ClientMessage::PtyInput(b"\x1b[Z".to_vec()))
                             .await?;
@@ -390,27 +509,57 @@ pub async fn run(
                         continue; // 编辑 ASR 文本时忽略这些键
                     }
                     // 旋钮按下:不再发按键,改为弹出会话选择器(NEXT 切换 / ACCEPT 确认 / ESC 取消)。
-                    open_session_picker(&mut server, ui, &mut rx, &mut popup, true).await?;
+                    open_session_picker(&mut server, ui, input, &mut popup, true).await?;
                 }
                 Event::Custom => {
                     if asr_editor.is_some() {
                         continue; // 编辑 ASR 文本时忽略这些键
                     }
-                    if let Some(bytes) = keymaps
-                        .keys
-                        .get(KeymapConfig::KEY_CUSTOM)
-                        .and_then(|action| key_action_to_ansi(action))
-                    {
-                        server
-                            .send(protocol::ClientMessage::PtyInput(bytes))
-                            .await?;
-                    } else {
+                    let sent = match keymaps.keys.get(KeymapConfig::KEY_CUSTOM) {
+                        Some(action) => {
+                            send_key_action(
+                                &mut server,
+                                &mut playback,
+                                action,
+                                ui.application_cursor(),
+                            )
+                            .await?
+                        }
+                        None => false,
+                    };
+                    if !sent {
                         server
                             .send(protocol::ClientMessage::PtyInput(b"/compact".to_vec()))
                             .await?;
                     }
                 }
+                Event::Chord(a, b) => {
+                    if asr_editor.is_some() {
+                        continue; // 编辑 ASR 文本时忽略这些键
+                    }
+                    // 和弦只有 keymap 里绑定了(或默认的 ESC+SWITCH 切模式)才有动作
+                    if let Some(action) = keymaps.chord_action(a, b) {
+                        send_key_action(
+                            &mut server,
+                            &mut playback,
+                            &action,
+                            ui.application_cursor(),
+                        )
+                        .await?;
+                    }
+                }
             },
+            SelectResult::Template(steps) => {
+                let bytes: Vec<u8> = steps
+                    .iter()
+                    .flat_map(|step| template_step_to_ansi(step, ui.application_cursor()))
+                    .collect();
+                if !bytes.is_empty() {
+                    server
+                        .send(protocol::ClientMessage::PtyInput(bytes))
+                        .await?;
+                }
+            }
             SelectResult::Mqtt(ev) => match ev {
                 crate::mqtt::MqttEvent::ActiveScreen(chunk) => {
                     if asr_editor.is_some() {
@@ -509,7 +658,7 @@ pub async fn run(
                         let _ = popup.hide(ui.display_mut());
                         ui.clear_terminal();
                         let _ =
-                            open_session_picker(&mut server, ui, &mut rx, &mut popup, false).await;
+                            open_session_picker(&mut server, ui, input, &mut popup, false).await;
                     }
                 }
                 crate::mqtt::MqttEvent::Disconnected => {
@@ -518,7 +667,7 @@ pub async fn run(
                     pending_scroll = None;
                     pending_since = None;
                     disconnected = true;
-                    let _ = popup.show(ui.display_mut(), "MQTT disconnected");
+                    let _ = popup.show(ui.display_mut(), offline_text());
                 }
                 crate::mqtt::MqttEvent::Reconnected => {
                     log::info!("MQTT reconnected; subscriptions restored");
@@ -530,9 +679,6 @@ pub async fn run(
                 }
             },
             SelectResult::MicPressed => {
-                if !mic_btn.is_low() {
-                    continue; // 误触发/错误,跳过
-                }
                 // ASR 跑在独立 OS 线程(见 main.rs 的 asr-worker):Whisper 流式录音 + 网络往返
                 // 耗时数十秒,绝不能阻塞本 async 任务(否则 single-thread runtime 冻死 →
                 // MQTT conn.next() 不被 poll → backpressure 冻死 broker task →
@@ -543,7 +689,7 @@ pub async fn run(
                     Some(c) => c.clone(),
                     None => {
                         log::warn!("MIC pressed but ASR not configured, ignoring");
-                        let _ = mic_btn.wait_for_high().await; // 等松开,避免重复触发
+                        input.wait_release(KeysPin::MIC).await; // 等松开,避免重复触发
                         continue;
                     }
                 };
@@ -565,7 +711,7 @@ pub async fn run(
                 if asr_tx.send(req).is_err() {
                     // worker 线程没起来 / 已退出。
                     let _ = popup.show(ui.display_mut(), "ASR unavailable");
-                    let _ = mic_btn.wait_for_high().await;
+                    input.wait_release(KeysPin::MIC).await;
                     continue;
                 }
 
@@ -590,19 +736,21 @@ pub async fn run(
                                 ColorFormat::CSS_GREEN,
                             );
                         }
-                        // 停止录音的边沿按麦克风模式分:
-                        //   PTT  → 松手停止(wait_for_high:此时按下为低,等 rising level 即松手);
-                        //   Toggle → 再按一下停止。此时按键仍处于按下(低电平),level 触发的
-                        //            wait_for_low 会立刻返回误取消,故用 edge 触发的
-                        //            wait_for_falling_edge —— 它要等下一次高→低跳变(松手后再按)。
-                        _ = async {
-                            match mic_mode {
-                                key_task::MicMode::PushToTalk => mic_btn.wait_for_high().await,
-                                key_task::MicMode::Toggle => mic_btn.wait_for_falling_edge().await,
+                        // 停止录音的事件按麦克风模式分:
+                        //   PTT  → MIC 松开停止;
+                        //   Toggle → MIC 再按一下停止(本次按下对应的松开不算)。
+                        // 录音期间其它按键直接丢弃。
+                        evt = input.next(), if !released => {
+                            let stop = match (mic_mode, evt) {
+                                (_, None) => true,
+                                (MicMode::PushToTalk, Some(InputEvent::Release(KeysPin::MIC))) => true,
+                                (MicMode::Toggle, Some(InputEvent::Press(KeysPin::MIC))) => true,
+                                _ => false,
+                            };
+                            if stop {
+                                released = true;
+                                cancel.store(true, Ordering::Relaxed);
                             }
-                        }, if !released => {
-                            released = true;
-                            cancel.store(true, Ordering::Relaxed);
                         }
                         // 仅排水保活:不更新 UI,避免覆盖 listening 弹窗。
                         // 连接已断时禁用本分支,免得 recv() 持续返回 None 空转。
@@ -638,7 +786,18 @@ pub async fn run(
         }
     }
 
-    Ok(())
+    Ok(None)
+}
+
+/// 远程模式下按键事件在 keymap 里绑定的动作(只看可绑定的 NEXT / SWITCH / CUSTOM 和和弦)。
+fn bound_action(keymaps: &KeymapConfig, e: &Event) -> Option<bt_keyboard_mode::KeyAction> {
+    match e {
+        Event::Chord(a, b) => keymaps.chord_action(*a, *b),
+        Event::NEXT => keymaps.keys.get(KeymapConfig::KEY_NEXT).cloned(),
+        Event::SwitchMode => keymaps.keys.get(KeymapConfig::KEY_SWITCH).cloned(),
+        Event::Custom => keymaps.keys.get(KeymapConfig::KEY_CUSTOM).cloned(),
+        _ => None,
+    }
 }
 
 /// 选择器内 select! 产出的事件:只负责取事件,真正借用 server 的处理放在下面
@@ -646,7 +805,7 @@ pub async fn run(
 enum PickerEvt {
     Key(Event),
     Mqtt(crate::mqtt::MqttEvent),
-    /// 事件源已关闭(rx / server 已销毁)。
+    /// 事件源已关闭(输入服务 / server 已销毁)。
     Closed,
 }
 
@@ -658,7 +817,7 @@ enum PickerEvt {
 async fn open_session_picker(
     server: &mut crate::mqtt::MqttServer,
     ui: &mut crate::lcd::UI,
-    rx: &mut tokio::sync::mpsc::Receiver<Event>,
+    input: &mut crate::input::Input,
     popup: &mut crate::ui::Popup,
     // `true` = 此刻正在观察活跃屏(中途旋钮按下重开选择器),进入时发 `close=true`
     // 让服务端停推,省得选择器期间推的帧被白白 drain。`false` = 冷启动首次挑选,
@@ -679,7 +838,7 @@ async fn open_session_picker(
             }
             tokio::select! {
                 _ = tokio::time::sleep_until(deadline) => break,
-                ev = rx.recv() => match ev {
+                ev = next_key(input) => match ev {
                     Some(Event::Esc) | None => return Ok(()),
                     _ => {}
                 },
@@ -734,7 +893,7 @@ async fn open_session_picker(
         }
 
         let evt: PickerEvt = tokio::select! {
-            ev = rx.recv() => match ev { Some(e) => PickerEvt::Key(e), None => PickerEvt::Closed },
+            ev = next_key(input) => match ev { Some(e) => PickerEvt::Key(e), None => PickerEvt::Closed },
             m = server.recv() => match m { Some(e) => PickerEvt::Mqtt(e), None => PickerEvt::Closed },
         };
 
@@ -809,429 +968,279 @@ async fn open_session_picker(
 
 /// Convert KeyAction to ANSI escape sequences for terminal input
 ///
+/// Special keys are encoded the way xterm does it: modified cursor / function keys carry
+/// the modifier parameter (`ESC[1;5D` = Ctrl+Left), Shift+Tab is `ESC[Z`, and Alt / Meta
+/// prefix ESC to ordinary characters.
+///
 /// # Arguments
 /// * `action` - The key action to convert
+/// * `app_cursor` - The remote screen is in application cursor mode (DECCKM), so
+///   unmodified arrows / Home / End are sent as SS3 (`ESC O A`) instead of CSI (`ESC [ A`)
 ///
 /// # Returns
 /// * `Some(bytes)` - ANSI bytes to send
-/// * `None` - Nothing to send (unknown key)
-pub fn key_action_to_ansi(action: &bt_keyboard_mode::KeyAction) -> Option<Vec<u8>> {
+/// * `None` - Nothing to send (unknown key, or a keyboard-mode-only action)
+pub fn key_action_to_ansi(
+    action: &bt_keyboard_mode::KeyAction,
+    app_cursor: bool,
+) -> Option<Vec<u8>> {
     use bt_keyboard_mode::KeyAction;
 
     match action {
         KeyAction::Combo { modifiers, key, .. } => {
-            let key_upper = key.to_uppercase();
-            let mut result = Vec::new();
-
-            // Check each modifier and apply corresponding ANSI sequence
-            let mut has_ctrl = false;
-            let mut has_alt = false;
-            let mut has_shift = false;
-
+            let mut mask = 0u8;
             for mod_name in modifiers {
-                match mod_name.as_str() {
-                    "ctrl" => has_ctrl = true,
-                    "shift" => has_shift = true,
-                    "alt" | "option" => has_alt = true,
-                    "meta" | "command" | "cmd" | "win" | "gui" => {
-                        // Meta not supported in ANSI, ignore
-                    }
-                    _ => {}
-                }
-            }
-
-            // Get the base key character
-            let base_char = match key_upper.as_str() {
-                // Letters
-                "A" => Some(b'a'),
-                "B" => Some(b'b'),
-                "C" => Some(b'c'),
-                "D" => Some(b'd'),
-                "E" => Some(b'e'),
-                "F" => Some(b'f'),
-                "G" => Some(b'g'),
-                "H" => Some(b'h'),
-                "I" => Some(b'i'),
-                "J" => Some(b'j'),
-                "K" => Some(b'k'),
-                "L" => Some(b'l'),
-                "M" => Some(b'm'),
-                "N" => Some(b'n'),
-                "O" => Some(b'o'),
-                "P" => Some(b'p'),
-                "Q" => Some(b'q'),
-                "R" => Some(b'r'),
-                "S" => Some(b's'),
-                "T" => Some(b't'),
-                "U" => Some(b'u'),
-                "V" => Some(b'v'),
-                "W" => Some(b'w'),
-                "X" => Some(b'x'),
-                "Y" => Some(b'y'),
-                "Z" => Some(b'z'),
-                // Numbers
-                "0" => Some(b'0'),
-                "1" => Some(b'1'),
-                "2" => Some(b'2'),
-                "3" => Some(b'3'),
-                "4" => Some(b'4'),
-                "5" => Some(b'5'),
-                "6" => Some(b'6'),
-                "7" => Some(b'7'),
-                "8" => Some(b'8'),
-                "9" => Some(b'9'),
-                // Special keys
-                "SPACE" => Some(b' '),
-                "ENTER" | "RETURN" => Some(0x0d),
-                "TAB" => Some(0x09),
-                "ESC" | "ESCAPE" => Some(0x1b),
-                "BACKSPACE" => Some(0x08),
-                "DELETE" | "DEL" => Some(0x7f),
-                // Arrow keys (ANSI escape sequences)
-                "UP" => return Some(b"\x1b[A".to_vec()),
-                "DOWN" => return Some(b"\x1b[B".to_vec()),
-                "RIGHT" => return Some(b"\x1b[C".to_vec()),
-                "LEFT" => return Some(b"\x1b[D".to_vec()),
-                // Function keys
-                "F1" => return Some(b"\x1bOP".to_vec()),
-                "F2" => return Some(b"\x1bOQ".to_vec()),
-                "F3" => return Some(b"\x1bOR".to_vec()),
-                "F4" => return Some(b"\x1bOS".to_vec()),
-                "F5" => return Some(b"\x1b[15~".to_vec()),
-                "F6" => return Some(b"\x1b[17~".to_vec()),
-                "F7" => return Some(b"\x1b[18~".to_vec()),
-                "F8" => return Some(b"\x1b[19~".to_vec()),
-                "F9" => return Some(b"\x1b[20~".to_vec()),
-                "F10" => return Some(b"\x1b[21~".to_vec()),
-                "F11" => return Some(b"\x1b[23~".to_vec()),
-                "F12" => return Some(b"\x1b[24~".to_vec()),
-                // Symbols (basic set)
-                "MINUS" | "-" => Some(b'-'),
-                "EQUAL" | "=" => Some(b'='),
-                "LEFT_BRACKET" | "[" => Some(b'['),
-                "RIGHT_BRACKET" | "]" => Some(b']'),
-                "BACKSLASH" | "\\" => Some(b'\\'),
-                "SEMICOLON" | ";" => Some(b';'),
-                "QUOTE" | "'" => Some(b'\''),
-                "COMMA" | "," => Some(b','),
-                "PERIOD" | "." => Some(b'.'),
-                "SLASH" | "/" => Some(b'/'),
-                "GRAVE" | "`" => Some(b'`'),
-                _ => {
-                    log::warn!("Unknown key for ANSI conversion: {}", key);
-                    None
-                }
-            };
-
-            if let Some(mut ch) = base_char {
-                // Apply shift modifier (for letters and symbols)
-                if has_shift && ch.is_ascii_lowercase() {
-                    ch = ch.to_ascii_uppercase();
-                }
-
-                // Handle Ctrl modifier - sends control character (0x00-0x1F)
-                // Ctrl+A = 0x01, Ctrl+B = 0x02, ..., Ctrl+Z = 0x1A
-                if has_ctrl {
-                    if ch.is_ascii_lowercase() || ch.is_ascii_uppercase() {
-                        // A-Z maps to 0x01-0x1A
-                        ch = ch.to_ascii_uppercase();
-                        ch = ch - b'@'; // A=0x41, 0x41-0x40=0x01
-                    } else if ch == b' ' {
-                        ch = 0x00; // Ctrl+Space = NUL
-                    }
-                    result.push(ch);
-                }
-
-                // Handle Alt modifier - sends ESC prefix + character
-                // Alt+T = ESC + t
-                if has_alt {
-                    result.push(0x1b); // ESC
-                    result.push(ch);
-                }
-
-                // If no modifiers or only shift, just send the character
-                if !has_ctrl && !has_alt {
-                    result.push(ch);
-                }
-            }
-
-            if result.is_empty() {
-                None
-            } else {
-                Some(result)
+                mask |= bt_keyboard_mode::modifier_name_to_mask(mod_name).unwrap_or(0);
             }
+            combo_to_ansi(mask, key, app_cursor)
         }
-        KeyAction::Text { value, .. } => Some(value.as_bytes().to_vec()),
+        // `{DELAY:n}` steps are dropped here, `send_key_action` honors them
+        KeyAction::Text { steps, .. } => Some(
+            steps
+                .iter()
+                .flat_map(|step| template_step_to_ansi(step, app_cursor))
+                .collect(),
+        ),
+        // BLE host slots only exist in keyboard mode; mode switches are handled by `run`
+        KeyAction::Host { .. } | KeyAction::Mode { .. } => None,
     }
 }
 
-/// 合并所有非 MIC 按钮到一个 select! 循环里,跑在专用线程上(释放主 runtime 的 8 个 task)。
-/// 任一按钮边沿 → 发对应 Event → 去抖 sleep。Backspace 长按连发;旋钮 A 任一边沿做正交解码。
-pub async fn listen_all_keys(
-    mut btn_custom: crate::AnyBtn,    // btn2
-    mut btn_next: crate::AnyBtn,      // btn4
-    mut btn_backspace: crate::AnyBtn, // btn5
-    mut btn_switch: crate::AnyBtn,    // btn6
-    mut btn_esc: crate::AnyBtn,       // btn3
-    mut btn_accept: crate::AnyBtn,    // btn7
-    mut rot_a: crate::AnyBtn,         // pin16
-    rot_b: crate::AnyBtn,             // pin17(只读电平做正交)
-    mut rot_push: crate::AnyBtn,      // pin18
-    tx: crate::audio::EventTx,
-) -> anyhow::Result<()> {
-    use std::time::Duration;
-    // 消抖参数:边沿后等 N ms 再确认 is_low(),过滤机械抖动。
-    const KEY_DEBOUNCE: Duration = Duration::from_millis(30);
-    const ROTATE_DEBOUNCE: Duration = Duration::from_millis(30);
-    // Backspace 长按连发:首次延迟 + 连发间隔。
-    const BACKSPACE_REPEAT_DELAY: Duration = Duration::from_millis(200);
-    const BACKSPACE_REPEAT_INTERVAL: Duration = Duration::from_millis(100);
-    loop {
-        tokio::select! {
-            biased;
-            _ = btn_custom.wait_for_falling_edge() => {
-                tokio::time::sleep(KEY_DEBOUNCE).await;
-                if !btn_custom.is_low() {
-                    continue
-                }
-                let _ = tx.send(Event::Custom).await;
-            }
-            _ = btn_next.wait_for_falling_edge() => {
-                tokio::time::sleep(KEY_DEBOUNCE).await;
-                if !btn_next.is_low() {
-                    continue
-                }
-                let _ = tx.send(Event::NEXT).await;
-            }
-            _ = btn_switch.wait_for_falling_edge() => {
-                tokio::time::sleep(KEY_DEBOUNCE).await;
-                if !btn_switch.is_low() {
-                    continue
-                }
-                let _ = tx.send(Event::SwitchMode).await;
-            }
-            _ = btn_esc.wait_for_falling_edge() => {
-                tokio::time::sleep(KEY_DEBOUNCE).await;
-                if !btn_esc.is_low() {
-                    continue
-                }
-                let _ = tx.send(Event::Esc).await;
-            }
-            _ = btn_accept.wait_for_falling_edge() => {
-                tokio::time::sleep(KEY_DEBOUNCE).await;
-                if !btn_accept.is_low() {
-                    continue
-                }
-                let _ = tx.send(Event::Accept).await;
-            }
-            _ = rot_push.wait_for_falling_edge() => {
-                tokio::time::sleep(KEY_DEBOUNCE).await;
-                if !rot_push.is_low() {
-                    continue
-                }
-                let _ = tx.send(Event::RotatePush).await;
-            }
-            // Backspace:首次 + 长按连发(每 BACKSPACE_REPEAT_INTERVAL 一次,直到松开)。
-            _ = btn_backspace.wait_for_falling_edge() => {
-                tokio::time::sleep(KEY_DEBOUNCE).await;
-                if !btn_backspace.is_low() {
-                    continue
-                }
-                let _ = tx.send(Event::Backspace).await;
-                tokio::time::sleep(BACKSPACE_REPEAT_DELAY).await;
-                while btn_backspace.is_low() {
-                    let _ = tx.send(Event::Backspace).await;
-                    tokio::time::sleep(BACKSPACE_REPEAT_INTERVAL).await;
-                }
-            }
-            // 旋钮 A 任一边沿:正交解码。A 高→B 高=Up/B 低=Down;A 低→B 低=Up/B 高=Down。
-            _ = rot_a.wait_for_any_edge() => {
-                let up = if rot_a.is_high() { rot_b.is_high() } else { rot_b.is_low() };
-                let _ = tx.send(if up { Event::RotateUp } else { Event::RotateDown }).await;
-                tokio::time::sleep(ROTATE_DEBOUNCE).await;
-            }
-        }
+/// 断线弹窗文字:WiFi 掉线时 MQTT 必然断开,提示正在重连 WiFi 更准确
+fn offline_text() -> &'static str {
+    if crate::wifi::link_state().connected {
+        "MQTT disconnected"
+    } else {
+        "WiFi reconnecting..."
     }
 }
 
-#[allow(dead_code)]
-pub mod key_task {
-
-    pub async fn esc_key(btn: crate::AnyBtn, tx: crate::audio::EventTx) -> anyhow::Result<()> {
-        listen_key_event(btn, tx, super::Event::Esc).await
-    }
-
-    pub async fn accept_key(btn: crate::AnyBtn, tx: crate::audio::EventTx) -> anyhow::Result<()> {
-        listen_key_event(btn, tx, super::Event::Accept).await
-    }
-
-    pub async fn rotate_key(
-        mut btn_a: crate::AnyBtn,
-        btn_b: crate::AnyBtn,
-        tx: crate::audio::EventTx,
-    ) -> anyhow::Result<()> {
-        loop {
-            if let Err(_) = btn_a.wait_for_any_edge().await {
-                return Err(anyhow::anyhow!("Failed to wait for button edge"));
-            }
-
-            if let Err(_) = if btn_a.is_high() {
-                if btn_b.is_low() {
-                    tx.send(super::Event::RotateDown)
-                } else {
-                    tx.send(super::Event::RotateUp)
-                }
-            } else {
-                if btn_b.is_low() {
-                    tx.send(super::Event::RotateUp)
-                } else {
-                    tx.send(super::Event::RotateDown)
-                }
-            }
-            .await
-            {
-                return Err(anyhow::anyhow!("Failed to send rotate event"));
-            }
+/// Bytes for one template step (delays produce nothing, nor do date/time while the clock
+/// is unset)
+fn template_step_to_ansi(step: &key_template::TemplateStep, app_cursor: bool) -> Vec<u8> {
+    match step {
+        key_template::TemplateStep::Key { modifiers, key } => {
+            combo_to_ansi(*modifiers, key, app_cursor).unwrap_or_default()
         }
+        step if step.needs_clock() && !key_template::clock_is_set() => {
+            log::warn!("{{DATE}}/{{TIME}} skipped: clock not synced");
+            Vec::new()
+        }
+        step => step
+            .text()
+            .map(|text| text.as_bytes().to_vec())
+            .unwrap_or_default(),
     }
+}
 
-    pub async fn rotate_push_key(
-        btn: crate::AnyBtn,
-        tx: crate::audio::EventTx,
-    ) -> anyhow::Result<()> {
-        listen_key_event(btn, tx, super::Event::RotatePush).await
-    }
+/// Send a key action to the active PTY. A text action's steps after its first
+/// `{DELAY:n}` are left to `playback` and sent when they come due (`SelectResult::Template`).
+/// Returns false when the action produced no input (e.g. unknown key name).
+async fn send_key_action(
+    server: &mut crate::mqtt::MqttServer,
+    playback: &mut key_template::Playback,
+    action: &bt_keyboard_mode::KeyAction,
+    app_cursor: bool,
+) -> anyhow::Result<bool> {
+    let bt_keyboard_mode::KeyAction::Text { steps, .. } = action else {
+        let Some(bytes) = key_action_to_ansi(action, app_cursor) else {
+            return Ok(false);
+        };
+        server
+            .send(protocol::ClientMessage::PtyInput(bytes))
+            .await?;
+        return Ok(true);
+    };
 
-    #[repr(u8)]
-    #[derive(Clone, Copy)]
-    pub enum MicMode {
-        PushToTalk,
-        Toggle,
+    let produces_input = steps
+        .iter()
+        .any(|step| !matches!(step, key_template::TemplateStep::Delay(_)));
+    let now: Vec<u8> = playback
+        .start(steps)
+        .iter()
+        .flat_map(|step| template_step_to_ansi(step, app_cursor))
+        .collect();
+    if !now.is_empty() {
+        server.send(protocol::ClientMessage::PtyInput(now)).await?;
     }
+    Ok(produces_input)
+}
 
-    impl Default for MicMode {
-        fn default() -> Self {
-            Self::Toggle
-        }
+/// xterm bytes for `key` pressed with the HID modifier `mask`
+fn combo_to_ansi(mask: u8, key: &str, app_cursor: bool) -> Option<Vec<u8>> {
+    let has_ctrl = mask & 0x01 != 0;
+    let mut has_shift = mask & 0x02 != 0;
+    let has_alt = mask & 0x04 != 0;
+    let has_meta = mask & 0x08 != 0;
+
+    // xterm reports F13-F24 as Shift+F1..F12
+    let mut key_upper = key.to_uppercase();
+    if let Some(n @ 13..=24) = key_upper
+        .strip_prefix('F')
+        .and_then(|n| n.parse::<u8>().ok())
+    {
+        key_upper = format!("F{}", n - 12);
+        has_shift = true;
     }
 
-    impl From<u8> for MicMode {
-        fn from(value: u8) -> Self {
-            match value {
-                0 => Self::PushToTalk,
-                1 => Self::Toggle,
-                _ => {
-                    log::warn!(
-                        "Invalid mic mode value: {}, defaulting to PushToTalk",
-                        value
-                    );
-                    Self::PushToTalk
-                }
+    // xterm modifier parameter: 1 + Shift(1) + Alt(2) + Ctrl(4) + Meta(8)
+    let m = 1 + has_shift as u8 + 2 * has_alt as u8 + 4 * has_ctrl as u8 + 8 * has_meta as u8;
+
+    let sequence = match key_upper.as_str() {
+        // Cursor keys follow application cursor mode
+        "UP" => Some(xterm_cursor_key(b'A', app_cursor, m)),
+        "DOWN" => Some(xterm_cursor_key(b'B', app_cursor, m)),
+        "RIGHT" => Some(xterm_cursor_key(b'C', app_cursor, m)),
+        "LEFT" => Some(xterm_cursor_key(b'D', app_cursor, m)),
+        "HOME" => Some(xterm_cursor_key(b'H', app_cursor, m)),
+        "END" => Some(xterm_cursor_key(b'F', app_cursor, m)),
+        // F1-F4 are always SS3 when unmodified
+        "F1" => Some(xterm_cursor_key(b'P', true, m)),
+        "F2" => Some(xterm_cursor_key(b'Q', true, m)),
+        "F3" => Some(xterm_cursor_key(b'R', true, m)),
+        "F4" => Some(xterm_cursor_key(b'S', true, m)),
+        // Editing keypad and F5-F12
+        "INSERT" | "INS" => Some(xterm_tilde_key(2, m)),
+        "DELETE" | "DEL" => Some(xterm_tilde_key(3, m)),
+        "PAGEUP" | "PAGE_UP" | "PGUP" => Some(xterm_tilde_key(5, m)),
+        "PAGEDOWN" | "PAGE_DOWN" | "PGDN" => Some(xterm_tilde_key(6, m)),
+        "F5" => Some(xterm_tilde_key(15, m)),
+        "F6" => Some(xterm_tilde_key(17, m)),
+        "F7" => Some(xterm_tilde_key(18, m)),
+        "F8" => Some(xterm_tilde_key(19, m)),
+        "F9" => Some(xterm_tilde_key(20, m)),
+        "F10" => Some(xterm_tilde_key(21, m)),
+        "F11" => Some(xterm_tilde_key(23, m)),
+        "F12" => Some(xterm_tilde_key(24, m)),
+        // Back-tab
+        "TAB" if has_shift && !has_ctrl => {
+            let mut seq = Vec::new();
+            if has_alt || has_meta {
+                seq.push(0x1b);
             }
+            seq.extend_from_slice(b"\x1b[Z");
+            Some(seq)
         }
+        _ => None,
+    };
+    if sequence.is_some() {
+        return sequence;
     }
 
-    async fn toggle_mic_key(mut btn: crate::AnyBtn) -> anyhow::Result<()> {
-        loop {
-            if let Err(e) = btn.wait_for_falling_edge().await {
-                log::error!("Button interrupt error: {:?}", e);
-                return Err(anyhow::anyhow!("Failed to wait for mic button edge"));
-            }
-
-            if !btn.is_low() {
-                continue;
+    // Get the base key character
+    let base_char = match key_upper.as_str() {
+        // Special keys
+        "ENTER" | "RETURN" | "KP_ENTER" => Some(0x0d),
+        "TAB" => Some(0x09),
+        "ESC" | "ESCAPE" => Some(0x1b),
+        "BACKSPACE" => Some(0x08),
+        // Letters, digits, symbols (incl. shifted aliases) and keypad keys:
+        // same names as keyboard mode, see key_name_to_hid_code
+        _ => match bt_keyboard_mode::key_name_to_char(key) {
+            Some(ch) => Some(ch),
+            None => {
+                log::warn!("Unknown key for ANSI conversion: {}", key);
+                None
             }
+        },
+    };
 
-            let r = crate::audio::MIC_ON.fetch_not(std::sync::atomic::Ordering::Relaxed);
-            log::info!("Button pressed, mic state changed to: {}", !r);
-
-            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
-        }
+    let mut ch = base_char?;
+    // Apply shift modifier (for letters)
+    if has_shift && ch.is_ascii_lowercase() {
+        ch = ch.to_ascii_uppercase();
     }
 
-    async fn push_to_talk_mic_key(mut btn: crate::AnyBtn) -> anyhow::Result<()> {
-        loop {
-            if let Err(e) = btn.wait_for_any_edge().await {
-                log::error!("Button interrupt error: {:?}", e);
-                return Err(anyhow::anyhow!("Failed to wait for mic button edge"));
-            }
-
-            let is_pressed = btn.is_low();
-            crate::audio::MIC_ON.store(is_pressed, std::sync::atomic::Ordering::Relaxed);
-            log::info!(
-                "Mic button state changed, mic is now {}",
-                if is_pressed { "ON" } else { "OFF" }
-            );
-        }
+    // Handle Ctrl modifier - sends control character (0x00-0x1F)
+    // Ctrl+A = 0x01, ..., Ctrl+Z = 0x1A, plus xterm's Ctrl+Space/[/\/]/^/_/?
+    if has_ctrl {
+        ch = match ch {
+            b'a'..=b'z' | b'A'..=b'Z' => ch.to_ascii_uppercase() - b'@',
+            b' ' | b'@' | b'2' => 0x00,
+            b'[' | b'3' => 0x1b,
+            b'\\' | b'4' => 0x1c,
+            b']' | b'5' => 0x1d,
+            b'^' | b'6' => 0x1e,
+            b'_' | b'/' | b'7' => 0x1f,
+            b'?' | b'8' => 0x7f,
+            _ => ch,
+        };
     }
 
-    pub async fn mic_key(btn: crate::AnyBtn, mode: MicMode) -> anyhow::Result<()> {
-        match mode {
-            MicMode::PushToTalk => push_to_talk_mic_key(btn).await,
-            MicMode::Toggle => toggle_mic_key(btn).await,
-        }
+    // Alt / Meta - ESC prefix (xterm metaSendsEscape), e.g. Alt+T = ESC t
+    let mut result = Vec::new();
+    if has_alt || has_meta {
+        result.push(0x1b);
     }
+    result.push(ch);
+    Some(result)
+}
 
-    pub async fn backspace_key(
-        mut btn: crate::AnyBtn,
-        tx: crate::audio::EventTx,
-    ) -> anyhow::Result<()> {
-        let port = btn.pin();
-        loop {
-            if let Err(e) = btn.wait_for_falling_edge().await {
-                log::error!("Button interrupt error: {:?}", e);
-                return Err(anyhow::anyhow!("Failed to wait for button K{port} edge"));
-            }
-            if !btn.is_low() {
-                continue;
-            }
-
-            log::info!("Button K{port} pressed");
-            if let Err(_) = tx.send(crate::app::Event::Backspace).await {
-                return Err(anyhow::anyhow!("Failed to send K{port} event"));
-            }
+/// Cursor-style key: `ESC [ A` / `ESC O A`, or `ESC [ 1 ; m A` with modifiers
+fn xterm_cursor_key(final_byte: u8, ss3: bool, modifier: u8) -> Vec<u8> {
+    if modifier > 1 {
+        format!("\x1b[1;{}{}", modifier, final_byte as char).into_bytes()
+    } else if ss3 {
+        vec![0x1b, b'O', final_byte]
+    } else {
+        vec![0x1b, b'[', final_byte]
+    }
+}
 
-            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+/// Tilde-style key: `ESC [ n ~`, or `ESC [ n ; m ~` with modifiers
+fn xterm_tilde_key(code: u8, modifier: u8) -> Vec<u8> {
+    if modifier > 1 {
+        format!("\x1b[{};{}~", code, modifier).into_bytes()
+    } else {
+        format!("\x1b[{}~", code).into_bytes()
+    }
+}
 
-            if btn.is_low() {
-                loop {
-                    if !btn.is_low() {
-                        break;
-                    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                    log::info!("Button K{port} pressed");
-                    if let Err(_) = tx.send(crate::app::Event::Backspace).await {
-                        return Err(anyhow::anyhow!("Failed to send K{port} event"));
-                    }
+    fn combo(modifiers: &[&str], key: &str, app_cursor: bool) -> Vec<u8> {
+        let action = bt_keyboard_mode::KeyAction::Combo {
+            raw: String::new(),
+            modifiers: modifiers.iter().map(|m| m.to_string()).collect(),
+            key: key.to_string(),
+        };
+        key_action_to_ansi(&action, app_cursor).unwrap()
+    }
 
-                    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
-                }
-            }
-        }
+    #[test]
+    fn modified_special_keys_use_xterm_encoding() {
+        assert_eq!(combo(&["ctrl"], "LEFT", false), b"\x1b[1;5D");
+        assert_eq!(combo(&["shift"], "TAB", false), b"\x1b[Z");
+        assert_eq!(combo(&["shift"], "F5", false), b"\x1b[15;2~");
+        assert_eq!(combo(&["ctrl"], "DELETE", false), b"\x1b[3;5~");
+        assert_eq!(combo(&[], "F13", false), b"\x1b[1;2P");
     }
 
-    pub async fn listen_key_event(
-        mut btn: crate::AnyBtn,
-        tx: crate::audio::EventTx,
-        event: super::Event,
-    ) -> anyhow::Result<()> {
-        let port = btn.pin();
-        loop {
-            if let Err(e) = btn.wait_for_falling_edge().await {
-                log::error!("Button interrupt error: {:?}", e);
-                return Err(anyhow::anyhow!("Failed to wait for button K{port} edge"));
-            }
+    #[test]
+    fn application_cursor_mode_uses_ss3() {
+        assert_eq!(combo(&[], "UP", false), b"\x1b[A");
+        assert_eq!(combo(&[], "UP", true), b"\x1bOA");
+        // modified arrows are CSI either way
+        assert_eq!(combo(&["alt"], "UP", true), b"\x1b[1;3A");
+    }
 
-            // 不再做 `is_low()` 二次确认:下降沿已由 ISR 捕获 = 真实按下。单线程 runtime
-            // 被(全屏 flush 等)阻塞时,处理会延迟到松手之后,此时 is_low() 为 false 会把
-            // 真实按下误判为抖动丢弃 → text 流期间按旋钮打不开 session list。改用 ISR 边沿
-            // 触发,下面的 sleep 做去抖。
-            log::info!("Button K{port} pressed");
-            if let Err(_) = tx.send(event.clone()).await {
-                return Err(anyhow::anyhow!("Failed to send K{port} event"));
-            }
+    #[test]
+    fn text_templates_share_keyboard_semantics() {
+        let action = bt_keyboard_mode::KeyAction::text(
+            String::new(),
+            "/compact{ENTER}{DELAY:10}{CTRL+C}".to_string(),
+        );
+        assert_eq!(
+            key_action_to_ansi(&action, false).unwrap(),
+            b"/compact\r\x03"
+        );
+    }
 
-            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-        }
+    #[test]
+    fn meta_and_alt_prefix_escape() {
+        assert_eq!(combo(&["meta"], "x", false), b"\x1bx");
+        assert_eq!(combo(&["ctrl", "alt"], "c", false), b"\x1b\x03");
     }
 }
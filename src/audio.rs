@@ -341,6 +341,55 @@ impl AsrConfig {
     }
 }
 
+/// 自建 ASR 服务的 CA 证书(PEM),经 BLE 分块传输写入(见 `crate::transfer`)。
+/// NVS 里存带结尾 NUL 的 PEM,可直接交给 `X509::pem_until_nul`。
+const CA_CERT_KEY: &str = "ca_cert";
+
+/// 启动时从 NVS 载入的 CA 证书;设置后 Whisper 客户端只信任它,不再用内置证书包。
+static CA_CERT: std::sync::OnceLock<&'static [u8]> = std::sync::OnceLock::new();
+
+pub fn is_pem_cert(data: &[u8]) -> bool {
+    std::str::from_utf8(data)
+        .map(|s| s.trim_start().starts_with("-----BEGIN CERTIFICATE-----"))
+        .unwrap_or(false)
+}
+
+/// 保存 CA 证书,重启后生效。
+pub fn save_ca_cert(nvs: &mut esp_idf_svc::nvs::EspDefaultNvs, pem: &[u8]) -> anyhow::Result<()> {
+    if !is_pem_cert(pem) {
+        anyhow::bail!("not a PEM certificate");
+    }
+    let mut buf = pem.to_vec();
+    if buf.last() != Some(&0) {
+        buf.push(0);
+    }
+    nvs.set_blob(CA_CERT_KEY, &buf)?;
+    Ok(())
+}
+
+/// 启动时调用一次,把 NVS 里的 CA 证书交给之后创建的 Whisper 客户端。
+pub fn load_ca_cert(nvs: &esp_idf_svc::nvs::EspDefaultNvs) {
+    let len = match nvs.blob_len(CA_CERT_KEY) {
+        Ok(Some(len)) if len > 0 => len,
+        _ => return,
+    };
+    let mut buf = vec![0u8; len];
+    match nvs.get_blob(CA_CERT_KEY, &mut buf) {
+        Ok(Some(pem)) if pem.last() == Some(&0) => {
+            log::info!("Using custom CA certificate ({} bytes)", pem.len());
+            let pem: &'static [u8] = Box::leak(pem.to_vec().into_boxed_slice());
+            let _ = CA_CERT.set(pem);
+        }
+        Ok(_) => log::warn!("Stored CA certificate is invalid, ignoring"),
+        Err(e) => log::error!("Failed to read CA certificate: {:?}", e),
+    }
+}
+
+/// 启动时载入的 CA 证书(带结尾 NUL 的 PEM);企业网 EAP 也用它校验认证服务器。
+pub fn ca_cert() -> Option<&'static [u8]> {
+    CA_CERT.get().copied()
+}
+
 /// `app_fut` → ASR worker 线程的一次识别请求。
 ///
 /// ASR(Whisper 流式录音 + 网络往返)是长阻塞调用,不能跑在 single-thread async
@@ -465,10 +514,17 @@ impl Driver {
             esp_idf_svc::sys::esp_crt_bundle_attach(conf)
         }
 
-        let config = esp_idf_svc::http::client::Configuration {
-            crt_bundle_attach: Some(wrap_esp_crt_bundle_attach),
-            keep_alive_enable: true,
-            ..Default::default()
+        let config = match CA_CERT.get() {
+            Some(pem) => esp_idf_svc::http::client::Configuration {
+                server_certificate: Some(esp_idf_svc::tls::X509::pem_until_nul(pem)),
+                keep_alive_enable: true,
+                ..Default::default()
+            },
+            None => esp_idf_svc::http::client::Configuration {
+                crt_bundle_attach: Some(wrap_esp_crt_bundle_attach),
+                keep_alive_enable: true,
+                ..Default::default()
+            },
         };
         let conn = esp_idf_svc::http::client::EspHttpConnection::new(&config)?;
         let client = embedded_svc::http::client::Client::wrap(conn);
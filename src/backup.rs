@@ -0,0 +1,346 @@
+//! Whole-device configuration as one versioned JSON document.
+//!
+//! The settings live under separate NVS keys; a backup collects them into a single
+//! [`ConfigDocument`] that can be saved on the host and restored later, over BLE (framed
+//! transfer, see `crate::transfer`) or the OTA HTTP server:
+//!
+//! ```json
+//! {"format":"vibekeys-config","version":1,"firmware":"0.4.0","secrets":false,
+//!  "settings":{"wifi_list":[{"ssid":"home","pass":"********"}],"server_url":"mqtt://…",
+//!              "mic_model":1,"prefer_builtin_asr":true,"ble_name":"","ble_appearance":961,
+//!              "open_pairing":true,"secure_config":false,"boot_mode":"menu"},
+//!  "asr_config":{"platform":"Whisper","uri":"…","api_key":"********","model":"…"},
+//!  "keymap":{"ESC":{"type":"text","raw":"x","value":"x"}},
+//!  "background":"<base64>"}
+//! ```
+//!
+//! Without secrets, passwords and the ASR `api_key` are exported as `SECRET_MASK`; restoring a
+//! masked value keeps what the device has stored. Bonds and host slots belong to one device
+//! and are not part of a backup. A restore is checked as a whole ([`ConfigDocument::validate`]
+//! here, plus what needs the device, in `Setting::restore`) before anything is written.
+
+use serde::{Deserialize, Serialize};
+
+use crate::bt_keyboard_mode::KeymapConfig;
+use crate::bt_wifi_mode::{validate_wifi_list, BootMode, WifiCred};
+
+pub const FORMAT: &str = "vibekeys-config";
+/// Document version written by this firmware; older versions are still read
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SettingsSection {
+    #[serde(default)]
+    pub wifi_list: Vec<WifiCred>,
+    #[serde(default)]
+    pub server_url: String,
+    #[serde(default = "default_mic_model")]
+    pub mic_model: u8,
+    #[serde(default = "default_true")]
+    pub prefer_builtin_asr: bool,
+    /// Empty = default name
+    #[serde(default)]
+    pub ble_name: String,
+    #[serde(default = "default_appearance")]
+    pub ble_appearance: u16,
+    #[serde(default = "default_true")]
+    pub open_pairing: bool,
+    #[serde(default)]
+    pub secure_config: bool,
+    #[serde(default)]
+    pub boot_mode: BootMode,
+}
+
+fn default_mic_model() -> u8 {
+    1
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_appearance() -> u16 {
+    crate::bt_keyboard_mode::DEFAULT_APPEARANCE
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigDocument {
+    pub format: String,
+    pub version: u32,
+    /// Firmware that wrote the document, informational
+    #[serde(default)]
+    pub firmware: String,
+    /// Whether secrets are included in clear
+    #[serde(default)]
+    pub secrets: bool,
+    pub settings: SettingsSection,
+    #[serde(default)]
+    pub asr_config: Option<serde_json::Value>,
+    /// Bindings as stored; `None` leaves the device's keymap alone
+    #[serde(default)]
+    pub keymap: Option<KeymapConfig>,
+    /// Base64 background image; `None` leaves the device's background alone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background: Option<String>,
+}
+
+impl ConfigDocument {
+    pub fn new(settings: SettingsSection, secrets: bool) -> Self {
+        Self {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            firmware: env!("CARGO_PKG_VERSION").to_string(),
+            secrets,
+            settings,
+            asr_config: None,
+            keymap: None,
+            background: None,
+        }
+    }
+
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        let doc: Self = serde_json::from_str(json)?;
+        doc.validate()?;
+        Ok(doc)
+    }
+
+    /// Everything that can be checked without the device. Errors name the offending field.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.format != FORMAT {
+            anyhow::bail!("format: expected {:?}, got {:?}", FORMAT, self.format);
+        }
+        if self.version == 0 || self.version > VERSION {
+            anyhow::bail!(
+                "version: {} is not supported (max {})",
+                self.version,
+                VERSION
+            );
+        }
+        let s = &self.settings;
+        validate_wifi_list(&s.wifi_list)
+            .map_err(|e| anyhow::anyhow!("settings.wifi_list: {}", e))?;
+        if s.mic_model > 1 {
+            anyhow::bail!(
+                "settings.mic_model: {} is not 0 (PTT) or 1 (Toggle)",
+                s.mic_model
+            );
+        }
+        if !s.ble_name.is_empty() && !crate::bt_keyboard_mode::is_valid_device_name(&s.ble_name) {
+            anyhow::bail!("settings.ble_name: invalid name {:?}", s.ble_name);
+        }
+        if let Some(asr) = &self.asr_config {
+            if !asr.is_object() {
+                anyhow::bail!("asr_config: expected an object");
+            }
+        }
+        if let Some(keymap) = &self.keymap {
+            let errors = keymap.validate();
+            if let Some(e) = errors.first() {
+                anyhow::bail!("keymap.{}: {:?}", e.key, e.code);
+            }
+        }
+        if let Some(bg) = &self.background {
+            base64_decode(bg).ok_or_else(|| anyhow::anyhow!("background: invalid base64"))?;
+        }
+        Ok(())
+    }
+
+    /// Decoded background image, if the document carries one
+    pub fn background_bytes(&self) -> Option<Vec<u8>> {
+        self.background.as_deref().and_then(base64_decode)
+    }
+}
+
+const B64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 with padding
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(B64[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Inverse of [`base64_encode`]; whitespace is ignored
+pub fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    let quads = digits.chunks_exact(4);
+    if !quads.remainder().is_empty() {
+        return None;
+    }
+    let mut out = Vec::with_capacity(digits.len() / 4 * 3);
+    for quad in quads {
+        let pad = quad.iter().rev().take_while(|&&b| b == b'=').count();
+        if pad > 2 {
+            return None;
+        }
+        let mut n = 0u32;
+        for &b in &quad[..4 - pad] {
+            let v = B64.iter().position(|&c| c == b)? as u32;
+            n = n << 6 | v;
+        }
+        n <<= 6 * pad as u32;
+        let bytes = [(n >> 16) as u8, (n >> 8) as u8, n as u8];
+        out.extend_from_slice(&bytes[..3 - pad]);
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> SettingsSection {
+        serde_json::from_str(r#"{"wifi_list":[{"ssid":"home","pass":"secret"}]}"#).unwrap()
+    }
+
+    #[test]
+    fn base64_round_trip() {
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foob", b"fooba", b"foobar"] {
+            let s = base64_encode(data);
+            assert_eq!(base64_decode(&s).as_deref(), Some(data));
+        }
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_decode("Zm9v\nYmE="), Some(b"fooba".to_vec()));
+        assert_eq!(base64_decode("Zm9"), None);
+        assert_eq!(base64_decode("Zm!v"), None);
+    }
+
+    #[test]
+    fn document_round_trip() {
+        let mut doc = ConfigDocument::new(settings(), true);
+        doc.keymap = Some(
+            KeymapConfig::from_json(r#"{"ESC":{"type":"text","raw":"x","value":"x"}}"#).unwrap(),
+        );
+        doc.background = Some(base64_encode(b"\x89PNG"));
+        let json = serde_json::to_string(&doc).unwrap();
+        let back = ConfigDocument::from_json(&json).unwrap();
+        assert_eq!(back.settings.wifi_list[0].pass, "secret");
+        assert_eq!(back.settings.mic_model, 1);
+        assert!(back.settings.prefer_builtin_asr);
+        assert_eq!(back.background_bytes(), Some(b"\x89PNG".to_vec()));
+        assert!(back.keymap.unwrap().keys.contains_key("ESC"));
+    }
+
+    #[test]
+    fn mic_model_must_be_known() {
+        let mut s = settings();
+        s.mic_model = 7;
+        let err = ConfigDocument::new(s, true).validate().unwrap_err();
+        assert!(err.to_string().starts_with("settings.mic_model:"));
+    }
+
+    #[test]
+    fn extended_wifi_creds() {
+        let s: SettingsSection = serde_json::from_str(
+            r#"{"wifi_list":[
+                {"ssid":"home","pass":"secret"},
+                {"ssid":"lab","pass":"x","hidden":true,
+                 "static_ip":{"ip":"10.0.0.5","gateway":"10.0.0.1","dns":"1.1.1.1"}},
+                {"ssid":"corp","pass":"pw","eap":{"identity":"anonymous","username":"alice"}}]}"#,
+        )
+        .unwrap();
+        let doc = ConfigDocument::new(s, true);
+        doc.validate().unwrap();
+        let [home, lab, corp] = &doc.settings.wifi_list[..] else {
+            panic!("expected 3 creds")
+        };
+        let ip = lab.static_ip.as_ref().unwrap();
+        assert!(lab.hidden && ip.prefix == 24 && ip.dns2.is_none());
+        assert_eq!(ip.gateway, std::net::Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(corp.eap.as_ref().unwrap().username, "alice");
+        // plain entries keep their old, short JSON form
+        assert_eq!(
+            serde_json::to_string(home).unwrap(),
+            r#"{"ssid":"home","pass":"secret"}"#
+        );
+        assert!(lab.in_range(&[]) && !home.in_range(&[]));
+
+        let mut bad = lab.clone();
+        bad.static_ip.as_mut().unwrap().prefix = 33;
+        assert!(bad.validate().unwrap_err().to_string().contains("prefix"));
+
+        let long = "x".repeat(crate::bt_wifi_mode::MAX_EAP_FIELD_LEN + 1);
+        let mut bad = corp.clone();
+        bad.eap.as_mut().unwrap().username = long.clone();
+        assert!(bad
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("eap.username"));
+        let mut bad = corp.clone();
+        bad.pass = long;
+        assert!(bad.validate().unwrap_err().to_string().contains("pass"));
+        let mut bad = corp.clone();
+        bad.pass.clear();
+        assert!(bad.validate().is_err());
+    }
+
+    #[test]
+    fn wifi_list_must_fit_nvs() {
+        // 8 entries, each well within its own limits, that together overflow one NVS string
+        let cred = WifiCred {
+            ssid: "s".repeat(32),
+            pass: "p".repeat(128),
+            eap: Some(crate::bt_wifi_mode::EapCred {
+                identity: "i".repeat(128),
+                username: "u".repeat(128),
+            }),
+            static_ip: Some(
+                serde_json::from_str(
+                    r#"{"ip":"192.168.100.200","gateway":"192.168.100.254",
+                    "dns":"192.168.100.253","dns2":"192.168.100.252"}"#,
+                )
+                .unwrap(),
+            ),
+            ..Default::default()
+        };
+        let list = vec![cred; crate::bt_wifi_mode::MAX_WIFI_CREDS];
+        let err = validate_wifi_list(&list).unwrap_err().to_string();
+        assert!(err.contains("as JSON"), "{}", err);
+        assert!(validate_wifi_list(&list[..4]).is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_documents() {
+        let check = |f: &dyn Fn(&mut ConfigDocument)| {
+            let mut doc = ConfigDocument::new(settings(), false);
+            f(&mut doc);
+            doc.validate().unwrap_err().to_string()
+        };
+        assert!(check(&|d| d.format = "other".into()).starts_with("format"));
+        assert!(check(&|d| d.version = VERSION + 1).starts_with("version"));
+        assert!(check(&|d| d.settings.wifi_list[0].ssid.clear()).starts_with("settings.wifi_list"));
+        assert!(check(&|d| {
+            d.settings.wifi_list[0].eap = Some(crate::bt_wifi_mode::EapCred {
+                identity: String::new(),
+                username: String::new(),
+            })
+        })
+        .starts_with("settings.wifi_list"));
+        assert!(check(&|d| d.settings.ble_name = "x".repeat(40)).starts_with("settings.ble_name"));
+        assert!(check(&|d| d.asr_config = Some(serde_json::json!("x"))).starts_with("asr_config"));
+        assert!(check(&|d| d.background = Some("!!".into())).starts_with("background"));
+        assert!(check(&|d| {
+            d.keymap = Some(
+                KeymapConfig::from_json(r#"{"NOPE":{"type":"text","raw":"x","value":"x"}}"#)
+                    .unwrap(),
+            )
+        })
+        .starts_with("keymap.NOPE"));
+    }
+}
@@ -5,8 +5,8 @@ use esp32_nimble::{
     enums::*,
     hid::*,
     utilities::{mutex::Mutex, BleUuid},
-    uuid128, BLEAdvertisementData, BLECharacteristic, BLEDevice, BLEHIDDevice, BLEService,
-    NimbleProperties,
+    uuid128, BLEAddress, BLEAdvertisementData, BLECharacteristic, BLEDevice, BLEHIDDevice,
+    BLEService, NimbleProperties,
 };
 use std::sync::Arc;
 use zerocopy::IntoBytes;
@@ -35,8 +35,52 @@ pub const KEY_F12: u8 = 0x45;
 
 // Key mapping configuration
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
-#[serde(tag = "type")]
+#[serde(tag = "type", from = "RawKeyAction")]
 pub enum KeyAction {
+    #[serde(rename = "combo")]
+    Combo {
+        raw: String,
+        modifiers: Vec<String>,
+        key: String,
+    },
+    #[serde(rename = "text")]
+    Text {
+        raw: String,
+        value: String,
+        /// `value` parsed into template steps when the keymap is loaded
+        #[serde(skip)]
+        steps: Vec<crate::key_template::TemplateStep>,
+    },
+    /// Switch to another BLE host slot (1-based); without `slot`, go to the next one
+    #[serde(rename = "host")]
+    Host {
+        raw: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        slot: Option<u8>,
+    },
+    /// Leave the running mode for `mode`, or for the other mode; see `crate::mode`
+    #[serde(rename = "mode")]
+    Mode {
+        raw: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<crate::mode::RunMode>,
+    },
+}
+
+impl KeyAction {
+    /// Text action with its template already parsed. A value with a bad delay is typed
+    /// literally (writes with one are rejected by `validate`).
+    pub fn text(raw: String, value: String) -> Self {
+        let steps = crate::key_template::parse(&value)
+            .unwrap_or_else(|_| vec![crate::key_template::TemplateStep::Text(value.clone())]);
+        Self::Text { raw, value, steps }
+    }
+}
+
+/// Wire format of `KeyAction`; deserializing through it parses text templates exactly once
+#[derive(serde::Deserialize)]
+#[serde(tag = "type")]
+enum RawKeyAction {
     #[serde(rename = "combo")]
     Combo {
         raw: String,
@@ -45,12 +89,100 @@ pub enum KeyAction {
     },
     #[serde(rename = "text")]
     Text { raw: String, value: String },
+    #[serde(rename = "host")]
+    Host {
+        raw: String,
+        #[serde(default)]
+        slot: Option<u8>,
+    },
+    #[serde(rename = "mode")]
+    Mode {
+        raw: String,
+        #[serde(default)]
+        mode: Option<crate::mode::RunMode>,
+    },
+}
+
+impl From<RawKeyAction> for KeyAction {
+    fn from(raw: RawKeyAction) -> Self {
+        match raw {
+            RawKeyAction::Combo {
+                raw,
+                modifiers,
+                key,
+            } => Self::Combo {
+                raw,
+                modifiers,
+                key,
+            },
+            RawKeyAction::Text { raw, value } => Self::text(raw, value),
+            RawKeyAction::Host { raw, slot } => Self::Host { raw, slot },
+            RawKeyAction::Mode { raw, mode } => Self::Mode { raw, mode },
+        }
+    }
+}
+
+/// Auto-repeat of one physical key while it is held, e.g. `{"enabled":true,"delay_ms":300}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct RepeatConfig {
+    #[serde(default = "RepeatConfig::default_enabled")]
+    pub enabled: bool,
+    /// Hold time before the first repeat
+    #[serde(default = "RepeatConfig::default_delay_ms")]
+    pub delay_ms: u32,
+    /// Time between repeats after that
+    #[serde(default = "RepeatConfig::default_interval_ms")]
+    pub interval_ms: u32,
+}
+
+impl RepeatConfig {
+    pub const DELAY_RANGE: std::ops::RangeInclusive<u32> = 100..=5000;
+    pub const INTERVAL_RANGE: std::ops::RangeInclusive<u32> = 20..=2000;
+
+    fn default_enabled() -> bool {
+        true
+    }
+
+    fn default_delay_ms() -> u32 {
+        300
+    }
+
+    fn default_interval_ms() -> u32 {
+        100
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            delay_ms: Self::default_delay_ms(),
+            interval_ms: Self::default_interval_ms(),
+        }
+    }
+
+    /// Built-in repeat of a physical key when the keymap does not set one:
+    /// only Backspace repeats
+    pub fn default_for(key_name: &str) -> Self {
+        match key_name {
+            KeymapConfig::KEY_BACKSPACE => Self {
+                enabled: true,
+                delay_ms: 200,
+                interval_ms: 100,
+            },
+            _ => Self::disabled(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub struct KeymapConfig {
     #[serde(flatten)]
     pub keys: std::collections::HashMap<String, KeyAction>,
+    /// Auto-repeat overrides by physical key name, under the top-level `repeat` field
+    #[serde(default, skip_serializing_if = "std::collections::HashMap::is_empty")]
+    pub repeat: std::collections::HashMap<String, RepeatConfig>,
+    /// Hybrid-mode routing overrides by physical key name, under the top-level `route` field
+    #[serde(default, skip_serializing_if = "std::collections::HashMap::is_empty")]
+    pub route: std::collections::HashMap<String, crate::mode::KeyRoute>,
 }
 
 impl KeymapConfig {
@@ -63,6 +195,19 @@ impl KeymapConfig {
     pub const KEY_SWITCH: &'static str = "SWITCH";
     pub const KEY_ACCEPT: &'static str = "ACCEPT";
     pub const KEY_ROTATE: &'static str = "ROTATE";
+    /// Chords with a built-in action, see `default_chord_action`
+    pub const DEFAULT_CHORDS: [&'static str; 1] = ["ESC+SWITCH"];
+    /// All physical key names, in pin order
+    pub const ALL_KEYS: [&'static str; 8] = [
+        Self::KEY_MIC,
+        Self::KEY_CUSTOM,
+        Self::KEY_ESC,
+        Self::KEY_NEXT,
+        Self::KEY_BACKSPACE,
+        Self::KEY_SWITCH,
+        Self::KEY_ACCEPT,
+        Self::KEY_ROTATE,
+    ];
 
     pub fn clear_nvs(nvs: &mut esp_idf_svc::nvs::EspDefaultNvs) -> anyhow::Result<()> {
         nvs.remove("keymap_config")?;
@@ -102,6 +247,8 @@ impl KeymapConfig {
     pub fn default() -> Self {
         Self {
             keys: std::collections::HashMap::new(),
+            repeat: std::collections::HashMap::new(),
+            route: std::collections::HashMap::new(),
         }
     }
 
@@ -120,16 +267,426 @@ impl KeymapConfig {
         }
     }
 
+    /// KeysPin index of a physical key name
+    pub fn pin_index(key_name: &str) -> Option<u8> {
+        Self::ALL_KEYS
+            .iter()
+            .position(|k| *k == key_name)
+            .map(|i| i as u8)
+    }
+
+    /// Pins of a chord name such as `ESC+ACCEPT`, lower pin index first
+    pub fn chord_pins(name: &str) -> Option<(u8, u8)> {
+        let (a, b) = name.split_once('+')?;
+        let (a, b) = (Self::pin_index(a)?, Self::pin_index(b)?);
+        match a.cmp(&b) {
+            std::cmp::Ordering::Less => Some((a, b)),
+            std::cmp::Ordering::Greater => Some((b, a)),
+            std::cmp::Ordering::Equal => None,
+        }
+    }
+
+    /// Stored name of a chord: both key names in pin order, e.g. `ESC+ACCEPT`
+    pub fn chord_name(a: u8, b: u8) -> String {
+        let (a, b) = if a <= b { (a, b) } else { (b, a) };
+        format!("{}+{}", Self::get_key_name(a), Self::get_key_name(b))
+    }
+
+    /// `ACCEPT+ESC` and `ESC+ACCEPT` are the same chord; keep one spelling
+    fn canonical_key_name(name: String) -> String {
+        match Self::chord_pins(&name) {
+            Some((a, b)) => Self::chord_name(a, b),
+            None => name,
+        }
+    }
+
+    /// Chords bound in this keymap or by default, for the chord detector
+    pub fn chords(&self) -> Vec<(u8, u8)> {
+        let mut chords: Vec<(u8, u8)> = self
+            .keys
+            .keys()
+            .filter_map(|name| Self::chord_pins(name))
+            .collect();
+        for name in Self::DEFAULT_CHORDS {
+            let pins = Self::chord_pins(name).unwrap();
+            if !chords.contains(&pins) {
+                chords.push(pins);
+            }
+        }
+        chords
+    }
+
+    /// Action bound to a chord, falling back to the built-in default
+    pub fn chord_action(&self, a: u8, b: u8) -> Option<KeyAction> {
+        let name = Self::chord_name(a, b);
+        self.keys
+            .get(&name)
+            .cloned()
+            .or_else(|| Self::default_chord_action(&name))
+    }
+
+    /// Built-in action of a chord when the keymap does not bind it. `ESC+SWITCH` switches
+    /// modes, so a stock keymap can always leave the mode the device booted into.
+    pub fn default_chord_action(chord_name: &str) -> Option<KeyAction> {
+        match chord_name {
+            "ESC+SWITCH" => Some(KeyAction::Mode {
+                raw: "switch mode".to_string(),
+                mode: None,
+            }),
+            _ => None,
+        }
+    }
+
     /// Merge with another keymap, new values override existing ones
     pub fn merge(&mut self, other: KeymapConfig) {
         for (key, value) in other.keys {
-            self.keys.insert(key, value);
+            self.keys.insert(Self::canonical_key_name(key), value);
         }
+        self.repeat.extend(other.repeat);
+        self.route.extend(other.route);
     }
 
-    /// Remove a key mapping by name
+    /// Remove a key mapping by name, together with its repeat and route overrides
     pub fn remove(&mut self, key_name: &str) {
-        self.keys.remove(key_name);
+        self.keys
+            .remove(&Self::canonical_key_name(key_name.to_string()));
+        self.repeat.remove(key_name);
+        self.route.remove(key_name);
+    }
+
+    /// Auto-repeat of a physical key, falling back to the built-in default
+    pub fn repeat_for(&self, key_name: &str) -> RepeatConfig {
+        self.repeat
+            .get(key_name)
+            .copied()
+            .unwrap_or_else(|| RepeatConfig::default_for(key_name))
+    }
+
+    /// Hybrid-mode route of a physical key, falling back to the built-in default
+    pub fn route_for(&self, key_name: &str) -> crate::mode::KeyRoute {
+        self.route
+            .get(key_name)
+            .copied()
+            .unwrap_or_else(|| crate::mode::KeyRoute::default_for(key_name))
+    }
+
+    /// Built-in action of a physical key when the keymap does not bind it
+    pub fn default_action(key_name: &str) -> Option<KeyAction> {
+        let combo = |raw: &str, modifiers: &[&str], key: &str| KeyAction::Combo {
+            raw: raw.to_string(),
+            modifiers: modifiers.iter().map(|m| m.to_string()).collect(),
+            key: key.to_string(),
+        };
+        let text = |value: &str| KeyAction::text(value.trim_end().to_string(), value.to_string());
+        let action = match key_name {
+            // Ctrl + Option, triggers the host's own dictation
+            Self::KEY_MIC => combo("ctrl+option", &["ctrl"], "OPTION"),
+            Self::KEY_CUSTOM => text("/compact\n"),
+            Self::KEY_ESC => combo("esc", &[], "ESC"),
+            Self::KEY_NEXT => combo("down", &[], "DOWN"),
+            Self::KEY_SWITCH => combo("shift+tab", &["shift"], "TAB"),
+            Self::KEY_BACKSPACE => combo("backspace", &[], "BACKSPACE"),
+            Self::KEY_ACCEPT => combo("enter", &[], "ENTER"),
+            Self::KEY_ROTATE => text("/"),
+            _ => return None,
+        };
+        Some(action)
+    }
+
+    /// Action bound to a physical key, falling back to the built-in default
+    pub fn action_for(&self, key_name: &str) -> Option<KeyAction> {
+        self.keys
+            .get(key_name)
+            .cloned()
+            .or_else(|| Self::default_action(key_name))
+    }
+
+    /// Full keymap as the device actually uses it: user bindings plus defaults for the rest
+    pub fn effective(&self) -> Self {
+        let mut keys = std::collections::HashMap::new();
+        for name in Self::ALL_KEYS {
+            if let Some(action) = self.action_for(name) {
+                keys.insert(name.to_string(), action);
+            }
+        }
+        for name in Self::DEFAULT_CHORDS {
+            if let Some(action) = Self::default_chord_action(name) {
+                keys.insert(name.to_string(), action);
+            }
+        }
+        for (name, action) in &self.keys {
+            if Self::chord_pins(name).is_some() {
+                keys.insert(name.clone(), action.clone());
+            }
+        }
+        let repeat = Self::ALL_KEYS
+            .iter()
+            .map(|name| (name.to_string(), self.repeat_for(name)))
+            .collect();
+        let route = Self::ALL_KEYS
+            .iter()
+            .map(|name| (name.to_string(), self.route_for(name)))
+            .collect();
+        Self {
+            keys,
+            repeat,
+            route,
+        }
+    }
+
+    /// Longest value a BLE attribute can hold
+    pub const MAX_READ_LEN: usize = 512;
+
+    /// Value for reads of the keymap characteristic: the effective keymap when it fits in one
+    /// attribute, otherwise `{"export":"keymap","len":N}`, which tells the host to fetch it
+    /// with a keymap `export` frame on the transfer characteristic (see `crate::transfer`)
+    pub fn read_value(&self) -> anyhow::Result<String> {
+        let json = self.effective().to_json()?;
+        if json.len() <= Self::MAX_READ_LEN {
+            return Ok(json);
+        }
+        Ok(serde_json::json!({ "export": "keymap", "len": json.len() }).to_string())
+    }
+
+    /// Names that may appear as top-level keys of a keymap JSON:
+    /// a physical key, or a chord of two different ones (`ESC+ACCEPT`)
+    pub fn is_known_key_name(name: &str) -> bool {
+        Self::ALL_KEYS.contains(&name) || Self::chord_pins(name).is_some()
+    }
+
+    /// Check every binding before it is merged, so bad names are rejected at write time
+    /// instead of failing silently inside `execute_key_action`.
+    /// Returns an empty list when the keymap is valid.
+    pub fn validate(&self) -> Vec<KeymapError> {
+        let mut errors = Vec::new();
+        // Sort by name so the reported order is stable (HashMap order is not)
+        let mut names: Vec<&String> = self.keys.keys().collect();
+        names.sort();
+        for name in names {
+            if !Self::is_known_key_name(name) {
+                errors.push(KeymapError::new(
+                    name,
+                    KeymapErrorCode::UnknownPhysicalKey,
+                    name,
+                ));
+                continue;
+            }
+            match &self.keys[name] {
+                KeyAction::Combo { modifiers, key, .. } => {
+                    for m in modifiers {
+                        if modifier_name_to_mask(m).is_none() {
+                            errors.push(KeymapError::new(
+                                name,
+                                KeymapErrorCode::InvalidModifier,
+                                m,
+                            ));
+                        }
+                    }
+                    if key_name_to_hid_code(key).is_err() {
+                        errors.push(KeymapError::new(name, KeymapErrorCode::UnknownKeyCode, key));
+                    }
+                }
+                KeyAction::Text { value, .. } => {
+                    if let Err(e) = crate::key_template::parse(value) {
+                        errors.push(KeymapError::new(
+                            name,
+                            KeymapErrorCode::InvalidTemplate,
+                            &e.to_string(),
+                        ));
+                    }
+                }
+                KeyAction::Host { slot, .. } => {
+                    if let Some(slot) = slot {
+                        if !(1..=HOST_SLOTS).contains(slot) {
+                            errors.push(KeymapError::new(
+                                name,
+                                KeymapErrorCode::InvalidHostSlot,
+                                &slot.to_string(),
+                            ));
+                        }
+                    }
+                }
+                KeyAction::Mode { .. } => {}
+            }
+        }
+        // Repeat applies to physical keys only; chords fire once
+        let mut names: Vec<&String> = self.repeat.keys().collect();
+        names.sort();
+        for name in names {
+            if !Self::ALL_KEYS.contains(&name.as_str()) {
+                errors.push(KeymapError::new(
+                    name,
+                    KeymapErrorCode::UnknownPhysicalKey,
+                    name,
+                ));
+                continue;
+            }
+            let repeat = &self.repeat[name];
+            if !RepeatConfig::DELAY_RANGE.contains(&repeat.delay_ms) {
+                errors.push(KeymapError::new(
+                    name,
+                    KeymapErrorCode::InvalidRepeat,
+                    &format!("delay_ms={}", repeat.delay_ms),
+                ));
+            }
+            if !RepeatConfig::INTERVAL_RANGE.contains(&repeat.interval_ms) {
+                errors.push(KeymapError::new(
+                    name,
+                    KeymapErrorCode::InvalidRepeat,
+                    &format!("interval_ms={}", repeat.interval_ms),
+                ));
+            }
+        }
+        // Routes, like repeat, are per physical key
+        let mut names: Vec<&String> = self.route.keys().collect();
+        names.sort();
+        for name in names {
+            if !Self::ALL_KEYS.contains(&name.as_str()) {
+                errors.push(KeymapError::new(
+                    name,
+                    KeymapErrorCode::UnknownPhysicalKey,
+                    name,
+                ));
+            }
+        }
+        errors
+    }
+}
+
+/// One write to the keymap characteristic.
+///
+/// A plain keymap object is merged as before; an object with an `op` field is an explicit
+/// operation: `{"op":"remove","keys":["NEXT"]}` drops bindings back to their defaults,
+/// `{"op":"reset"}` drops all of them.
+#[derive(Debug)]
+pub enum KeymapWrite {
+    Merge(KeymapConfig),
+    Remove(Vec<String>),
+    Reset,
+}
+
+impl KeymapWrite {
+    pub fn parse(json: &str) -> Result<Self, Vec<KeymapError>> {
+        let value: serde_json::Value =
+            serde_json::from_str(json).map_err(|e| vec![KeymapError::invalid_json(e)])?;
+        let Some(op) = value.get("op") else {
+            let keymap: KeymapConfig =
+                serde_json::from_value(value).map_err(|e| vec![KeymapError::invalid_json(e)])?;
+            let errors = keymap.validate();
+            return if errors.is_empty() {
+                Ok(Self::Merge(keymap))
+            } else {
+                Err(errors)
+            };
+        };
+
+        match op.as_str() {
+            Some("reset") => Ok(Self::Reset),
+            Some("remove") => {
+                let keys: Vec<String> = value
+                    .get("keys")
+                    .cloned()
+                    .map(serde_json::from_value)
+                    .transpose()
+                    .map_err(|e| vec![KeymapError::invalid_json(e)])?
+                    .unwrap_or_default();
+                let errors: Vec<KeymapError> = keys
+                    .iter()
+                    .filter(|k| !KeymapConfig::is_known_key_name(k))
+                    .map(|k| KeymapError::new(k, KeymapErrorCode::UnknownPhysicalKey, k))
+                    .collect();
+                if errors.is_empty() {
+                    Ok(Self::Remove(keys))
+                } else {
+                    Err(errors)
+                }
+            }
+            _ => Err(vec![KeymapError::invalid_json(format_args!(
+                "unknown keymap op: {}",
+                op
+            ))]),
+        }
+    }
+}
+
+/// Machine-readable category of a rejected keymap entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeymapErrorCode {
+    /// Payload is not valid UTF-8 / JSON, or an action has the wrong shape
+    InvalidJson,
+    /// Top-level key is not one of the physical key names
+    UnknownPhysicalKey,
+    /// `key` of a combo is not in the HID key name table
+    UnknownKeyCode,
+    /// Entry of `modifiers` is not a known modifier name
+    InvalidModifier,
+    /// `value` of a text action has a malformed `{DELAY:n}` token
+    InvalidTemplate,
+    /// `delay_ms` / `interval_ms` of a repeat entry is out of range
+    InvalidRepeat,
+    /// `slot` of a host action is not between 1 and `HOST_SLOTS`
+    InvalidHostSlot,
+    /// The keymap was valid but could not be written to NVS; nothing changed
+    StorageFailed,
+    /// Written while the device is not in Keyboard mode, which owns the keymap; nothing changed
+    WrongMode,
+}
+
+/// One validation error, reported to the companion page via `ControllerService::notify`
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct KeymapError {
+    /// Physical key name the error belongs to (empty for `invalid_json`, `storage_failed` and
+    /// `wrong_mode`)
+    pub key: String,
+    pub code: KeymapErrorCode,
+    /// The offending value (or the parser / NVS message for `invalid_json` / `storage_failed`,
+    /// the current mode for `wrong_mode`)
+    pub value: String,
+}
+
+impl KeymapError {
+    pub fn new(key: &str, code: KeymapErrorCode, value: &str) -> Self {
+        Self {
+            key: key.to_string(),
+            code,
+            value: value.to_string(),
+        }
+    }
+
+    pub fn invalid_json(message: impl std::fmt::Display) -> Self {
+        Self {
+            key: String::new(),
+            code: KeymapErrorCode::InvalidJson,
+            value: message.to_string(),
+        }
+    }
+
+    pub fn wrong_mode(mode: &str) -> Self {
+        Self {
+            key: String::new(),
+            code: KeymapErrorCode::WrongMode,
+            value: mode.to_string(),
+        }
+    }
+
+    pub fn storage_failed(error: impl std::fmt::Display) -> Self {
+        Self {
+            key: String::new(),
+            code: KeymapErrorCode::StorageFailed,
+            value: error.to_string(),
+        }
+    }
+}
+
+/// Convert a modifier name to its HID modifier bit, `None` if unknown
+pub fn modifier_name_to_mask(name: &str) -> Option<u8> {
+    match name.to_ascii_lowercase().as_str() {
+        "ctrl" | "control" => Some(0x01),
+        "shift" => Some(0x02),
+        "alt" | "option" => Some(0x04),
+        "meta" | "command" | "cmd" | "win" | "gui" => Some(0x08),
+        _ => None,
     }
 }
 
@@ -166,13 +723,15 @@ const HID_KEYBOARD_REPORT_DISCRIPTOR: &[u8] = hid!(
     (REPORT_COUNT, 0x06), //   REPORT_COUNT (6) ; 6 bytes (Keys)
     (REPORT_SIZE, 0x08), //   REPORT_SIZE(8)
     (LOGICAL_MINIMUM, 0x00), //   LOGICAL_MINIMUM(0)
-    (LOGICAL_MAXIMUM, 0x65), //   LOGICAL_MAXIMUM(0x65) ; 101 keys
-    (USAGE_PAGE, 0x07), //   USAGE_PAGE (Kbrd/Keypad)
-    (USAGE_MINIMUM, 0x00), //   USAGE_MINIMUM (0)
-    (USAGE_MAXIMUM, 0x65), //   USAGE_MAXIMUM (0x65)
-    (HIDINPUT, 0x00),  //   INPUT (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
-    (END_COLLECTION),  // END_COLLECTION
-    (END_COLLECTION)   // END_COLLECTION
+    // 0xA4 covers the whole Kbrd/Keypad page (F13-F24, Intl, Lang). It needs the 2-byte
+    // form: a 1-byte 0xA4 would be read as a negative logical maximum.
+    (LOGICAL_MAXIMUM, 0xA4, 0x00), //   LOGICAL_MAXIMUM(0xA4)
+    (USAGE_PAGE, 0x07),            //   USAGE_PAGE (Kbrd/Keypad)
+    (USAGE_MINIMUM, 0x00),         //   USAGE_MINIMUM (0)
+    (USAGE_MAXIMUM, 0xA4),         //   USAGE_MAXIMUM (0xA4)
+    (HIDINPUT, 0x00), //   INPUT (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    (END_COLLECTION), // END_COLLECTION
+    (END_COLLECTION)  // END_COLLECTION
 );
 
 const HID_REPORT_DISCRIPTOR: &[u8] = hid!(
@@ -204,12 +763,14 @@ const HID_REPORT_DISCRIPTOR: &[u8] = hid!(
     (REPORT_COUNT, 0x06), //   REPORT_COUNT (6) ; 6 bytes (Keys)
     (REPORT_SIZE, 0x08), //   REPORT_SIZE(8)
     (LOGICAL_MINIMUM, 0x00), //   LOGICAL_MINIMUM(0)
-    (LOGICAL_MAXIMUM, 0x65), //   LOGICAL_MAXIMUM(0x65) ; 101 keys
-    (USAGE_PAGE, 0x07), //   USAGE_PAGE (Kbrd/Keypad)
-    (USAGE_MINIMUM, 0x00), //   USAGE_MINIMUM (0)
-    (USAGE_MAXIMUM, 0x65), //   USAGE_MAXIMUM (0x65)
-    (HIDINPUT, 0x00),  //   INPUT (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
-    (END_COLLECTION),  // END_COLLECTION
+    // 0xA4 covers the whole Kbrd/Keypad page (F13-F24, Intl, Lang). It needs the 2-byte
+    // form: a 1-byte 0xA4 would be read as a negative logical maximum.
+    (LOGICAL_MAXIMUM, 0xA4, 0x00), //   LOGICAL_MAXIMUM(0xA4)
+    (USAGE_PAGE, 0x07),            //   USAGE_PAGE (Kbrd/Keypad)
+    (USAGE_MINIMUM, 0x00),         //   USAGE_MINIMUM (0)
+    (USAGE_MAXIMUM, 0xA4),         //   USAGE_MAXIMUM (0xA4)
+    (HIDINPUT, 0x00), //   INPUT (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    (END_COLLECTION), // END_COLLECTION
     // ------------------------------------------------- Media Keys
     (USAGE_PAGE, 0x0C),         // USAGE_PAGE (Consumer)
     (USAGE, 0x01),              // USAGE (Consumer Control)
@@ -499,19 +1060,18 @@ impl KeysPin {
             && self.rotate_button.is_high()
     }
 
-    pub async fn wait_for_high(&mut self, pin_index: u8) -> Result<(), esp_idf_svc::sys::EspError> {
+    /// Raw level of a key by `KeysPin` index (no debounce)
+    pub fn is_low(&self, pin_index: u8) -> bool {
         match pin_index {
-            KeysPin::MIC => self.mic.wait_for_high().await,
-            KeysPin::CUSTOM => self.custom.wait_for_high().await,
-            KeysPin::ESC => self.esc.wait_for_high().await,
-            KeysPin::NEXT => self.next.wait_for_high().await,
-            KeysPin::BACKSPACE => self.backspace.wait_for_high().await,
-            KeysPin::SWITCH => self.switch.wait_for_high().await,
-            KeysPin::ACCEPT => self.accept.wait_for_high().await,
-            KeysPin::ROTATE_BUTTON => self.rotate_button.wait_for_high().await,
-            _ => {
-                unreachable!("Invalid pin code: {}", pin_index);
-            }
+            KeysPin::MIC => self.mic.is_low(),
+            KeysPin::CUSTOM => self.custom.is_low(),
+            KeysPin::ESC => self.esc.is_low(),
+            KeysPin::NEXT => self.next.is_low(),
+            KeysPin::BACKSPACE => self.backspace.is_low(),
+            KeysPin::SWITCH => self.switch.is_low(),
+            KeysPin::ACCEPT => self.accept.is_low(),
+            KeysPin::ROTATE_BUTTON => self.rotate_button.is_low(),
+            _ => false,
         }
     }
 }
@@ -527,177 +1087,6 @@ impl KeysPin {
     pub const ROTATE_BUTTON: u8 = 7;
 }
 
-pub async fn key_event(
-    key_pins: &mut KeysPin,
-    rx: &mut tokio::sync::mpsc::Receiver<ControllerCommand>,
-) -> ControllerCommand {
-    tokio::select! {
-        _ = key_pins.mic.wait_for_any_edge() => {
-            if key_pins.mic.is_low() {
-                ControllerCommand::KeyboardPress(KeysPin::MIC)
-            } else {
-                ControllerCommand::KeyboardRelease(KeysPin::MIC)
-            }
-        },
-        _ = key_pins.custom.wait_for_any_edge() => {
-            if key_pins.custom.is_low() {
-                ControllerCommand::KeyboardPress(KeysPin::CUSTOM)
-            } else {
-                ControllerCommand::KeyboardRelease(KeysPin::CUSTOM)
-            }
-        }
-        _ = key_pins.esc.wait_for_any_edge() => {
-            if key_pins.esc.is_low() {
-                log::info!("ESC key pressed");
-                ControllerCommand::KeyboardPress(KeysPin::ESC)
-            } else {
-                log::info!("ESC key released");
-                ControllerCommand::KeyboardRelease(KeysPin::ESC)
-            }
-        },
-        _ = key_pins.next.wait_for_any_edge() => {
-            if key_pins.next.is_low() {
-                ControllerCommand::KeyboardPress(KeysPin::NEXT)
-            } else {
-                ControllerCommand::KeyboardRelease(KeysPin::NEXT)
-            }
-        },
-        _ = key_pins.switch.wait_for_any_edge() => {
-            if key_pins.switch.is_low() {
-                ControllerCommand::KeyboardPress(KeysPin::SWITCH)
-            } else {
-                ControllerCommand::KeyboardRelease(KeysPin::SWITCH)
-            }
-        },
-        _ = key_pins.backspace.wait_for_any_edge() => {
-            if key_pins.backspace.is_low() {
-                ControllerCommand::KeyboardPress(KeysPin::BACKSPACE)
-            } else {
-                ControllerCommand::KeyboardRelease(KeysPin::BACKSPACE)
-            }
-        },
-        _ = key_pins.accept.wait_for_any_edge() => {
-            if key_pins.accept.is_low() {
-                ControllerCommand::KeyboardPress(KeysPin::ACCEPT)
-            } else {
-                ControllerCommand::KeyboardRelease(KeysPin::ACCEPT)
-            }
-        },
-        _ = key_pins.rotate_a.wait_for_any_edge() => {
-            if key_pins.rotate_a.is_high() {
-                if key_pins.rotate_b.is_low() {
-                    ControllerCommand::RotateDown
-                } else {
-                    ControllerCommand::RotateUp
-                }
-            } else {
-                if key_pins.rotate_b.is_low() {
-                    ControllerCommand::RotateUp
-                } else {
-                    ControllerCommand::RotateDown
-                }
-            }
-        },
-        _ = key_pins.rotate_button.wait_for_any_edge() => {
-            if key_pins.rotate_button.is_low() {
-                ControllerCommand::KeyboardPress(KeysPin::ROTATE_BUTTON)
-            } else {
-                ControllerCommand::KeyboardRelease(KeysPin::ROTATE_BUTTON)
-            }
-        },
-        Some(event) = rx.recv() => {
-            event
-        }
-    }
-}
-
-/// Wait for key event only (without ControllerCommand from rx)
-pub async fn wait_key_event(key_pins: &mut KeysPin) -> ControllerCommand {
-    tokio::select! {
-        _ = key_pins.mic.wait_for_any_edge() => {
-            tokio::time::sleep(std::time::Duration::from_millis(20)).await; // Debounce delay
-            if key_pins.mic.is_low() {
-                ControllerCommand::KeyboardPress(KeysPin::MIC)
-            } else {
-                ControllerCommand::KeyboardRelease(KeysPin::MIC)
-            }
-        },
-        _ = key_pins.custom.wait_for_any_edge() => {
-            tokio::time::sleep(std::time::Duration::from_millis(20)).await; // Debounce delay
-            if key_pins.custom.is_low() {
-                ControllerCommand::KeyboardPress(KeysPin::CUSTOM)
-            } else {
-                ControllerCommand::KeyboardRelease(KeysPin::CUSTOM)
-            }
-        }
-        _ = key_pins.esc.wait_for_any_edge() => {
-            tokio::time::sleep(std::time::Duration::from_millis(20)).await; // Debounce delay
-            if key_pins.esc.is_low() {
-                log::info!("ESC key pressed");
-                ControllerCommand::KeyboardPress(KeysPin::ESC)
-            } else {
-                log::info!("ESC key released");
-                ControllerCommand::KeyboardRelease(KeysPin::ESC)
-            }
-        },
-        _ = key_pins.next.wait_for_any_edge() => {
-            tokio::time::sleep(std::time::Duration::from_millis(20)).await; // Debounce delay
-            if key_pins.next.is_low() {
-                ControllerCommand::KeyboardPress(KeysPin::NEXT)
-            } else {
-                ControllerCommand::KeyboardRelease(KeysPin::NEXT)
-            }
-        },
-        _ = key_pins.switch.wait_for_any_edge() => {
-            tokio::time::sleep(std::time::Duration::from_millis(20)).await; // Debounce delay
-            if key_pins.switch.is_low() {
-                ControllerCommand::KeyboardPress(KeysPin::SWITCH)
-            } else {
-                ControllerCommand::KeyboardRelease(KeysPin::SWITCH)
-            }
-        },
-        _ = key_pins.backspace.wait_for_any_edge() => {
-            tokio::time::sleep(std::time::Duration::from_millis(20)).await; // Debounce delay
-            if key_pins.backspace.is_low() {
-                ControllerCommand::KeyboardPress(KeysPin::BACKSPACE)
-            } else {
-                ControllerCommand::KeyboardRelease(KeysPin::BACKSPACE)
-            }
-        },
-        _ = key_pins.accept.wait_for_any_edge() => {
-            tokio::time::sleep(std::time::Duration::from_millis(20)).await; // Debounce delay
-            if key_pins.accept.is_low() {
-                ControllerCommand::KeyboardPress(KeysPin::ACCEPT)
-            } else {
-                ControllerCommand::KeyboardRelease(KeysPin::ACCEPT)
-            }
-        },
-        _ = key_pins.rotate_a.wait_for_any_edge() => {
-            if key_pins.rotate_a.is_high() {
-                if key_pins.rotate_b.is_low() {
-                    ControllerCommand::RotateDown
-                } else {
-                    ControllerCommand::RotateUp
-                }
-            } else {
-                if key_pins.rotate_b.is_low() {
-                    ControllerCommand::RotateUp
-                } else {
-                    ControllerCommand::RotateDown
-                }
-            }
-        },
-        _ = key_pins.rotate_button.wait_for_any_edge() => {
-            tokio::time::sleep(std::time::Duration::from_millis(20)).await; // Debounce delay
-            if key_pins.rotate_button.is_low() {
-                ControllerCommand::KeyboardPress(KeysPin::ROTATE_BUTTON)
-            } else {
-                ControllerCommand::KeyboardRelease(KeysPin::ROTATE_BUTTON)
-            }
-        },
-    }
-}
-
 pub struct Keyboard {
     hid_service_id: BleUuid,
     input_keyboard: Arc<Mutex<BLECharacteristic>>,
@@ -998,10 +1387,15 @@ const KEYBOARD_DISPLAY_ID: BleUuid = uuid128!("cdaa6472-67a8-4241-93cf-145051608
 const KEYBOARD_NOTIFY_ID: BleUuid = uuid128!("d4f7e1b3-3c4d-4f4e-8e2a-8f4e5c6d7e8f");
 const KEYMAP_CONFIG_ID: BleUuid = uuid128!("6f2a291c-0e4d-4f0f-9446-50bcd0b73bb0");
 const KEYMAP_ASR_RESULT_ID: BleUuid = uuid128!("f67f3c25-c9f0-456e-955e-cd9d9dd91051");
+/// Framed transfers (see `crate::transfer`): frames are written, replies are notified
+const TRANSFER_ID: BleUuid = uuid128!("8b1f4c7e-2d3a-4e5b-9c6d-7e8f9a0b1c2d");
 
 pub struct ControllerService {
     pub notify_characteristic: Arc<Mutex<BLECharacteristic>>,
     pub paster_characteristic: Arc<Mutex<BLECharacteristic>>,
+    pub keymap_characteristic: Arc<Mutex<BLECharacteristic>>,
+    pub transfer_characteristic: Arc<Mutex<BLECharacteristic>>,
+    pub status: crate::status::StatusTracker,
 }
 
 impl ControllerService {
@@ -1018,6 +1412,63 @@ impl ControllerService {
             .set_value(message.as_bytes())
             .notify();
     }
+
+    /// Refresh the value returned by reads of the keymap characteristic.
+    /// Reads return the effective keymap, so unbound keys show their default action, or a
+    /// pointer to the keymap export when it is too long for one attribute.
+    pub fn publish_keymap(&self, keymap: &KeymapConfig) {
+        match keymap.read_value() {
+            Ok(json) => {
+                self.keymap_characteristic.lock().set_value(json.as_bytes());
+            }
+            Err(e) => log::error!("Failed to serialize keymap: {:?}", e),
+        }
+    }
+
+    /// Report the outcome of a keymap write, e.g.
+    /// `{"type":"keymap_result","ok":false,"errors":[{"key":"NEXT","code":"unknown_key_code","value":"DWON"}]}`
+    pub fn notify_keymap_result(&self, errors: &[KeymapError]) {
+        let msg = serde_json::json!({
+            "type": "keymap_result",
+            "ok": errors.is_empty(),
+            "errors": errors,
+        });
+        self.notify(&msg.to_string());
+    }
+
+    /// Report the outcome of a config characteristic write, e.g.
+    /// `{"type":"config_result","ok":false,"error":"mic_model: 3 is not 0 (PTT) or 1 (Toggle)"}`
+    pub fn notify_config_result(&self, error: Option<&str>) {
+        let msg = serde_json::json!({
+            "type": "config_result",
+            "ok": error.is_none(),
+            "error": error,
+        });
+        self.notify(&msg.to_string());
+    }
+
+    /// Change the device status; a `status` notification goes out if anything changed
+    pub fn update_status(&self, f: impl FnOnce(&mut crate::status::DeviceStatus)) {
+        if let Some(json) = self.status.update(f) {
+            self.notify(&json);
+        }
+    }
+
+    /// Answer one frame written to the transfer characteristic
+    pub fn notify_transfer(&self, reply: &crate::transfer::Reply) {
+        self.transfer_characteristic
+            .lock()
+            .set_value(reply.to_json().as_bytes())
+            .notify();
+    }
+
+    /// Raw transfer frame sent to the host, used when exporting
+    pub fn notify_transfer_frame(&self, frame: &[u8]) {
+        self.transfer_characteristic
+            .lock()
+            .set_value(frame)
+            .notify();
+    }
 }
 
 #[derive(Debug)]
@@ -1025,9 +1476,16 @@ pub enum ControllerCommand {
     DisplayKeyboard(String),
     KeyboardPress(u8),
     KeyboardRelease(u8),
+    /// Auto-repeat of a held key (see `RepeatConfig`)
+    KeyboardRepeat(u8),
+    /// Two keys of a bound chord pressed together (pin indexes, lower first)
+    KeyboardChord(u8, u8),
+    KeyboardChordRelease(u8, u8),
     RotateDown,
     RotateUp,
     KeymapConfig(String),
+    /// One frame written to the transfer characteristic
+    Transfer(Vec<u8>),
     Paste(u8),
 }
 
@@ -1065,8 +1523,10 @@ pub fn new_controller_service(
     let notify_characteristic =
         service.create_characteristic(KEYBOARD_NOTIFY_ID, NimbleProperties::NOTIFY);
 
-    let keymap_config_characteristic =
-        service.create_characteristic(KEYMAP_CONFIG_ID, NimbleProperties::WRITE);
+    let keymap_config_characteristic = service.create_characteristic(
+        KEYMAP_CONFIG_ID,
+        NimbleProperties::READ | NimbleProperties::WRITE,
+    );
 
     let tx_ = tx.clone();
     keymap_config_characteristic.lock().on_write(move |args| {
@@ -1078,20 +1538,205 @@ pub fn new_controller_service(
         let _ = tx_.blocking_send(ControllerCommand::KeymapConfig(s));
     });
 
+    let transfer_characteristic = service.create_characteristic(
+        TRANSFER_ID,
+        NimbleProperties::WRITE | NimbleProperties::NOTIFY,
+    );
+
+    let tx_ = tx.clone();
+    transfer_characteristic.lock().on_write(move |args| {
+        let _ = tx_.blocking_send(ControllerCommand::Transfer(args.recv_data().to_vec()));
+    });
+
     Ok(ControllerService {
         notify_characteristic,
         paster_characteristic,
+        keymap_characteristic: keymap_config_characteristic,
+        transfer_characteristic,
+        status: crate::status::StatusTracker::new(crate::status::Mode::Keyboard),
     })
 }
 
+/// Number of hosts the keypad can be paired with at once. Slot 0 advertises the public
+/// address (so bonds made before slots existed keep working); every other slot uses its
+/// own random static address, so each host bonds with what looks like a separate keyboard.
+pub const HOST_SLOTS: u8 = 3;
+
+/// Host slot in use, for the status bar
+pub static HOST_SLOT: std::sync::atomic::AtomicU8 = std::sync::atomic::AtomicU8::new(0);
+
+/// Set when the keymap in NVS was written outside the keyboard loop (the REST API); the
+/// copy the running mode holds in memory is reloaded from NVS once it sees this
+pub static KEYMAP_STALE: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Random static address of a host slot other than 0, derived from the BT MAC so it is
+/// the same after every boot. Most significant byte first.
+pub fn slot_address(mac: [u8; 6], slot: u8) -> [u8; 6] {
+    let mut addr = mac;
+    addr[5] = addr[5].wrapping_add(slot);
+    // Random static address: the two top bits are 1
+    addr[0] |= 0xC0;
+    addr
+}
+
+/// Advertised appearance when none is configured: HID keyboard
+pub const DEFAULT_APPEARANCE: u16 = 0x03C1;
+
+/// Longest advertised name, so it still fits the scan response
+pub const MAX_DEVICE_NAME_LEN: usize = 20;
+
+/// BT MAC of this chip, most significant byte first
+pub fn bt_mac() -> anyhow::Result<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    esp_idf_svc::sys::esp!(unsafe {
+        esp_idf_svc::sys::esp_read_mac(
+            mac.as_mut_ptr(),
+            esp_idf_svc::sys::esp_mac_type_t_ESP_MAC_BT,
+        )
+    })?;
+    Ok(mac)
+}
+
+/// Name used when none is configured: the last two MAC bytes tell units in one room apart
+pub fn default_device_name(mac: [u8; 6]) -> String {
+    format!("VibeKeys-{:02X}{:02X}", mac[4], mac[5])
+}
+
+/// Printable ASCII, 1 to `MAX_DEVICE_NAME_LEN` bytes
+pub fn is_valid_device_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_DEVICE_NAME_LEN
+        && name.bytes().all(|b| b.is_ascii_graphic() || b == b' ')
+}
+
+/// Next slot after `slot`, wrapping around
+pub fn next_host_slot(slot: u8) -> u8 {
+    (slot + 1) % HOST_SLOTS
+}
+
+/// Advertise (and accept connections) as host slot `slot`; advertising must not be running
+pub fn use_host_slot(device: &mut BLEDevice, slot: u8) -> anyhow::Result<()> {
+    let slot = slot % HOST_SLOTS;
+    if slot == 0 {
+        device.set_own_addr_type(OwnAddrType::Public);
+    } else {
+        device.set_rnd_addr(slot_address(bt_mac()?, slot))?;
+        device.set_own_addr_type(OwnAddrType::Random);
+    }
+    HOST_SLOT.store(slot, std::sync::atomic::Ordering::Relaxed);
+    log::info!("Using BLE host slot {}", slot + 1);
+    Ok(())
+}
+
+/// Drop the current host and start advertising as another slot
+pub fn switch_host_slot(device: &mut BLEDevice, slot: u8) -> anyhow::Result<()> {
+    disconnect_hosts(device);
+    use_host_slot(device, slot)?;
+    device.get_advertising().lock().start()?;
+    Ok(())
+}
+
+/// Stop advertising and disconnect every host. The GATT services stay registered, so
+/// [`switch_host_slot`] brings the keyboard back later.
+pub fn disconnect_hosts(device: &mut BLEDevice) {
+    // Not advertising is fine here
+    let _ = device.get_advertising().lock().stop();
+    let server = device.get_server();
+    let handles: Vec<u16> = server.connections().map(|c| c.conn_handle()).collect();
+    for handle in handles {
+        if let Err(e) = server.disconnect(handle) {
+            log::warn!("Failed to disconnect {}: {:?}", handle, e);
+        }
+    }
+}
+
+/// What to do with a host that just finished pairing / encryption in slot `slot`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerAdmission {
+    /// Already bonded to this slot
+    Known,
+    /// Slot was free; the host is now bound to it and the table must be saved
+    Bound,
+    /// Host belongs to another slot: disconnect it
+    OtherSlot(u8),
+    /// Slot already belongs to another host: disconnect the newcomer and drop its new bond
+    SlotTaken,
+    /// Unknown host while pairing is closed: disconnect it and drop its new bond
+    Rejected,
+}
+
+/// Bind hosts to slots. `peers[i]` is the identity address of slot `i`'s host,
+/// empty while the slot is free. The first host to pair in a slot owns it until its bond
+/// is deleted (`forget_peer`). With `open` false only hosts already in the table get in.
+pub fn admit_peer(peers: &mut Vec<String>, slot: u8, addr: &str, open: bool) -> PeerAdmission {
+    let slot = slot as usize;
+    if let Some(other) = peers.iter().position(|p| p == addr) {
+        return if other == slot {
+            PeerAdmission::Known
+        } else {
+            PeerAdmission::OtherSlot(other as u8)
+        };
+    }
+    if !open {
+        return PeerAdmission::Rejected;
+    }
+    if peers.len() <= slot {
+        peers.resize(slot + 1, String::new());
+    }
+    if !peers[slot].is_empty() {
+        return PeerAdmission::SlotTaken;
+    }
+    peers[slot] = addr.to_string();
+    PeerAdmission::Bound
+}
+
+/// Free the slot of a host whose bond was deleted. Returns whether the table changed.
+pub fn forget_peer(peers: &mut [String], addr: &str) -> bool {
+    let mut changed = false;
+    for p in peers.iter_mut().filter(|p| *p == addr) {
+        p.clear();
+        changed = true;
+    }
+    changed
+}
+
+/// Slot a bonded host is bound to, if any
+pub fn peer_slot(peers: &[String], addr: &str) -> Option<u8> {
+    peers.iter().position(|p| p == addr).map(|i| i as u8)
+}
+
+/// Hosts NimBLE keeps bonds for. Starts the BLE stack if it is not running yet.
+pub fn bonded_hosts() -> anyhow::Result<Vec<BLEAddress>> {
+    Ok(BLEDevice::take().bonded_addresses()?)
+}
+
+/// Stop the BLE stack that `bonded_hosts` started for the settings page, before any mode
+/// has set up its services. The next `BLEDevice::take` starts it again.
+pub fn stop_ble() -> anyhow::Result<()> {
+    BLEDevice::deinit()?;
+    Ok(())
+}
+
+pub fn delete_bond(addr: &BLEAddress) -> anyhow::Result<()> {
+    BLEDevice::take().delete_bond(addr)?;
+    Ok(())
+}
+
+pub fn delete_all_bonds() -> anyhow::Result<()> {
+    BLEDevice::take().delete_all_bonds()?;
+    Ok(())
+}
+
 pub fn start_ble_advertising(
     device: &mut BLEDevice,
+    name: &str,
+    appearance: u16,
     service_ids: &[BleUuid],
 ) -> anyhow::Result<()> {
     let ble_advertising = device.get_advertising();
     let mut adv = BLEAdvertisementData::new();
-    adv.name("VibeKeys-MAX");
-    adv.appearance(0x03C1);
+    adv.name(name);
+    adv.appearance(appearance);
 
     for service_id in service_ids {
         adv.add_service_uuid(*service_id);
@@ -1108,54 +1753,29 @@ pub fn start_ble_advertising(
 
 /// Convert key name string to HID keycode with optional modifier bit
 /// Returns (keycode, modifier_bit)
-/// For modifier keys (Ctrl, Shift, Alt, GUI), modifier_bit is non-zero
+/// For modifier keys (Ctrl, Shift, Alt, GUI), modifier_bit is non-zero.
+/// Shifted symbols (`!`, `?`, `PLUS`, `COLON`, ...) return the base key with the Shift bit set.
 pub fn key_name_to_hid_code(key: &str) -> anyhow::Result<(u8, u8)> {
+    // A single printable character is looked up in ASCII_MAP, which already knows
+    // which symbols need Shift. Letters stay unshifted so "a" and "A" are the same key.
+    if let [c] = key.as_bytes() {
+        if c.is_ascii_graphic() {
+            let c = c.to_ascii_lowercase();
+            let code = ASCII_MAP[c as usize];
+            let modifier = if code & SHIFT != 0 { 0x02 } else { 0 };
+            return Ok((code & !SHIFT, modifier));
+        }
+    }
+
     let key_upper = key.to_uppercase();
     let (code, modifier) = match key_upper.as_str() {
-        // Letters A-Z
-        "A" => (0x04, 0),
-        "B" => (0x05, 0),
-        "C" => (0x06, 0),
-        "D" => (0x07, 0),
-        "E" => (0x08, 0),
-        "F" => (0x09, 0),
-        "G" => (0x0A, 0),
-        "H" => (0x0B, 0),
-        "I" => (0x0C, 0),
-        "J" => (0x0D, 0),
-        "K" => (0x0E, 0),
-        "L" => (0x0F, 0),
-        "M" => (0x10, 0),
-        "N" => (0x11, 0),
-        "O" => (0x12, 0),
-        "P" => (0x13, 0),
-        "Q" => (0x14, 0),
-        "R" => (0x15, 0),
-        "S" => (0x16, 0),
-        "T" => (0x17, 0),
-        "U" => (0x18, 0),
-        "V" => (0x19, 0),
-        "W" => (0x1A, 0),
-        "X" => (0x1B, 0),
-        "Y" => (0x1C, 0),
-        "Z" => (0x1D, 0),
-        // Numbers
-        "1" => (0x1E, 0),
-        "2" => (0x1F, 0),
-        "3" => (0x20, 0),
-        "4" => (0x21, 0),
-        "5" => (0x22, 0),
-        "6" => (0x23, 0),
-        "7" => (0x24, 0),
-        "8" => (0x25, 0),
-        "9" => (0x26, 0),
-        "0" => (0x27, 0),
         // Special keys
         "ENTER" | "RETURN" => (0x28, 0),
         "ESCAPE" | "ESC" => (0x29, 0),
         "BACKSPACE" => (0x2A, 0),
         "TAB" => (0x2B, 0),
         "SPACE" => (0x2C, 0),
+        "CAPSLOCK" | "CAPS_LOCK" => (0x39, 0),
         // F keys
         "F1" => (KEY_F1, 0),
         "F2" => (KEY_F2, 0),
@@ -1169,23 +1789,115 @@ pub fn key_name_to_hid_code(key: &str) -> anyhow::Result<(u8, u8)> {
         "F10" => (KEY_F10, 0),
         "F11" => (KEY_F11, 0),
         "F12" => (KEY_F12, 0),
+        "F13" => (0x68, 0),
+        "F14" => (0x69, 0),
+        "F15" => (0x6A, 0),
+        "F16" => (0x6B, 0),
+        "F17" => (0x6C, 0),
+        "F18" => (0x6D, 0),
+        "F19" => (0x6E, 0),
+        "F20" => (0x6F, 0),
+        "F21" => (0x70, 0),
+        "F22" => (0x71, 0),
+        "F23" => (0x72, 0),
+        "F24" => (0x73, 0),
+        // Navigation / editing
+        "PRINTSCREEN" | "PRINT_SCREEN" | "PRTSC" => (0x46, 0),
+        "SCROLLLOCK" | "SCROLL_LOCK" => (0x47, 0),
+        "PAUSE" | "BREAK" => (0x48, 0),
+        "INSERT" | "INS" => (0x49, 0),
+        "HOME" => (0x4A, 0),
+        "PAGEUP" | "PAGE_UP" | "PGUP" => (0x4B, 0),
+        "DELETE" | "DEL" => (0x4C, 0),
+        "END" => (0x4D, 0),
+        "PAGEDOWN" | "PAGE_DOWN" | "PGDN" => (0x4E, 0),
         // Arrow keys
         "RIGHT" => (0x4F, 0),
         "LEFT" => (0x50, 0),
         "DOWN" => (0x51, 0),
         "UP" => (0x52, 0),
+        // Keypad
+        "NUMLOCK" | "NUM_LOCK" => (0x53, 0),
+        "KP_SLASH" | "KP_DIVIDE" => (0x54, 0),
+        "KP_ASTERISK" | "KP_MULTIPLY" => (0x55, 0),
+        "KP_MINUS" | "KP_SUBTRACT" => (0x56, 0),
+        "KP_PLUS" | "KP_ADD" => (0x57, 0),
+        "KP_ENTER" => (0x58, 0),
+        "KP_1" => (0x59, 0),
+        "KP_2" => (0x5A, 0),
+        "KP_3" => (0x5B, 0),
+        "KP_4" => (0x5C, 0),
+        "KP_5" => (0x5D, 0),
+        "KP_6" => (0x5E, 0),
+        "KP_7" => (0x5F, 0),
+        "KP_8" => (0x60, 0),
+        "KP_9" => (0x61, 0),
+        "KP_0" => (0x62, 0),
+        "KP_PERIOD" | "KP_DECIMAL" => (0x63, 0),
+        "KP_EQUAL" => (0x67, 0),
+        "KP_COMMA" => (0x85, 0),
+        // Misc keys of the Keyboard/Keypad page
+        "NONUS_HASH" => (0x32, 0),
+        "NONUS_BACKSLASH" => (0x64, 0),
+        "APPLICATION" | "COMPOSE" => (0x65, 0),
+        "POWER" => (0x66, 0),
+        "EXECUTE" => (0x74, 0),
+        "HELP" => (0x75, 0),
+        "MENU" => (0x76, 0),
+        "SELECT" => (0x77, 0),
+        "STOP" => (0x78, 0),
+        "AGAIN" => (0x79, 0),
+        "UNDO" => (0x7A, 0),
+        "CUT" => (0x7B, 0),
+        "COPY" => (0x7C, 0),
+        "PASTE" => (0x7D, 0),
+        "FIND" => (0x7E, 0),
+        "MUTE" => (0x7F, 0),
+        "VOLUMEUP" | "VOLUME_UP" => (0x80, 0),
+        "VOLUMEDOWN" | "VOLUME_DOWN" => (0x81, 0),
+        "INTL1" | "RO" => (0x87, 0),
+        "INTL2" | "KATAKANAHIRAGANA" => (0x88, 0),
+        "INTL3" | "YEN" => (0x89, 0),
+        "INTL4" | "HENKAN" => (0x8A, 0),
+        "INTL5" | "MUHENKAN" => (0x8B, 0),
+        "LANG1" | "HANGEUL" => (0x90, 0),
+        "LANG2" | "HANJA" => (0x91, 0),
+        "SYSREQ" => (0x9A, 0),
+        "CLEAR" => (0x9C, 0),
         // Symbols
-        "MINUS" | "PLUS" => (0x2D, 0),
+        "MINUS" => (0x2D, 0),
         "EQUAL" => (0x2E, 0),
+        "BRACKETLEFT" | "LEFT_BRACKET" => (0x2F, 0),
+        "BRACKETRIGHT" | "RIGHT_BRACKET" => (0x30, 0),
+        "BACKSLASH" => (0x31, 0),
         "SEMICOLON" => (0x33, 0),
         "QUOTE" => (0x34, 0),
-        "BACKQUOTE" => (0x35, 0),
-        "BACKSLASH" => (0x31, 0),
+        "BACKQUOTE" | "GRAVE" => (0x35, 0),
         "COMMA" => (0x36, 0),
         "PERIOD" => (0x37, 0),
         "SLASH" => (0x38, 0),
-        "BRACKETLEFT" => (0x2F, 0),
-        "BRACKETRIGHT" => (0x30, 0),
+        // Shifted symbols - base key + Shift
+        "EXCLAIM" => (0x1E, 0x02),
+        "AT" => (0x1F, 0x02),
+        "HASH" => (0x20, 0x02),
+        "DOLLAR" => (0x21, 0x02),
+        "PERCENT" => (0x22, 0x02),
+        "CARET" => (0x23, 0x02),
+        "AMPERSAND" => (0x24, 0x02),
+        "ASTERISK" => (0x25, 0x02),
+        "PARENLEFT" | "LEFT_PAREN" => (0x26, 0x02),
+        "PARENRIGHT" | "RIGHT_PAREN" => (0x27, 0x02),
+        "UNDERSCORE" => (0x2D, 0x02),
+        "PLUS" => (0x2E, 0x02),
+        "BRACELEFT" | "LEFT_BRACE" => (0x2F, 0x02),
+        "BRACERIGHT" | "RIGHT_BRACE" => (0x30, 0x02),
+        "PIPE" => (0x31, 0x02),
+        "COLON" => (0x33, 0x02),
+        "DOUBLEQUOTE" => (0x34, 0x02),
+        "TILDE" => (0x35, 0x02),
+        "LESS" => (0x36, 0x02),
+        "GREATER" => (0x37, 0x02),
+        "QUESTION" => (0x38, 0x02),
         // Modifier keys - keycode + modifier bit
         "CTRL" | "CONTROL" => (0xE0, 0x01),
         "SHIFT" => (0xE1, 0x02),
@@ -1201,3 +1913,380 @@ pub fn key_name_to_hid_code(key: &str) -> anyhow::Result<(u8, u8)> {
     };
     Ok((code, modifier))
 }
+
+/// Printable ASCII character a key name types (with its implied Shift), if any.
+/// Built on `key_name_to_hid_code`, so remote mode accepts exactly the keyboard-mode names.
+pub fn key_name_to_char(key: &str) -> Option<u8> {
+    let (code, modifier) = key_name_to_hid_code(key).ok()?;
+    let wanted = match modifier {
+        0 => code,
+        0x02 => code | SHIFT,
+        _ => return None,
+    };
+    let keypad = match wanted {
+        0x54 => Some(b'/'),
+        0x55 => Some(b'*'),
+        0x56 => Some(b'-'),
+        0x57 => Some(b'+'),
+        0x59..=0x61 => Some(b'1' + (wanted - 0x59)),
+        0x62 => Some(b'0'),
+        0x63 => Some(b'.'),
+        0x67 => Some(b'='),
+        0x85 => Some(b','),
+        _ => None,
+    };
+    keypad.or_else(|| (b' '..=b'~').find(|&c| ASCII_MAP[c as usize] == wanted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_accepts_known_bindings() {
+        let k = KeymapConfig::from_json(
+            r#"{"NEXT":{"type":"combo","raw":"ctrl+c","modifiers":["ctrl"],"key":"C"},
+                "CUSTOM":{"type":"text","raw":"/compact","value":"/compact\n"}}"#,
+        )
+        .unwrap();
+        assert!(k.validate().is_empty());
+    }
+
+    #[test]
+    fn validate_reports_each_bad_field() {
+        let k = KeymapConfig::from_json(
+            r#"{"NXT":{"type":"text","raw":"x","value":"x"},
+                "ESC":{"type":"combo","raw":"hyper+dwon","modifiers":["hyper"],"key":"DWON"}}"#,
+        )
+        .unwrap();
+        let errors = k.validate();
+        let codes: Vec<(&str, KeymapErrorCode, &str)> = errors
+            .iter()
+            .map(|e| (e.key.as_str(), e.code, e.value.as_str()))
+            .collect();
+        assert_eq!(
+            codes,
+            vec![
+                ("ESC", KeymapErrorCode::InvalidModifier, "hyper"),
+                ("ESC", KeymapErrorCode::UnknownKeyCode, "DWON"),
+                ("NXT", KeymapErrorCode::UnknownPhysicalKey, "NXT"),
+            ]
+        );
+    }
+
+    #[test]
+    fn effective_fills_defaults() {
+        let mut k = KeymapConfig::default();
+        k.merge(
+            KeymapConfig::from_json(r#"{"NEXT":{"type":"text","raw":"hi","value":"hi"}}"#).unwrap(),
+        );
+        let e = k.effective();
+        assert_eq!(
+            e.keys.len(),
+            KeymapConfig::ALL_KEYS.len() + KeymapConfig::DEFAULT_CHORDS.len()
+        );
+        assert!(matches!(&e.keys["NEXT"], KeyAction::Text { value, .. } if value == "hi"));
+        assert!(matches!(&e.keys["ESC"], KeyAction::Combo { key, .. } if key == "ESC"));
+        // every default must itself pass validation
+        assert!(e.validate().is_empty());
+    }
+
+    #[test]
+    fn read_value_fits_an_attribute() {
+        let k = KeymapConfig::default();
+        let value = k.read_value().unwrap();
+        assert!(value.len() <= KeymapConfig::MAX_READ_LEN);
+        // the defaults alone are too long, so reads point at the export
+        let v: serde_json::Value = serde_json::from_str(&value).unwrap();
+        assert_eq!(v["export"], "keymap");
+        assert_eq!(v["len"], k.effective().to_json().unwrap().len());
+    }
+
+    #[test]
+    fn chord_bindings() {
+        let mut k = KeymapConfig::default();
+        k.merge(
+            KeymapConfig::from_json(
+                r#"{"ACCEPT+ESC":{"type":"combo","raw":"ctrl+c","modifiers":["ctrl"],"key":"C"}}"#,
+            )
+            .unwrap(),
+        );
+        // stored under the pin-order spelling, found from either key order
+        assert!(k.keys.contains_key("ESC+ACCEPT"));
+        assert_eq!(
+            k.chords(),
+            vec![
+                (KeysPin::ESC, KeysPin::ACCEPT),
+                (KeysPin::ESC, KeysPin::SWITCH)
+            ]
+        );
+        assert!(k.chord_action(KeysPin::ACCEPT, KeysPin::ESC).is_some());
+        assert!(k.chord_action(KeysPin::MIC, KeysPin::CUSTOM).is_none());
+        assert!(k.effective().keys.contains_key("ESC+ACCEPT"));
+        assert!(k.validate().is_empty());
+
+        assert!(!KeymapConfig::is_known_key_name("ESC+ESC"));
+        assert!(!KeymapConfig::is_known_key_name("ESC+NXT"));
+        assert!(!KeymapConfig::is_known_key_name("ESC+NEXT+ACCEPT"));
+
+        k.remove("ACCEPT+ESC");
+        assert_eq!(k.chords(), vec![(KeysPin::ESC, KeysPin::SWITCH)]);
+    }
+
+    #[test]
+    fn default_mode_chord() {
+        let mut k = KeymapConfig::default();
+        assert_eq!(k.chords(), vec![(KeysPin::ESC, KeysPin::SWITCH)]);
+        assert!(matches!(
+            k.chord_action(KeysPin::SWITCH, KeysPin::ESC),
+            Some(KeyAction::Mode { mode: None, .. })
+        ));
+        assert!(k.effective().keys.contains_key("ESC+SWITCH"));
+
+        // a binding of its own replaces the default, removing it brings the default back
+        k.merge(
+            KeymapConfig::from_json(r#"{"SWITCH+ESC":{"type":"text","raw":"x","value":"x"}}"#)
+                .unwrap(),
+        );
+        assert_eq!(k.chords(), vec![(KeysPin::ESC, KeysPin::SWITCH)]);
+        assert!(matches!(
+            k.chord_action(KeysPin::ESC, KeysPin::SWITCH),
+            Some(KeyAction::Text { .. })
+        ));
+        k.remove("ESC+SWITCH");
+        assert!(matches!(
+            k.chord_action(KeysPin::ESC, KeysPin::SWITCH),
+            Some(KeyAction::Mode { .. })
+        ));
+    }
+
+    #[test]
+    fn repeat_settings() {
+        let mut k = KeymapConfig::default();
+        assert!(k.repeat_for("BACKSPACE").enabled);
+        assert!(!k.repeat_for("NEXT").enabled);
+
+        let write = KeymapWrite::parse(r#"{"repeat":{"NEXT":{"delay_ms":400}}}"#).unwrap();
+        let KeymapWrite::Merge(other) = write else {
+            panic!("expected merge");
+        };
+        assert!(other.keys.is_empty());
+        k.merge(other);
+        assert_eq!(
+            k.repeat_for("NEXT"),
+            RepeatConfig {
+                enabled: true,
+                delay_ms: 400,
+                interval_ms: 100
+            }
+        );
+        // the effective keymap lists every key's repeat, and round-trips
+        let json = k.effective().to_json().unwrap();
+        let back = KeymapConfig::from_json(&json).unwrap();
+        assert_eq!(back.repeat.len(), KeymapConfig::ALL_KEYS.len());
+        assert!(back.repeat["BACKSPACE"].enabled);
+
+        k.remove("NEXT");
+        assert!(!k.repeat_for("NEXT").enabled);
+
+        let errors =
+            KeymapWrite::parse(r#"{"repeat":{"NXT":{},"ESC":{"interval_ms":5}}}"#).unwrap_err();
+        assert_eq!(errors[0].code, KeymapErrorCode::InvalidRepeat);
+        assert_eq!(errors[0].key, "ESC");
+        assert_eq!(errors[1].code, KeymapErrorCode::UnknownPhysicalKey);
+    }
+
+    #[test]
+    fn route_settings() {
+        use crate::mode::KeyRoute;
+        let mut k = KeymapConfig::default();
+        assert_eq!(k.route_for("NEXT"), KeyRoute::Ble);
+        assert_eq!(k.route_for("ROTATE"), KeyRoute::Remote);
+
+        let KeymapWrite::Merge(other) =
+            KeymapWrite::parse(r#"{"route":{"NEXT":"remote"}}"#).unwrap()
+        else {
+            panic!("expected merge");
+        };
+        k.merge(other);
+        assert_eq!(k.route_for("NEXT"), KeyRoute::Remote);
+        let back = KeymapConfig::from_json(&k.effective().to_json().unwrap()).unwrap();
+        assert_eq!(back.route.len(), KeymapConfig::ALL_KEYS.len());
+        assert_eq!(back.route["NEXT"], KeyRoute::Remote);
+
+        k.remove("NEXT");
+        assert_eq!(k.route_for("NEXT"), KeyRoute::Ble);
+
+        let errors = KeymapWrite::parse(r#"{"route":{"NXT":"ble"}}"#).unwrap_err();
+        assert_eq!(errors[0].code, KeymapErrorCode::UnknownPhysicalKey);
+        let errors = KeymapWrite::parse(r#"{"route":{"NEXT":"host"}}"#).unwrap_err();
+        assert_eq!(errors[0].code, KeymapErrorCode::InvalidJson);
+    }
+
+    #[test]
+    fn device_names() {
+        let mac = [0x24, 0x0a, 0xc4, 0x12, 0x3b, 0x07];
+        assert_eq!(default_device_name(mac), "VibeKeys-3B07");
+        assert!(is_valid_device_name(&default_device_name(mac)));
+        assert!(is_valid_device_name("Desk keys 2"));
+        assert!(!is_valid_device_name(""));
+        assert!(!is_valid_device_name("a name that is far too long"));
+        assert!(!is_valid_device_name("键盘"));
+        assert!(!is_valid_device_name("tab\there"));
+    }
+
+    #[test]
+    fn host_slots() {
+        let mac = [0x24, 0x0a, 0xc4, 0x12, 0x34, 0xff];
+        assert_eq!(slot_address(mac, 1), [0xe4, 0x0a, 0xc4, 0x12, 0x34, 0x00]);
+        assert_ne!(slot_address(mac, 1), slot_address(mac, 2));
+        assert_eq!(next_host_slot(HOST_SLOTS - 1), 0);
+
+        let mut peers = Vec::new();
+        assert_eq!(admit_peer(&mut peers, 1, "aa", true), PeerAdmission::Bound);
+        assert_eq!(peers, ["", "aa"]);
+        assert_eq!(admit_peer(&mut peers, 1, "aa", true), PeerAdmission::Known);
+        assert_eq!(
+            admit_peer(&mut peers, 0, "aa", true),
+            PeerAdmission::OtherSlot(1)
+        );
+        assert_eq!(
+            admit_peer(&mut peers, 0, "bb", false),
+            PeerAdmission::Rejected
+        );
+        assert_eq!(admit_peer(&mut peers, 0, "bb", true), PeerAdmission::Bound);
+        // the first host in a slot keeps it
+        assert_eq!(
+            admit_peer(&mut peers, 1, "cc", true),
+            PeerAdmission::SlotTaken
+        );
+        assert_eq!(peers, ["bb", "aa"]);
+        assert!(forget_peer(&mut peers, "aa"));
+        assert_eq!(admit_peer(&mut peers, 1, "cc", true), PeerAdmission::Bound);
+        assert_eq!(peers, ["bb", "cc"]);
+        // closed pairing still lets bound hosts back in
+        assert_eq!(admit_peer(&mut peers, 1, "cc", false), PeerAdmission::Known);
+
+        assert_eq!(peer_slot(&peers, "cc"), Some(1));
+        assert!(forget_peer(&mut peers, "cc"));
+        assert!(!forget_peer(&mut peers, "cc"));
+        assert_eq!(peers, ["bb", ""]);
+        assert_eq!(peer_slot(&peers, "cc"), None);
+
+        let k = KeymapConfig::from_json(r#"{"SWITCH+NEXT":{"type":"host","raw":"next host"}}"#)
+            .unwrap();
+        assert!(k.validate().is_empty());
+        let errors =
+            KeymapWrite::parse(r#"{"NEXT":{"type":"host","raw":"h","slot":9}}"#).unwrap_err();
+        assert_eq!(errors[0].code, KeymapErrorCode::InvalidHostSlot);
+    }
+
+    #[test]
+    fn mode_action() {
+        let k = KeymapConfig::from_json(
+            r#"{"ESC+SWITCH":{"type":"mode","raw":"mode"},
+                "CUSTOM":{"type":"mode","raw":"remote","mode":"remote"}}"#,
+        )
+        .unwrap();
+        assert!(k.validate().is_empty());
+        assert!(matches!(
+            k.chord_action(KeysPin::ESC, KeysPin::SWITCH),
+            Some(KeyAction::Mode { mode: None, .. })
+        ));
+        assert!(matches!(
+            &k.keys["CUSTOM"],
+            KeyAction::Mode {
+                mode: Some(crate::mode::RunMode::Remote),
+                ..
+            }
+        ));
+        assert!(
+            KeymapConfig::from_json(r#"{"ESC":{"type":"mode","raw":"m","mode":"x"}}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn parse_keymap_ops() {
+        assert!(matches!(
+            KeymapWrite::parse(r#"{"op":"reset"}"#),
+            Ok(KeymapWrite::Reset)
+        ));
+        assert!(matches!(
+            KeymapWrite::parse(r#"{"op":"remove","keys":["NEXT","MIC"]}"#),
+            Ok(KeymapWrite::Remove(keys)) if keys == ["NEXT", "MIC"]
+        ));
+        assert!(matches!(
+            KeymapWrite::parse(r#"{"ESC":{"type":"text","raw":"x","value":"x"}}"#),
+            Ok(KeymapWrite::Merge(_))
+        ));
+
+        let errors = KeymapWrite::parse(r#"{"op":"remove","keys":["NXT"]}"#).unwrap_err();
+        assert_eq!(errors[0].code, KeymapErrorCode::UnknownPhysicalKey);
+        let errors = KeymapWrite::parse(r#"{"op":"wipe"}"#).unwrap_err();
+        assert_eq!(errors[0].code, KeymapErrorCode::InvalidJson);
+        let errors = KeymapWrite::parse("{").unwrap_err();
+        assert_eq!(errors[0].code, KeymapErrorCode::InvalidJson);
+    }
+
+    #[test]
+    fn hid_codes_for_extended_and_shifted_keys() {
+        assert_eq!(key_name_to_hid_code("PLUS").unwrap(), (0x2E, 0x02));
+        assert_eq!(key_name_to_hid_code("MINUS").unwrap(), (0x2D, 0));
+        assert_eq!(key_name_to_hid_code("!").unwrap(), (0x1E, 0x02));
+        assert_eq!(key_name_to_hid_code("?").unwrap(), (0x38, 0x02));
+        assert_eq!(key_name_to_hid_code("/").unwrap(), (0x38, 0));
+        assert_eq!(key_name_to_hid_code("a").unwrap(), (0x04, 0));
+        assert_eq!(key_name_to_hid_code("A").unwrap(), (0x04, 0));
+        assert_eq!(key_name_to_hid_code("pgdn").unwrap(), (0x4E, 0));
+        assert_eq!(key_name_to_hid_code("F24").unwrap(), (0x73, 0));
+        assert_eq!(key_name_to_hid_code("KP_0").unwrap(), (0x62, 0));
+        assert!(key_name_to_hid_code("F25").is_err());
+    }
+
+    #[test]
+    fn chars_for_key_names() {
+        assert_eq!(key_name_to_char("PLUS"), Some(b'+'));
+        assert_eq!(key_name_to_char("QUESTION"), Some(b'?'));
+        assert_eq!(key_name_to_char("C"), Some(b'c'));
+        assert_eq!(key_name_to_char("SPACE"), Some(b' '));
+        assert_eq!(key_name_to_char("KP_7"), Some(b'7'));
+        assert_eq!(key_name_to_char("KP_SLASH"), Some(b'/'));
+        assert_eq!(key_name_to_char("HOME"), None);
+        assert_eq!(key_name_to_char("CTRL"), None);
+    }
+
+    #[test]
+    fn text_templates_parsed_on_load_and_validated() {
+        let k = KeymapConfig::from_json(
+            r#"{"CUSTOM":{"type":"text","raw":"/compact","value":"/compact{ENTER}"}}"#,
+        )
+        .unwrap();
+        assert!(k.validate().is_empty());
+        let KeyAction::Text { steps, .. } = &k.keys["CUSTOM"] else {
+            panic!("not a text action");
+        };
+        assert_eq!(steps.len(), 2);
+
+        // not a token: typed as text, as before templates existed
+        let k = KeymapConfig::from_json(r#"{"CUSTOM":{"type":"text","raw":"x","value":"{ENTR}"}}"#)
+            .unwrap();
+        assert!(k.validate().is_empty());
+
+        let k = KeymapConfig::from_json(
+            r#"{"CUSTOM":{"type":"text","raw":"x","value":"{DELAY:soon}"}}"#,
+        )
+        .unwrap();
+        let errors = k.validate();
+        assert_eq!(errors[0].code, KeymapErrorCode::InvalidTemplate);
+        assert_eq!(errors[0].value, "{DELAY:soon}: delay is not a number");
+    }
+
+    #[test]
+    fn keymap_error_json_shape() {
+        let e = KeymapError::new("NEXT", KeymapErrorCode::UnknownKeyCode, "DWON");
+        assert_eq!(
+            serde_json::to_string(&e).unwrap(),
+            r#"{"key":"NEXT","code":"unknown_key_code","value":"DWON"}"#
+        );
+    }
+}
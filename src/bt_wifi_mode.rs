@@ -4,6 +4,7 @@ use esp32_nimble::{utilities::BleUuid, uuid128, BLEService, NimbleProperties};
 use serde::{Deserialize, Serialize};
 
 use crate::audio::AsrConfig;
+use crate::backup::{ConfigDocument, SettingsSection};
 use crate::lcd;
 
 pub const SERVICE_ID: BleUuid = uuid128!("623fa3e2-631b-4f8f-a6e7-a7b09c03e7e0");
@@ -20,6 +21,28 @@ pub const WIFI_LIST_KEY: &str = "wifi_list";
 /// 会触发 ESP_ERR_NVS_KEY_TOO_LONG,故缩写;JSON 的 type 字段和 Rust 字段名保持长名不变。
 const PREFER_BUILTIN_ASR_KEY: &str = "prefer_asr";
 
+/// 当前 BLE 主机槽位(0 起),见 `bt_keyboard_mode::HOST_SLOTS`。
+const HOST_SLOT_KEY: &str = "host_slot";
+/// 各槽位绑定的主机身份地址(JSON 字符串数组,下标即槽位,空串 = 空闲)。
+const HOST_PEERS_KEY: &str = "host_peers";
+/// 是否接受新主机配对(0/1)。
+const OPEN_PAIRING_KEY: &str = "open_pairing";
+/// BLE 广播名;未设置时用带 MAC 后缀的默认名,见 `Setting::device_name`。
+const BLE_NAME_KEY: &str = "ble_name";
+const BLE_APPEARANCE_KEY: &str = "ble_appear";
+/// 配置特征值是否要求加密链路(0/1),见 `Setting::secure_config`。
+const SECURE_CONFIG_KEY: &str = "secure_cfg";
+/// 开机自动进入的模式(`BootMode::to_u8`)。
+const BOOT_MODE_KEY: &str = "boot_mode";
+/// 上次进入的模式,`BootMode::Last` 用。
+const LAST_MODE_KEY: &str = "last_mode";
+/// 局域网 REST API 的 Bearer token,空 = 关闭 API,见 `crate::rest_api`。
+const API_TOKEN_KEY: &str = "api_token";
+
+/// 读配置时代替已设置的密钥(WiFi 密码、ASR api_key)返回的掩码。
+/// 写回掩码表示「不改」,保留已存的密钥。
+pub const SECRET_MASK: &str = "********";
+
 /// 统一配置特征值的写入载荷:部分配置对象,如
 /// `{"server_url":"...","prefer_builtin_asr":false}`。只出现(非 None)的字段才更新,
 /// 缺失字段保持原状 —— 一次写可携带任意多项,免得改多项发多次。
@@ -31,9 +54,17 @@ struct ConfigSaveSnapshot {
     asr_config: Option<serde_json::Value>,
     mic_model: Option<u8>,
     prefer_builtin_asr: Option<bool>,
+    /// 空串 = 恢复默认名。
+    ble_name: Option<String>,
+    ble_appearance: Option<u16>,
+    secure_config: Option<bool>,
+    boot_mode: Option<BootMode>,
+    /// 空串 = 关闭 REST API;`SECRET_MASK` = 不改。
+    api_token: Option<String>,
 }
 
-/// 统一配置特征值的读取快照:整份 wifi_list + server_url + asr_config + mic_model + prefer_builtin_asr。
+/// 统一配置特征值的读取快照:整份 wifi_list + server_url + asr_config + mic_model + prefer_builtin_asr
+/// + 实际使用的 BLE 名称/外观。
 #[derive(Serialize)]
 struct ConfigSnapshot<'a> {
     wifi_list: &'a [WifiCred],
@@ -42,23 +73,394 @@ struct ConfigSnapshot<'a> {
     asr_config: Option<serde_json::Value>,
     mic_model: u8,
     prefer_builtin_asr: bool,
+    ble_name: String,
+    ble_appearance: u16,
+    secure_config: bool,
+    boot_mode: BootMode,
+    /// 设置过时为 `SECRET_MASK`,未设置为空串。
+    api_token: &'static str,
 }
+
+/// 开机菜单倒计时后自动进入的模式;`Menu` 表示一直等按键。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BootMode {
+    #[default]
+    Menu,
+    Keyboard,
+    Remote,
+    /// 上次进入的模式(Keyboard / Remote / Hybrid)
+    Last,
+    Hybrid,
+}
+
+impl BootMode {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            BootMode::Menu => 0,
+            BootMode::Keyboard => 1,
+            BootMode::Remote => 2,
+            BootMode::Last => 3,
+            BootMode::Hybrid => 4,
+        }
+    }
+
+    pub fn from_u8(v: u8) -> Self {
+        match v {
+            1 => BootMode::Keyboard,
+            2 => BootMode::Remote,
+            3 => BootMode::Last,
+            4 => BootMode::Hybrid,
+            _ => BootMode::Menu,
+        }
+    }
+
+    /// 设置页里循环切换的下一项。
+    pub fn next(self) -> Self {
+        Self::from_u8((self.to_u8() + 1) % 5)
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            BootMode::Menu => "Off",
+            BootMode::Keyboard => "Keyboard",
+            BootMode::Remote => "Remote",
+            BootMode::Last => "Last used",
+            BootMode::Hybrid => "Hybrid",
+        }
+    }
+}
+
 /// 单条 WiFi 凭据。顺序即连接优先级。
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///
+/// 除 ssid/pass 外的字段都可省略,省略时不写进 JSON:旧的 `{"ssid","pass"}` 条目原样可读,
+/// NVS 布局不变。
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct WifiCred {
     pub ssid: String,
+    /// WPA2-Personal 密码;有 `eap` 时是 EAP 密码。空串 = 开放网络。
     pub pass: String,
+    /// 不广播 SSID 的网络:扫描里看不到,也照样按名字去连。
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub hidden: bool,
+    /// 静态 IPv4;不设时走 DHCP。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub static_ip: Option<StaticIp>,
+    /// WPA2-Enterprise(PEAP/MSCHAPv2)的身份;设置后按企业网认证。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eap: Option<EapCred>,
+}
+
+/// 静态 IPv4 配置,如 `{"ip":"192.168.1.50","prefix":24,"gateway":"192.168.1.1","dns":"1.1.1.1"}`。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StaticIp {
+    pub ip: std::net::Ipv4Addr,
+    /// 子网前缀长度(24 = 255.255.255.0)
+    #[serde(default = "StaticIp::default_prefix")]
+    pub prefix: u8,
+    pub gateway: std::net::Ipv4Addr,
+    /// 不设时用网关当 DNS。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns: Option<std::net::Ipv4Addr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns2: Option<std::net::Ipv4Addr>,
+}
+
+impl StaticIp {
+    fn default_prefix() -> u8 {
+        24
+    }
+}
+
+/// WPA2-Enterprise 凭据,密码在 `WifiCred::pass`。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EapCred {
+    /// 外层身份(PEAP 的匿名身份)
+    pub identity: String,
+    /// 内层 MSCHAPv2 用户名;空串时用 `identity`。
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub username: String,
+}
+
+impl WifiCred {
+    /// boot 扫描看到了这个网络;隐藏网络扫不到,一律当作可能在范围内。
+    pub fn in_range(&self, scan_list: &[String]) -> bool {
+        self.hidden || scan_list.contains(&self.ssid)
+    }
+
+    /// 写入前的检查;错误信息带上 SSID,方便定位是哪一条。
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.ssid.is_empty() {
+            anyhow::bail!("empty ssid");
+        }
+        if self.ssid.len() > 32 {
+            anyhow::bail!("{:?}: ssid longer than 32 bytes", self.ssid);
+        }
+        if let Some(ip) = &self.static_ip {
+            if !(1..=32).contains(&ip.prefix) {
+                anyhow::bail!(
+                    "{:?}: static_ip.prefix {} not in 1..=32",
+                    self.ssid,
+                    ip.prefix
+                );
+            }
+        }
+        if let Some(eap) = &self.eap {
+            if eap.identity.is_empty() {
+                anyhow::bail!("{:?}: eap.identity is empty", self.ssid);
+            }
+            if self.pass.is_empty() {
+                anyhow::bail!("{:?}: eap needs a pass", self.ssid);
+            }
+            for (field, value) in [
+                ("eap.identity", &eap.identity),
+                ("eap.username", &eap.username),
+                ("pass", &self.pass),
+            ] {
+                if value.len() > MAX_EAP_FIELD_LEN {
+                    anyhow::bail!(
+                        "{:?}: {} longer than {} bytes",
+                        self.ssid,
+                        field,
+                        MAX_EAP_FIELD_LEN
+                    );
+                }
+            }
+        } else if self.pass.len() > 64 {
+            anyhow::bail!("{:?}: pass longer than 64 bytes", self.ssid);
+        }
+        Ok(())
+    }
+}
+
+/// EAP 身份、用户名和密码各自的长度上限(ESP-IDF EAP 客户端只收 1..=128 字节)
+pub const MAX_EAP_FIELD_LEN: usize = 128;
+
+/// wifi_list 序列化后的长度上限:NVS 字符串值连结尾 NUL 最多 4000 字节
+pub const MAX_WIFI_LIST_JSON: usize = 3999;
+
+/// 整个 wifi_list 写入前的检查:条数、每一条,以及存进 NVS 的 JSON 长度。
+pub fn validate_wifi_list(list: &[WifiCred]) -> anyhow::Result<()> {
+    if list.len() > MAX_WIFI_CREDS {
+        anyhow::bail!("{} entries, at most {}", list.len(), MAX_WIFI_CREDS);
+    }
+    for c in list {
+        c.validate()?;
+    }
+    let len = serde_json::to_string(list)?.len();
+    if len > MAX_WIFI_LIST_JSON {
+        anyhow::bail!(
+            "{} bytes as JSON, at most {}; shorten or drop an entry",
+            len,
+            MAX_WIFI_LIST_JSON
+        );
+    }
+    Ok(())
+}
+
+/// 读快照用:密码非空的条目换成 `SECRET_MASK`,空密码(开放网络)原样返回。
+fn redact_wifi_list(list: &[WifiCred]) -> Vec<WifiCred> {
+    list.iter()
+        .map(|c| WifiCred {
+            pass: if c.pass.is_empty() {
+                String::new()
+            } else {
+                SECRET_MASK.to_string()
+            },
+            ..c.clone()
+        })
+        .collect()
+}
+
+/// 写入的 wifi_list 里密码为 `SECRET_MASK` 的条目,沿用已存同名 SSID 的密码。
+/// 找不到同名条目(比如页面上改了 SSID)时没有可沿用的密码,整次写入拒绝、错误里带 SSID,
+/// 免得悄悄删掉这个网络。
+fn unmask_wifi_list(list: Vec<WifiCred>, stored: &[WifiCred]) -> anyhow::Result<Vec<WifiCred>> {
+    list.into_iter()
+        .map(|mut c| {
+            if c.pass != SECRET_MASK {
+                return Ok(c);
+            }
+            match stored.iter().find(|s| s.ssid == c.ssid) {
+                Some(s) => {
+                    c.pass = s.pass.clone();
+                    Ok(c)
+                }
+                None => anyhow::bail!(
+                    "{:?}: masked password but no stored network of that name, enter the password",
+                    c.ssid
+                ),
+            }
+        })
+        .collect()
+}
+
+/// 读快照/导出用:非空的 api_key 换成 `SECRET_MASK`。
+fn redact_asr_config(mut v: serde_json::Value) -> serde_json::Value {
+    if let Some(key) = v.get_mut("api_key") {
+        if key.as_str().is_some_and(|k| !k.is_empty()) {
+            *key = SECRET_MASK.into();
+        }
+    }
+    v
+}
+
+/// NVS 里一个键的值,`None` 表示没有这个键。restore 写之前先按同样类型读出原值,失败时写回。
+enum NvsValue {
+    Str(Option<String>),
+    U8(Option<u8>),
+    U16(Option<u16>),
+    Blob(Option<Vec<u8>>),
 }
 
-/// 最多保存多少个 WiFi 配置:把 JSON 体量压在 NVS 单值 ~4KB 限额内。
+impl NvsValue {
+    /// 按 `self` 的类型读出 `key` 现在的值
+    fn read_same_kind(
+        &self,
+        nvs: &esp_idf_svc::nvs::EspDefaultNvs,
+        key: &str,
+    ) -> anyhow::Result<NvsValue> {
+        Ok(match self {
+            NvsValue::Str(_) => NvsValue::Str(match nvs.str_len(key)? {
+                Some(len) => {
+                    let mut buf = vec![0u8; len];
+                    nvs.get_str(key, &mut buf)?.map(str::to_string)
+                }
+                None => None,
+            }),
+            NvsValue::U8(_) => NvsValue::U8(nvs.get_u8(key)?),
+            NvsValue::U16(_) => NvsValue::U16(nvs.get_u16(key)?),
+            NvsValue::Blob(_) => NvsValue::Blob(match nvs.blob_len(key)? {
+                Some(len) => {
+                    let mut buf = vec![0u8; len];
+                    nvs.get_blob(key, &mut buf)?.map(<[u8]>::to_vec)
+                }
+                None => None,
+            }),
+        })
+    }
+
+    fn write(&self, nvs: &mut esp_idf_svc::nvs::EspDefaultNvs, key: &str) -> anyhow::Result<()> {
+        match self {
+            NvsValue::Str(Some(v)) => nvs.set_str(key, v)?,
+            NvsValue::U8(Some(v)) => nvs.set_u8(key, *v)?,
+            NvsValue::U16(Some(v)) => nvs.set_u16(key, *v)?,
+            NvsValue::Blob(Some(v)) => nvs.set_blob(key, v)?,
+            NvsValue::Str(None)
+            | NvsValue::U8(None)
+            | NvsValue::U16(None)
+            | NvsValue::Blob(None) => {
+                nvs.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 依次写入;某个键写失败时,把已经写过的键倒序写回原值,再返回那个错误。
+fn write_or_roll_back(
+    nvs: &mut esp_idf_svc::nvs::EspDefaultNvs,
+    writes: Vec<(&str, NvsValue)>,
+) -> anyhow::Result<()> {
+    let mut undo = Vec::with_capacity(writes.len());
+    let mut failed = None;
+    for (key, value) in writes {
+        let result = value.read_same_kind(nvs, key).and_then(|old| {
+            undo.push((key, old));
+            value.write(nvs, key)
+        });
+        if let Err(e) = result {
+            failed = Some(save_failed(key, e));
+            break;
+        }
+    }
+    let Some(e) = failed else {
+        return Ok(());
+    };
+    log::error!("Config restore: {}, rolling back", e);
+    for (key, old) in undo.iter().rev() {
+        if let Err(rollback) = old.write(nvs, key) {
+            log::error!(
+                "Config restore: rolling back {} failed: {:?}",
+                key,
+                rollback
+            );
+            return Err(e.context("could not roll back, settings may be partly restored"));
+        }
+    }
+    Err(e.context("nothing restored"))
+}
+
+/// `apply_config` / `restore` 校验通过但写 NVS 失败;REST 据此回 500 而不是 400。
+#[derive(Debug)]
+pub struct SaveFailed(String);
+
+impl std::fmt::Display for SaveFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SaveFailed {}
+
+fn save_failed(field: &str, e: impl std::fmt::Display) -> anyhow::Error {
+    SaveFailed(format!("{}: failed to save ({})", field, e)).into()
+}
+
+/// 合并写(默认值 < 现有 NVS < 本次传入):只覆盖传入里出现的 key,
+/// 缺失的 key 保持原状 —— 不完整的 JSON 也能增量更新。api_key 为掩码时保留原值。
+fn merge_asr_config(
+    nvs: &esp_idf_svc::nvs::EspDefaultNvs,
+    asr: &serde_json::Value,
+) -> anyhow::Result<AsrConfig> {
+    let mut base = AsrConfig::load_from_nvs(nvs)
+        .and_then(|c| serde_json::to_value(&c).ok())
+        .unwrap_or_else(
+            || serde_json::json!({"platform":"whisper","uri":"","api_key":"","model":""}),
+        );
+    if let (Some(base_obj), Some(in_obj)) = (base.as_object_mut(), asr.as_object()) {
+        for (k, v) in in_obj {
+            if k == "api_key" && v.as_str() == Some(SECRET_MASK) {
+                continue;
+            }
+            base_obj.insert(k.clone(), v.clone());
+        }
+    }
+    Ok(serde_json::from_value::<AsrConfig>(base)?)
+}
+
+/// 迁移(`crate::nvs_schema`)经这层访问 NVS,测试里换成内存 KV。
+impl crate::nvs_schema::KvStore for esp_idf_svc::nvs::EspDefaultNvs {
+    fn get_u8(&self, key: &str) -> anyhow::Result<Option<u8>> {
+        Ok(esp_idf_svc::nvs::EspDefaultNvs::get_u8(self, key)?)
+    }
+
+    fn set_u8(&mut self, key: &str, value: u8) -> anyhow::Result<()> {
+        Ok(esp_idf_svc::nvs::EspDefaultNvs::set_u8(self, key, value)?)
+    }
+
+    fn get_string(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let mut buf = [0u8; 4096];
+        Ok(self.get_str(key, &mut buf)?.map(|s| s.to_string()))
+    }
+
+    fn set_string(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        Ok(self.set_str(key, value)?)
+    }
+
+    fn remove(&mut self, key: &str) -> anyhow::Result<()> {
+        esp_idf_svc::nvs::EspDefaultNvs::remove(self, key)?;
+        Ok(())
+    }
+}
+
+/// 最多保存多少个 WiFi 配置;整份 JSON 另有 `MAX_WIFI_LIST_JSON` 的长度上限。
 pub const MAX_WIFI_CREDS: usize = 8;
 
 /// 在已配置凭据里找出第一个出现在扫描结果中的(顺序即优先级)。
-/// 连接时用:不重新扫描,复用 boot 阶段的 `scan_list`。
+/// 连接时用:不重新扫描,复用 boot 阶段的 `scan_list`。隐藏网络扫不到,视为在范围内。
 pub fn pick_cred<'a>(scan_list: &[String], creds: &'a [WifiCred]) -> Option<&'a WifiCred> {
-    creds
-        .iter()
-        .find(|c| scan_list.iter().any(|s| s == &c.ssid))
+    creds.iter().find(|c| c.in_range(scan_list))
 }
 
 #[derive(Debug, Clone)]
@@ -70,6 +472,22 @@ pub struct Setting {
     pub mic_model: u8,
     /// 键盘模式下是否优先用内置 ASR(Whisper);false 时 MIC 透传给主机(触发主机自带听写)。
     pub prefer_builtin_asr: bool,
+    /// 键盘模式用哪个 BLE 主机槽位广播(0 起)。
+    pub host_slot: u8,
+    /// 各槽位已绑定主机的身份地址,见 `bt_keyboard_mode::admit_peer`。
+    pub host_peers: Vec<String>,
+    /// false 时只有已绑定槽位的主机能连上,新配对一律断开。
+    pub open_pairing: bool,
+    /// 自定义 BLE 名称,空串表示用默认名。
+    pub ble_name: String,
+    pub ble_appearance: u16,
+    /// true 时配置特征值只能经加密(已配对)链路读写,重启后生效。
+    pub secure_config: bool,
+    pub boot_mode: BootMode,
+    /// 上次进入的模式,只会是 Keyboard / Remote(从没进过时为 Menu)。
+    pub last_mode: BootMode,
+    /// 局域网 REST API 的 token,空串表示不开 API。
+    pub api_token: String,
     state: u8,
 }
 
@@ -80,16 +498,97 @@ impl Setting {
         list: &[WifiCred],
     ) -> anyhow::Result<()> {
         let json = serde_json::to_string(list)?;
+        if json.len() > MAX_WIFI_LIST_JSON {
+            anyhow::bail!(
+                "wifi_list: {} bytes as JSON, at most {}",
+                json.len(),
+                MAX_WIFI_LIST_JSON
+            );
+        }
         nvs.set_str(WIFI_LIST_KEY, &json)?;
         Ok(())
     }
 
+    pub fn save_host_slot(
+        nvs: &mut esp_idf_svc::nvs::EspDefaultNvs,
+        slot: u8,
+    ) -> anyhow::Result<()> {
+        nvs.set_u8(HOST_SLOT_KEY, slot)?;
+        Ok(())
+    }
+
+    pub fn save_host_peers(
+        nvs: &mut esp_idf_svc::nvs::EspDefaultNvs,
+        peers: &[String],
+    ) -> anyhow::Result<()> {
+        let json = serde_json::to_string(peers)?;
+        nvs.set_str(HOST_PEERS_KEY, &json)?;
+        Ok(())
+    }
+
+    pub fn save_server_url(
+        nvs: &mut esp_idf_svc::nvs::EspDefaultNvs,
+        url: &str,
+    ) -> anyhow::Result<()> {
+        nvs.set_str("server_url", url)?;
+        Ok(())
+    }
+
+    pub fn save_mic_model(
+        nvs: &mut esp_idf_svc::nvs::EspDefaultNvs,
+        mode: u8,
+    ) -> anyhow::Result<()> {
+        nvs.set_u8("mic_model", mode)?;
+        Ok(())
+    }
+
+    pub fn save_prefer_builtin_asr(
+        nvs: &mut esp_idf_svc::nvs::EspDefaultNvs,
+        prefer: bool,
+    ) -> anyhow::Result<()> {
+        nvs.set_u8(PREFER_BUILTIN_ASR_KEY, prefer as u8)?;
+        Ok(())
+    }
+
+    pub fn save_boot_mode(
+        nvs: &mut esp_idf_svc::nvs::EspDefaultNvs,
+        mode: BootMode,
+    ) -> anyhow::Result<()> {
+        nvs.set_u8(BOOT_MODE_KEY, mode.to_u8())?;
+        Ok(())
+    }
+
+    pub fn save_last_mode(
+        nvs: &mut esp_idf_svc::nvs::EspDefaultNvs,
+        mode: BootMode,
+    ) -> anyhow::Result<()> {
+        nvs.set_u8(LAST_MODE_KEY, mode.to_u8())?;
+        Ok(())
+    }
+
+    pub fn save_open_pairing(
+        nvs: &mut esp_idf_svc::nvs::EspDefaultNvs,
+        open: bool,
+    ) -> anyhow::Result<()> {
+        nvs.set_u8(OPEN_PAIRING_KEY, open as u8)?;
+        Ok(())
+    }
+
     pub fn clear_nvs(nvs: &mut esp_idf_svc::nvs::EspDefaultNvs) -> anyhow::Result<()> {
         nvs.remove(WIFI_LIST_KEY)?;
         nvs.remove("server_url")?;
         nvs.remove("background_png")?;
         nvs.remove("mic_model")?;
         nvs.remove(PREFER_BUILTIN_ASR_KEY)?;
+        nvs.remove(HOST_SLOT_KEY)?;
+        nvs.remove(HOST_PEERS_KEY)?;
+        nvs.remove(OPEN_PAIRING_KEY)?;
+        nvs.remove(BLE_NAME_KEY)?;
+        nvs.remove(BLE_APPEARANCE_KEY)?;
+        nvs.remove(SECURE_CONFIG_KEY)?;
+        nvs.remove(BOOT_MODE_KEY)?;
+        nvs.remove(LAST_MODE_KEY)?;
+        nvs.remove(API_TOKEN_KEY)?;
         nvs.remove("state")?;
         Ok(())
     }
@@ -97,7 +596,7 @@ impl Setting {
     pub fn load_from_nvs(nvs: &esp_idf_svc::nvs::EspDefaultNvs) -> anyhow::Result<Self> {
         let mut str_buf = [0; 128];
 
-        // wifi_list 以单个 JSON 值存放。旧的 ssid/pass NVS 数据直接丢弃(不迁移)。
+        // wifi_list 以单个 JSON 值存放。旧的 ssid/pass 已由 `nvs_schema::migrate` 转换过。
         let mut json_buf = [0u8; 4096];
         let wifi_list = nvs
             .get_str(WIFI_LIST_KEY, &mut json_buf)
@@ -162,16 +661,362 @@ impl Setting {
         let mic_model = nvs.get_u8("mic_model")?.unwrap_or(1);
         let prefer_builtin_asr = nvs.get_u8(PREFER_BUILTIN_ASR_KEY)?.unwrap_or(1) != 0;
 
+        let host_slot = nvs.get_u8(HOST_SLOT_KEY)?.unwrap_or(0);
+        let mut peers_buf = [0u8; 512];
+        let host_peers = nvs
+            .get_str(HOST_PEERS_KEY, &mut peers_buf)
+            .map_err(|e| log::error!("Failed to get host_peers: {:?}", e))
+            .ok()
+            .flatten()
+            .and_then(|s| serde_json::from_str::<Vec<String>>(s).ok())
+            .unwrap_or_default();
+        let open_pairing = nvs.get_u8(OPEN_PAIRING_KEY)?.unwrap_or(1) != 0;
+        let ble_name = nvs
+            .get_str(BLE_NAME_KEY, &mut str_buf)
+            .map_err(|e| log::error!("Failed to get ble_name: {:?}", e))
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+            .to_string();
+        let ble_appearance = nvs
+            .get_u16(BLE_APPEARANCE_KEY)?
+            .unwrap_or(crate::bt_keyboard_mode::DEFAULT_APPEARANCE);
+        let secure_config = nvs.get_u8(SECURE_CONFIG_KEY)?.unwrap_or(0) != 0;
+        let boot_mode = BootMode::from_u8(nvs.get_u8(BOOT_MODE_KEY)?.unwrap_or(0));
+        let last_mode = BootMode::from_u8(nvs.get_u8(LAST_MODE_KEY)?.unwrap_or(0));
+        let api_token = nvs
+            .get_str(API_TOKEN_KEY, &mut str_buf)
+            .map_err(|e| log::error!("Failed to get api_token: {:?}", e))
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+            .to_string();
+
         Ok(Setting {
             wifi_list,
             server_url,
             background_png: (background_png, false),
             mic_model,
             prefer_builtin_asr,
+            host_slot,
+            host_peers,
+            open_pairing,
+            ble_name,
+            ble_appearance,
+            secure_config,
+            boot_mode,
+            last_mode,
+            api_token,
             state,
         })
     }
 
+    /// 开机要自动进入的模式(Keyboard / Remote);`Last` 解析成上次的模式,
+    /// 关闭或从没进过任何模式时为 None。
+    pub fn auto_boot(&self) -> Option<BootMode> {
+        let mode = match self.boot_mode {
+            BootMode::Last => self.last_mode,
+            m => m,
+        };
+        matches!(
+            mode,
+            BootMode::Keyboard | BootMode::Remote | BootMode::Hybrid
+        )
+        .then_some(mode)
+    }
+
+    /// 实际广播的 BLE 名称:自定义名,或「VibeKeys-」加 MAC 末两字节。
+    pub fn device_name(&self) -> String {
+        if !self.ble_name.is_empty() {
+            return self.ble_name.clone();
+        }
+        match crate::bt_keyboard_mode::bt_mac() {
+            Ok(mac) => crate::bt_keyboard_mode::default_device_name(mac),
+            Err(e) => {
+                log::error!("Failed to read BT MAC: {:?}", e);
+                "VibeKeys".to_string()
+            }
+        }
+    }
+
+    /// 导出整份配置(格式见 `crate::backup`)。`secrets` 为 false 时密钥以掩码代替;
+    /// `background` 为 true 且设置过背景图时附带背景图(base64,体积较大)。
+    pub fn export(
+        &self,
+        nvs: &esp_idf_svc::nvs::EspDefaultNvs,
+        keymap: &crate::bt_keyboard_mode::KeymapConfig,
+        secrets: bool,
+        background: bool,
+    ) -> ConfigDocument {
+        let wifi_list = if secrets {
+            self.wifi_list.clone()
+        } else {
+            redact_wifi_list(&self.wifi_list)
+        };
+        let mut doc = ConfigDocument::new(
+            SettingsSection {
+                wifi_list,
+                server_url: self.server_url.clone(),
+                mic_model: self.mic_model,
+                prefer_builtin_asr: self.prefer_builtin_asr,
+                ble_name: self.ble_name.clone(),
+                ble_appearance: self.ble_appearance,
+                open_pairing: self.open_pairing,
+                secure_config: self.secure_config,
+                boot_mode: self.boot_mode,
+            },
+            secrets,
+        );
+        doc.asr_config = AsrConfig::load_from_nvs(nvs)
+            .and_then(|cfg| serde_json::to_value(&cfg).ok())
+            .map(|v| if secrets { v } else { redact_asr_config(v) });
+        doc.keymap = Some(keymap.clone());
+        let has_background = nvs.blob_len("background_png").ok().flatten().unwrap_or(0) > 0;
+        if background && has_background {
+            doc.background = Some(crate::backup::base64_encode(&self.background_png.0));
+        }
+        doc
+    }
+
+    /// 整份恢复。先做完所有检查(文档本身、asr_config 合并、掩码密钥)才开始写 NVS;写到一半
+    /// 失败(如 NVS 满)时把已写的键按原值写回再返回错误,设备保持恢复前的配置。只有回滚也失败、
+    /// 或写的过程中掉电,才会留下新旧混合的配置。keymap 直接写 NVS,调用方恢复后应重启让各模块
+    /// 重新加载。
+    pub fn restore(
+        &mut self,
+        nvs: &mut esp_idf_svc::nvs::EspDefaultNvs,
+        doc: ConfigDocument,
+    ) -> anyhow::Result<()> {
+        doc.validate()?;
+        let asr = doc
+            .asr_config
+            .as_ref()
+            .map(|v| merge_asr_config(nvs, v))
+            .transpose()?;
+        let background = doc.background_bytes();
+        let s = doc.settings;
+        // 掩码换回真实密码后 JSON 会变长,要再查一次长度
+        let wifi_list = unmask_wifi_list(s.wifi_list, &self.wifi_list)
+            .and_then(|list| validate_wifi_list(&list).map(|()| list))
+            .map_err(|e| anyhow::anyhow!("settings.wifi_list: {}", e))?;
+
+        let mut writes = vec![
+            (
+                WIFI_LIST_KEY,
+                NvsValue::Str(Some(serde_json::to_string(&wifi_list)?)),
+            ),
+            ("server_url", NvsValue::Str(Some(s.server_url.clone()))),
+            ("mic_model", NvsValue::U8(Some(s.mic_model))),
+            (
+                PREFER_BUILTIN_ASR_KEY,
+                NvsValue::U8(Some(s.prefer_builtin_asr as u8)),
+            ),
+            (
+                BLE_NAME_KEY,
+                NvsValue::Str((!s.ble_name.is_empty()).then(|| s.ble_name.clone())),
+            ),
+            (BLE_APPEARANCE_KEY, NvsValue::U16(Some(s.ble_appearance))),
+            (OPEN_PAIRING_KEY, NvsValue::U8(Some(s.open_pairing as u8))),
+            (SECURE_CONFIG_KEY, NvsValue::U8(Some(s.secure_config as u8))),
+            (BOOT_MODE_KEY, NvsValue::U8(Some(s.boot_mode.to_u8()))),
+        ];
+        if let Some(asr) = &asr {
+            writes.push((
+                "asr_config",
+                NvsValue::Str(Some(serde_json::to_string(asr)?)),
+            ));
+        }
+        if let Some(keymap) = &doc.keymap {
+            writes.push((
+                "keymap_config",
+                NvsValue::Blob(Some(keymap.to_json()?.into_bytes())),
+            ));
+        }
+        if let Some(png) = &background {
+            writes.push(("background_png", NvsValue::Blob(Some(png.clone()))));
+        }
+        write_or_roll_back(nvs, writes)?;
+
+        if let Some(png) = background {
+            self.background_png = (png, false);
+        }
+        self.wifi_list = wifi_list;
+        self.server_url = s.server_url;
+        self.mic_model = s.mic_model;
+        self.prefer_builtin_asr = s.prefer_builtin_asr;
+        self.ble_name = s.ble_name;
+        self.ble_appearance = s.ble_appearance;
+        self.open_pairing = s.open_pairing;
+        self.secure_config = s.secure_config;
+        self.boot_mode = s.boot_mode;
+        log::info!(
+            "Configuration restored from backup (version {})",
+            doc.version
+        );
+        Ok(())
+    }
+
+    /// 统一配置特征值的读取快照(JSON),密钥以 `SECRET_MASK` 代替。SoftAP 配网页也用它。
+    pub fn config_snapshot(&self, nvs: &esp_idf_svc::nvs::EspDefaultNvs) -> anyhow::Result<String> {
+        // 密钥只写不读:WiFi 密码和 api_key 以 SECRET_MASK 代替。
+        let asr_config = AsrConfig::load_from_nvs(nvs);
+        log::info!(
+            "Config read: asr_config = {}",
+            asr_config.as_ref().map(|_| "present").unwrap_or("absent")
+        );
+        let asr_config = asr_config
+            .and_then(|cfg| serde_json::to_value(&cfg).ok())
+            .map(redact_asr_config);
+        let wifi_list = redact_wifi_list(&self.wifi_list);
+        let snap = ConfigSnapshot {
+            wifi_list: &wifi_list,
+            server_url: self.server_url.as_str(),
+            asr_config,
+            mic_model: self.mic_model,
+            prefer_builtin_asr: self.prefer_builtin_asr,
+            ble_name: self.device_name(),
+            ble_appearance: self.ble_appearance,
+            secure_config: self.secure_config,
+            boot_mode: self.boot_mode,
+            api_token: if self.api_token.is_empty() {
+                ""
+            } else {
+                SECRET_MASK
+            },
+        };
+        Ok(serde_json::to_string(&snap)?)
+    }
+
+    /// 应用一次配置写入(`ConfigSaveSnapshot` JSON):只更新出现的字段,各自即时落盘。
+    /// BLE 配置特征值、SoftAP 配网页和 REST API 共用。先校验全部字段,任一不合法整次
+    /// 拒绝、什么都不写(返回的错误带字段名)。某个字段写 NVS 失败时在那里停下,返回
+    /// `SaveFailed`(REST 和配网页回 500,BLE 经 config_result 回报);之前已写的字段保留,
+    /// 内存里只更新写成功的字段。
+    pub fn apply_config(
+        &mut self,
+        nvs: &mut esp_idf_svc::nvs::EspDefaultNvs,
+        payload: &str,
+    ) -> anyhow::Result<()> {
+        // 载荷里可能带明文密钥,不打印原文。
+        let save = serde_json::from_str::<ConfigSaveSnapshot>(payload)
+            .map_err(|e| anyhow::anyhow!("invalid JSON ({})", e))?;
+
+        let wifi_list = save
+            .wifi_list
+            .map(|list| unmask_wifi_list(list, &self.wifi_list))
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("wifi_list: {}", e))?;
+        if let Some(list) = &wifi_list {
+            validate_wifi_list(list).map_err(|e| anyhow::anyhow!("wifi_list: {}", e))?;
+        }
+        // asr_config 与已存的合并后才能判断是否完整
+        let asr = save
+            .asr_config
+            .as_ref()
+            .map(|v| merge_asr_config(nvs, v))
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("asr_config: {}", e))?;
+        if let Some(m) = save.mic_model {
+            if m > 1 {
+                anyhow::bail!("mic_model: {} is not 0 (PTT) or 1 (Toggle)", m);
+            }
+        }
+        let ble_name = save.ble_name.map(|n| n.trim().to_string());
+        if let Some(name) = &ble_name {
+            if !name.is_empty() && !crate::bt_keyboard_mode::is_valid_device_name(name) {
+                anyhow::bail!("ble_name: invalid name {:?}", name);
+            }
+        }
+        let api_token = save.api_token.filter(|t| t != SECRET_MASK);
+        if let Some(t) = &api_token {
+            if !t.is_empty() && !crate::api_auth::valid_token(t) {
+                anyhow::bail!(
+                    "api_token: {} to {} printable characters without spaces",
+                    crate::api_auth::MIN_TOKEN_LEN,
+                    crate::api_auth::MAX_TOKEN_LEN
+                );
+            }
+        }
+
+        // 写 NVS 失败就把错误交给调用方(BLE / 配网页 / REST 据此报失败);
+        // 出错之前的字段已经写入,出错的字段及之后的保持原状。
+        if let Some(list) = wifi_list {
+            Setting::save_wifi_list(nvs, &list).map_err(|e| save_failed("wifi_list", e))?;
+            self.wifi_list = list;
+        }
+
+        if let Some(url) = save.server_url {
+            nvs.set_str("server_url", &url)
+                .map_err(|e| save_failed("server_url", e))?;
+            self.server_url = url;
+        }
+
+        // asr_config 走独立 NVS 键,重启后由 main.rs 重新加载。
+        if let Some(cfg) = asr {
+            cfg.save_to_nvs(nvs)
+                .map_err(|e| save_failed("asr_config", e))?;
+        }
+
+        if let Some(m) = save.mic_model {
+            nvs.set_u8("mic_model", m)
+                .map_err(|e| save_failed("mic_model", e))?;
+            self.mic_model = m;
+        }
+
+        if let Some(b) = save.prefer_builtin_asr {
+            nvs.set_u8(PREFER_BUILTIN_ASR_KEY, b as u8)
+                .map_err(|e| save_failed("prefer_builtin_asr", e))?;
+            self.prefer_builtin_asr = b;
+        }
+
+        // 名称/外观在下次启动广播时生效。
+        if let Some(name) = ble_name {
+            let r = if name.is_empty() {
+                nvs.remove(BLE_NAME_KEY).map(|_| ())
+            } else {
+                nvs.set_str(BLE_NAME_KEY, &name)
+            };
+            r.map_err(|e| save_failed("ble_name", e))?;
+            self.ble_name = name;
+        }
+
+        if let Some(a) = save.ble_appearance {
+            nvs.set_u16(BLE_APPEARANCE_KEY, a)
+                .map_err(|e| save_failed("ble_appearance", e))?;
+            self.ble_appearance = a;
+        }
+
+        if let Some(b) = save.secure_config {
+            nvs.set_u8(SECURE_CONFIG_KEY, b as u8)
+                .map_err(|e| save_failed("secure_config", e))?;
+            self.secure_config = b;
+        }
+
+        if let Some(m) = save.boot_mode {
+            Setting::save_boot_mode(nvs, m).map_err(|e| save_failed("boot_mode", e))?;
+            self.boot_mode = m;
+        }
+
+        // 立即生效:REST 鉴权每次都读当前 token
+        if let Some(t) = api_token {
+            let r = if t.is_empty() {
+                nvs.remove(API_TOKEN_KEY).map(|_| ())
+            } else {
+                nvs.set_str(API_TOKEN_KEY, &t)
+            };
+            r.map_err(|e| save_failed("api_token", e))?;
+            self.api_token = t;
+        }
+        Ok(())
+    }
+
+    /// `GET /api/asr` 用:已存的 asr_config,api_key 以掩码代替。
+    pub fn asr_snapshot(nvs: &esp_idf_svc::nvs::EspDefaultNvs) -> Option<serde_json::Value> {
+        AsrConfig::load_from_nvs(nvs)
+            .and_then(|cfg| serde_json::to_value(&cfg).ok())
+            .map(redact_asr_config)
+    }
+
     pub fn need_init(&self) -> bool {
         self.state == 1 || self.wifi_list.is_empty() || self.server_url.is_empty()
     }
@@ -179,6 +1024,8 @@ impl Setting {
 
 pub enum BTevent {
     Reset,
+    /// 一次配置写入的结果:`None` 成功,否则是错误信息;由运行中的模式经通知特征值回给页面
+    ConfigResult(Option<String>),
 }
 
 pub fn new_setting_service(
@@ -188,28 +1035,20 @@ pub fn new_setting_service(
 ) -> anyhow::Result<()> {
     let config_r = setting.clone();
     let config_w = setting.clone();
+    let evt_tx_config = evt_tx.clone();
 
-    let config_characteristic =
-        service.create_characteristic(CONFIG_ID, NimbleProperties::READ | NimbleProperties::WRITE);
+    // 开启后要求加密链路:未配对的 central 读写会先触发配对。
+    let mut config_props = NimbleProperties::READ | NimbleProperties::WRITE;
+    if setting.lock().unwrap().0.secure_config {
+        config_props |= NimbleProperties::READ_ENC | NimbleProperties::WRITE_ENC;
+    }
+    let config_characteristic = service.create_characteristic(CONFIG_ID, config_props);
     config_characteristic
         .lock()
         .on_read(move |c, _| {
             // 读一次返回整份快照(wifi_list + server_url + asr_config + mic_model),免去多次读。
             let setting = config_r.lock().unwrap();
-            let asr_config = AsrConfig::load_from_nvs(&setting.1);
-            log::info!(
-                "Config read: asr_config = {}",
-                asr_config.as_ref().map(|_| "present").unwrap_or("absent")
-            );
-            let asr_config = asr_config.and_then(|cfg| serde_json::to_value(&cfg).ok());
-            let snap = ConfigSnapshot {
-                wifi_list: &setting.0.wifi_list,
-                server_url: setting.0.server_url.as_str(),
-                asr_config,
-                mic_model: setting.0.mic_model,
-                prefer_builtin_asr: setting.0.prefer_builtin_asr,
-            };
-            match serde_json::to_string(&snap) {
+            match setting.0.config_snapshot(&setting.1) {
                 Ok(json) => {
                     c.set_value(json.as_bytes());
                 }
@@ -219,111 +1058,32 @@ pub fn new_setting_service(
         .on_write(move |args| {
             // 写入载荷:部分配置对象,如 {"server_url":"...","prefer_builtin_asr":false}。
             // 只出现(非 None)的字段才更新,缺失字段保持原状 —— 一次写可携带任意多项。
-            log::info!("Config write: {:?}", args.recv_data());
-            let payload = match std::str::from_utf8(args.recv_data()) {
-                Ok(s) => s,
-                Err(_) => {
-                    log::error!("Config write: payload not UTF-8");
-                    return;
-                }
-            };
-            log::info!("Config write payload: {}", payload);
-            let save = match serde_json::from_str::<ConfigSaveSnapshot>(payload) {
-                Ok(s) => s,
-                Err(e) => {
-                    log::error!("Config write: invalid JSON ({}): {}", e, payload);
-                    return;
-                }
-            };
-            log::info!("Config write: {:?}", save);
-            let mut setting = config_w.lock().unwrap();
-
-            if let Some(mut list) = save.wifi_list {
-                if list.len() > MAX_WIFI_CREDS {
-                    log::warn!(
-                        "wifi_list has {} entries, truncating to {}",
-                        list.len(),
-                        MAX_WIFI_CREDS
-                    );
-                    list.truncate(MAX_WIFI_CREDS);
-                }
-                setting.0.wifi_list = list;
-                // 经 MutexGuard 的 Deref,先 clone 再写 NVS,避免同时借 guard。
-                let l = setting.0.wifi_list.clone();
-                if let Err(e) = Setting::save_wifi_list(&mut setting.1, &l) {
-                    log::error!("Failed to save wifi_list: {:?}", e);
-                }
-            }
-
-            if let Some(url) = save.server_url {
-                setting.0.server_url = url.clone();
-                if let Err(e) = setting.1.set_str("server_url", &url) {
-                    log::error!("Failed to save server_url: {:?}", e);
-                }
-            }
-
-            if let Some(asr) = save.asr_config {
-                // 合并写(默认值 < 现有 NVS < 本次传入):只覆盖传入里出现的 key,
-                // 缺失的 key 保持原状 —— 不完整的 JSON 也能增量更新。
-                // asr_config 走独立 NVS 键,重启后由 main.rs 重新加载。
-                let mut base = AsrConfig::load_from_nvs(&setting.1)
-                    .and_then(|c| serde_json::to_value(&c).ok())
-                    .unwrap_or_else(|| {
-                        serde_json::json!({"platform":"whisper","uri":"","api_key":"","model":""})
-                    });
-                if let (Some(base_obj), Some(in_obj)) = (base.as_object_mut(), asr.as_object()) {
-                    for (k, v) in in_obj {
-                        base_obj.insert(k.clone(), v.clone());
-                    }
-                }
-                match serde_json::from_value::<AsrConfig>(base) {
-                    Ok(cfg) => {
-                        if let Err(e) = cfg.save_to_nvs(&mut setting.1) {
-                            log::error!("Failed to save asr_config: {:?}", e);
-                        }
-                    }
-                    Err(e) => log::error!("asr_config value invalid after merge: {:?}", e),
-                }
-            }
-
-            if let Some(m) = save.mic_model {
-                setting.0.mic_model = m;
-                if let Err(e) = setting.1.set_u8("mic_model", m) {
-                    log::error!("Failed to save mic_model: {:?}", e);
-                }
-            }
-
-            if let Some(b) = save.prefer_builtin_asr {
-                setting.0.prefer_builtin_asr = b;
-                if let Err(e) = setting.1.set_u8(PREFER_BUILTIN_ASR_KEY, b as u8) {
-                    log::error!("Failed to save prefer_builtin_asr: {:?}", e);
+            let result = std::str::from_utf8(args.recv_data())
+                .map_err(|_| anyhow::anyhow!("payload not UTF-8"))
+                .and_then(|payload| {
+                    let mut setting = config_w.lock().unwrap();
+                    let (setting, nvs) = &mut *setting;
+                    setting.apply_config(nvs, payload)
+                });
+            let error = result.err().map(|e| {
+                log::error!("Config write: {}", e);
+                e.to_string()
+            });
+            // 不能在 NimBLE 任务里阻塞:队列满了就只留日志
+            if let Some(tx) = &evt_tx_config {
+                if tx.try_send(BTevent::ConfigResult(error)).is_err() {
+                    log::warn!("Config write: result not reported, event queue full");
                 }
             }
         });
 
-    let setting_gif = setting.clone();
-
+    // 旧背景图特征值:没有长度和校验,不足 512 字节的写就当作结尾。背景图改走同一服务上的
+    // 分块传输特征值(`bt_keyboard_mode::TRANSFER_ID`),这里只保留 UUID 让旧页面收到明确的拒绝。
     let background_png_characteristic =
         service.create_characteristic(BACKGROUND_PNG_ID, NimbleProperties::WRITE);
-    background_png_characteristic.lock().on_write(move |args| {
-        let gif_chunk = args.recv_data();
-
-        if gif_chunk.len() <= 1024 * 1024 && gif_chunk.len() > 0 {
-            log::info!("New background GIF received, size: {}", gif_chunk.len());
-            let mut setting = setting_gif.lock().unwrap();
-            setting.0.background_png.0.extend_from_slice(gif_chunk);
-            if gif_chunk.len() < 512 {
-                setting.0.background_png.1 = true; // Mark as valid
-            }
-            if setting.0.background_png.0.len() > 1024 * 1024 {
-                log::warn!("Background GIF size exceeds 1024KB, resetting to default.");
-                setting.0.background_png.0.clear();
-                setting.0.background_png.1 = false;
-                args.reject();
-            }
-        } else {
-            log::error!("Failed to parse new background GIF from bytes.");
-        }
+    background_png_characteristic.lock().on_write(|args| {
+        log::warn!("Background write on the retired characteristic rejected; use the transfer characteristic");
+        args.reject();
     });
 
     let evt_tx_reset = evt_tx.clone();
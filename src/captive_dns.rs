@@ -0,0 +1,124 @@
+//! DNS answers for the SoftAP captive portal (`crate::provision`).
+//!
+//! While provisioning, every name resolves to the device. Phones probe a known URL
+//! (`connectivitycheck.gstatic.com/generate_204`, `captive.apple.com/hotspot-detect.html`, …)
+//! after joining a network; the probe lands on the device's HTTP server, which redirects it
+//! to the setup page, and the OS shows its "sign in to network" sheet.
+//!
+//! Only the first question of a standard query is answered. `A` (and `ANY`) get the device
+//! address; other types get an empty `NOERROR` reply so clients fall back to IPv4 instead
+//! of waiting for a timeout.
+
+use std::net::Ipv4Addr;
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Short, so clients re-resolve soon after the device leaves provisioning
+const TTL_SECS: u32 = 60;
+
+/// Reply to `query` pointing every name at `ip`; `None` for anything that is not a
+/// well-formed standard query.
+pub fn answer(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    if query.len() < HEADER_LEN {
+        return None;
+    }
+    let flags = u16::from_be_bytes([query[2], query[3]]);
+    let qdcount = u16::from_be_bytes([query[4], query[5]]);
+    // QR must be 0 (query) and the opcode 0 (standard query)
+    if flags & 0x8000 != 0 || (flags >> 11) & 0xf != 0 || qdcount == 0 {
+        return None;
+    }
+
+    // QNAME: length-prefixed labels ending with a zero byte; queries carry no compression
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        if len > 63 {
+            return None;
+        }
+        pos += len;
+    }
+    let question_end = pos + 4;
+    let question = query.get(HEADER_LEN..question_end)?;
+    let qtype = u16::from_be_bytes([query[pos], query[pos + 1]]);
+    let qclass = u16::from_be_bytes([query[pos + 2], query[pos + 3]]);
+    let answered = matches!(qtype, TYPE_A | TYPE_ANY) && qclass == CLASS_IN;
+
+    let mut reply = Vec::with_capacity(question_end + 16);
+    reply.extend_from_slice(&query[..2]);
+    // QR + AA, RD copied from the query, RCODE 0
+    reply.extend_from_slice(&(0x8400 | (flags & 0x0100)).to_be_bytes());
+    reply.extend_from_slice(&1u16.to_be_bytes());
+    reply.extend_from_slice(&(answered as u16).to_be_bytes());
+    reply.extend_from_slice(&[0, 0, 0, 0]);
+    reply.extend_from_slice(question);
+    if answered {
+        // name: pointer to the question's QNAME at offset 12
+        reply.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        reply.extend_from_slice(&TYPE_A.to_be_bytes());
+        reply.extend_from_slice(&CLASS_IN.to_be_bytes());
+        reply.extend_from_slice(&TTL_SECS.to_be_bytes());
+        reply.extend_from_slice(&4u16.to_be_bytes());
+        reply.extend_from_slice(&ip.octets());
+    }
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 1);
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut q = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            q.push(label.len() as u8);
+            q.extend_from_slice(label.as_bytes());
+        }
+        q.push(0);
+        q.extend_from_slice(&qtype.to_be_bytes());
+        q.extend_from_slice(&CLASS_IN.to_be_bytes());
+        q
+    }
+
+    #[test]
+    fn answers_a_queries_with_the_device() {
+        let q = query("captive.apple.com", TYPE_A);
+        let r = answer(&q, IP).unwrap();
+        // id kept, QR/AA/RD set, one question, one answer
+        assert_eq!(&r[..12], &[0x12, 0x34, 0x85, 0x00, 0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(&r[12..q.len()], &q[12..]);
+        let rr = &r[q.len()..];
+        assert_eq!(&rr[..2], &[0xc0, 12]);
+        assert_eq!(&rr[rr.len() - 4..], &[192, 168, 71, 1]);
+        assert_eq!(rr.len(), 16);
+    }
+
+    #[test]
+    fn other_types_get_an_empty_reply() {
+        let q = query("example.com", 28);
+        let r = answer(&q, IP).unwrap();
+        assert_eq!(&r[6..8], &[0, 0]);
+        assert_eq!(r.len(), q.len());
+    }
+
+    #[test]
+    fn ignores_malformed_packets() {
+        let q = query("example.com", TYPE_A);
+        assert!(answer(&q[..8], IP).is_none());
+        assert!(answer(&q[..q.len() - 2], IP).is_none());
+        let mut response = q.clone();
+        response[2] |= 0x80;
+        assert!(answer(&response, IP).is_none());
+        let mut no_question = q.clone();
+        no_question[5] = 0;
+        assert!(answer(&no_question, IP).is_none());
+    }
+}
@@ -0,0 +1,241 @@
+//! Two-key chords as extra virtual keys.
+//!
+//! A chord such as `ESC+ACCEPT` is bound in the keymap like any physical key. When two keys
+//! of a bound chord go down within [`CHORD_WINDOW`], the chord fires instead of either key.
+//!
+//! Only keys that are part of some bound chord are held back, and only for the window; every
+//! other key passes through at once. The detector is pure state — the input service
+//! (`crate::input`) feeds it debounced presses/releases and wakes it at
+//! [`ChordDetector::deadline`].
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How long the first key of a possible chord waits for its partner
+pub const CHORD_WINDOW: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEvent {
+    Press(u8),
+    Release(u8),
+    /// Both keys of a bound chord, lower pin index first
+    Chord(u8, u8),
+    /// Both keys of the chord are up again
+    ChordRelease(u8, u8),
+}
+
+#[derive(Debug, Default)]
+pub struct ChordDetector {
+    /// Bound chords, each stored lower pin index first
+    chords: Vec<(u8, u8)>,
+    /// First key of a possible chord, and when it went down
+    pending: Option<(u8, Instant)>,
+    /// Chord that fired and is still held; its keys produce no events of their own
+    active: Option<(u8, u8)>,
+    /// Which keys of the active chord are already up
+    active_up: (bool, bool),
+    out: VecDeque<KeyEvent>,
+}
+
+fn ordered(a: u8, b: u8) -> (u8, u8) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+impl ChordDetector {
+    pub fn new(chords: impl IntoIterator<Item = (u8, u8)>) -> Self {
+        let mut detector = Self::default();
+        detector.set_chords(chords);
+        detector
+    }
+
+    /// Replace the bound chords (after a keymap write)
+    pub fn set_chords(&mut self, chords: impl IntoIterator<Item = (u8, u8)>) {
+        self.chords = chords
+            .into_iter()
+            .filter(|(a, b)| a != b)
+            .map(|(a, b)| ordered(a, b))
+            .collect();
+        self.chords.sort_unstable();
+        self.chords.dedup();
+    }
+
+    fn in_any_chord(&self, pin: u8) -> bool {
+        self.chords.iter().any(|&(a, b)| a == pin || b == pin)
+    }
+
+    /// Key went down
+    pub fn press(&mut self, pin: u8, now: Instant) {
+        if let Some((a, b)) = self.active {
+            if pin == a {
+                self.active_up.0 = false;
+                return;
+            }
+            if pin == b {
+                self.active_up.1 = false;
+                return;
+            }
+        }
+        if let Some((first, at)) = self.pending.take() {
+            let chord = ordered(first, pin);
+            if now.duration_since(at) <= CHORD_WINDOW && self.chords.contains(&chord) {
+                self.active = Some(chord);
+                self.active_up = (false, false);
+                self.out.push_back(KeyEvent::Chord(chord.0, chord.1));
+                return;
+            }
+            self.out.push_back(KeyEvent::Press(first));
+        }
+        if self.in_any_chord(pin) {
+            self.pending = Some((pin, now));
+        } else {
+            self.out.push_back(KeyEvent::Press(pin));
+        }
+    }
+
+    /// Key went up
+    pub fn release(&mut self, pin: u8, _now: Instant) {
+        if let Some((a, b)) = self.active {
+            if pin == a || pin == b {
+                if pin == a {
+                    self.active_up.0 = true;
+                } else {
+                    self.active_up.1 = true;
+                }
+                if self.active_up == (true, true) {
+                    // Both up: the keys become ordinary keys again
+                    self.active = None;
+                    self.out.push_back(KeyEvent::ChordRelease(a, b));
+                }
+                return;
+            }
+        }
+        if let Some((first, _)) = self.pending {
+            if first == pin {
+                // Tapped faster than the window: still a plain key press
+                self.pending = None;
+                self.out.push_back(KeyEvent::Press(pin));
+            }
+        }
+        self.out.push_back(KeyEvent::Release(pin));
+    }
+
+    /// When the pending key, if any, must be resolved as a plain press
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.map(|(_, at)| at + CHORD_WINDOW)
+    }
+
+    /// Resolve a pending key whose window has passed
+    pub fn poll(&mut self, now: Instant) {
+        if let Some((pin, at)) = self.pending {
+            if now >= at + CHORD_WINDOW {
+                self.pending = None;
+                self.out.push_back(KeyEvent::Press(pin));
+            }
+        }
+    }
+
+    /// Chord that fired and is still held
+    #[cfg(test)]
+    pub fn active(&self) -> Option<(u8, u8)> {
+        self.active
+    }
+
+    pub fn next_event(&mut self) -> Option<KeyEvent> {
+        self.out.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ESC: u8 = 2;
+    const NEXT: u8 = 3;
+    const ACCEPT: u8 = 6;
+
+    fn drain(d: &mut ChordDetector) -> Vec<KeyEvent> {
+        std::iter::from_fn(|| d.next_event()).collect()
+    }
+
+    #[test]
+    fn unbound_keys_pass_through() {
+        let t0 = Instant::now();
+        let mut d = ChordDetector::new([(ACCEPT, ESC)]);
+        d.press(NEXT, t0);
+        assert_eq!(d.deadline(), None);
+        d.release(NEXT, t0 + Duration::from_millis(80));
+        assert_eq!(
+            drain(&mut d),
+            vec![KeyEvent::Press(NEXT), KeyEvent::Release(NEXT)]
+        );
+    }
+
+    #[test]
+    fn two_keys_within_window_fire_the_chord_only() {
+        let t0 = Instant::now();
+        let mut d = ChordDetector::new([(ACCEPT, ESC)]);
+        d.press(ACCEPT, t0);
+        assert!(drain(&mut d).is_empty());
+        d.press(ESC, t0 + Duration::from_millis(20));
+        assert_eq!(drain(&mut d), vec![KeyEvent::Chord(ESC, ACCEPT)]);
+        assert_eq!(d.deadline(), None);
+
+        // Re-pressing a key of the held chord does not end it
+        d.release(ESC, t0 + Duration::from_millis(200));
+        d.press(ESC, t0 + Duration::from_millis(205));
+        d.release(ACCEPT, t0 + Duration::from_millis(210));
+        assert!(drain(&mut d).is_empty());
+        assert_eq!(d.active(), Some((ESC, ACCEPT)));
+
+        d.release(ESC, t0 + Duration::from_millis(250));
+        assert_eq!(drain(&mut d), vec![KeyEvent::ChordRelease(ESC, ACCEPT)]);
+        assert_eq!(d.active(), None);
+        d.press(ESC, t0 + Duration::from_millis(300));
+        d.press(NEXT, t0 + Duration::from_millis(310));
+        assert_eq!(
+            drain(&mut d),
+            vec![KeyEvent::Press(ESC), KeyEvent::Press(NEXT)]
+        );
+    }
+
+    #[test]
+    fn chord_key_alone_resolves_after_window() {
+        let t0 = Instant::now();
+        let mut d = ChordDetector::new([(ESC, ACCEPT)]);
+        d.press(ESC, t0);
+        assert_eq!(d.deadline(), Some(t0 + CHORD_WINDOW));
+        d.poll(t0 + Duration::from_millis(10));
+        assert!(drain(&mut d).is_empty());
+        d.poll(t0 + CHORD_WINDOW);
+        assert_eq!(drain(&mut d), vec![KeyEvent::Press(ESC)]);
+
+        // Too late for a chord: the second key is a plain press of its own
+        d.press(ACCEPT, t0 + Duration::from_millis(100));
+        d.poll(t0 + Duration::from_millis(200));
+        assert_eq!(drain(&mut d), vec![KeyEvent::Press(ACCEPT)]);
+    }
+
+    #[test]
+    fn quick_tap_and_other_key_flush_pending() {
+        let t0 = Instant::now();
+        let mut d = ChordDetector::new([(ESC, ACCEPT)]);
+        d.press(ESC, t0);
+        d.release(ESC, t0 + Duration::from_millis(30));
+        assert_eq!(
+            drain(&mut d),
+            vec![KeyEvent::Press(ESC), KeyEvent::Release(ESC)]
+        );
+
+        // A key that is not the partner ends the wait immediately, in press order
+        d.press(ESC, t0 + Duration::from_millis(100));
+        d.press(NEXT, t0 + Duration::from_millis(110));
+        assert_eq!(
+            drain(&mut d),
+            vec![KeyEvent::Press(ESC), KeyEvent::Press(NEXT)]
+        );
+    }
+}
@@ -0,0 +1,559 @@
+//! Unified input service.
+//!
+//! One thread owns every key pin and the rotary encoder. It debounces all keys the same way,
+//! decodes the encoder, runs chord detection (`crate::chord`), auto-repeat and long-press,
+//! and sends [`InputEvent`]s to whichever mode is running: boot menu, settings, OTA,
+//! keyboard or remote. Modes never touch the pins themselves; they read events from
+//! [`Input`] and ask [`Input::is_held`] for the current level of a key.
+//!
+//! [`InputCore`] holds all the timing logic and has no hardware in it; the thread around it
+//! only turns pin edges into calls and sleeps until the core's next deadline.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::bt_keyboard_mode::{KeymapConfig, KeysPin};
+use crate::chord::{ChordDetector, KeyEvent};
+
+/// Keys with a `KeysPin` index (the encoder's A/B pins are not keys)
+pub const KEY_COUNT: usize = 8;
+
+/// While any key is down its level is re-read this often, so a release edge lost between
+/// two waits cannot leave the key stuck
+const HELD_RESAMPLE: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    /// Key went down (after debounce and chord detection)
+    Press(u8),
+    Release(u8),
+    /// Key is still held; sent every `Repeat::interval` after `Repeat::delay`
+    Repeat(u8),
+    /// Key has been held for `InputConfig::long_press`; sent once per press
+    LongPress(u8),
+    /// Both keys of a bound chord, lower pin index first
+    Chord(u8, u8),
+    ChordRelease(u8, u8),
+    RotateUp,
+    RotateDown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Repeat {
+    pub delay: Duration,
+    pub interval: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct InputConfig {
+    /// A key's level must be stable this long before it counts
+    pub debounce: Duration,
+    /// Encoder steps closer together than this are dropped
+    pub rotate_debounce: Duration,
+    pub long_press: Duration,
+    /// Auto-repeat per key, indexed by `KeysPin` index
+    pub repeat: [Option<Repeat>; KEY_COUNT],
+    /// Bound chords, see `KeymapConfig::chords`
+    pub chords: Vec<(u8, u8)>,
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        Self {
+            debounce: Duration::from_millis(30),
+            rotate_debounce: Duration::from_millis(30),
+            long_press: Duration::from_millis(800),
+            // Built-in repeat settings (Backspace only)
+            repeat: repeat_table(&KeymapConfig::default()),
+            chords: Vec::new(),
+        }
+    }
+}
+
+impl InputConfig {
+    pub fn from_keymap(keymap: &KeymapConfig) -> Self {
+        Self {
+            repeat: repeat_table(keymap),
+            chords: keymap.chords(),
+            ..Self::default()
+        }
+    }
+}
+
+/// Per-pin repeat timings from the keymap's `repeat` settings
+fn repeat_table(keymap: &KeymapConfig) -> [Option<Repeat>; KEY_COUNT] {
+    let mut repeat = [None; KEY_COUNT];
+    for (pin, slot) in repeat.iter_mut().enumerate() {
+        let r = keymap.repeat_for(KeymapConfig::get_key_name(pin as u8));
+        *slot = r.enabled.then(|| Repeat {
+            delay: Duration::from_millis(r.delay_ms as u64),
+            interval: Duration::from_millis(r.interval_ms as u64),
+        });
+    }
+    repeat
+}
+
+/// Timers of a key whose `Press` has been sent
+#[derive(Debug, Clone, Copy)]
+struct Held {
+    next_repeat: Option<Instant>,
+    long_press_at: Option<Instant>,
+}
+
+/// Debounce, encoder, chord, repeat and long-press state, without any pins
+#[derive(Debug)]
+pub struct InputCore {
+    config: InputConfig,
+    chords: ChordDetector,
+    /// Debounced level of each key
+    pressed: [bool; KEY_COUNT],
+    /// When each key's level is due to be read after its last edge
+    settle_at: [Option<Instant>; KEY_COUNT],
+    held: [Option<Held>; KEY_COUNT],
+    last_rotate: Option<Instant>,
+    out: VecDeque<InputEvent>,
+}
+
+impl InputCore {
+    pub fn new(config: InputConfig) -> Self {
+        Self {
+            chords: ChordDetector::new(config.chords.iter().copied()),
+            config,
+            pressed: [false; KEY_COUNT],
+            settle_at: [None; KEY_COUNT],
+            held: [None; KEY_COUNT],
+            last_rotate: None,
+            out: VecDeque::new(),
+        }
+    }
+
+    /// New settings take effect from the next press
+    pub fn set_config(&mut self, config: InputConfig) {
+        self.chords.set_chords(config.chords.iter().copied());
+        self.config = config;
+    }
+
+    /// An edge on a key pin; every edge restarts that key's debounce
+    pub fn edge(&mut self, pin: u8, now: Instant) {
+        if let Some(at) = self.settle_at.get_mut(pin as usize) {
+            *at = Some(now + self.config.debounce);
+        }
+    }
+
+    /// Keys whose level must be read now and passed to `settle`
+    pub fn due_settles(&self, now: Instant) -> Vec<u8> {
+        (0..KEY_COUNT as u8)
+            .filter(|&pin| matches!(self.settle_at[pin as usize], Some(at) if at <= now))
+            .collect()
+    }
+
+    /// Held keys that should be re-read in case their release edge was missed
+    pub fn pressed_keys(&self) -> Vec<u8> {
+        (0..KEY_COUNT as u8)
+            .filter(|&pin| self.pressed[pin as usize])
+            .collect()
+    }
+
+    /// Level of a held key read outside of an edge. A key found up is debounced as if its
+    /// release edge had been seen.
+    pub fn resample(&mut self, pin: u8, is_low: bool, now: Instant) {
+        let i = pin as usize;
+        if i < KEY_COUNT && self.pressed[i] && !is_low && self.settle_at[i].is_none() {
+            self.settle_at[i] = Some(now + self.config.debounce);
+        }
+    }
+
+    /// Debounced level of a key after its edge has settled
+    pub fn settle(&mut self, pin: u8, is_low: bool, now: Instant) {
+        let i = pin as usize;
+        if i >= KEY_COUNT {
+            return;
+        }
+        self.settle_at[i] = None;
+        if self.pressed[i] == is_low {
+            return;
+        }
+        self.pressed[i] = is_low;
+        if is_low {
+            self.chords.press(pin, now);
+        } else {
+            self.chords.release(pin, now);
+        }
+        self.drain_chords(now);
+    }
+
+    /// An edge on encoder pin A, with both pin levels read right after it
+    pub fn rotate(&mut self, a_high: bool, b_high: bool, now: Instant) {
+        if let Some(last) = self.last_rotate {
+            if now.duration_since(last) < self.config.rotate_debounce {
+                return;
+            }
+        }
+        self.last_rotate = Some(now);
+        // A high: B high = up, B low = down; A low: the other way round
+        let up = if a_high { b_high } else { !b_high };
+        self.out.push_back(if up {
+            InputEvent::RotateUp
+        } else {
+            InputEvent::RotateDown
+        });
+    }
+
+    fn drain_chords(&mut self, now: Instant) {
+        while let Some(event) = self.chords.next_event() {
+            let event = match event {
+                KeyEvent::Press(pin) => {
+                    self.held[pin as usize] = Some(Held {
+                        next_repeat: self.config.repeat[pin as usize].map(|r| now + r.delay),
+                        long_press_at: Some(now + self.config.long_press),
+                    });
+                    InputEvent::Press(pin)
+                }
+                KeyEvent::Release(pin) => {
+                    self.held[pin as usize] = None;
+                    InputEvent::Release(pin)
+                }
+                KeyEvent::Chord(a, b) => InputEvent::Chord(a, b),
+                KeyEvent::ChordRelease(a, b) => InputEvent::ChordRelease(a, b),
+            };
+            self.out.push_back(event);
+        }
+    }
+
+    /// Fire every timer that is due
+    pub fn poll(&mut self, now: Instant) {
+        self.chords.poll(now);
+        self.drain_chords(now);
+        for pin in 0..KEY_COUNT {
+            let Some(held) = self.held[pin].as_mut() else {
+                continue;
+            };
+            if matches!(held.long_press_at, Some(at) if at <= now) {
+                held.long_press_at = None;
+                self.out.push_back(InputEvent::LongPress(pin as u8));
+            }
+            if matches!(held.next_repeat, Some(at) if at <= now) {
+                held.next_repeat = self.config.repeat[pin].map(|r| now + r.interval);
+                self.out.push_back(InputEvent::Repeat(pin as u8));
+            }
+        }
+    }
+
+    /// When `poll` (or a level re-read) is next needed
+    pub fn deadline(&self, now: Instant) -> Option<Instant> {
+        let timers = self
+            .held
+            .iter()
+            .flatten()
+            .flat_map(|h| [h.next_repeat, h.long_press_at]);
+        let resample = self.pressed.iter().any(|p| *p).then(|| now + HELD_RESAMPLE);
+        self.settle_at
+            .iter()
+            .copied()
+            .chain(timers)
+            .chain([self.chords.deadline(), resample])
+            .flatten()
+            .min()
+    }
+
+    /// Bit `n` set = key with `KeysPin` index `n` is down
+    pub fn pressed_mask(&self) -> u8 {
+        self.pressed
+            .iter()
+            .enumerate()
+            .fold(0, |mask, (i, p)| mask | ((*p as u8) << i))
+    }
+
+    pub fn next_event(&mut self) -> Option<InputEvent> {
+        self.out.pop_front()
+    }
+}
+
+/// A mode's end of the input service
+pub struct Input {
+    rx: tokio::sync::mpsc::UnboundedReceiver<InputEvent>,
+    config_tx: tokio::sync::mpsc::UnboundedSender<InputConfig>,
+    held: Arc<AtomicU8>,
+}
+
+impl Input {
+    pub async fn next(&mut self) -> Option<InputEvent> {
+        self.rx.recv().await
+    }
+
+    /// For code that runs outside the tokio runtime (time-sync prompt, OTA)
+    pub fn blocking_next(&mut self) -> Option<InputEvent> {
+        self.rx.blocking_recv()
+    }
+
+    /// Debounced level of a key, e.g. for push-to-talk while the event loop is blocked
+    pub fn is_held(&self, pin: u8) -> bool {
+        self.held.load(Ordering::Relaxed) & (1 << pin) != 0
+    }
+
+    /// Wait until `pin` is up, dropping the events in between
+    pub async fn wait_release(&mut self, pin: u8) {
+        while self.is_held(pin) {
+            match self.next().await {
+                Some(InputEvent::Release(p)) if p == pin => return,
+                None => return,
+                _ => {}
+            }
+        }
+    }
+
+    /// Drop queued events, e.g. presses made while a blocking operation ran
+    pub fn flush(&mut self) {
+        while self.rx.try_recv().is_ok() {}
+    }
+
+    pub fn set_config(&self, config: InputConfig) {
+        let _ = self.config_tx.send(config);
+    }
+}
+
+/// Start the input thread; it owns the pins from now on
+pub fn spawn(pins: KeysPin, config: InputConfig) -> anyhow::Result<Input> {
+    // Unbounded: a mode that blocks (built-in ASR) must never stall the input thread,
+    // or `is_held` would stop updating
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let (config_tx, config_rx) = tokio::sync::mpsc::unbounded_channel();
+    let held = Arc::new(AtomicU8::new(0));
+    let held_ = held.clone();
+    std::thread::Builder::new()
+        .name("input".to_string())
+        .stack_size(6 * 1024)
+        .spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("input-thread runtime");
+            rt.block_on(run(pins, InputCore::new(config), tx, config_rx, held_));
+            log::info!("Input thread exited");
+        })?;
+    Ok(Input {
+        rx,
+        config_tx,
+        held,
+    })
+}
+
+async fn run(
+    mut pins: KeysPin,
+    mut core: InputCore,
+    tx: tokio::sync::mpsc::UnboundedSender<InputEvent>,
+    mut config_rx: tokio::sync::mpsc::UnboundedReceiver<InputConfig>,
+    held: Arc<AtomicU8>,
+) {
+    loop {
+        let now = Instant::now();
+        for pin in core.due_settles(now) {
+            core.settle(pin, pins.is_low(pin), now);
+        }
+        for pin in core.pressed_keys() {
+            core.resample(pin, pins.is_low(pin), now);
+        }
+        core.poll(now);
+        held.store(core.pressed_mask(), Ordering::Relaxed);
+        while let Some(event) = core.next_event() {
+            if tx.send(event).is_err() {
+                return;
+            }
+        }
+
+        let deadline = core.deadline(now);
+        let sleep_deadline = deadline
+            .map(tokio::time::Instant::from_std)
+            .unwrap_or_else(tokio::time::Instant::now);
+        tokio::select! {
+            _ = pins.mic.wait_for_any_edge() => core.edge(KeysPin::MIC, Instant::now()),
+            _ = pins.custom.wait_for_any_edge() => core.edge(KeysPin::CUSTOM, Instant::now()),
+            _ = pins.esc.wait_for_any_edge() => core.edge(KeysPin::ESC, Instant::now()),
+            _ = pins.next.wait_for_any_edge() => core.edge(KeysPin::NEXT, Instant::now()),
+            _ = pins.backspace.wait_for_any_edge() => core.edge(KeysPin::BACKSPACE, Instant::now()),
+            _ = pins.switch.wait_for_any_edge() => core.edge(KeysPin::SWITCH, Instant::now()),
+            _ = pins.accept.wait_for_any_edge() => core.edge(KeysPin::ACCEPT, Instant::now()),
+            _ = pins.rotate_button.wait_for_any_edge() => {
+                core.edge(KeysPin::ROTATE_BUTTON, Instant::now())
+            }
+            // Encoder: decode from the levels right at the edge, no key debounce
+            _ = pins.rotate_a.wait_for_any_edge() => {
+                core.rotate(pins.rotate_a.is_high(), pins.rotate_b.is_high(), Instant::now())
+            }
+            Some(config) = config_rx.recv() => core.set_config(config),
+            _ = tokio::time::sleep_until(sleep_deadline), if deadline.is_some() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    fn drain(core: &mut InputCore) -> Vec<InputEvent> {
+        std::iter::from_fn(|| core.next_event()).collect()
+    }
+
+    /// Edge at `at`, level read once the debounce is over
+    fn step(core: &mut InputCore, t0: Instant, at: u64, pin: u8, is_low: bool) {
+        core.edge(pin, t0 + ms(at));
+        let due = t0 + ms(at) + core.config.debounce;
+        assert_eq!(core.due_settles(due), vec![pin]);
+        core.settle(pin, is_low, due);
+        core.poll(due);
+    }
+
+    #[test]
+    fn debounce_drops_bounces() {
+        let t0 = Instant::now();
+        let mut core = InputCore::new(InputConfig::default());
+        core.edge(KeysPin::NEXT, t0);
+        core.edge(KeysPin::NEXT, t0 + ms(5));
+        // the second edge pushed the read back
+        assert!(core.due_settles(t0 + ms(30)).is_empty());
+        assert_eq!(core.deadline(t0), Some(t0 + ms(35)));
+        // bounced back up before it settled: nothing happened
+        core.settle(KeysPin::NEXT, false, t0 + ms(35));
+        assert!(drain(&mut core).is_empty());
+
+        step(&mut core, t0, 100, KeysPin::NEXT, true);
+        assert!(core.pressed_mask() & (1 << KeysPin::NEXT) != 0);
+        step(&mut core, t0, 200, KeysPin::NEXT, false);
+        assert_eq!(
+            drain(&mut core),
+            vec![
+                InputEvent::Press(KeysPin::NEXT),
+                InputEvent::Release(KeysPin::NEXT)
+            ]
+        );
+        assert_eq!(core.pressed_mask(), 0);
+    }
+
+    #[test]
+    fn missed_release_edge_is_recovered() {
+        let t0 = Instant::now();
+        let mut core = InputCore::new(InputConfig::default());
+        step(&mut core, t0, 0, KeysPin::ACCEPT, true);
+        assert_eq!(core.deadline(t0 + ms(30)), Some(t0 + ms(80)));
+        core.resample(KeysPin::ACCEPT, false, t0 + ms(80));
+        // a second look before the debounce is over does not push it back
+        core.resample(KeysPin::ACCEPT, false, t0 + ms(90));
+        assert_eq!(core.due_settles(t0 + ms(110)), vec![KeysPin::ACCEPT]);
+        core.settle(KeysPin::ACCEPT, false, t0 + ms(110));
+        assert_eq!(
+            drain(&mut core),
+            vec![
+                InputEvent::Press(KeysPin::ACCEPT),
+                InputEvent::Release(KeysPin::ACCEPT)
+            ]
+        );
+    }
+
+    #[test]
+    fn repeat_and_long_press() {
+        let t0 = Instant::now();
+        let mut core = InputCore::new(InputConfig::default());
+        step(&mut core, t0, 0, KeysPin::BACKSPACE, true);
+        let pressed = t0 + ms(30);
+        assert_eq!(
+            drain(&mut core),
+            vec![InputEvent::Press(KeysPin::BACKSPACE)]
+        );
+
+        core.poll(pressed + ms(199));
+        assert!(drain(&mut core).is_empty());
+        core.poll(pressed + ms(200));
+        core.poll(pressed + ms(300));
+        core.poll(pressed + ms(800));
+        assert_eq!(
+            drain(&mut core),
+            vec![
+                InputEvent::Repeat(KeysPin::BACKSPACE),
+                InputEvent::Repeat(KeysPin::BACKSPACE),
+                InputEvent::LongPress(KeysPin::BACKSPACE),
+                InputEvent::Repeat(KeysPin::BACKSPACE),
+            ]
+        );
+
+        // keys without a repeat setting only get the long press
+        let mut core = InputCore::new(InputConfig::default());
+        step(&mut core, t0, 2000, KeysPin::ESC, true);
+        core.poll(t0 + ms(2030) + ms(1000));
+        assert_eq!(
+            drain(&mut core),
+            vec![
+                InputEvent::Press(KeysPin::ESC),
+                InputEvent::LongPress(KeysPin::ESC)
+            ]
+        );
+    }
+
+    #[test]
+    fn repeat_follows_the_keymap() {
+        let mut keymap = KeymapConfig::default();
+        keymap.merge(
+            KeymapConfig::from_json(
+                r#"{"repeat":{"NEXT":{"delay_ms":400,"interval_ms":50},"BACKSPACE":{"enabled":false}}}"#,
+            )
+            .unwrap(),
+        );
+        let config = InputConfig::from_keymap(&keymap);
+        assert_eq!(
+            config.repeat[KeysPin::NEXT as usize],
+            Some(Repeat {
+                delay: ms(400),
+                interval: ms(50)
+            })
+        );
+        assert_eq!(config.repeat[KeysPin::BACKSPACE as usize], None);
+        assert_eq!(config.repeat[KeysPin::ESC as usize], None);
+    }
+
+    #[test]
+    fn chords_go_through_the_detector() {
+        let t0 = Instant::now();
+        let mut core = InputCore::new(InputConfig {
+            chords: vec![(KeysPin::ESC, KeysPin::ACCEPT)],
+            ..InputConfig::default()
+        });
+        step(&mut core, t0, 0, KeysPin::ESC, true);
+        step(&mut core, t0, 10, KeysPin::ACCEPT, true);
+        // held chord keys neither repeat nor long-press
+        core.poll(t0 + ms(2000));
+        step(&mut core, t0, 2000, KeysPin::ESC, false);
+        step(&mut core, t0, 2010, KeysPin::ACCEPT, false);
+        assert_eq!(
+            drain(&mut core),
+            vec![
+                InputEvent::Chord(KeysPin::ESC, KeysPin::ACCEPT),
+                InputEvent::ChordRelease(KeysPin::ESC, KeysPin::ACCEPT),
+            ]
+        );
+    }
+
+    #[test]
+    fn encoder_decoding() {
+        let t0 = Instant::now();
+        let mut core = InputCore::new(InputConfig::default());
+        core.rotate(true, true, t0);
+        core.rotate(true, false, t0 + ms(10)); // too soon, dropped
+        core.rotate(true, false, t0 + ms(40));
+        core.rotate(false, false, t0 + ms(80));
+        core.rotate(false, true, t0 + ms(120));
+        assert_eq!(
+            drain(&mut core),
+            vec![
+                InputEvent::RotateUp,
+                InputEvent::RotateDown,
+                InputEvent::RotateUp,
+                InputEvent::RotateDown,
+            ]
+        );
+    }
+}
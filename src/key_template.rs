@@ -0,0 +1,434 @@
+//! Template tokens inside `Text` key actions.
+//!
+//! A text value such as `"/compact{ENTER}"` or `"{CTRL+C}{DELAY:200}{CTRL+V}"` is parsed
+//! once, when the keymap is loaded, into a list of [`TemplateStep`]s. Keyboard mode and
+//! remote mode both run the same steps in order, through a [`Playback`] so that delays do
+//! not hold up the mode's event loop.
+//!
+//! | Token | Meaning |
+//! |---|---|
+//! | `{ENTER}`, `{TAB}`, `{F5}`, ... | press and release one key (any `key_name_to_hid_code` name) |
+//! | `{CTRL+C}`, `{CTRL+SHIFT+T}` | the same, with modifiers |
+//! | `{DELAY:200}` | wait 200 ms (at most [`MAX_DELAY_MS`]) |
+//! | `{DATE}` / `{TIME}` | current UTC date `YYYY-MM-DD` / time `HH:MM:SS`, skipped while the clock is unset |
+//! | `{{` / `}}` | a literal `{` / `}` |
+//!
+//! Token names are case-insensitive. Braces that do not hold a token (`{"a":1}`, `{ENTR}`,
+//! a lone `{` or `}`) are kept as text, so values written before templates existed still
+//! type the same. Only a malformed `{DELAY:...}` is an error.
+
+use crate::bt_keyboard_mode::{key_name_to_hid_code, modifier_name_to_mask};
+
+/// Longest pause a single `{DELAY:n}` may request
+pub const MAX_DELAY_MS: u32 = 10_000;
+
+/// Clock readings before this (2024-01-01 UTC) mean SNTP has not run since boot and the
+/// clock still counts from 1970
+pub const CLOCK_SET_AFTER: u64 = 1_704_067_200;
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Whether `{DATE}` / `{TIME}` can be typed: the clock has been synced
+pub fn clock_is_set() -> bool {
+    now_secs() >= CLOCK_SET_AFTER
+}
+
+/// Whether running `steps` now skips a `{DATE}` / `{TIME}` because the clock is unset
+pub fn skips_clock_tokens(steps: &[TemplateStep]) -> bool {
+    steps.iter().any(TemplateStep::needs_clock) && !clock_is_set()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateStep {
+    /// Literal text, typed as-is
+    Text(String),
+    /// One key press + release. `modifiers` is the HID mask of the token's modifier names;
+    /// the key's own implied Shift (e.g. `PLUS`) is added when it is sent.
+    Key { modifiers: u8, key: String },
+    /// Pause in milliseconds
+    Delay(u32),
+    /// Current date, rendered when the step runs
+    Date,
+    /// Current time, rendered when the step runs
+    Time,
+}
+
+impl TemplateStep {
+    /// Text typed by `Text`, `Date` and `Time` steps. `Date` and `Time` give `None` while
+    /// the clock is unset, rather than a date in 1970.
+    pub fn text(&self) -> Option<std::borrow::Cow<'_, str>> {
+        match self {
+            Self::Text(s) => Some(s.as_str().into()),
+            Self::Date | Self::Time if !clock_is_set() => None,
+            Self::Date => Some(format_date(now_secs()).into()),
+            Self::Time => Some(format_time(now_secs()).into()),
+            Self::Key { .. } | Self::Delay(_) => None,
+        }
+    }
+
+    /// `Date` and `Time`, which need a synced clock
+    pub fn needs_clock(&self) -> bool {
+        matches!(self, Self::Date | Self::Time)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateError {
+    /// The offending token, without braces
+    pub token: String,
+    pub reason: &'static str,
+}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{{{}}}: {}", self.token, self.reason)
+    }
+}
+
+/// Parse a text value into steps. Adjacent text is merged into one `Text` step.
+pub fn parse(value: &str) -> Result<Vec<TemplateStep>, TemplateError> {
+    let mut steps = Vec::new();
+    let mut text = String::new();
+    let mut rest = value;
+
+    while let Some(i) = rest.find(['{', '}']) {
+        text.push_str(&rest[..i]);
+        let brace = rest.as_bytes()[i];
+        rest = &rest[i + 1..];
+
+        if brace == b'}' {
+            // `}}` and a lone `}` both mean one literal `}`
+            text.push('}');
+            rest = rest.strip_prefix('}').unwrap_or(rest);
+            continue;
+        }
+        if let Some(r) = rest.strip_prefix('{') {
+            text.push('{');
+            rest = r;
+            continue;
+        }
+
+        // A token runs to the next `}`; a `{` before it (or no `}` at all) means this
+        // brace is plain text
+        let token = match rest.find(['{', '}']) {
+            Some(end) if rest.as_bytes()[end] == b'}' => &rest[..end],
+            _ => {
+                text.push('{');
+                continue;
+            }
+        };
+        let Some(step) = parse_token(token)? else {
+            text.push('{');
+            continue;
+        };
+        rest = &rest[token.len() + 1..];
+
+        if !text.is_empty() {
+            steps.push(TemplateStep::Text(std::mem::take(&mut text)));
+        }
+        steps.push(step);
+    }
+    text.push_str(rest);
+    if !text.is_empty() {
+        steps.push(TemplateStep::Text(text));
+    }
+    Ok(steps)
+}
+
+/// The step `token` stands for, `None` if it is not a token (then it is typed as text)
+fn parse_token(token: &str) -> Result<Option<TemplateStep>, TemplateError> {
+    let err = |reason| TemplateError {
+        token: token.to_string(),
+        reason,
+    };
+    let upper = token.trim().to_ascii_uppercase();
+
+    if let Some(ms) = upper.strip_prefix("DELAY:") {
+        let ms: u32 = ms
+            .trim()
+            .parse()
+            .map_err(|_| err("delay is not a number"))?;
+        if ms > MAX_DELAY_MS {
+            return Err(err("delay too long"));
+        }
+        return Ok(Some(TemplateStep::Delay(ms)));
+    }
+    match upper.as_str() {
+        "DATE" => return Ok(Some(TemplateStep::Date)),
+        "TIME" => return Ok(Some(TemplateStep::Time)),
+        "" => return Ok(None),
+        _ => {}
+    }
+
+    // `MOD+MOD+KEY`; the key itself may be `+` (`{CTRL++}`)
+    let (mods, key) = if upper == "+" {
+        ("", "+")
+    } else if let Some(mods) = upper.strip_suffix("++") {
+        (mods, "+")
+    } else {
+        upper.rsplit_once('+').unwrap_or(("", upper.as_str()))
+    };
+
+    let mut modifiers = 0;
+    for m in mods.split('+').filter(|m| !m.is_empty()) {
+        let Some(mask) = modifier_name_to_mask(m.trim()) else {
+            return Ok(None);
+        };
+        modifiers |= mask;
+    }
+    let key = key.trim();
+    if key_name_to_hid_code(key).is_err() {
+        return Ok(None);
+    }
+    Ok(Some(TemplateStep::Key {
+        modifiers,
+        key: key.to_string(),
+    }))
+}
+
+/// Steps split at the delays: `(pause before, steps)` pairs, each run without waiting.
+/// Back-to-back delays add up; a trailing delay is dropped.
+pub fn segments(steps: &[TemplateStep]) -> Vec<(u32, Vec<TemplateStep>)> {
+    let mut segments = vec![(0, Vec::new())];
+    for step in steps {
+        let last = segments.last_mut().expect("never empty");
+        match step {
+            TemplateStep::Delay(ms) if last.1.is_empty() => last.0 += ms,
+            TemplateStep::Delay(ms) => segments.push((*ms, Vec::new())),
+            step => last.1.push(step.clone()),
+        }
+    }
+    segments.retain(|(_, steps)| !steps.is_empty());
+    segments
+}
+
+/// Runs templates in the background, so a `{DELAY:n}` does not hold up the event loop.
+///
+/// `start` returns what is due right away; a spawned task waits out the delays and hands
+/// each later segment to `next`, which the mode's loop selects on alongside its other
+/// events. Starting another template, `cancel` or dropping the playback (leaving the
+/// mode) stops the one in progress.
+pub struct Playback {
+    task: Option<tokio::task::JoinHandle<()>>,
+    /// Kept so that `next` stays pending, instead of ending, while nothing is playing
+    tx: tokio::sync::mpsc::UnboundedSender<Vec<TemplateStep>>,
+    rx: tokio::sync::mpsc::UnboundedReceiver<Vec<TemplateStep>>,
+}
+
+impl Playback {
+    pub fn new() -> Self {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        Self { task: None, tx, rx }
+    }
+
+    /// Cancel the template in progress and start `steps`. Returns the steps before the
+    /// first delay, for the caller to run now.
+    pub fn start(&mut self, steps: &[TemplateStep]) -> Vec<TemplateStep> {
+        self.cancel();
+        let mut segments = segments(steps);
+        let now = match segments.first() {
+            Some((0, _)) => segments.remove(0).1,
+            _ => Vec::new(),
+        };
+        if !segments.is_empty() {
+            let tx = self.tx.clone();
+            self.task = Some(tokio::spawn(async move {
+                for (delay_ms, steps) in segments {
+                    tokio::time::sleep(std::time::Duration::from_millis(delay_ms as u64)).await;
+                    if tx.send(steps).is_err() {
+                        return;
+                    }
+                }
+            }));
+        }
+        now
+    }
+
+    /// Drop whatever is still to come of the template in progress
+    pub fn cancel(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        while self.rx.try_recv().is_ok() {}
+    }
+
+    /// The next segment whose delay has passed; pending while none is due
+    pub async fn next(&mut self) -> Vec<TemplateStep> {
+        self.rx.recv().await.unwrap_or_default()
+    }
+}
+
+impl Default for Playback {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Playback {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// `YYYY-MM-DD` of a unix timestamp (UTC)
+pub fn format_date(unix_secs: u64) -> String {
+    // Howard Hinnant's civil_from_days
+    let z = (unix_secs / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// `HH:MM:SS` of a unix timestamp (UTC)
+pub fn format_time(unix_secs: u64) -> String {
+    let s = unix_secs % 86_400;
+    format!("{:02}:{:02}:{:02}", s / 3_600, s / 60 % 60, s % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(modifiers: u8, key: &str) -> TemplateStep {
+        TemplateStep::Key {
+            modifiers,
+            key: key.to_string(),
+        }
+    }
+
+    #[test]
+    fn plain_text_is_one_step() {
+        assert_eq!(
+            parse("/compact\n").unwrap(),
+            vec![TemplateStep::Text("/compact\n".into())]
+        );
+        assert_eq!(parse("").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn tokens_split_text() {
+        assert_eq!(
+            parse("/compact{ENTER}").unwrap(),
+            vec![TemplateStep::Text("/compact".into()), key(0, "ENTER")]
+        );
+        assert_eq!(
+            parse("{ctrl+c}{DELAY:200}{Ctrl+Shift+v} {date}").unwrap(),
+            vec![
+                key(0x01, "C"),
+                TemplateStep::Delay(200),
+                key(0x03, "V"),
+                TemplateStep::Text(" ".into()),
+                TemplateStep::Date,
+            ]
+        );
+    }
+
+    #[test]
+    fn plus_key_and_braces() {
+        assert_eq!(parse("{CTRL++}").unwrap(), vec![key(0x01, "+")]);
+        assert_eq!(parse("{+}").unwrap(), vec![key(0, "+")]);
+        assert_eq!(
+            parse("fn x() {{}} }").unwrap(),
+            vec![TemplateStep::Text("fn x() {} }".into())]
+        );
+    }
+
+    #[test]
+    fn unknown_tokens_are_text() {
+        for value in [
+            "{ENTER",
+            "{HYPER+C}",
+            "{ENTR}",
+            "{}",
+            r#"{"a":1}"#,
+            "if (x) { y(); }",
+        ] {
+            assert_eq!(
+                parse(value).unwrap(),
+                vec![TemplateStep::Text(value.into())],
+                "{value}"
+            );
+        }
+        // the brace before a real token is text, the token still counts
+        assert_eq!(
+            parse("{a{ENTER}").unwrap(),
+            vec![TemplateStep::Text("{a".into()), key(0, "ENTER")]
+        );
+    }
+
+    #[test]
+    fn bad_delays_are_errors() {
+        assert_eq!(
+            parse("{DELAY:soon}").unwrap_err().reason,
+            "delay is not a number"
+        );
+        assert_eq!(parse("{DELAY:60000}").unwrap_err().reason, "delay too long");
+        assert_eq!(
+            parse("{delay:}").unwrap_err().to_string(),
+            "{delay:}: delay is not a number"
+        );
+    }
+
+    #[test]
+    fn segments_split_at_delays() {
+        let steps = parse("{DELAY:50}a{DELAY:100}{DELAY:100}b{ENTER}{DELAY:10}").unwrap();
+        assert_eq!(
+            segments(&steps),
+            vec![
+                (50, vec![TemplateStep::Text("a".into())]),
+                (200, vec![TemplateStep::Text("b".into()), key(0, "ENTER")]),
+            ]
+        );
+        assert_eq!(
+            segments(&parse("/compact{ENTER}").unwrap()),
+            vec![(0, parse("/compact{ENTER}").unwrap())]
+        );
+        assert!(segments(&[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn playback_runs_delays_in_the_background() {
+        let mut playback = Playback::new();
+        let now = playback.start(&parse("a{DELAY:20}b").unwrap());
+        assert_eq!(now, vec![TemplateStep::Text("a".into())]);
+        assert_eq!(playback.next().await, vec![TemplateStep::Text("b".into())]);
+
+        // a new template cancels the rest of the previous one
+        playback.start(&parse("x{DELAY:20}y").unwrap());
+        let now = playback.start(&parse("z").unwrap());
+        assert_eq!(now, vec![TemplateStep::Text("z".into())]);
+        let idle = tokio::time::timeout(std::time::Duration::from_millis(100), playback.next());
+        assert!(idle.await.is_err());
+    }
+
+    #[test]
+    fn date_and_time_formatting() {
+        assert_eq!(format_date(0), "1970-01-01");
+        // 2024-02-29T13:05:09Z
+        assert_eq!(format_date(1_709_211_909), "2024-02-29");
+        assert_eq!(format_time(1_709_211_909), "13:05:09");
+        assert_eq!(format_date(951_782_400), "2000-02-29");
+    }
+
+    #[test]
+    fn clock_tokens() {
+        assert_eq!(format_date(CLOCK_SET_AFTER), "2024-01-01");
+        assert!(TemplateStep::Date.needs_clock());
+        assert!(!TemplateStep::Text("x".into()).needs_clock());
+        // the host running the tests has a real clock
+        assert!(clock_is_set());
+        assert!(TemplateStep::Time.text().is_some());
+        assert!(!skips_clock_tokens(&[TemplateStep::Date]));
+    }
+}
@@ -588,6 +588,11 @@ pub fn terminal_text_cells() -> (u16, u16) {
 }
 
 impl UI {
+    /// 交还底层 FrameBuffer(离开 remote 模式时给下一个模式用)。
+    pub fn into_display(self) -> FrameBuffer {
+        self.display
+    }
+
     /// 借出底层 FrameBuffer,供外部直接绘制(如模式外壳)。
     pub fn display_mut(&mut self) -> &mut FrameBuffer {
         &mut self.display
@@ -631,6 +636,14 @@ impl UI {
         self.terminal_parser.is_some()
     }
 
+    /// 远端程序是否开启了应用光标模式(DECCKM,vim/less 等会开)。
+    /// 开启时方向键要发 SS3(`ESC O A`)而不是 CSI(`ESC [ A`)。JPEG 模式下无从得知,按关闭处理。
+    pub fn application_cursor(&self) -> bool {
+        self.terminal_parser
+            .as_ref()
+            .is_some_and(|p| p.screen().application_cursor())
+    }
+
     /// 渲染一帧 screen_text。payload 首字节是 tag:
     /// `0x00` = 整屏基线(重置 vt100 解析器后重放,含 ANSI 颜色/光标),
     /// `0x01` = PTY 增量(直接喂进解析器)。后续字节是 ANSI 终端流。
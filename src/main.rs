@@ -6,20 +6,34 @@ use esp_idf_svc::hal::gpio::{AnyIOPin, PinDriver};
 use crate::lcd::DisplayTargetDrive;
 
 mod ansi_plugin;
+mod api_auth;
 mod app;
 mod audio;
+mod backup;
 mod bt_keyboard_mode;
 mod bt_wifi_mode;
+mod captive_dns;
+mod chord;
 #[cfg(feature = "i2c_oled")]
 mod i2c;
+mod input;
+mod key_template;
 mod lcd;
+mod mode;
 mod mqtt;
 mod new_jpg;
+mod nvs_schema;
 mod ota;
 mod protocol;
+mod provision;
+mod rest_api;
+mod status;
+mod transfer;
 mod ui;
 mod util;
+mod widget;
 mod wifi;
+mod wifi_roam;
 
 type AnyBtn = PinDriver<'static, esp_idf_svc::hal::gpio::Input>;
 
@@ -40,6 +54,8 @@ const DEFAULT_SNTP_SERVERS: [&str; 4] = [
     "time.cloudflare.com",
 ];
 const SNTP_SYNC_TIMEOUT_SECS: usize = 30;
+/// 进入模式时等 WiFi 连接管理线程连上的最长时间;之后它仍在后台重连。
+const WIFI_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
 const TIME_SYNC_FAILED_PROMPT: &str = "Time sync failed\nAccept=Retry\nESC=Exit";
 
 enum TimeSyncFailureAction {
@@ -85,42 +101,33 @@ pub fn sync_time(display_target: &mut crate::lcd::FrameBuffer) -> anyhow::Result
     ))
 }
 
-fn wait_button_release(btn: &AnyBtn) {
-    while btn.is_low() {
-        std::thread::sleep(std::time::Duration::from_millis(20));
-    }
-}
-
 fn wait_time_sync_failure_action(
     display_target: &mut lcd::FrameBuffer,
-    accept: &AnyBtn,
-    esc: &AnyBtn,
+    input: &mut input::Input,
 ) -> TimeSyncFailureAction {
     let _ = ui::render_keyboard_view(display_target, false, false, TIME_SYNC_FAILED_PROMPT);
+    // 同步期间误按的键不算数
+    input.flush();
     loop {
-        if accept.is_low() {
-            wait_button_release(accept);
-            return TimeSyncFailureAction::Retry;
-        }
-        if esc.is_low() {
-            wait_button_release(esc);
-            return TimeSyncFailureAction::Exit;
+        match input.blocking_next() {
+            Some(input::InputEvent::Press(bt_keyboard_mode::KeysPin::ACCEPT)) => {
+                return TimeSyncFailureAction::Retry;
+            }
+            Some(input::InputEvent::Press(bt_keyboard_mode::KeysPin::ESC)) | None => {
+                return TimeSyncFailureAction::Exit;
+            }
+            Some(_) => {}
         }
-        std::thread::sleep(std::time::Duration::from_millis(20));
     }
 }
 
-fn sync_time_with_retry(
-    display_target: &mut lcd::FrameBuffer,
-    accept: &AnyBtn,
-    esc: &AnyBtn,
-) -> bool {
+fn sync_time_with_retry(display_target: &mut lcd::FrameBuffer, input: &mut input::Input) -> bool {
     loop {
         match sync_time(display_target) {
             Ok(()) => return true,
             Err(e) => {
                 log::error!("Failed to sync time: {:?}", e);
-                match wait_time_sync_failure_action(display_target, accept, esc) {
+                match wait_time_sync_failure_action(display_target, input) {
                     TimeSyncFailureAction::Retry => continue,
                     TimeSyncFailureAction::Exit => return false,
                 }
@@ -170,22 +177,22 @@ fn main() -> anyhow::Result<()> {
         "VibeKeys Starting...\n Read setting",
     );
 
-    // MIC(远程模式下由 app::run 直接持有,用于本地 ASR,故声明为 mut)
-    let mut btn0 = new_btn(
+    // MIC
+    let btn0 = new_btn(
         peripherals.pins.gpio0.into(),
         esp_idf_svc::hal::gpio::Pull::Up,
         esp_idf_svc::hal::gpio::InterruptType::AnyEdge,
     )?;
 
     // NEXT
-    let mut btn4 = new_btn(
+    let btn4 = new_btn(
         peripherals.pins.gpio4.into(),
         esp_idf_svc::hal::gpio::Pull::Up,
         esp_idf_svc::hal::gpio::InterruptType::AnyEdge,
     )?;
 
     // ESC
-    let mut btn3 = new_btn(
+    let btn3 = new_btn(
         peripherals.pins.gpio3.into(),
         esp_idf_svc::hal::gpio::Pull::Up,
         esp_idf_svc::hal::gpio::InterruptType::AnyEdge,
@@ -199,7 +206,7 @@ fn main() -> anyhow::Result<()> {
     )?;
 
     // Backspace
-    let mut btn5 = new_btn(
+    let btn5 = new_btn(
         peripherals.pins.gpio5.into(),
         esp_idf_svc::hal::gpio::Pull::Up,
         esp_idf_svc::hal::gpio::InterruptType::AnyEdge,
@@ -213,21 +220,21 @@ fn main() -> anyhow::Result<()> {
     )?;
 
     // Accept
-    let mut btn7 = new_btn(
+    let btn7 = new_btn(
         peripherals.pins.gpio7.into(),
         esp_idf_svc::hal::gpio::Pull::Up,
         esp_idf_svc::hal::gpio::InterruptType::AnyEdge,
     )?;
 
     // Rotate A
-    let mut pin16 = new_btn(
+    let pin16 = new_btn(
         peripherals.pins.gpio16.into(),
         esp_idf_svc::hal::gpio::Pull::Up,
         esp_idf_svc::hal::gpio::InterruptType::AnyEdge,
     )?;
 
     // Rotate B
-    let mut pin17 = new_btn(
+    let pin17 = new_btn(
         peripherals.pins.gpio17.into(),
         esp_idf_svc::hal::gpio::Pull::Up,
         esp_idf_svc::hal::gpio::InterruptType::AnyEdge,
@@ -240,13 +247,35 @@ fn main() -> anyhow::Result<()> {
         esp_idf_svc::hal::gpio::InterruptType::AnyEdge,
     )?;
 
-    let mut nvs = esp_idf_svc::nvs::EspDefaultNvs::new(partition, "setting", true)?;
+    let mut nvs = esp_idf_svc::nvs::EspDefaultNvs::new(partition.clone(), "setting", true)?;
 
+    // 先把旧固件留下的 NVS 布局升级到当前版本;失败只记日志,按现有内容继续加载
+    if let Err(e) = nvs_schema::migrate(&mut nvs) {
+        log::error!("NVS migration failed: {:?}", e);
+    }
     let mut setting = bt_wifi_mode::Setting::load_from_nvs(&nvs)?;
     // Load keymap config before moving nvs
     let mut keymap = bt_keyboard_mode::KeymapConfig::load_from_nvs(&nvs)?;
     log::info!("Loaded keymap config with {} keys", keymap.keys.len());
-    let asr_config = audio::AsrConfig::load_from_nvs(&nvs);
+
+    // 所有按键和旋钮交给统一输入服务(独立线程),各模式只从 input 读事件
+    let mut input = input::spawn(
+        bt_keyboard_mode::KeysPin {
+            mic: btn0,
+            custom: btn2,
+            esc: btn3,
+            next: btn4,
+            backspace: btn5,
+            switch: btn6,
+            accept: btn7,
+            rotate_a: pin16,
+            rotate_b: pin17,
+            rotate_button: pin18,
+        },
+        input::InputConfig::from_keymap(&keymap),
+    )?;
+    let mut asr_config = audio::AsrConfig::load_from_nvs(&nvs);
+    audio::load_ca_cert(&nvs);
 
     let mut wifi = esp_idf_svc::wifi::EspWifi::new(peripherals.modem, sysloop.clone(), None)?;
     let mac = wifi.sta_netif().get_mac().unwrap();
@@ -287,41 +316,70 @@ fn main() -> anyhow::Result<()> {
 
     let runtime = runtime.unwrap();
 
-    let mode = loop {
-        let choice = runtime.block_on(ui::boot_menu(&mut target, &mut btn7, &mut btn3, &mut btn4));
+    // 默认模式只在第一次显示开机菜单时倒计时,从 Setting 返回后不再自动进入
+    let mut auto_boot = setting.auto_boot().map(|m| match m {
+        bt_wifi_mode::BootMode::Remote => ui::BootChoice::Remote,
+        bt_wifi_mode::BootMode::Hybrid => ui::BootChoice::Hybrid,
+        _ => ui::BootChoice::Keyboard,
+    });
+    let run_mode = loop {
+        let choice = runtime.block_on(ui::boot_menu(
+            &mut target,
+            &mut input,
+            &setting.device_name(),
+            auto_boot.take(),
+        ));
         match choice {
-            ui::BootChoice::Keyboard => break 3,
-            ui::BootChoice::Remote => break 1,
+            ui::BootChoice::Keyboard => break mode::RunMode::Keyboard,
+            ui::BootChoice::Remote => break mode::RunMode::Remote,
+            ui::BootChoice::Hybrid => break mode::RunMode::Hybrid,
             ui::BootChoice::Setting => {
                 match runtime.block_on(ui::setting_page(
                     &mut target,
                     &scan_list,
-                    &mut btn7,
-                    &mut btn3,
-                    &mut btn4,
-                    &mut pin16,
-                    &mut pin17,
-                    &mut btn5,
+                    &mut input,
                     &mut setting,
                     &mut nvs,
                 )) {
-                    ui::SettingOutcome::Back => continue,
+                    ui::SettingOutcome::Back => {
+                        // 设置页可能改了 ASR 配置,重新读一次
+                        asr_config = audio::AsrConfig::load_from_nvs(&nvs);
+                        continue;
+                    }
                     ui::SettingOutcome::Ota => {
-                        // 同进程进入 OTA 模式:复用已建好的 wifi/按钮/显示。返回=ESC/失败
+                        // 同进程进入 OTA 模式:复用已建好的 wifi/输入/显示。返回=ESC/失败
                         // → 回 boot menu;成功在 ota::run 内部 restart。
                         let _ = ota::run(
                             &mut target,
-                            &mut btn7,
-                            &mut btn3,
+                            &mut input,
                             &mut wifi,
                             sysloop.clone(),
                             &ota::OtaData {
                                 scan_list: &scan_list,
                                 setting: &setting,
+                                nvs_partition: partition.clone(),
                             },
                         );
                         continue;
                     }
+                    ui::SettingOutcome::Provision => {
+                        // 同 OTA:复用已建好的 wifi/输入/显示。保存成功在 server 线程里
+                        // restart;ESC / 失败回 boot menu。
+                        if let Err(e) = provision::run(
+                            &mut target,
+                            &mut input,
+                            &mut wifi,
+                            sysloop.clone(),
+                            &provision::ProvisionData {
+                                scan_list: &scan_list,
+                                setting: &setting,
+                                nvs_partition: partition.clone(),
+                            },
+                        ) {
+                            log::error!("Provisioning failed: {:?}", e);
+                        }
+                        continue;
+                    }
                     ui::SettingOutcome::ClearConfig => {
                         bt_wifi_mode::Setting::clear_nvs(&mut nvs)?;
                         bt_keyboard_mode::KeymapConfig::clear_nvs(&mut nvs)?;
@@ -341,7 +399,7 @@ fn main() -> anyhow::Result<()> {
         ota.mark_running_slot_valid()?;
     }
 
-    if mode == 1 && setting.need_init() {
+    if run_mode != mode::RunMode::Keyboard && setting.need_init() {
         let _ = ui::render_keyboard_view(
             &mut target,
             false,
@@ -350,17 +408,423 @@ fn main() -> anyhow::Result<()> {
         );
         std::thread::sleep(std::time::Duration::from_secs(1));
     }
+    let mut run_mode = if setting.need_init() {
+        mode::RunMode::Keyboard
+    } else {
+        run_mode
+    };
 
-    if mode == 3 || setting.need_init() {
-        let _ = ui::render_keyboard_view(&mut target, false, false, "Starting in keyboard mode...");
-        std::thread::sleep(std::time::Duration::from_secs(1));
+    // WiFi 交给连接管理线程:掉线自动重连,并按 wifi_list 的顺序换网络
+    if let Err(e) =
+        wifi::spawn_manager(wifi, sysloop.clone(), setting.wifi_list.clone(), &scan_list)
+    {
+        log::error!("Failed to start WiFi manager: {:?}", e);
+    }
+
+    // 两种模式共用同一份 Setting/NVS、显示、输入、WiFi 和 I2S;绑定了 `mode` 动作的
+    // 按键/和弦让当前模式返回下一个模式,在这里直接切换,不重启。
+    let mut setting_arc = Arc::new(Mutex::new((setting, nvs)));
+    // 设置了 api_token 才开局域网 REST API;server 句柄要一直留着
+    let api_enabled = !setting_arc.lock().unwrap().0.api_token.is_empty();
+    let _rest_server = if api_enabled {
+        rest_api::spawn(setting_arc.clone())
+            .map_err(|e| log::error!("Failed to start REST API: {:?}", e))
+            .ok()
+    } else {
+        None
+    };
+    let mut audio_worker = Some(audio::AudioWorker {
+        in_i2s: peripherals.i2s0,
+        in_ws: peripherals.pins.gpio41.into(),
+        in_clk: peripherals.pins.gpio42.into(),
+        din: peripherals.pins.gpio40.into(),
+        in_mclk: None,
+    });
+    let mut driver: Option<audio::Driver> = None;
+    let mut time_synced = false;
+    let mut keyboard_ble: Option<KeyboardBle> = None;
 
+    loop {
+        // 其他模式下 REST 改的 keymap 在切换模式时生效
+        if reload_stale_keymap(&setting_arc, &mut keymap) {
+            input.set_config(input::InputConfig::from_keymap(&keymap));
+        }
+        {
+            let last_mode = match run_mode {
+                mode::RunMode::Keyboard => bt_wifi_mode::BootMode::Keyboard,
+                mode::RunMode::Remote => bt_wifi_mode::BootMode::Remote,
+                mode::RunMode::Hybrid => bt_wifi_mode::BootMode::Hybrid,
+            };
+            let mut lock = setting_arc.lock().unwrap();
+            let (setting, nvs) = &mut *lock;
+            if setting.last_mode != last_mode {
+                setting.last_mode = last_mode;
+                if let Err(e) = bt_wifi_mode::Setting::save_last_mode(nvs, last_mode) {
+                    log::error!("Failed to save last_mode: {:?}", e);
+                }
+            }
+        }
+
+        run_mode = match run_mode {
+            mode::RunMode::Keyboard => {
+                let _ = ui::render_keyboard_view(
+                    &mut target,
+                    false,
+                    false,
+                    "Starting in keyboard mode...",
+                );
+                std::thread::sleep(std::time::Duration::from_secs(1));
+
+                let kb = KeyboardBle::enter(
+                    &mut keyboard_ble,
+                    &setting_arc,
+                    &keymap,
+                    status::Mode::Keyboard,
+                )?;
+
+                let (wifi_list, host_slot, prefer_builtin_asr) = {
+                    let lock = setting_arc.lock().unwrap();
+                    (
+                        lock.0.wifi_list.clone(),
+                        lock.0.host_slot,
+                        lock.0.prefer_builtin_asr,
+                    )
+                };
+                // 键盘模式不依赖网络:boot 扫描里没有已知网络就不等,管理线程在后台继续连
+                let wifi_on = if bt_wifi_mode::pick_cred(&scan_list, &wifi_list).is_some() {
+                    wifi::wait_connected(WIFI_CONNECT_TIMEOUT)
+                } else {
+                    wifi::link_state().connected
+                };
+                let link = wifi::link_state();
+                kb.controller.update_status(|s| {
+                    s.wifi = status::WifiStatus {
+                        connected: wifi_on,
+                        ssid: link.ssid.clone(),
+                        rssi: wifi::sta_rssi(),
+                    };
+                    s.ble.slot = host_slot + 1;
+                    if !wifi_on {
+                        s.last_error = Some("WiFi: not connected".to_string());
+                    }
+                });
+                if !wifi_on {
+                    log::error!("WiFi not connected, still retrying in the background");
+                    let _ = ui::render_keyboard_view(
+                        &mut target,
+                        false,
+                        false,
+                        " WiFi not connected\n Retrying in background",
+                    );
+                    std::thread::sleep(std::time::Duration::from_secs(3));
+                } else {
+                    log::info!("WiFi connected successfully");
+                    log::info!("ASR config loaded from NVS: {:?}", asr_config);
+
+                    if let Some(ref asr_config) = asr_config {
+                        // 关闭「优先内置 ASR」时键盘模式不会用 Whisper(MIC 透传给主机),
+                        // 也就不需要为 HTTPS 证书校验同步时间 —— 跳过省一段启动耗时。
+                        if prefer_builtin_asr && asr_config.requires_tls() && !time_synced {
+                            time_synced = sync_time_with_retry(&mut target, &mut input);
+                            if !time_synced {
+                                log::warn!(
+                                    "Time sync canceled; starting keyboard mode without TLS ASR"
+                                );
+                            }
+                        }
+                        if time_synced || !(prefer_builtin_asr && asr_config.requires_tls()) {
+                            ensure_audio_driver(&mut driver, &mut audio_worker);
+                        }
+                    }
+                }
+
+                log_heap();
+                std::thread::sleep(std::time::Duration::from_millis(500));
+                let _ = ui::render_keyboard_view(&mut target, false, false, "Keyboard Mode");
+
+                let next = runtime.block_on(keyboard_mode_main(
+                    &mut target,
+                    kb.ble_device,
+                    &mut kb.keyboard,
+                    &mut input,
+                    &mut setting_arc,
+                    &mut kb.setting_rx,
+                    &mut kb.rx,
+                    &mut keymap,
+                    &mut driver,
+                    asr_config.clone(),
+                    &kb.controller,
+                    wifi_on,
+                ));
+                log::info!("Leaving keyboard mode for {:?}", next);
+                kb.keyboard.release();
+                if !next.uses_ble() {
+                    kb.leave();
+                }
+                next
+            }
+            mode::RunMode::Remote | mode::RunMode::Hybrid => {
+                let (server_url, mic_model, prefer_builtin_asr) = {
+                    let lock = setting_arc.lock().unwrap();
+                    let setting = &lock.0;
+                    if setting.need_init() {
+                        let _ = ui::render_keyboard_view(
+                            &mut target,
+                            false,
+                            false,
+                            "Remote Control mode requires network/server config",
+                        );
+                        std::thread::sleep(std::time::Duration::from_secs(1));
+                        run_mode = mode::RunMode::Keyboard;
+                        continue;
+                    }
+
+                    log::info!("Displaying PNG image on LCD...");
+                    if setting.background_png.0.is_empty() {
+                        log::info!("No background PNG found in settings, using default.");
+                        std::thread::sleep(std::time::Duration::from_secs(2));
+                    } else {
+                        log::info!(
+                            "Background PNG found in settings, size: {} bytes",
+                            setting.background_png.0.len()
+                        );
+                        lcd::display_png(
+                            &mut target,
+                            setting.background_png.0.as_slice(),
+                            std::time::Duration::from_secs(2),
+                        )?;
+                    }
+                    (
+                        setting.server_url.clone(),
+                        setting.mic_model,
+                        setting.prefer_builtin_asr,
+                    )
+                };
+                // 混合模式:BLE 键盘照常广播/保持连接,屏幕交给远程会话
+                if run_mode == mode::RunMode::Hybrid {
+                    KeyboardBle::enter(
+                        &mut keyboard_ble,
+                        &setting_arc,
+                        &keymap,
+                        status::Mode::Hybrid,
+                    )?;
+                }
+
+                let _ =
+                    ui::render_keyboard_view(&mut target, false, false, "Connecting the WiFi...");
+
+                // 远程模式离不开网络:等不到连接或取消对时就退回键盘模式
+                if !wifi::wait_connected(WIFI_CONNECT_TIMEOUT) {
+                    log::error!("WiFi not connected after {:?}", WIFI_CONNECT_TIMEOUT);
+                    let _ = ui::render_keyboard_view(
+                        &mut target,
+                        false,
+                        false,
+                        " WiFi connection failed\n",
+                    );
+                    std::thread::sleep(std::time::Duration::from_secs(3));
+                    run_mode = mode::RunMode::Keyboard;
+                    continue;
+                }
+
+                if !time_synced
+                    && (server_url.starts_with("mqtts")
+                        || asr_config.as_ref().map_or(false, |c| c.requires_tls()))
+                {
+                    let _ = ui::render_keyboard_view(&mut target, false, false, "Syncing time...");
+                    time_synced = sync_time_with_retry(&mut target, &mut input);
+                    if !time_synced {
+                        log::warn!("Time sync canceled; falling back to keyboard mode");
+                        run_mode = mode::RunMode::Keyboard;
+                        continue;
+                    }
+                }
+
+                // 远程模式改用本地 ASR(MQTT 无语音通道):audio::Driver 持有 I2S,
+                // 不再把音频流发给服务器。
+                ensure_audio_driver(&mut driver, &mut audio_worker);
+
+                log::info!("start ASR worker thread");
+                log_heap();
+
+                // ASR 跑在独立 OS 线程上,栈 64KB(与主任务一致,够跑 Whisper HTTP+TLS 流式录音;
+                // tokio::spawn_blocking 的池线程栈太小会溢出)。Driver 由该线程独占,app_fut 通过
+                // channel 发请求/收结果,避免长阻塞冻死 async runtime 上的 MQTT keepalive。
+                // app_fut 结束 → asr_tx drop → channel 关闭 → worker 的 recv() 返回 Err → 线程退出,
+                // 把 Driver 交还给下一个模式。
+                let (asr_tx, asr_rx) = std::sync::mpsc::channel::<audio::AsrRequest>();
+                let asr_driver = driver.take();
+                let asr_worker = std::thread::Builder::new()
+                    .name("asr-worker".to_string())
+                    .stack_size(1024 * 16)
+                    .spawn(move || {
+                        let mut driver = asr_driver;
+                        while let Ok(req) = asr_rx.recv() {
+                            // on_start_listen:连上 server(TLS 完成、开始上传录音)时 fire connected_tx,
+                            // 通知 UI 把弹窗从「connecting 黄框」切到「listening 绿框」。
+                            let mut connected_tx = Some(req.connected_tx);
+                            let r = match driver.as_mut() {
+                                Some(d) => d.start_asr(
+                                    &req.config,
+                                    move || {
+                                        if let Some(tx) = connected_tx.take() {
+                                            let _ = tx.send(());
+                                        }
+                                    },
+                                    || req.cancel.load(std::sync::atomic::Ordering::Relaxed),
+                                ),
+                                None => Err(anyhow::anyhow!("audio driver unavailable")),
+                            };
+                            let _ = req.respond.send(r);
+                        }
+                        log::info!("ASR worker thread exited");
+                        driver
+                    })
+                    .map_err(|e| log::error!("Failed to spawn ASR worker thread: {e:?}"))
+                    .ok();
+
+                let _ =
+                    ui::render_keyboard_view(&mut target, false, false, "Connecting the Server...");
+
+                let mut ui = lcd::UI::new_with_target(target);
+
+                let mic_mode = app::MicMode::from(mic_model);
+                // 混合模式下路由到 BLE 的按键经 ble_tx 交给 hybrid_ble_main;开启内置 ASR 时
+                // 它的 MIC 也用同一个 asr-worker。
+                let (ble_tx, ble_rx) = match run_mode {
+                    mode::RunMode::Hybrid => {
+                        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+                        (Some(tx), Some(rx))
+                    }
+                    _ => (None, None),
+                };
+                let ble_asr = asr_config
+                    .clone()
+                    .filter(|_| prefer_builtin_asr)
+                    .map(|c| (c, mic_mode, asr_tx.clone()));
+                let app_fut = app::run(
+                    server_url,
+                    &client_id,
+                    &mut ui,
+                    &mut input,
+                    &keymap,
+                    asr_tx,
+                    asr_config.as_ref(),
+                    mic_mode,
+                    ble_tx,
+                );
+                let r = match (keyboard_ble.as_mut(), ble_rx) {
+                    (Some(kb), Some(ble_rx)) => runtime.block_on(async {
+                        let ble_fut = hybrid_ble_main(kb, &setting_arc, &keymap, ble_rx, ble_asr);
+                        tokio::join!(app_fut, ble_fut).0
+                    }),
+                    _ => {
+                        // 多出来的 asr_tx 要先放掉,否则 app 返回后 asr-worker 不会退出
+                        drop(ble_asr);
+                        runtime.block_on(app_fut)
+                    }
+                };
+                target = ui.into_display();
+                if let Some(worker) = asr_worker {
+                    driver = worker.join().ok().flatten();
+                }
+                // 会话结束或连不上服务器时与 WiFi 失败一样退回键盘模式,不重启
+                let next = match r {
+                    Ok(Some(next)) => next,
+                    Ok(None) => {
+                        log::warn!("Remote session ended; falling back to keyboard mode");
+                        let _ = ui::render_keyboard_view(
+                            &mut target,
+                            false,
+                            false,
+                            " Remote session ended\n",
+                        );
+                        std::thread::sleep(std::time::Duration::from_secs(3));
+                        mode::RunMode::Keyboard
+                    }
+                    Err(e) => {
+                        log::error!("App error: {:?}; falling back to keyboard mode", e);
+                        let _ = ui::render_keyboard_view(
+                            &mut target,
+                            false,
+                            false,
+                            &format!(" Server connection failed\n {}", e),
+                        );
+                        std::thread::sleep(std::time::Duration::from_secs(3));
+                        mode::RunMode::Keyboard
+                    }
+                };
+                if let Some(kb) = keyboard_ble.as_mut().filter(|kb| kb.active) {
+                    kb.keyboard.release();
+                    if !next.uses_ble() {
+                        kb.leave();
+                    }
+                }
+                next
+            }
+        };
+    }
+}
+
+/// 键盘模式和混合模式的 BLE 资源。NimBLE 的 GATT 服务只能在 server 启动前注册,所以
+/// 第一次进入时建好后一直保留;切到不用 BLE 的模式时只停广播、断开主机,回来时重新广播。
+struct KeyboardBle {
+    ble_device: &'static mut esp32_nimble::BLEDevice,
+    /// 正在广播 / 保持连接(没有被 `leave` 停掉)
+    active: bool,
+    keyboard: bt_keyboard_mode::KeyboardAndMouse,
+    controller: bt_keyboard_mode::ControllerService,
+    setting_rx: tokio::sync::mpsc::Receiver<bt_wifi_mode::BTevent>,
+    rx: tokio::sync::mpsc::Receiver<bt_keyboard_mode::ControllerCommand>,
+}
+
+impl KeyboardBle {
+    /// 进入用 BLE 的模式:第一次调用时建好服务并开始广播,之后只在 `leave` 过时重新广播。
+    /// `mode` 写进推给配套页面的状态。
+    fn enter<'a>(
+        this: &'a mut Option<Self>,
+        setting_arc: &Arc<Mutex<(bt_wifi_mode::Setting, esp_idf_svc::nvs::EspDefaultNvs)>>,
+        keymap: &bt_keyboard_mode::KeymapConfig,
+        mode: status::Mode,
+    ) -> anyhow::Result<&'a mut Self> {
+        let kb = match this.take() {
+            Some(kb) => kb,
+            None => Self::start(setting_arc, keymap)?,
+        };
+        let kb = this.insert(kb);
+        if !kb.active {
+            let slot = setting_arc.lock().unwrap().0.host_slot;
+            if let Err(e) = bt_keyboard_mode::switch_host_slot(kb.ble_device, slot) {
+                log::error!("Failed to resume advertising: {:?}", e);
+            }
+            kb.active = true;
+        }
+        kb.controller.update_status(|s| s.mode = mode);
+        Ok(kb)
+    }
+
+    /// 离开到不用 BLE 的模式:停广播、断开所有主机
+    fn leave(&mut self) {
+        bt_keyboard_mode::disconnect_hosts(self.ble_device);
+        self.active = false;
+    }
+
+    fn start(
+        setting_arc: &Arc<Mutex<(bt_wifi_mode::Setting, esp_idf_svc::nvs::EspDefaultNvs)>>,
+        keymap: &bt_keyboard_mode::KeymapConfig,
+    ) -> anyhow::Result<Self> {
         let (tx, rx) = tokio::sync::mpsc::channel(64);
         let (setting_tx, setting_rx) = tokio::sync::mpsc::channel(8);
 
-        let mut setting_arc = Arc::new(Mutex::new((setting.clone(), nvs)));
-
-        esp32_nimble::BLEDevice::set_device_name("VibeKeys-MAX")?;
+        let (device_name, ble_appearance, host_slot) = {
+            let lock = setting_arc.lock().unwrap();
+            (
+                lock.0.device_name(),
+                lock.0.ble_appearance,
+                lock.0.host_slot,
+            )
+        };
+        esp32_nimble::BLEDevice::set_device_name(&device_name)?;
 
         let ble_device = esp32_nimble::BLEDevice::take();
 
@@ -380,254 +844,101 @@ fn main() -> anyhow::Result<()> {
         });
         let service = server.create_service(bt_wifi_mode::SERVICE_ID);
 
-        let mut keyboard = bt_keyboard_mode::KeyboardAndMouse::new(ble_device, 100)?;
+        let keyboard = bt_keyboard_mode::KeyboardAndMouse::new(ble_device, 100)?;
         let (controller, service_id) = {
             let mut lock = service.lock();
             let controller = bt_keyboard_mode::new_controller_service(&mut lock, tx)?;
+            controller.publish_keymap(keymap);
             // Start setting service
             bt_wifi_mode::new_setting_service(&mut lock, setting_arc.clone(), Some(setting_tx))?;
             (controller, lock.uuid())
         };
 
         let server = ble_device.get_server();
-        server.start()?;
-        bt_keyboard_mode::start_ble_advertising(
-            ble_device,
-            &[keyboard.hid_service_id(), service_id],
-        )?;
-
-        let mut key_pins = bt_keyboard_mode::KeysPin {
-            mic: btn0,
-            custom: btn2,
-            esc: btn3,
-            next: btn4,
-            backspace: btn5,
-            switch: btn6,
-            accept: btn7,
-            rotate_a: pin16,
-            rotate_b: pin17,
-            rotate_button: pin18,
-        };
-
-        let mut driver: Option<audio::Driver> = None;
-
-        // 用 boot 阶段的扫描结果与已配置 wifi_list 匹配,挑当前在范围内的网络连接。
-        let r = match bt_wifi_mode::pick_cred(&scan_list, &setting.wifi_list) {
-            Some(c) => wifi::connect(&mut wifi, &c.ssid, &c.pass, sysloop.clone()),
-            None => {
-                log::error!(
-                    "No known WiFi network in range (scan_list has {})",
-                    scan_list.len()
-                );
-                anyhow::Result::<()>::Err(anyhow::anyhow!("no known network in range"))
+        // 每个主机槽位各有一个地址、各绑定一台主机(先配对的占住,删掉 bond 才让出);
+        // 别的槽位的主机连进来就断开。槽位已被占、或关闭配对时,不在任何槽位里的新主机
+        // 也断开,并删掉它刚建立的 bond。
+        let peers_arc = setting_arc.clone();
+        server.on_authentication_complete(move |desc, result| {
+            if result.is_err() {
+                return;
             }
-        };
-        let wifi_on = r.is_ok();
-        if r.is_err() {
-            let e = r.err();
-            log::error!("Failed to connect to WiFi: {:?}", e);
-            let _ = ui::render_keyboard_view(
-                &mut target,
-                false,
-                false,
-                &format!(" WiFi connection failed: {:?}\n", e),
-            );
-            std::thread::sleep(std::time::Duration::from_secs(3));
-        } else {
-            log::info!("WiFi connected successfully");
-            log::info!("ASR config loaded from NVS: {:?}", asr_config);
-
-            if let Some(ref asr_config) = asr_config {
-                // 关闭「优先内置 ASR」时键盘模式不会用 Whisper(MIC 透传给主机),
-                // 也就不需要为 HTTPS 证书校验同步时间 —— 跳过省一段启动耗时。
-                if setting.prefer_builtin_asr && asr_config.requires_tls() {
-                    if sync_time_with_retry(&mut target, &key_pins.accept, &key_pins.esc) {
-                        let worker = audio::AudioWorker {
-                            in_i2s: peripherals.i2s0,
-                            in_ws: peripherals.pins.gpio41.into(),
-                            in_clk: peripherals.pins.gpio42.into(),
-                            din: peripherals.pins.gpio40.into(),
-                            in_mclk: None,
-                        };
-                        let _ = driver.insert(audio::Driver::new(worker)?);
-                    } else {
-                        log::warn!("Time sync canceled; starting keyboard mode without TLS ASR");
+            let addr = desc.id_address().to_string();
+            let slot = bt_keyboard_mode::HOST_SLOT.load(std::sync::atomic::Ordering::Relaxed);
+            let mut lock = peers_arc.lock().unwrap();
+            let (setting, nvs) = &mut *lock;
+            let open = setting.open_pairing;
+            match bt_keyboard_mode::admit_peer(&mut setting.host_peers, slot, &addr, open) {
+                bt_keyboard_mode::PeerAdmission::Known => {}
+                bt_keyboard_mode::PeerAdmission::Bound => {
+                    log::info!("Host {} bound to slot {}", addr, slot + 1);
+                    if let Err(e) = bt_wifi_mode::Setting::save_host_peers(nvs, &setting.host_peers)
+                    {
+                        log::error!("Failed to save host_peers: {:?}", e);
+                    }
+                }
+                bt_keyboard_mode::PeerAdmission::OtherSlot(other) => {
+                    log::info!("Host {} belongs to slot {}, disconnecting", addr, other + 1);
+                    let server = esp32_nimble::BLEDevice::take().get_server();
+                    if let Err(e) = server.disconnect(desc.conn_handle()) {
+                        log::error!("Failed to disconnect host: {:?}", e);
+                    }
+                }
+                bt_keyboard_mode::PeerAdmission::SlotTaken
+                | bt_keyboard_mode::PeerAdmission::Rejected => {
+                    log::info!(
+                        "Rejecting new host {} in slot {} ({})",
+                        addr,
+                        slot + 1,
+                        if open { "slot taken" } else { "pairing closed" }
+                    );
+                    let server = esp32_nimble::BLEDevice::take().get_server();
+                    if let Err(e) = server.disconnect(desc.conn_handle()) {
+                        log::error!("Failed to disconnect host: {:?}", e);
+                    }
+                    if let Err(e) = bt_keyboard_mode::delete_bond(&desc.id_address()) {
+                        log::error!("Failed to delete bond: {:?}", e);
                     }
-                } else {
-                    let worker = audio::AudioWorker {
-                        in_i2s: peripherals.i2s0,
-                        in_ws: peripherals.pins.gpio41.into(),
-                        in_clk: peripherals.pins.gpio42.into(),
-                        din: peripherals.pins.gpio40.into(),
-                        in_mclk: None,
-                    };
-                    let _ = driver.insert(audio::Driver::new(worker)?);
                 }
             }
-        }
+        });
 
-        log_heap();
-        std::thread::sleep(std::time::Duration::from_millis(500));
-        let _ = ui::render_keyboard_view(&mut target, false, false, "Keyboard Mode");
+        server.start()?;
+        if let Err(e) = bt_keyboard_mode::use_host_slot(ble_device, host_slot) {
+            log::error!("Failed to use host slot {}: {:?}", host_slot, e);
+        }
+        bt_keyboard_mode::start_ble_advertising(
+            ble_device,
+            &device_name,
+            ble_appearance,
+            &[keyboard.hid_service_id(), service_id],
+        )?;
 
-        runtime.block_on(keyboard_mode_main(
-            &mut target,
+        Ok(Self {
             ble_device,
-            &mut keyboard,
-            &mut key_pins,
-            &mut setting_arc,
+            active: true,
+            keyboard,
+            controller,
             setting_rx,
             rx,
-            &mut keymap,
-            driver,
-            asr_config,
-            controller,
-            wifi_on,
-        ));
-    }
-
-    log::info!("Displaying PNG image on LCD...");
-
-    if setting.background_png.0.is_empty() {
-        log::info!("No background PNG found in settings, using default.");
-        std::thread::sleep(std::time::Duration::from_secs(2));
-    } else {
-        log::info!(
-            "Background PNG found in settings, size: {} bytes",
-            setting.background_png.0.len()
-        );
-        lcd::display_png(
-            &mut target,
-            setting.background_png.0.as_slice(),
-            std::time::Duration::from_secs(2),
-        )?;
+        })
     }
+}
 
-    let (tx, rx) = tokio::sync::mpsc::channel::<app::Event>(64);
-
-    let _ = ui::render_keyboard_view(&mut target, false, false, "Connecting the WiFi...");
-
-    // 用 boot 阶段的扫描结果与已配置 wifi_list 匹配,挑当前在范围内的网络连接。
-    let r = match bt_wifi_mode::pick_cred(&scan_list, &setting.wifi_list) {
-        Some(c) => wifi::connect(&mut wifi, &c.ssid, &c.pass, sysloop.clone()),
-        None => {
-            log::error!(
-                "No known WiFi network in range (scan_list has {})",
-                scan_list.len()
-            );
-            anyhow::Result::<()>::Err(anyhow::anyhow!("no known network in range"))
-        }
-    };
-    if r.is_err() {
-        log::error!("Failed to connect to WiFi: {:?}", r.err());
-        let _ = ui::render_keyboard_view(&mut target, false, false, " WiFi connection failed\n");
-        std::thread::sleep(std::time::Duration::from_secs(60));
-        esp_idf_svc::hal::reset::restart();
+/// 第一次需要录音时才用 I2S 外设建 audio::Driver,之后各模式共用;失败只记日志。
+fn ensure_audio_driver(
+    driver: &mut Option<audio::Driver>,
+    worker: &mut Option<audio::AudioWorker>,
+) {
+    if driver.is_some() {
+        return;
     }
-
-    if setting.server_url.starts_with("mqtts")
-        || asr_config.as_ref().map_or(false, |c| c.requires_tls())
-    {
-        let _ = ui::render_keyboard_view(&mut target, false, false, "Syncing time...");
-        if !sync_time_with_retry(&mut target, &btn7, &btn3) {
-            log::warn!("Time sync canceled; restarting before remote mode");
-            esp_idf_svc::hal::reset::restart();
+    if let Some(worker) = worker.take() {
+        match audio::Driver::new(worker) {
+            Ok(d) => *driver = Some(d),
+            Err(e) => log::error!("Failed to create audio driver: {e:?}"),
         }
     }
-
-    {
-        // 所有非 MIC 按钮合并成一个 select! 循环,跑在专用 4k 线程上
-        // (自带 current-thread tokio runtime 驱动 select! 和 sleep)。
-        // btn0 (MIC) 由 app::run 直接持有用于本地 ASR,不在此。
-        let key_tx = tx.clone();
-        std::thread::Builder::new()
-            .name("keys".to_string())
-            .stack_size(6 * 1024)
-            .spawn(move || {
-                let rt = tokio::runtime::Builder::new_current_thread()
-                    .enable_all()
-                    .build()
-                    .expect("key-thread runtime");
-                rt.block_on(app::listen_all_keys(
-                    btn2, btn4, btn5, btn6, btn3, btn7, pin16, pin17, pin18, key_tx,
-                ))
-                .unwrap();
-            })?;
-    }
-
-    // 远程模式改用本地 ASR(MQTT 无语音通道):创建 audio::Driver 持有 I2S,
-    // 不再把音频流发给服务器。
-    let worker = audio::AudioWorker {
-        in_i2s: peripherals.i2s0,
-        in_ws: peripherals.pins.gpio41.into(),
-        in_clk: peripherals.pins.gpio42.into(),
-        din: peripherals.pins.gpio40.into(),
-        in_mclk: None,
-    };
-    let driver = audio::Driver::new(worker)
-        .map_err(|e| log::error!("Failed to create audio driver: {e:?}"))
-        .ok();
-
-    log::info!("start ASR worker thread");
-    log_heap();
-
-    // ASR 跑在独立 OS 线程上,栈 64KB(与主任务一致,够跑 Whisper HTTP+TLS 流式录音;
-    // tokio::spawn_blocking 的池线程栈太小会溢出)。Driver 由该线程独占,app_fut 通过
-    // channel 发请求/收结果,避免长阻塞冻死 async runtime 上的 MQTT keepalive。
-    // app_fut 结束 → asr_tx drop → channel 关闭 → worker 的 recv() 返回 Err → 线程退出。
-    let (asr_tx, asr_rx) = std::sync::mpsc::channel::<audio::AsrRequest>();
-    if let Err(e) = std::thread::Builder::new()
-        .name("asr-worker".to_string())
-        .stack_size(1024 * 16)
-        .spawn(move || {
-            let mut driver = driver;
-            while let Ok(req) = asr_rx.recv() {
-                // on_start_listen:连上 server(TLS 完成、开始上传录音)时 fire connected_tx,
-                // 通知 UI 把弹窗从「connecting 黄框」切到「listening 绿框」。
-                let mut connected_tx = Some(req.connected_tx);
-                let r = match driver.as_mut() {
-                    Some(d) => d.start_asr(
-                        &req.config,
-                        move || {
-                            if let Some(tx) = connected_tx.take() {
-                                let _ = tx.send(());
-                            }
-                        },
-                        || req.cancel.load(std::sync::atomic::Ordering::Relaxed),
-                    ),
-                    None => Err(anyhow::anyhow!("audio driver unavailable")),
-                };
-                let _ = req.respond.send(r);
-            }
-            log::info!("ASR worker thread exited");
-        })
-    {
-        log::error!("Failed to spawn ASR worker thread: {e:?}");
-    }
-
-    let _ = ui::render_keyboard_view(&mut target, false, false, "Connecting the Server...");
-
-    let mut ui = lcd::UI::new_with_target(target);
-
-    let app_fut = app::run(
-        setting.server_url,
-        &client_id,
-        &mut ui,
-        rx,
-        &keymap,
-        asr_tx,
-        asr_config.as_ref(),
-        app::key_task::MicMode::from(setting.mic_model),
-        &mut btn0,
-    );
-    let r = runtime.block_on(app_fut);
-    if let Err(e) = r {
-        log::error!("App error: {:?}", e);
-    } else {
-        log::info!("App exited successfully");
-    }
-
-    esp_idf_svc::hal::reset::restart();
 }
 
 pub fn log_heap() {
@@ -646,7 +957,7 @@ pub fn log_heap() {
 }
 
 fn handle_reset_event(
-    setting_arc: &mut Arc<Mutex<(bt_wifi_mode::Setting, esp_idf_svc::nvs::EspDefaultNvs)>>,
+    setting_arc: &Arc<Mutex<(bt_wifi_mode::Setting, esp_idf_svc::nvs::EspDefaultNvs)>>,
 ) -> ! {
     let lock = setting_arc.lock().unwrap();
     let png_to_save = if lock.0.background_png.1 {
@@ -674,44 +985,214 @@ fn handle_reset_event(
     esp_idf_svc::hal::reset::restart();
 }
 
+/// 处理一次 keymap 写入:合并 / 删除指定按键 / 全部恢复默认。
+/// 合并的基础是 NVS 里存的那份,不是内存里的(REST 可能刚改过,见 `KEYMAP_STALE`)。
+/// 任一条目非法则整份拒绝(不做部分合并);写 NVS 失败同样整次拒绝(`storage_failed`),
+/// 内存里的 keymap 保持不变,免得和 NVS 不一致。
+/// 返回的错误列表由调用方经 `ControllerService::notify_keymap_result` 回报配置页。
 fn handle_keymap_config(
     config: String,
     nvs: &mut esp_idf_svc::nvs::EspDefaultNvs,
     keymap: &mut bt_keyboard_mode::KeymapConfig,
-) {
+) -> Result<(), Vec<bt_keyboard_mode::KeymapError>> {
     log::info!("Received keymap config: {}", config);
-    match bt_keyboard_mode::KeymapConfig::from_json(&config) {
-        Ok(keymap_) => {
-            keymap.merge(keymap_);
-            match keymap.save_to_nvs(nvs) {
-                Ok(()) => {
-                    log::info!("Keymap config merged and saved to NVS successfully");
-                }
-                Err(e) => {
-                    log::error!("Failed to save keymap to NVS: {:?}", e);
-                }
+    let write = bt_keyboard_mode::KeymapWrite::parse(&config).map_err(|errors| {
+        log::error!("Keymap config rejected: {:?}", errors);
+        errors
+    })?;
+
+    let storage_failed = |e: anyhow::Error| {
+        log::error!("Failed to save keymap to NVS: {:?}", e);
+        vec![bt_keyboard_mode::KeymapError::storage_failed(e)]
+    };
+    let mut updated = bt_keyboard_mode::KeymapConfig::load_from_nvs(nvs).unwrap_or_else(|e| {
+        log::warn!(
+            "Failed to reload keymap from NVS, merging into memory copy: {:?}",
+            e
+        );
+        keymap.clone()
+    });
+    match write {
+        bt_keyboard_mode::KeymapWrite::Merge(keymap_) => updated.merge(keymap_),
+        bt_keyboard_mode::KeymapWrite::Remove(keys) => {
+            for key in &keys {
+                updated.remove(key);
             }
         }
+        bt_keyboard_mode::KeymapWrite::Reset => {
+            bt_keyboard_mode::KeymapConfig::clear_nvs(nvs).map_err(storage_failed)?;
+            *keymap = bt_keyboard_mode::KeymapConfig::default();
+            return Ok(());
+        }
+    }
+
+    updated.save_to_nvs(nvs).map_err(storage_failed)?;
+    log::info!("Keymap config merged and saved to NVS successfully");
+    *keymap = updated;
+    Ok(())
+}
+
+/// REST API 改过 keymap 后从 NVS 重新加载内存里这份;返回 true 表示换了新的。
+fn reload_stale_keymap(
+    setting_arc: &Arc<Mutex<(bt_wifi_mode::Setting, esp_idf_svc::nvs::EspDefaultNvs)>>,
+    keymap: &mut bt_keyboard_mode::KeymapConfig,
+) -> bool {
+    if !bt_keyboard_mode::KEYMAP_STALE.swap(false, std::sync::atomic::Ordering::Relaxed) {
+        return false;
+    }
+    match bt_keyboard_mode::KeymapConfig::load_from_nvs(&setting_arc.lock().unwrap().1) {
+        Ok(reloaded) => {
+            log::info!("Keymap changed over the REST API, reloaded from NVS");
+            *keymap = reloaded;
+            true
+        }
         Err(e) => {
-            log::error!("Failed to parse keymap JSON: {:?}", e);
+            log::error!("Failed to reload keymap from NVS: {:?}", e);
+            false
         }
     }
 }
 
+/// 键盘模式里:REST 改过 keymap 就换上新的,同步输入服务并推送给配置页。
+fn sync_stale_keymap(
+    input: &mut input::Input,
+    controller: &bt_keyboard_mode::ControllerService,
+    setting_arc: &Arc<Mutex<(bt_wifi_mode::Setting, esp_idf_svc::nvs::EspDefaultNvs)>>,
+    keymap: &mut bt_keyboard_mode::KeymapConfig,
+) {
+    if reload_stale_keymap(setting_arc, keymap) {
+        input.set_config(input::InputConfig::from_keymap(keymap));
+        controller.publish_keymap(keymap);
+    }
+}
+
+/// 应用一次 keymap 写入(特征值直写或分块传输),并把结果回报配置页。
+fn apply_keymap_config(
+    display: &mut lcd::FrameBuffer,
+    input: &mut input::Input,
+    controller: &bt_keyboard_mode::ControllerService,
+    setting_arc: &Arc<Mutex<(bt_wifi_mode::Setting, esp_idf_svc::nvs::EspDefaultNvs)>>,
+    keymap: &mut bt_keyboard_mode::KeymapConfig,
+    config: String,
+) {
+    let r = handle_keymap_config(config, &mut setting_arc.lock().unwrap().1, keymap);
+    match r {
+        Ok(()) => {
+            input.set_config(input::InputConfig::from_keymap(keymap));
+            controller.publish_keymap(keymap);
+            controller.notify_keymap_result(&[]);
+            let _ = ui::render_keyboard_view(display, false, false, "keymap updated!");
+        }
+        Err(errors) => {
+            controller.notify_keymap_result(&errors);
+            let _ = ui::render_keyboard_view(display, false, false, "keymap rejected");
+        }
+    }
+}
+
+/// 分块传输校验通过后,按类型落地:背景图立即写 NVS(下次启动显示),
+/// keymap 走与特征值直写相同的路径,CA 证书写 NVS(重启后生效),
+/// 整份配置校验后一次写入。返回 true 表示需要重启让新配置生效。
+fn handle_transfer(
+    display: &mut lcd::FrameBuffer,
+    input: &mut input::Input,
+    controller: &bt_keyboard_mode::ControllerService,
+    setting_arc: &Arc<Mutex<(bt_wifi_mode::Setting, esp_idf_svc::nvs::EspDefaultNvs)>>,
+    keymap: &mut bt_keyboard_mode::KeymapConfig,
+    done: transfer::Completed,
+) -> anyhow::Result<bool> {
+    log::info!(
+        "Transfer of {:?} complete, {} bytes",
+        done.kind,
+        done.data.len()
+    );
+    match done.kind {
+        transfer::TransferKind::Background => {
+            let mut lock = setting_arc.lock().unwrap();
+            lock.1.set_blob("background_png", &done.data)?;
+            lock.0.background_png = (done.data, true);
+            drop(lock);
+            let _ = ui::render_keyboard_view(display, false, false, "background updated!");
+        }
+        transfer::TransferKind::Keymap => {
+            let config = String::from_utf8(done.data)?;
+            apply_keymap_config(display, input, controller, setting_arc, keymap, config);
+        }
+        transfer::TransferKind::Cert => {
+            audio::save_ca_cert(&mut setting_arc.lock().unwrap().1, &done.data)?;
+            let _ = ui::render_keyboard_view(display, false, false, "certificate saved!");
+        }
+        transfer::TransferKind::Config => {
+            let doc = backup::ConfigDocument::from_json(std::str::from_utf8(&done.data)?)?;
+            let mut lock = setting_arc.lock().unwrap();
+            let (setting, nvs) = &mut *lock;
+            setting.restore(nvs, doc)?;
+            let _ = ui::render_keyboard_view(display, false, false, "config restored!");
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// 按 export 帧导出整份配置或生效的 keymap:分帧经 transfer 特征值通知发出(不等 ack)。
+/// 帧长按当前连接里最小的 MTU 算,帧间稍作停顿,免得 NimBLE 的发送缓冲被塞满。
+/// 通知发给所有连接,所以只有全部连接都已加密时才带明文密钥,否则按 `secrets: false` 导出。
+async fn send_export(
+    ble_device: &esp32_nimble::BLEDevice,
+    controller: &bt_keyboard_mode::ControllerService,
+    setting_arc: &Arc<Mutex<(bt_wifi_mode::Setting, esp_idf_svc::nvs::EspDefaultNvs)>>,
+    keymap: &bt_keyboard_mode::KeymapConfig,
+    mut req: transfer::ExportRequest,
+) -> anyhow::Result<()> {
+    if req.secrets && !ble_device.get_server().connections().all(|c| c.encrypted()) {
+        log::warn!("Export with secrets requested over an unencrypted link, masking them");
+        req.secrets = false;
+    }
+    let data = match req.kind {
+        transfer::TransferKind::Keymap => keymap.effective().to_json()?.into_bytes(),
+        _ => {
+            let doc = {
+                let lock = setting_arc.lock().unwrap();
+                lock.0.export(&lock.1, keymap, req.secrets, req.background)
+            };
+            serde_json::to_vec(&doc)?
+        }
+    };
+    let mtu = ble_device
+        .get_server()
+        .connections()
+        .map(|c| c.mtu())
+        .min()
+        .unwrap_or(23) as usize;
+    // ATT 通知头 3 字节 + chunk 帧头 3 字节
+    let frames = transfer::encode(req.kind, &data, mtu.saturating_sub(6));
+    log::info!(
+        "Exporting {:?}: {} bytes in {} frames",
+        req.kind,
+        data.len(),
+        frames.len()
+    );
+    for frame in frames {
+        controller.notify_transfer_frame(&frame);
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+    Ok(())
+}
+
 async fn keyboard_mode_main(
     display: &mut lcd::FrameBuffer,
     ble_device: &mut esp32_nimble::BLEDevice,
     keyboard: &mut bt_keyboard_mode::KeyboardAndMouse,
-    key_pins: &mut bt_keyboard_mode::KeysPin,
+    input: &mut input::Input,
     setting_arc: &mut Arc<Mutex<(bt_wifi_mode::Setting, esp_idf_svc::nvs::EspDefaultNvs)>>,
-    mut setting_rx: tokio::sync::mpsc::Receiver<bt_wifi_mode::BTevent>,
-    mut rx: tokio::sync::mpsc::Receiver<bt_keyboard_mode::ControllerCommand>,
+    setting_rx: &mut tokio::sync::mpsc::Receiver<bt_wifi_mode::BTevent>,
+    rx: &mut tokio::sync::mpsc::Receiver<bt_keyboard_mode::ControllerCommand>,
     keymap: &mut bt_keyboard_mode::KeymapConfig,
-    mut driver: Option<audio::Driver>,
+    driver: &mut Option<audio::Driver>,
     asr_config: Option<audio::AsrConfig>,
-    controller: bt_keyboard_mode::ControllerService,
+    controller: &bt_keyboard_mode::ControllerService,
     wifi_on: bool,
-) -> ! {
+) -> mode::RunMode {
     let _ = ui::render_keyboard_view(
         display,
         true,
@@ -719,24 +1200,96 @@ async fn keyboard_mode_main(
         "Keyboard",
     );
     let mut popup = ui::popup_centered(display.bounding_box());
+    let mut transfer_rx = transfer::Receiver::new();
+    // 定期刷新 RSSI / 已连接主机数;状态有变化才会推送。
+    let mut status_tick = tokio::time::interval(std::time::Duration::from_secs(5));
+    // 文本动作里 `{DELAY:n}` 之后的部分;离开本函数时随之取消
+    let mut playback = key_template::Playback::new();
     loop {
         let event = tokio::select! {
             // Handle setting events (e.g., reset)
-            Some(bt_wifi_mode::BTevent::Reset) = setting_rx.recv() => {
-                handle_reset_event(setting_arc);
+            Some(evt) = setting_rx.recv() => {
+                match evt {
+                    bt_wifi_mode::BTevent::Reset => handle_reset_event(setting_arc),
+                    bt_wifi_mode::BTevent::ConfigResult(error) => {
+                        controller.notify_config_result(error.as_deref());
+                    }
+                }
+                continue;
+            }
+            steps = playback.next() => {
+                if let Err(e) = type_template_steps(keyboard, &steps) {
+                    log::error!("Failed to type template: {:?}", e);
+                    controller.update_status(|s| s.last_error = Some(format!("template: {}", e)));
+                }
+                continue;
+            }
+            _ = status_tick.tick() => {
+                sync_stale_keymap(input, controller, setting_arc, keymap);
+                let connected = ble_device.get_server().connected_count();
+                let slot = bt_keyboard_mode::HOST_SLOT.load(std::sync::atomic::Ordering::Relaxed);
+                let link = wifi::link_state();
+                controller.update_status(|s| {
+                    s.ble.connected = connected;
+                    s.ble.slot = slot + 1;
+                    s.wifi.connected = link.connected;
+                    s.wifi.ssid = link.ssid;
+                    s.wifi.rssi = if link.connected { wifi::sta_rssi() } else { None };
+                });
+                continue;
             }
             // Handle physical key events
-            key_evt = bt_keyboard_mode::wait_key_event(key_pins) => key_evt,
+            Some(key_evt) = input.next() => match key_command(key_evt) {
+                Some(cmd) => cmd,
+                None => continue,
+            },
             // Handle controller commands from BLE
             Some(evt) = rx.recv() => {
                 match evt {
                     bt_keyboard_mode::ControllerCommand::KeymapConfig(config) => {
-                        handle_keymap_config(
-                            config,
-                            &mut setting_arc.lock().unwrap().1,
-                            keymap,
-                        );
-                        let _ = ui::render_keyboard_view(display, false, false, "keymap updated!");
+                        apply_keymap_config(display, input, controller, setting_arc, keymap, config);
+                        continue;
+                    }
+                    bt_keyboard_mode::ControllerCommand::Transfer(frame) => {
+                        match transfer::ExportRequest::parse(&frame) {
+                            Some(Ok(req)) => {
+                                if let Err(e) =
+                                    send_export(ble_device, controller, setting_arc, keymap, req)
+                                        .await
+                                {
+                                    log::error!("Export failed: {:?}", e);
+                                }
+                                continue;
+                            }
+                            Some(Err(reason)) => {
+                                controller.notify_transfer(&transfer::Reply::Error { reason });
+                                continue;
+                            }
+                            None => {}
+                        }
+                        let (mut reply, completed) = transfer_rx.handle(&frame);
+                        let mut restart = false;
+                        if let Some(done) = completed {
+                            match handle_transfer(
+                                display, input, controller, setting_arc, keymap, done,
+                            ) {
+                                Ok(r) => restart = r,
+                                Err(e) => {
+                                    log::error!("Transfer rejected: {:?}", e);
+                                    controller.update_status(|s| {
+                                        s.last_error = Some(format!("transfer: {}", e))
+                                    });
+                                    reply = transfer::Reply::Error {
+                                        reason: transfer::TransferError::Rejected,
+                                    };
+                                }
+                            }
+                        }
+                        controller.notify_transfer(&reply);
+                        if restart {
+                            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                            esp_idf_svc::hal::reset::restart();
+                        }
                         continue;
                     }
                     controller_evt => controller_evt,
@@ -746,6 +1299,49 @@ async fn keyboard_mode_main(
 
         // 每轮事件先关闭上一轮的弹窗(增量 restore),再处理新事件
         let _ = popup.hide(display);
+        // 按键要按最新的 keymap 执行,不等下一次 status_tick
+        sync_stale_keymap(input, controller, setting_arc, keymap);
+
+        // 切换模式(type "mode")和 BLE 主机槽位(type "host")的按键/和弦:要离开本循环
+        // 或断开当前主机并落盘,故在这里处理,不进 handle_key_event。
+        let bound_action = match &event {
+            bt_keyboard_mode::ControllerCommand::KeyboardPress(pin) => {
+                keymap.action_for(bt_keyboard_mode::KeymapConfig::get_key_name(*pin))
+            }
+            bt_keyboard_mode::ControllerCommand::KeyboardChord(a, b) => keymap.chord_action(*a, *b),
+            _ => None,
+        };
+        if let Some(bt_keyboard_mode::KeyAction::Mode { mode: target, .. }) = bound_action {
+            if let Some(next) = mode::RunMode::Keyboard.switch_target(target) {
+                keyboard.release();
+                return next;
+            }
+            continue;
+        }
+        if let Some(bt_keyboard_mode::KeyAction::Host { slot, .. }) = bound_action {
+            let current = bt_keyboard_mode::HOST_SLOT.load(std::sync::atomic::Ordering::Relaxed);
+            let slot = match slot {
+                Some(s) => s - 1,
+                None => bt_keyboard_mode::next_host_slot(current),
+            };
+            // 没打完的模板别打到新主机上
+            playback.cancel();
+            keyboard.release();
+            match bt_keyboard_mode::switch_host_slot(ble_device, slot) {
+                Ok(()) => {
+                    let mut lock = setting_arc.lock().unwrap();
+                    lock.0.host_slot = slot;
+                    if let Err(e) = bt_wifi_mode::Setting::save_host_slot(&mut lock.1, slot) {
+                        log::error!("Failed to save host_slot: {:?}", e);
+                    }
+                }
+                Err(e) => log::error!("Failed to switch to host slot {}: {:?}", slot + 1, e),
+            }
+            controller.update_status(|s| s.ble.slot = slot + 1);
+            let _ =
+                ui::render_keyboard_view(display, wifi_on, false, &format!("Host {}", slot + 1));
+            continue;
+        }
 
         // 内置 ASR(Whisper)只在本设置开启、且驱动与配置都在时才接管 MIC;
         // 否则 MIC 按键透传给主机(默认映射成 Ctrl+Option,触发主机自带听写)。
@@ -760,41 +1356,56 @@ async fn keyboard_mode_main(
                 )
             {
                 // 麦克风模式取自 setting_arc(每次触发都读最新值,setup 改了即时生效)。
-                let mic_mode =
-                    app::key_task::MicMode::from(setting_arc.lock().unwrap().0.mic_model);
+                let mic_mode = app::MicMode::from(setting_arc.lock().unwrap().0.mic_model);
+                controller.update_status(|s| s.asr = status::AsrState::Connecting);
                 match mic_mode {
-                    app::key_task::MicMode::PushToTalk => {
-                        // 按住说话:松手(is_high)停止 —— 现状不变。
-                        match driver.start_asr(
+                    app::MicMode::PushToTalk => {
+                        // 按住说话:输入服务报告松手即停止。
+                        let r = driver.start_asr(
                             asr_config,
                             || {
                                 let _ = popup.show(display, "recording...");
+                                controller.update_status(|s| s.asr = status::AsrState::Recording);
                             },
-                            || key_pins.mic.is_high(),
-                        ) {
+                            || {
+                                let stop = !input.is_held(bt_keyboard_mode::KeysPin::MIC);
+                                if stop {
+                                    controller
+                                        .update_status(|s| s.asr = status::AsrState::Uploading);
+                                }
+                                stop
+                            },
+                        );
+                        match r {
                             Ok(asr) => {
                                 let _ = popup.show(display, &asr);
                                 controller.notify_asr(&asr);
+                                controller.update_status(|s| s.asr = status::AsrState::Idle);
                             }
                             Err(e) => {
                                 log::error!("ASR error: {:?}", e);
                                 let _ = popup.show(display, "ASR error");
+                                controller.update_status(|s| {
+                                    s.asr = status::AsrState::Error;
+                                    s.last_error = Some(format!("ASR: {}", e));
+                                });
                             }
                         }
                     }
-                    app::key_task::MicMode::Toggle => {
+                    app::MicMode::Toggle => {
                         // 按一下开始、再按一下停止。start_asr 同步阻塞本事件循环,第二次
-                        // 按下无法作为 KeyboardPress 事件到达,只能在 is_stop 里轮询引脚电平
+                        // 按下无法作为 KeyboardPress 事件到达,只能在 is_stop 里轮询按键状态
                         // 做状态机:state 0 = 等首按松开;state 1 = 等第二次按下 → 返回 true 停止。
                         // is_stop 是 FnMut,可直接捕获可变 state,不必用原子。
                         let mut state: u8 = 0;
-                        match driver.start_asr(
+                        let r = driver.start_asr(
                             asr_config,
                             || {
                                 let _ = popup.show(display, "recording...");
+                                controller.update_status(|s| s.asr = status::AsrState::Recording);
                             },
                             || {
-                                if key_pins.mic.is_low() {
+                                let stop = if input.is_held(bt_keyboard_mode::KeysPin::MIC) {
                                     // 已松开过(state 1)之后的按下即第二次 → 停止
                                     state == 1
                                 } else {
@@ -802,20 +1413,33 @@ async fn keyboard_mode_main(
                                         state = 1;
                                     }
                                     false
+                                };
+                                if stop {
+                                    controller
+                                        .update_status(|s| s.asr = status::AsrState::Uploading);
                                 }
+                                stop
                             },
-                        ) {
+                        );
+                        match r {
                             Ok(asr) => {
                                 let _ = popup.show(display, &asr);
                                 controller.notify_asr(&asr);
+                                controller.update_status(|s| s.asr = status::AsrState::Idle);
                             }
                             Err(e) => {
                                 log::error!("ASR error: {:?}", e);
                                 let _ = popup.show(display, "ASR error");
+                                controller.update_status(|s| {
+                                    s.asr = status::AsrState::Error;
+                                    s.last_error = Some(format!("ASR: {}", e));
+                                });
                             }
                         }
                     }
                 }
+                // 录音期间排队的 MIC 按下/松开已经用过了,别让它再触发一次
+                input.flush();
                 continue;
             }
         }
@@ -830,16 +1454,176 @@ async fn keyboard_mode_main(
             _ => {}
         }
 
-        let _ = handle_key_event(
-            display, ble_device, keyboard, event, keymap, key_pins, wifi_on,
+        if let Err(e) = handle_key_event(
+            display,
+            ble_device,
+            keyboard,
+            &mut playback,
+            event,
+            keymap,
+            wifi_on,
         )
-        .await;
+        .await
+        {
+            log::error!("Key event failed: {:?}", e);
+            controller.update_status(|s| s.last_error = Some(format!("key: {}", e)));
+        }
+    }
+}
+
+/// 混合模式里 BLE 主机那一侧:路由过来的按键照键盘模式发 HID 报告。`asr` 为 Some
+/// (开启了内置 ASR)时 MIC 交给远程模式的 asr-worker 识别,结果打到主机上;否则照 keymap
+/// 透传(默认触发主机自带听写)。屏幕归远程会话,所以主机发来的显示内容不处理;
+/// keymap 和传输要改正在用的 keymap,留给键盘模式。`keys` 关闭(app::run 返回)即退出。
+async fn hybrid_ble_main(
+    kb: &mut KeyboardBle,
+    setting_arc: &Arc<Mutex<(bt_wifi_mode::Setting, esp_idf_svc::nvs::EspDefaultNvs)>>,
+    keymap: &bt_keyboard_mode::KeymapConfig,
+    mut keys: tokio::sync::mpsc::UnboundedReceiver<input::InputEvent>,
+    asr: Option<(
+        audio::AsrConfig,
+        app::MicMode,
+        std::sync::mpsc::Sender<audio::AsrRequest>,
+    )>,
+) {
+    use bt_keyboard_mode::ControllerCommand;
+    let mut playback = key_template::Playback::new();
+    loop {
+        let cmd = tokio::select! {
+            Some(evt) = kb.setting_rx.recv() => {
+                match evt {
+                    bt_wifi_mode::BTevent::Reset => handle_reset_event(setting_arc),
+                    bt_wifi_mode::BTevent::ConfigResult(error) => {
+                        kb.controller.notify_config_result(error.as_deref());
+                    }
+                }
+                continue;
+            }
+            steps = playback.next() => {
+                if let Err(e) = type_template_steps(&mut kb.keyboard, &steps) {
+                    log::error!("Failed to type template: {:?}", e);
+                    kb.controller
+                        .update_status(|s| s.last_error = Some(format!("template: {}", e)));
+                }
+                continue;
+            }
+            Some(cmd) = kb.rx.recv() => match cmd {
+                ControllerCommand::Paste(_) => cmd,
+                ControllerCommand::Transfer(_) => {
+                    kb.controller.notify_transfer(&transfer::Reply::Error {
+                        reason: transfer::TransferError::Rejected,
+                    });
+                    continue;
+                }
+                ControllerCommand::KeymapConfig(_) => {
+                    log::warn!("Keymap writes are applied in keyboard mode only");
+                    kb.controller
+                        .notify_keymap_result(&[bt_keyboard_mode::KeymapError::wrong_mode("hybrid")]);
+                    continue;
+                }
+                _ => continue,
+            },
+            evt = keys.recv() => match (evt, asr.as_ref()) {
+                (None, _) => return,
+                (
+                    Some(input::InputEvent::Press(bt_keyboard_mode::KeysPin::MIC)),
+                    Some((config, mic_mode, asr_tx)),
+                ) => {
+                    match hybrid_asr(&mut keys, config, *mic_mode, asr_tx).await {
+                        Ok(text) if !text.trim().is_empty() => kb.keyboard.write(text.trim()),
+                        Ok(_) => log::info!("Hybrid ASR returned empty"),
+                        Err(e) => {
+                            log::error!("Hybrid ASR error: {:?}", e);
+                            kb.controller
+                                .update_status(|s| s.last_error = Some(format!("ASR: {}", e)));
+                        }
+                    }
+                    continue;
+                }
+                (Some(evt), _) => match key_command(evt) {
+                    Some(cmd) => cmd,
+                    None => continue,
+                },
+            },
+        };
+        if let Err(e) =
+            send_key_command(kb.ble_device, &mut kb.keyboard, &mut playback, cmd, keymap).await
+        {
+            log::error!("Failed to send key to BLE host: {:?}", e);
+            kb.controller
+                .update_status(|s| s.last_error = Some(format!("key: {}", e)));
+        }
+    }
+}
+
+/// 混合模式的一次内置 ASR:请求交给 asr-worker,按麦克风模式等停止(PTT 松开 MIC,
+/// Toggle 再按一下 MIC);录音期间路由到 BLE 的其它按键丢弃。
+async fn hybrid_asr(
+    keys: &mut tokio::sync::mpsc::UnboundedReceiver<input::InputEvent>,
+    config: &audio::AsrConfig,
+    mic_mode: app::MicMode,
+    asr_tx: &std::sync::mpsc::Sender<audio::AsrRequest>,
+) -> anyhow::Result<String> {
+    use bt_keyboard_mode::KeysPin;
+    use input::InputEvent;
+
+    let (respond, result) = tokio::sync::oneshot::channel();
+    // 没有弹窗要切换,连上 server 的信号不用等
+    let (connected_tx, _) = tokio::sync::oneshot::channel();
+    let cancel = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    asr_tx
+        .send(audio::AsrRequest {
+            config: config.clone(),
+            cancel: cancel.clone(),
+            respond,
+            connected_tx,
+        })
+        .map_err(|_| anyhow::anyhow!("ASR worker unavailable"))?;
+
+    tokio::pin!(result);
+    let mut stopped = false;
+    loop {
+        tokio::select! {
+            r = &mut result => {
+                return r.unwrap_or_else(|_| Err(anyhow::anyhow!("ASR worker dropped request")));
+            }
+            evt = keys.recv(), if !stopped => {
+                let stop = match (mic_mode, evt) {
+                    (_, None) => true,
+                    (app::MicMode::PushToTalk, Some(InputEvent::Release(KeysPin::MIC))) => true,
+                    (app::MicMode::Toggle, Some(InputEvent::Press(KeysPin::MIC))) => true,
+                    _ => false,
+                };
+                if stop {
+                    stopped = true;
+                    cancel.store(true, std::sync::atomic::Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+/// Map an input event to the controller command it triggers in keyboard mode.
+/// Long-press has no HID meaning here yet.
+fn key_command(event: input::InputEvent) -> Option<bt_keyboard_mode::ControllerCommand> {
+    use bt_keyboard_mode::ControllerCommand;
+    use input::InputEvent;
+    match event {
+        InputEvent::Press(pin) => Some(ControllerCommand::KeyboardPress(pin)),
+        InputEvent::Release(pin) => Some(ControllerCommand::KeyboardRelease(pin)),
+        InputEvent::Chord(a, b) => Some(ControllerCommand::KeyboardChord(a, b)),
+        InputEvent::ChordRelease(a, b) => Some(ControllerCommand::KeyboardChordRelease(a, b)),
+        InputEvent::RotateUp => Some(ControllerCommand::RotateUp),
+        InputEvent::RotateDown => Some(ControllerCommand::RotateDown),
+        InputEvent::Repeat(pin) => Some(ControllerCommand::KeyboardRepeat(pin)),
+        InputEvent::LongPress(_) => None,
     }
 }
 
 // Execute key action based on keymap configuration
 fn execute_key_action(
     keyboard: &mut bt_keyboard_mode::KeyboardAndMouse,
+    playback: &mut key_template::Playback,
     action: &bt_keyboard_mode::KeyAction,
     is_press: bool,
 ) -> anyhow::Result<()> {
@@ -852,13 +1636,7 @@ fn execute_key_action(
                 let mut modifier_mask = 0u8;
 
                 for mod_name in modifiers {
-                    match mod_name.as_str() {
-                        "ctrl" => modifier_mask |= 0x01,
-                        "shift" => modifier_mask |= 0x02,
-                        "alt" | "option" => modifier_mask |= 0x04,
-                        "meta" | "command" | "cmd" | "win" | "gui" => modifier_mask |= 0x08,
-                        _ => {}
-                    }
+                    modifier_mask |= bt_keyboard_mode::modifier_name_to_mask(mod_name).unwrap_or(0);
                 }
 
                 // Convert key name to HID code (may include modifier bit for modifier keys)
@@ -868,26 +1646,89 @@ fn execute_key_action(
                 keyboard.release();
             }
         }
-        KeyAction::Text { value, .. } => {
+        KeyAction::Text { steps, .. } => {
             if is_press {
-                keyboard.write(value);
+                // 第一个 `{DELAY:n}` 之前的部分立即打,其余由 playback 在后台等,
+                // 到点后经 `Playback::next` 交回事件循环再打
+                let now = playback.start(steps);
+                type_template_steps(keyboard, &now)?;
             }
         }
+        // Host slot and mode switching are handled in keyboard_mode_main
+        KeyAction::Host { .. } | KeyAction::Mode { .. } => {}
     }
 
     Ok(())
 }
 
+/// Template steps (see key_template): text, single keys, date/time. Delays have already
+/// been split off by `key_template::Playback`. 时钟还没同步时跳过 date/time,打完其余部分
+/// 后返回错误,由调用方报到 status 的 last_error。
+fn type_template_steps(
+    keyboard: &mut bt_keyboard_mode::KeyboardAndMouse,
+    steps: &[key_template::TemplateStep],
+) -> anyhow::Result<()> {
+    for step in steps {
+        match step {
+            key_template::TemplateStep::Key { modifiers, key } => {
+                let (key_code, key_modifier) = bt_keyboard_mode::key_name_to_hid_code(key)?;
+                keyboard.press_raw(key_code, modifiers | key_modifier);
+                keyboard.release();
+            }
+            step => {
+                if let Some(text) = step.text() {
+                    keyboard.write(&text);
+                }
+            }
+        }
+    }
+    if key_template::skips_clock_tokens(steps) {
+        anyhow::bail!("{{DATE}}/{{TIME}} skipped: clock not synced");
+    }
+    Ok(())
+}
+
 pub async fn handle_key_event(
     display: &mut lcd::FrameBuffer,
     ble_device: &mut esp32_nimble::BLEDevice,
     keyboard: &mut bt_keyboard_mode::KeyboardAndMouse,
+    playback: &mut key_template::Playback,
     event: bt_keyboard_mode::ControllerCommand,
     keymap: &bt_keyboard_mode::KeymapConfig,
-    key_pins: &mut bt_keyboard_mode::KeysPin,
     wifi_on: bool,
 ) -> anyhow::Result<()> {
     log::info!("Handling controller command: {:?}", event);
+    match event {
+        bt_keyboard_mode::ControllerCommand::DisplayKeyboard(text) => {
+            // 纯文本照旧显示;JSON 对象按布局渲染(标题/彩色行/进度条/按键说明)。
+            let _ = match widget::DisplayContent::parse(&text) {
+                widget::DisplayContent::Text(text) => {
+                    ui::render_keyboard_view(display, wifi_on, true, &text)
+                }
+                widget::DisplayContent::Layout(layout) => {
+                    ui::render_widget_view(display, wifi_on, true, &layout)
+                }
+            };
+        }
+        bt_keyboard_mode::ControllerCommand::KeymapConfig(_)
+        | bt_keyboard_mode::ControllerCommand::Transfer(_) => {
+            // KeymapConfig / Transfer are handled separately in keyboard_mode_main
+        }
+        event => send_key_command(ble_device, keyboard, playback, event, keymap).await?,
+    }
+
+    Ok(())
+}
+
+/// Key, rotary and paste commands: everything that only talks to the BLE host.
+/// Hybrid mode uses this directly, since the screen belongs to the remote session there.
+async fn send_key_command(
+    ble_device: &mut esp32_nimble::BLEDevice,
+    keyboard: &mut bt_keyboard_mode::KeyboardAndMouse,
+    playback: &mut key_template::Playback,
+    event: bt_keyboard_mode::ControllerCommand,
+    keymap: &bt_keyboard_mode::KeymapConfig,
+) -> anyhow::Result<()> {
     use bt_keyboard_mode::KeysPin;
     match event {
         bt_keyboard_mode::ControllerCommand::Paste(p) => {
@@ -899,9 +1740,6 @@ pub async fn handle_key_event(
                 keyboard.release();
             }
         }
-        bt_keyboard_mode::ControllerCommand::DisplayKeyboard(text) => {
-            let _ = ui::render_keyboard_view(display, wifi_on, true, &text);
-        }
         bt_keyboard_mode::ControllerCommand::KeyboardPress(pin_index) => {
             if pin_index == KeysPin::ACCEPT {
                 log::info!("Accept button pressed, starting advertising");
@@ -911,35 +1749,44 @@ pub async fn handle_key_event(
                 }
             }
 
+            // Custom binding, or the built-in default (see KeymapConfig::default_action)
             let key_name = bt_keyboard_mode::KeymapConfig::get_key_name(pin_index);
-            if let Some(action) = keymap.keys.get(key_name) {
-                log::info!("Executing custom keymap for {}: {:?}", key_name, action);
-                let _ = execute_key_action(keyboard, action, true);
-            } else {
-                // Default behavior
-                match pin_index {
-                    KeysPin::MIC => keyboard.press_raw(0xE2, 0x01 | 0x04), // Ctrl + Option
-                    KeysPin::CUSTOM => keyboard.write("/compact\n"),
-                    KeysPin::ESC => keyboard.press_raw(0x29, 0),
-                    KeysPin::NEXT => keyboard.press_raw(0x51, 0), // Down arrow
-                    KeysPin::SWITCH => keyboard.shift_press(b'\t'),
-                    KeysPin::BACKSPACE => keyboard.press_raw(0x2a, 0),
-                    KeysPin::ACCEPT => {
-                        keyboard.press(b'\n');
-                    }
-                    KeysPin::ROTATE_BUTTON => keyboard.write("/"),
-                    _ => {}
+            if let Some(action) = keymap.action_for(key_name) {
+                log::info!("Executing keymap for {}: {:?}", key_name, action);
+                let _ = execute_key_action(keyboard, playback, &action, true);
+            }
+        }
+        bt_keyboard_mode::ControllerCommand::KeyboardRepeat(pin_index) => {
+            // 连发:先松开再按下,主机才会把同一份报告当成新的一次按键;
+            // 文本动作本身就是按下即松开,直接再打一遍。
+            let key_name = bt_keyboard_mode::KeymapConfig::get_key_name(pin_index);
+            if let Some(action) = keymap.action_for(key_name) {
+                if matches!(action, bt_keyboard_mode::KeyAction::Combo { .. }) {
+                    keyboard.release();
                 }
+                let _ = execute_key_action(keyboard, playback, &action, true);
+            }
+        }
+        bt_keyboard_mode::ControllerCommand::KeyboardChord(a, b) => {
+            if let Some(action) = keymap.chord_action(a, b) {
+                let chord_name = bt_keyboard_mode::KeymapConfig::chord_name(a, b);
+                log::info!("Executing keymap for {}: {:?}", chord_name, action);
+                let _ = execute_key_action(keyboard, playback, &action, true);
+            }
+        }
+        bt_keyboard_mode::ControllerCommand::KeyboardChordRelease(a, b) => {
+            match keymap.chord_action(a, b) {
+                Some(action) => {
+                    let _ = execute_key_action(keyboard, playback, &action, false);
+                }
+                None => keyboard.release(),
             }
-
-            key_pins.wait_for_high(pin_index).await?;
-            keyboard.release();
         }
         bt_keyboard_mode::ControllerCommand::KeyboardRelease(pin_index) => {
             let key_name = bt_keyboard_mode::KeymapConfig::get_key_name(pin_index);
             if let Some(action) = keymap.keys.get(key_name) {
                 log::info!("Releasing custom keymap for {}: {:?}", key_name, action);
-                let _ = execute_key_action(keyboard, action, false);
+                let _ = execute_key_action(keyboard, playback, action, false);
             } else {
                 keyboard.release();
             }
@@ -950,9 +1797,7 @@ pub async fn handle_key_event(
         bt_keyboard_mode::ControllerCommand::RotateUp => {
             keyboard.mouse_move(0, 0, 1, 0); // Wheel up
         }
-        bt_keyboard_mode::ControllerCommand::KeymapConfig(_) => {
-            // KeymapConfig is handled separately in keyboard_mode_main
-        }
+        _ => {}
     }
 
     Ok(())
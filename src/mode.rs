@@ -0,0 +1,154 @@
+//! Top-level modes and switching between them without a reboot.
+//!
+//! A key or chord bound to `{"type":"mode"}` leaves the running mode; `main` then hands the
+//! display, input, WiFi and audio driver to the next one. `{"type":"mode","mode":"remote"}`
+//! names the target; without it Keyboard and Remote swap and Hybrid goes back to Keyboard.
+//!
+//! Hybrid mode shows the remote session while the BLE keyboard stays connected. Each key
+//! goes to one side, chosen by the keymap's top-level `route` object (see [`route`]).
+
+use serde::{Deserialize, Serialize};
+
+use crate::bt_keyboard_mode::{KeyAction, KeymapConfig};
+use crate::input::InputEvent;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunMode {
+    /// BLE HID keyboard with built-in ASR
+    Keyboard,
+    /// MQTT remote terminal
+    Remote,
+    /// Remote terminal on screen, keys split between the session and the BLE host
+    Hybrid,
+}
+
+impl RunMode {
+    /// Mode a `mode` key action pressed in `self` leads to; `None` when it names the mode
+    /// already running.
+    pub fn switch_target(self, target: Option<RunMode>) -> Option<RunMode> {
+        let next = target.unwrap_or(match self {
+            RunMode::Keyboard => RunMode::Remote,
+            RunMode::Remote | RunMode::Hybrid => RunMode::Keyboard,
+        });
+        (next != self).then_some(next)
+    }
+
+    /// Whether the mode keeps the BLE keyboard advertising and connected
+    pub fn uses_ble(self) -> bool {
+        matches!(self, RunMode::Keyboard | RunMode::Hybrid)
+    }
+}
+
+/// Side a key is handled by in hybrid mode, e.g. `{"route":{"NEXT":"remote"}}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyRoute {
+    /// Sent to the BLE host as in keyboard mode
+    Ble,
+    /// Handled by the remote session as in remote mode
+    Remote,
+}
+
+impl KeyRoute {
+    /// Built-in route of a key the keymap does not route: the rotary (scrolling and the
+    /// session picker) drives the session, every other key types on the BLE host
+    pub fn default_for(key_name: &str) -> Self {
+        match key_name {
+            KeymapConfig::KEY_ROTATE => KeyRoute::Remote,
+            _ => KeyRoute::Ble,
+        }
+    }
+}
+
+/// Side an input event goes to in hybrid mode.
+///
+/// Keys and chords bound to a `mode` action always go to the session, which handles mode
+/// switches. Turning the rotary follows `ROTATE`. A chord goes to the session only when
+/// both of its keys do.
+pub fn route(keymap: &KeymapConfig, event: &InputEvent) -> KeyRoute {
+    let key = |pin: u8| {
+        let name = KeymapConfig::get_key_name(pin);
+        if matches!(keymap.action_for(name), Some(KeyAction::Mode { .. })) {
+            KeyRoute::Remote
+        } else {
+            keymap.route_for(name)
+        }
+    };
+    match *event {
+        InputEvent::Press(pin)
+        | InputEvent::Release(pin)
+        | InputEvent::Repeat(pin)
+        | InputEvent::LongPress(pin) => key(pin),
+        InputEvent::Chord(a, b) | InputEvent::ChordRelease(a, b) => {
+            if matches!(keymap.chord_action(a, b), Some(KeyAction::Mode { .. })) {
+                return KeyRoute::Remote;
+            }
+            let (a, b) = (KeymapConfig::get_key_name(a), KeymapConfig::get_key_name(b));
+            if keymap.route_for(a) == KeyRoute::Remote && keymap.route_for(b) == KeyRoute::Remote {
+                KeyRoute::Remote
+            } else {
+                KeyRoute::Ble
+            }
+        }
+        InputEvent::RotateUp | InputEvent::RotateDown => keymap.route_for(KeymapConfig::KEY_ROTATE),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bt_keyboard_mode::KeysPin;
+
+    #[test]
+    fn switch_targets() {
+        assert_eq!(RunMode::Keyboard.switch_target(None), Some(RunMode::Remote));
+        assert_eq!(RunMode::Remote.switch_target(None), Some(RunMode::Keyboard));
+        assert_eq!(RunMode::Hybrid.switch_target(None), Some(RunMode::Keyboard));
+        assert_eq!(
+            RunMode::Keyboard.switch_target(Some(RunMode::Remote)),
+            Some(RunMode::Remote)
+        );
+        assert_eq!(
+            RunMode::Remote.switch_target(Some(RunMode::Hybrid)),
+            Some(RunMode::Hybrid)
+        );
+        assert_eq!(RunMode::Remote.switch_target(Some(RunMode::Remote)), None);
+    }
+
+    #[test]
+    fn routes() {
+        let keymap = KeymapConfig::from_json(
+            r#"{"route":{"NEXT":"remote","ACCEPT":"remote","ROTATE":"ble"},
+                "ESC+SWITCH":{"type":"mode","raw":"mode"},
+                "SWITCH":{"type":"mode","raw":"mode","mode":"keyboard"}}"#,
+        )
+        .unwrap();
+        let r = |e: InputEvent| route(&keymap, &e);
+        assert_eq!(r(InputEvent::Press(KeysPin::NEXT)), KeyRoute::Remote);
+        assert_eq!(r(InputEvent::Release(KeysPin::NEXT)), KeyRoute::Remote);
+        assert_eq!(r(InputEvent::Press(KeysPin::MIC)), KeyRoute::Ble);
+        assert_eq!(r(InputEvent::RotateUp), KeyRoute::Ble);
+        // mode bindings stay with the session whatever the route says
+        assert_eq!(r(InputEvent::Press(KeysPin::SWITCH)), KeyRoute::Remote);
+        assert_eq!(
+            r(InputEvent::Chord(KeysPin::ESC, KeysPin::SWITCH)),
+            KeyRoute::Remote
+        );
+        assert_eq!(
+            r(InputEvent::Chord(KeysPin::NEXT, KeysPin::ACCEPT)),
+            KeyRoute::Remote
+        );
+        assert_eq!(
+            r(InputEvent::ChordRelease(KeysPin::MIC, KeysPin::NEXT)),
+            KeyRoute::Ble
+        );
+
+        let defaults = KeymapConfig::default();
+        assert_eq!(route(&defaults, &InputEvent::RotateDown), KeyRoute::Remote);
+        assert_eq!(
+            route(&defaults, &InputEvent::Press(KeysPin::ACCEPT)),
+            KeyRoute::Ble
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! Versioned layout of the `setting` NVS namespace.
+//!
+//! The namespace stores its layout version under [`SCHEMA_KEY`]. On boot, [`migrate`] runs every
+//! step between the stored version and [`SCHEMA_VERSION`] before `Setting::load_from_nvs` reads
+//! anything, so a new firmware picks up what an older one saved instead of dropping it.
+//!
+//! | version | layout |
+//! |---------|--------|
+//! | 0 | no version key; WiFi as single `ssid` / `pass` strings (0.3.x) |
+//! | 1 | WiFi as the `wifi_list` JSON array |
+//!
+//! Firmware up to 0.4.0 wrote no version either, so version 0 may already hold a later layout;
+//! every step only acts on the keys it finds and is a no-op otherwise. The version is written
+//! after each step, so a chain cut short by a reset resumes where it stopped.
+//!
+//! Steps go through [`KvStore`], implemented for `EspDefaultNvs` in `bt_wifi_mode` and for a
+//! `HashMap` in the tests below.
+
+use crate::bt_wifi_mode::{WifiCred, WIFI_LIST_KEY};
+
+/// NVS key holding the layout version (u8)
+pub const SCHEMA_KEY: &str = "schema_ver";
+/// Layout written by this firmware
+pub const SCHEMA_VERSION: u8 = 1;
+
+/// The subset of NVS the migrations need
+pub trait KvStore {
+    fn get_u8(&self, key: &str) -> anyhow::Result<Option<u8>>;
+    fn set_u8(&mut self, key: &str, value: u8) -> anyhow::Result<()>;
+    fn get_string(&self, key: &str) -> anyhow::Result<Option<String>>;
+    fn set_string(&mut self, key: &str, value: &str) -> anyhow::Result<()>;
+    fn remove(&mut self, key: &str) -> anyhow::Result<()>;
+}
+
+type Step = fn(&mut dyn KvStore) -> anyhow::Result<()>;
+
+/// `STEPS[n]` upgrades version `n` to `n + 1`
+const STEPS: [Step; SCHEMA_VERSION as usize] = [wifi_list_from_single_cred];
+
+/// Bring the store up to [`SCHEMA_VERSION`]. Returns the version found before migrating.
+///
+/// A store written by a newer firmware is left untouched; its unknown keys are ignored by
+/// `Setting::load_from_nvs`.
+pub fn migrate(kv: &mut dyn KvStore) -> anyhow::Result<u8> {
+    let found = kv.get_u8(SCHEMA_KEY)?.unwrap_or(0);
+    if found > SCHEMA_VERSION {
+        log::warn!(
+            "NVS schema {} is newer than this firmware ({}), not migrating",
+            found,
+            SCHEMA_VERSION
+        );
+        return Ok(found);
+    }
+    for version in found..SCHEMA_VERSION {
+        log::info!("Migrating NVS schema {} -> {}", version, version + 1);
+        STEPS[version as usize](kv)?;
+        kv.set_u8(SCHEMA_KEY, version + 1)?;
+    }
+    Ok(found)
+}
+
+/// 0 -> 1: the single `ssid` / `pass` pair becomes the first `wifi_list` entry. An existing
+/// `wifi_list` wins; the old keys are removed either way.
+fn wifi_list_from_single_cred(kv: &mut dyn KvStore) -> anyhow::Result<()> {
+    let ssid = kv.get_string("ssid")?.unwrap_or_default();
+    if !ssid.is_empty() && kv.get_string(WIFI_LIST_KEY)?.is_none() {
+        let pass = kv.get_string("pass")?.unwrap_or_default();
+        let list = [WifiCred {
+            ssid,
+            pass,
+            ..Default::default()
+        }];
+        kv.set_string(WIFI_LIST_KEY, &serde_json::to_string(&list)?)?;
+    }
+    kv.remove("ssid")?;
+    kv.remove("pass")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Value {
+        U8(u8),
+        Str(String),
+    }
+
+    /// NVS stand-in; like NVS, reading a key with the wrong type finds nothing
+    #[derive(Default)]
+    struct MemStore(HashMap<String, Value>);
+
+    impl KvStore for MemStore {
+        fn get_u8(&self, key: &str) -> anyhow::Result<Option<u8>> {
+            Ok(match self.0.get(key) {
+                Some(Value::U8(v)) => Some(*v),
+                _ => None,
+            })
+        }
+        fn set_u8(&mut self, key: &str, value: u8) -> anyhow::Result<()> {
+            self.0.insert(key.into(), Value::U8(value));
+            Ok(())
+        }
+        fn get_string(&self, key: &str) -> anyhow::Result<Option<String>> {
+            Ok(match self.0.get(key) {
+                Some(Value::Str(v)) => Some(v.clone()),
+                _ => None,
+            })
+        }
+        fn set_string(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+            self.0.insert(key.into(), Value::Str(value.into()));
+            Ok(())
+        }
+        fn remove(&mut self, key: &str) -> anyhow::Result<()> {
+            self.0.remove(key);
+            Ok(())
+        }
+    }
+
+    fn store(entries: &[(&str, Value)]) -> MemStore {
+        MemStore(
+            entries
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect(),
+        )
+    }
+
+    fn wifi_list(kv: &MemStore) -> Vec<(String, String)> {
+        let json = kv.get_string(WIFI_LIST_KEY).unwrap().unwrap();
+        serde_json::from_str::<Vec<WifiCred>>(&json)
+            .unwrap()
+            .into_iter()
+            .map(|c| (c.ssid, c.pass))
+            .collect()
+    }
+
+    #[test]
+    fn migrates_v0_layout() {
+        let mut kv = store(&[
+            ("ssid", Value::Str("home".into())),
+            ("pass", Value::Str("secret".into())),
+            ("server_url", Value::Str("mqtt://broker".into())),
+        ]);
+        assert_eq!(migrate(&mut kv).unwrap(), 0);
+        assert_eq!(wifi_list(&kv), vec![("home".into(), "secret".into())]);
+        assert_eq!(kv.get_u8(SCHEMA_KEY).unwrap(), Some(SCHEMA_VERSION));
+        for old in ["ssid", "pass"] {
+            assert!(!kv.0.contains_key(old), "{} left behind", old);
+        }
+        assert_eq!(
+            kv.get_string("server_url").unwrap().as_deref(),
+            Some("mqtt://broker")
+        );
+    }
+
+    #[test]
+    fn unversioned_current_layout_is_kept() {
+        let list = r#"[{"ssid":"office","pass":"x"}]"#;
+        let mut kv = store(&[
+            (WIFI_LIST_KEY, Value::Str(list.into())),
+            ("ssid", Value::Str("stale".into())),
+            ("prefer_asr", Value::U8(1)),
+        ]);
+        migrate(&mut kv).unwrap();
+        assert_eq!(wifi_list(&kv), vec![("office".into(), "x".into())]);
+        assert_eq!(kv.get_u8("prefer_asr").unwrap(), Some(1));
+        assert!(!kv.0.contains_key("ssid"));
+    }
+
+    #[test]
+    fn runs_only_missing_steps() {
+        let mut empty = MemStore::default();
+        migrate(&mut empty).unwrap();
+        assert_eq!(empty.0, store(&[(SCHEMA_KEY, Value::U8(SCHEMA_VERSION))]).0);
+
+        // Version 1 skips the WiFi step: a leftover ssid is not picked up
+        let mut kv = store(&[
+            (SCHEMA_KEY, Value::U8(1)),
+            ("ssid", Value::Str("home".into())),
+        ]);
+        assert_eq!(migrate(&mut kv).unwrap(), 1);
+        assert!(kv.get_string(WIFI_LIST_KEY).unwrap().is_none());
+
+        // Migrating again changes nothing
+        let before = kv.0.clone();
+        assert_eq!(migrate(&mut kv).unwrap(), SCHEMA_VERSION);
+        assert_eq!(kv.0, before);
+    }
+
+    #[test]
+    fn newer_schema_is_left_alone() {
+        let mut kv = store(&[
+            (SCHEMA_KEY, Value::U8(SCHEMA_VERSION + 1)),
+            ("ssid", Value::Str("home".into())),
+        ]);
+        assert_eq!(migrate(&mut kv).unwrap(), SCHEMA_VERSION + 1);
+        assert_eq!(kv.get_string("ssid").unwrap().as_deref(), Some("home"));
+        assert_eq!(kv.get_u8(SCHEMA_KEY).unwrap(), Some(SCHEMA_VERSION + 1));
+    }
+}
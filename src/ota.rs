@@ -1,5 +1,6 @@
 //! OTA 模式:主固件设置菜单进入,同进程跑 HTTP server,把新固件写到对面 OTA 分区后重启。
 //! 两种更新来源:浏览器上传(`/ota` PUT)、从 GitHub release 拉最新(`DownloadLatest`)。
+//! 设置了 api_token 时同一个 server 也挂上 REST API(`crate::rest_api`,含配置备份/恢复)。
 //! 复用主固件的 `crate::lcd` / `crate::wifi` / `crate::bt_wifi_mode`,不再像旧版那样
 //! 独立成一个小二进制并复制一份 lcd/wifi 驱动。
 
@@ -11,6 +12,9 @@ use esp_idf_svc::{
     ota::EspOta,
 };
 
+use crate::bt_keyboard_mode::KeysPin;
+use crate::input::InputEvent;
+
 /// 从 GitHub release 拉最新固件的目标 URL。
 ///
 /// 默认指向本项目(second-state/vibekeys_firmware)的 `releases/latest`(稳定版)。CI 构建
@@ -42,19 +46,20 @@ enum OtaEvent {
 pub struct OtaData<'a> {
     pub scan_list: &'a Vec<String>,
     pub setting: &'a crate::bt_wifi_mode::Setting,
+    /// 挂 REST API 时开 NVS 句柄用
+    pub nvs_partition: esp_idf_svc::nvs::EspDefaultNvsPartition,
 }
 
-/// 进入 OTA 模式。复用调用方(main)已建好的 WiFi/显示/按钮。
+/// 进入 OTA 模式。复用调用方(main)已建好的 WiFi/显示/输入服务。
 ///
 /// - 先用 boot 阶段的 `scan_list` 与 `setting.wifi_list` 匹配连 WiFi;
 /// - 起 HTTP server(上传 `/ota`、下载触发 `/ota/download`、页面 `/`);
 /// - `ota_task` 在 worker 线程里写分区;
-/// - 主线程轮询按钮:`accept` 触发 download-latest,`esc` 退出回 boot menu;
+/// - 主线程阻塞等按键事件:`accept` 触发 download-latest,`esc` 退出回 boot menu;
 /// - 任一更新路径完成都在 worker 里 `restart()`;ESC 时干净关闭 server 让 worker 退出后返回。
 pub fn run(
     target: &mut crate::lcd::FrameBuffer,
-    accept_btn: &mut crate::AnyBtn,
-    esc_btn: &mut crate::AnyBtn,
+    input: &mut crate::input::Input,
     wifi: &mut esp_idf_svc::wifi::EspWifi<'static>,
     sysloop: EspSystemEventLoop,
     data: &OtaData,
@@ -78,7 +83,7 @@ pub fn run(
         let r = match crate::bt_wifi_mode::pick_cred(scan_list.as_slice(), &setting.wifi_list) {
             Some(c) => {
                 log::info!("OTA: picked ssid={:?} pass_len={}", c.ssid, c.pass.len());
-                crate::wifi::connect(wifi, &c.ssid, &c.pass, sysloop)
+                crate::wifi::connect(wifi, c, sysloop)
             }
             None => anyhow::Result::<()>::Err(anyhow::anyhow!(
                 "no known network in range (scan {})",
@@ -91,7 +96,11 @@ pub fn run(
     }
     if !wifi.is_connected().unwrap_or(false) {
         crate::lcd::display_text(target, "OTA Mode\n Connect wifi Failed\n ESC to back", 0)?;
-        wait_button_release(esc_btn);
+        input.flush();
+        while !matches!(
+            input.blocking_next(),
+            Some(InputEvent::Press(KeysPin::ESC)) | None
+        ) {}
         return Ok(());
     }
 
@@ -118,7 +127,7 @@ pub fn run(
 
     let (tx, rx) = std::sync::mpsc::channel::<OtaEvent>();
     let screen_tx = tx.clone();
-    let http_server = ota_http_server(tx)?;
+    let http_server = ota_http_server(tx, data.nvs_partition.clone())?;
     let ota_worker = std::thread::Builder::new()
         .name("ota-worker".to_string())
         .stack_size(1024 * 24)
@@ -128,10 +137,11 @@ pub fn run(
             }
         })?;
 
-    // 轮询按钮:accept 触发下载最新;esc 退出回 boot menu。HTTP 上传通路始终在线。
+    // 等按键:accept 触发下载最新;esc 退出回 boot menu。HTTP 上传通路始终在线。
+    input.flush();
     loop {
-        if accept_btn.is_low() {
-            wait_button_release(accept_btn);
+        let evt = input.blocking_next();
+        if evt == Some(InputEvent::Press(KeysPin::ACCEPT)) {
             log::info!("OTA: accept pressed, downloading latest from release");
             crate::lcd::display_text(
                 target,
@@ -141,12 +151,10 @@ pub fn run(
             let _ = screen_tx.send(OtaEvent::DownloadLatest);
             break;
         }
-        if esc_btn.is_low() {
-            wait_button_release(esc_btn);
+        if matches!(evt, Some(InputEvent::Press(KeysPin::ESC)) | None) {
             log::info!("OTA: esc pressed, exiting to boot menu");
             break;
         }
-        std::thread::sleep(std::time::Duration::from_millis(20));
     }
 
     // 关闭所有 sender(http_server 持有 upload/download 的 clone,screen_tx 是我们的),
@@ -157,16 +165,9 @@ pub fn run(
     Ok(())
 }
 
-/// 等按钮松开 + 简单消抖(按下期间一直 is_low)。
-fn wait_button_release(btn: &crate::AnyBtn) {
-    while btn.is_low() {
-        std::thread::sleep(std::time::Duration::from_millis(10));
-    }
-    std::thread::sleep(std::time::Duration::from_millis(20));
-}
-
 fn ota_http_server(
     tx: std::sync::mpsc::Sender<OtaEvent>,
+    nvs_partition: esp_idf_svc::nvs::EspDefaultNvsPartition,
 ) -> anyhow::Result<EspHttpServer<'static>> {
     let mut server = EspHttpServer::new(&HttpServerConf {
         stack_size: 10240,
@@ -216,6 +217,15 @@ fn ota_http_server(
         Result::<(), anyhow::Error>::Ok(())
     })?;
 
+    let nvs = esp_idf_svc::nvs::EspDefaultNvs::new(nvs_partition, "setting", true)?;
+    let setting = crate::bt_wifi_mode::Setting::load_from_nvs(&nvs)?;
+    if !setting.api_token.is_empty() {
+        crate::rest_api::register(
+            &mut server,
+            std::sync::Arc::new(std::sync::Mutex::new((setting, nvs))),
+        )?;
+    }
+
     server.fn_handler("/", Method::Get, |req| {
         let html = OTA_INDEX_HTML.replace("{{OTA_DOWNLOAD_URL}}", OTA_DOWNLOAD_URL);
         req.into_ok_response()?.write_all(html.as_bytes())?;
@@ -0,0 +1,213 @@
+//! SoftAP 配网模式:设置菜单进入,不依赖 Web Bluetooth(Firefox / iOS Safari 都没有)。
+//! 设备开一个以自己名字命名的 WPA2 热点,密码每次随机生成、显示在屏幕上(配网页读得到
+//! WiFi 密码和 ASR api_key,不能让附近任何人都连上)。手机连上后由 `crate::captive_dns`
+//! 把所有域名解析到设备、HTTP server 把系统的联网探测重定向到配网页,系统随即弹出
+//! 「登录网络」页。
+//! 配网页读写的是和 BLE 配置特征值同一份 JSON(`Setting::config_snapshot` /
+//! `Setting::apply_config`),保存成功后重启生效。
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    hal::reset::restart,
+    http::server::{Configuration as HttpServerConf, EspHttpServer, Method},
+    io::Write,
+};
+
+use crate::bt_keyboard_mode::KeysPin;
+use crate::input::InputEvent;
+
+static PROVISION_HTML: &str = include_str!("../assets/provision.html");
+
+/// 配网 HTTP 请求体上限:wifi_list 满 8 条加上其余配置也远小于此
+const MAX_BODY: usize = 8 * 1024;
+
+/// 热点密码的字符表:去掉易混的 0/o、1/l/i,方便照着屏幕输入
+const PASSPHRASE_CHARS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const PASSPHRASE_LEN: usize = 10;
+
+/// 与 `ota::OtaData` 同理:只读输入打包成一个引用,控制 `run` 的参数个数。
+pub struct ProvisionData<'a> {
+    pub scan_list: &'a Vec<String>,
+    pub setting: &'a crate::bt_wifi_mode::Setting,
+    /// 处理函数在 server 线程里各自开 NVS 句柄
+    pub nvs_partition: esp_idf_svc::nvs::EspDefaultNvsPartition,
+}
+
+/// 进入配网模式,ESC 退出回 boot menu;网页保存成功则在 server 线程里重启。
+pub fn run(
+    target: &mut crate::lcd::FrameBuffer,
+    input: &mut crate::input::Input,
+    wifi: &mut esp_idf_svc::wifi::EspWifi<'static>,
+    sysloop: EspSystemEventLoop,
+    data: &ProvisionData,
+) -> anyhow::Result<()> {
+    let ssid = data.setting.device_name();
+    let password = random_passphrase();
+    crate::lcd::display_text(target, "WiFi setup\n Starting hotspot...", 0)?;
+    let ip = crate::wifi::start_ap(wifi, &ssid, &password, sysloop)?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let dns_stop = stop.clone();
+    let dns = std::thread::Builder::new()
+        .name("captive-dns".to_string())
+        .stack_size(4 * 1024)
+        .spawn(move || {
+            if let Err(e) = dns_task(ip, &dns_stop) {
+                log::error!("Captive DNS failed: {e:?}");
+            }
+        })?;
+    let http_server = provision_http_server(ip, data)?;
+
+    crate::lcd::display_text(
+        target,
+        &format!(
+            "WiFi setup\n Join WiFi: {ssid}\n Password: {password}\n Open http://{ip}\n ESC: exit"
+        ),
+        0,
+    )?;
+
+    input.flush();
+    while !matches!(
+        input.blocking_next(),
+        Some(InputEvent::Press(KeysPin::ESC)) | None
+    ) {}
+    log::info!("Provisioning: esc pressed, exiting to boot menu");
+
+    drop(http_server);
+    stop.store(true, Ordering::Relaxed);
+    let _ = dns.join();
+    // 停掉热点;之后 STA 连接会重新配置并启动驱动
+    wifi.stop()?;
+    Ok(())
+}
+
+/// 硬件随机数生成的热点密码;WiFi 已启动(boot 扫描过),`esp_random` 是真随机。
+fn random_passphrase() -> String {
+    (0..PASSPHRASE_LEN)
+        .map(|_| {
+            let r = unsafe { esp_idf_svc::sys::esp_random() } as usize;
+            PASSPHRASE_CHARS[r % PASSPHRASE_CHARS.len()] as char
+        })
+        .collect()
+}
+
+/// UDP 53 上的 DNS:所有 A 查询都答设备地址。读超时用来定期检查 `stop`。
+fn dns_task(ip: std::net::Ipv4Addr, stop: &AtomicBool) -> anyhow::Result<()> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:53")?;
+    socket.set_read_timeout(Some(std::time::Duration::from_millis(500)))?;
+    let mut buf = [0u8; 512];
+    while !stop.load(Ordering::Relaxed) {
+        let (n, peer) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(reply) = crate::captive_dns::answer(&buf[..n], ip) {
+            let _ = socket.send_to(&reply, peer);
+        }
+    }
+    Ok(())
+}
+
+fn provision_http_server(
+    ip: std::net::Ipv4Addr,
+    data: &ProvisionData,
+) -> anyhow::Result<EspHttpServer<'static>> {
+    let mut server = EspHttpServer::new(&HttpServerConf {
+        stack_size: 10240,
+        // 末尾的 `/*` 接住系统的联网探测(generate_204、hotspot-detect.html 等)
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+
+    server.fn_handler("/", Method::Get, |req| {
+        req.into_ok_response()?
+            .write_all(PROVISION_HTML.as_bytes())?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+
+    // boot 阶段的扫描结果,页面用来给 SSID 输入框做候选(热点模式下不能再扫描)
+    let scan_json = serde_json::to_vec(data.scan_list)?;
+    server.fn_handler("/api/scan", Method::Get, move |req| {
+        req.into_response(200, None, &[("Content-Type", "application/json")])?
+            .write_all(&scan_json)?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+
+    let read_partition = data.nvs_partition.clone();
+    server.fn_handler("/api/config", Method::Get, move |req| {
+        let nvs = esp_idf_svc::nvs::EspDefaultNvs::new(read_partition.clone(), "setting", true)?;
+        let setting = crate::bt_wifi_mode::Setting::load_from_nvs(&nvs)?;
+        let json = setting.config_snapshot(&nvs)?;
+        req.into_response(200, None, &[("Content-Type", "application/json")])?
+            .write_all(json.as_bytes())?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+
+    // 载荷同 BLE 配置特征值:部分配置对象,只更新出现的字段
+    let write_partition = data.nvs_partition.clone();
+    server.fn_handler("/api/config", Method::Put, move |mut req| {
+        let mut body = Vec::new();
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = req.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            body.extend_from_slice(&buf[..n]);
+            if body.len() > MAX_BODY {
+                req.into_status_response(413)?
+                    .write_all(b"config too large")?;
+                return Ok(());
+            }
+        }
+        let mut nvs =
+            esp_idf_svc::nvs::EspDefaultNvs::new(write_partition.clone(), "setting", true)?;
+        let result = std::str::from_utf8(&body)
+            .map_err(anyhow::Error::from)
+            .and_then(|payload| {
+                let mut setting = crate::bt_wifi_mode::Setting::load_from_nvs(&nvs)?;
+                setting.apply_config(&mut nvs, payload)
+            });
+        match result {
+            Ok(()) => {
+                req.into_ok_response()?
+                    .write_all(b"Saved. Device will reboot.")?;
+                std::thread::spawn(|| {
+                    std::thread::sleep(std::time::Duration::from_secs(1));
+                    restart();
+                });
+            }
+            Err(e) => {
+                log::warn!("Provisioning write rejected: {:?}", e);
+                let status = if e.is::<crate::bt_wifi_mode::SaveFailed>() {
+                    500
+                } else {
+                    400
+                };
+                req.into_status_response(status)?
+                    .write_all(e.to_string().as_bytes())?;
+            }
+        }
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+
+    let location = format!("http://{ip}/");
+    server.fn_handler("/*", Method::Get, move |req| {
+        req.into_response(302, None, &[("Location", location.as_str())])?
+            .write_all(&[])?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+
+    Ok(server)
+}
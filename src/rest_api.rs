@@ -0,0 +1,349 @@
+//! 局域网 REST API:连上 WiFi 后在设备的 HTTP server 上提供 JSON 配置接口,方便用脚本
+//! 批量配置多台设备。所有请求都要带 `Authorization: Bearer <api_token>`(见
+//! `crate::api_auth`);token 经 BLE 配置特征值设置,没设置时不开 server。
+//!
+//! | 方法 | 路径 | 说明 |
+//! |------|------|------|
+//! | GET / PUT | `/api/config` | 同 BLE 配置特征值的快照 / 部分写入 |
+//! | GET / PUT | `/api/keymap` | 同 keymap 特征值:读到生效的 keymap(含默认),写入合并 / `op` 操作,错误列表同样格式 |
+//! | GET / PUT | `/api/asr` | asr_config,写入与已存的合并 |
+//! | GET / PUT / DELETE | `/api/background` | 开机背景图原始字节(没设置时 GET 回 404)/ 恢复默认 |
+//! | GET / PUT | `/api/backup` | 整份配置导出(`?secrets=1` 带明文密钥、`?background=1` 带背景图)/ 恢复后重启,格式见 `crate::backup` |
+//! | POST | `/api/reboot` | 1 秒后重启 |
+//!
+//! 校验全部复用 BLE 路径(`Setting::apply_config`、`KeymapWrite::parse`),不合法整次拒绝,
+//! 回 400 和原因。写入即落盘;除 api_token 外大多在重启后生效。
+
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::{
+    hal::reset::restart,
+    http::server::{
+        Configuration as HttpServerConf, EspHttpConnection, EspHttpServer, Method, Request,
+    },
+    io::Write,
+};
+
+use crate::bt_keyboard_mode::KeymapErrorCode;
+use crate::bt_wifi_mode::Setting;
+use crate::transfer::TransferKind;
+
+type State = Arc<Mutex<(Setting, esp_idf_svc::nvs::EspDefaultNvs)>>;
+type Req<'a, 'b> = Request<&'a mut EspHttpConnection<'b>>;
+
+/// `/api/config`、`/api/asr` 的请求体上限
+const MAX_CONFIG_BODY: usize = 8 * 1024;
+
+/// 单独起一个 HTTP server 只挂 REST API(各运行模式共用,一直开着)。
+pub fn spawn(state: State) -> anyhow::Result<EspHttpServer<'static>> {
+    let mut server = EspHttpServer::new(&HttpServerConf {
+        stack_size: 10240,
+        ..Default::default()
+    })?;
+    register(&mut server, state)?;
+    log::info!("REST API listening on port 80");
+    Ok(server)
+}
+
+/// 把 REST API 挂到已有的 server 上(OTA 模式的 server 也挂)。
+pub fn register(server: &mut EspHttpServer<'static>, state: State) -> anyhow::Result<()> {
+    route(server, "/api/config", Method::Get, &state, |req, state| {
+        let json = {
+            let lock = state.lock().unwrap();
+            lock.0.config_snapshot(&lock.1)?
+        };
+        respond_json(req, 200, json.as_bytes())
+    })?;
+
+    route(
+        server,
+        "/api/config",
+        Method::Put,
+        &state,
+        |mut req, state| {
+            let Some(body) = read_body(&mut req, MAX_CONFIG_BODY)? else {
+                return respond_text(req, 413, "body too large");
+            };
+            let mut lock = state.lock().unwrap();
+            let (setting, nvs) = &mut *lock;
+            let result = std::str::from_utf8(&body)
+                .map_err(anyhow::Error::from)
+                .and_then(|payload| setting.apply_config(nvs, payload));
+            match result {
+                Ok(()) => {
+                    let json = setting.config_snapshot(nvs)?;
+                    drop(lock);
+                    respond_json(req, 200, json.as_bytes())
+                }
+                Err(e) => {
+                    drop(lock);
+                    respond_text(req, config_error_status(&e), &e.to_string())
+                }
+            }
+        },
+    )?;
+
+    route(server, "/api/keymap", Method::Get, &state, |req, state| {
+        let keymap = {
+            let lock = state.lock().unwrap();
+            crate::bt_keyboard_mode::KeymapConfig::load_from_nvs(&lock.1)?
+        };
+        respond_json(req, 200, keymap.effective().to_json()?.as_bytes())
+    })?;
+
+    route(
+        server,
+        "/api/keymap",
+        Method::Put,
+        &state,
+        |mut req, state| {
+            let Some(body) = read_body(&mut req, TransferKind::Keymap.max_len())? else {
+                return respond_text(req, 413, "body too large");
+            };
+            let config = String::from_utf8(body)?;
+            let result = {
+                let mut lock = state.lock().unwrap();
+                let mut keymap = crate::bt_keyboard_mode::KeymapConfig::load_from_nvs(&lock.1)?;
+                crate::handle_keymap_config(config, &mut lock.1, &mut keymap).map(|()| keymap)
+            };
+            match result {
+                Ok(keymap) => {
+                    // 正在跑的模式持有内存里的 keymap,让它从 NVS 重新加载
+                    crate::bt_keyboard_mode::KEYMAP_STALE
+                        .store(true, std::sync::atomic::Ordering::Relaxed);
+                    respond_json(req, 200, keymap.effective().to_json()?.as_bytes())
+                }
+                // 与 keymap 特征值的错误通知同一格式;写 NVS 失败算服务端错误
+                Err(errors) => {
+                    let status = if errors
+                        .iter()
+                        .any(|e| e.code == KeymapErrorCode::StorageFailed)
+                    {
+                        500
+                    } else {
+                        400
+                    };
+                    respond_json(req, status, &serde_json::to_vec(&errors)?)
+                }
+            }
+        },
+    )?;
+
+    route(server, "/api/asr", Method::Get, &state, |req, state| {
+        let asr = Setting::asr_snapshot(&state.lock().unwrap().1);
+        match asr {
+            Some(v) => respond_json(req, 200, &serde_json::to_vec(&v)?),
+            None => respond_text(req, 404, "asr_config not set"),
+        }
+    })?;
+
+    // 走 `apply_config` 的 asr_config 字段,合并 / 掩码 / 校验与 BLE 相同
+    route(server, "/api/asr", Method::Put, &state, |mut req, state| {
+        let Some(body) = read_body(&mut req, MAX_CONFIG_BODY)? else {
+            return respond_text(req, 413, "body too large");
+        };
+        let result = serde_json::from_slice::<serde_json::Value>(&body)
+            .map_err(|e| anyhow::anyhow!("invalid JSON ({})", e))
+            .and_then(|asr| {
+                let payload = serde_json::json!({ "asr_config": asr }).to_string();
+                let mut lock = state.lock().unwrap();
+                let (setting, nvs) = &mut *lock;
+                setting.apply_config(nvs, &payload)
+            });
+        match result {
+            Ok(()) => {
+                let asr = Setting::asr_snapshot(&state.lock().unwrap().1);
+                respond_json(req, 200, &serde_json::to_vec(&asr)?)
+            }
+            Err(e) => respond_text(req, config_error_status(&e), &e.to_string()),
+        }
+    })?;
+
+    route(
+        server,
+        "/api/background",
+        Method::Get,
+        &state,
+        |req, state| {
+            let image = {
+                let lock = state.lock().unwrap();
+                match lock.1.blob_len("background_png")? {
+                    Some(len) if len > 0 => {
+                        let mut buf = vec![0u8; len];
+                        lock.1.get_blob("background_png", &mut buf)?;
+                        Some(buf)
+                    }
+                    _ => None,
+                }
+            };
+            match image {
+                Some(image) => {
+                    req.into_response(200, None, &[("Content-Type", "application/octet-stream")])?
+                        .write_all(&image)?;
+                    Ok(())
+                }
+                None => respond_text(req, 404, "background not set"),
+            }
+        },
+    )?;
+
+    // 与分块传输的背景图相同:原样写 NVS,下次启动显示
+    route(
+        server,
+        "/api/background",
+        Method::Put,
+        &state,
+        |mut req, state| {
+            let Some(body) = read_body(&mut req, TransferKind::Background.max_len())? else {
+                return respond_text(req, 413, "body too large");
+            };
+            if body.is_empty() {
+                return respond_text(req, 400, "empty image");
+            }
+            {
+                let mut lock = state.lock().unwrap();
+                lock.1.set_blob("background_png", &body)?;
+                lock.0.background_png = (body, true);
+            }
+            respond_text(req, 200, "background saved")
+        },
+    )?;
+
+    route(
+        server,
+        "/api/background",
+        Method::Delete,
+        &state,
+        |req, state| {
+            {
+                let mut lock = state.lock().unwrap();
+                lock.1.remove("background_png")?;
+                lock.0.background_png = (Vec::new(), false);
+            }
+            respond_text(req, 200, "background cleared")
+        },
+    )?;
+
+    route(server, "/api/backup", Method::Get, &state, |req, state| {
+        let query = req.uri().split_once('?').map(|(_, q)| q).unwrap_or("");
+        let flag = |name: &str| query.split('&').any(|kv| kv == format!("{name}=1"));
+        let doc = {
+            let lock = state.lock().unwrap();
+            let keymap = crate::bt_keyboard_mode::KeymapConfig::load_from_nvs(&lock.1)?;
+            lock.0
+                .export(&lock.1, &keymap, flag("secrets"), flag("background"))
+        };
+        respond_json(req, 200, &serde_json::to_vec(&doc)?)
+    })?;
+
+    // 整份校验通过才写 NVS,成功后重启生效;写到一半失败会回滚(见 `Setting::restore`)
+    route(
+        server,
+        "/api/backup",
+        Method::Put,
+        &state,
+        |mut req, state| {
+            let Some(body) = read_body(&mut req, TransferKind::Config.max_len())? else {
+                return respond_text(req, 413, "body too large");
+            };
+            let result = std::str::from_utf8(&body)
+                .map_err(anyhow::Error::from)
+                .and_then(crate::backup::ConfigDocument::from_json)
+                .and_then(|doc| {
+                    let mut lock = state.lock().unwrap();
+                    let (setting, nvs) = &mut *lock;
+                    setting.restore(nvs, doc)
+                });
+            match result {
+                Ok(()) => {
+                    respond_text(req, 200, "config restored, rebooting")?;
+                    std::thread::spawn(|| {
+                        std::thread::sleep(std::time::Duration::from_secs(1));
+                        restart();
+                    });
+                    Ok(())
+                }
+                Err(e) => {
+                    log::warn!("Config restore rejected: {:?}", e);
+                    respond_text(req, config_error_status(&e), &format!("{:#}", e))
+                }
+            }
+        },
+    )?;
+
+    route(server, "/api/reboot", Method::Post, &state, |req, _| {
+        respond_text(req, 200, "rebooting")?;
+        std::thread::spawn(|| {
+            std::thread::sleep(std::time::Duration::from_secs(1));
+            restart();
+        });
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+/// 挂一个要鉴权的处理函数:token 每次请求时从 `state` 读,改了立即生效。
+fn route<F>(
+    server: &mut EspHttpServer<'static>,
+    uri: &str,
+    method: Method,
+    state: &State,
+    handler: F,
+) -> anyhow::Result<()>
+where
+    F: for<'a, 'b> Fn(Req<'a, 'b>, &State) -> anyhow::Result<()> + Send + Sync + 'static,
+{
+    let state = state.clone();
+    server.fn_handler(uri, method, move |req| {
+        let authorized = {
+            let token = &state.lock().unwrap().0.api_token;
+            crate::api_auth::authorized(req.header("Authorization"), token)
+        };
+        if !authorized {
+            log::warn!("REST API: unauthorized {:?} {}", method, req.uri());
+            req.into_response(401, None, &[("WWW-Authenticate", "Bearer")])?
+                .write_all(b"unauthorized")?;
+            return Ok(());
+        }
+        handler(req, &state)
+    })?;
+    Ok(())
+}
+
+/// `apply_config` 的错误:写 NVS 失败算服务端错误,其余是请求不合法
+fn config_error_status(e: &anyhow::Error) -> u16 {
+    if e.is::<crate::bt_wifi_mode::SaveFailed>() {
+        500
+    } else {
+        400
+    }
+}
+
+/// 读完请求体;超过 `limit` 返回 None(调用方回 413)。
+fn read_body(req: &mut Req, limit: usize) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut body = Vec::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        let n = req.read(&mut buf)?;
+        if n == 0 {
+            return Ok(Some(body));
+        }
+        body.extend_from_slice(&buf[..n]);
+        if body.len() > limit {
+            return Ok(None);
+        }
+    }
+}
+
+fn respond_json(req: Req, status: u16, body: &[u8]) -> anyhow::Result<()> {
+    req.into_response(status, None, &[("Content-Type", "application/json")])?
+        .write_all(body)?;
+    Ok(())
+}
+
+fn respond_text(req: Req, status: u16, text: &str) -> anyhow::Result<()> {
+    req.into_response(status, None, &[("Content-Type", "text/plain")])?
+        .write_all(text.as_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,197 @@
+//! Device status pushed to the companion pages.
+//!
+//! The controller service notifies a `{"type":"status",...}` object on its notify
+//! characteristic whenever a field changes (the schema is documented in the README):
+//!
+//! ```json
+//! {"type":"status","mode":"keyboard","version":"0.4.0",
+//!  "wifi":{"connected":true,"ssid":"home","rssi":-61},
+//!  "asr":"idle","ble":{"slot":1,"connected":1},"last_error":null}
+//! ```
+//!
+//! [`StatusTracker`] only keeps the state and tells whether an update changed it; sending is
+//! up to `bt_keyboard_mode::ControllerService::update_status`. RSSI is polled every few seconds
+//! and jitters by a dB or two, so it only counts as a change once it has moved by
+//! [`RSSI_STEP`] from the value last pushed.
+
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    Keyboard,
+    /// BLE keyboard with the screen and MIC owned by the remote session
+    Hybrid,
+}
+
+/// dB the RSSI has to move from the last pushed value to be pushed again
+pub const RSSI_STEP: u8 = 5;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AsrState {
+    #[default]
+    Idle,
+    /// Waiting for the ASR server before recording
+    Connecting,
+    Recording,
+    /// Recording stopped, waiting for the transcript
+    Uploading,
+    /// The last recognition failed, see `last_error`
+    Error,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct WifiStatus {
+    pub connected: bool,
+    pub ssid: Option<String>,
+    /// dBm of the access point, while connected
+    pub rssi: Option<i8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BleStatus {
+    /// Host slot in use, 1-based like the `host` key action
+    pub slot: u8,
+    /// Centrals connected right now
+    pub connected: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeviceStatus {
+    pub mode: Mode,
+    pub version: &'static str,
+    pub wifi: WifiStatus,
+    pub asr: AsrState,
+    pub ble: BleStatus,
+    /// Most recent failure shown to the user, kept until the next one
+    pub last_error: Option<String>,
+}
+
+impl DeviceStatus {
+    pub fn new(mode: Mode) -> Self {
+        Self {
+            mode,
+            version: env!("CARGO_PKG_VERSION"),
+            wifi: WifiStatus::default(),
+            asr: AsrState::Idle,
+            ble: BleStatus {
+                slot: 1,
+                connected: 0,
+            },
+            last_error: None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        if let Some(obj) = value.as_object_mut() {
+            obj.insert("type".into(), "status".into());
+        }
+        value.to_string()
+    }
+}
+
+/// Current status behind a lock, so callbacks holding `&self` can update it
+#[derive(Debug)]
+pub struct StatusTracker {
+    state: Mutex<Tracked>,
+}
+
+#[derive(Debug)]
+struct Tracked {
+    status: DeviceStatus,
+    /// `wifi.rssi` as last pushed
+    pushed_rssi: Option<i8>,
+}
+
+impl StatusTracker {
+    pub fn new(mode: Mode) -> Self {
+        Self {
+            state: Mutex::new(Tracked {
+                status: DeviceStatus::new(mode),
+                pushed_rssi: None,
+            }),
+        }
+    }
+
+    /// Apply a change. Returns the JSON to push when the status is now different.
+    pub fn update(&self, f: impl FnOnce(&mut DeviceStatus)) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.status.clone();
+        f(&mut state.status);
+
+        let mut rest = state.status.clone();
+        rest.wifi.rssi = before.wifi.rssi;
+        let rssi_moved = match (state.pushed_rssi, state.status.wifi.rssi) {
+            (Some(pushed), Some(now)) => pushed.abs_diff(now) >= RSSI_STEP,
+            (pushed, now) => pushed.is_some() != now.is_some(),
+        };
+        if rest == before && !rssi_moved {
+            return None;
+        }
+        state.pushed_rssi = state.status.wifi.rssi;
+        Some(state.status.to_json())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pushes_only_changes() {
+        let tracker = StatusTracker::new(Mode::Keyboard);
+        assert!(tracker.update(|s| s.asr = AsrState::Idle).is_none());
+        let json = tracker.update(|s| s.asr = AsrState::Recording).unwrap();
+        assert!(json.contains(r#""asr":"recording""#));
+        assert!(tracker.update(|s| s.asr = AsrState::Recording).is_none());
+    }
+
+    #[test]
+    fn rssi_jitter_is_not_pushed() {
+        let tracker = StatusTracker::new(Mode::Hybrid);
+        assert!(tracker.update(|s| s.wifi.rssi = Some(-60)).is_some());
+        assert!(tracker.update(|s| s.wifi.rssi = Some(-62)).is_none());
+        assert!(tracker.update(|s| s.wifi.rssi = Some(-58)).is_none());
+        // measured from the pushed -60, not the previous poll
+        let json = tracker.update(|s| s.wifi.rssi = Some(-65)).unwrap();
+        assert!(json.contains(r#""rssi":-65"#));
+        assert!(json.contains(r#""mode":"hybrid""#));
+        // another change carries the current RSSI along
+        assert!(tracker.update(|s| s.wifi.rssi = Some(-67)).is_none());
+        let json = tracker.update(|s| s.asr = AsrState::Recording).unwrap();
+        assert!(json.contains(r#""rssi":-67"#));
+        assert!(tracker.update(|s| s.wifi.rssi = None).is_some());
+    }
+
+    #[test]
+    fn status_json_shape() {
+        let tracker = StatusTracker::new(Mode::Keyboard);
+        let json = tracker
+            .update(|s| {
+                s.wifi = WifiStatus {
+                    connected: true,
+                    ssid: Some("home".into()),
+                    rssi: Some(-61),
+                };
+                s.ble.connected = 2;
+            })
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "type": "status",
+                "mode": "keyboard",
+                "version": env!("CARGO_PKG_VERSION"),
+                "wifi": {"connected": true, "ssid": "home", "rssi": -61},
+                "asr": "idle",
+                "ble": {"slot": 1, "connected": 2},
+                "last_error": null,
+            })
+        );
+    }
+}
@@ -0,0 +1,521 @@
+//! Chunked, acknowledged transfers over one BLE characteristic.
+//!
+//! A single write is limited by the negotiated MTU, so background images, large keymaps and
+//! CA certificates are sent as framed transfers. All integers are little-endian:
+//!
+//! | frame | bytes |
+//! |-------|-------|
+//! | start | `0x01` kind:u8 length:u32 crc32:u32 |
+//! | chunk | `0x02` seq:u16 data… |
+//! | end   | `0x03` |
+//! | abort | `0x04` |
+//! | export | `0x05` kind:u8 flags:u8 |
+//!
+//! Chunks are numbered from 0 (wrapping at `u16::MAX`) and must arrive in order. Every frame is
+//! answered with one [`Reply`] notification; the sender waits for it before sending the next
+//! chunk. A chunk that is out of order gets a `nack` carrying the sequence number expected
+//! next, so the sender resumes from there. Re-sending the `start` of the transfer in progress
+//! (same kind, length and CRC) resumes it too, e.g. after a reconnect. `end` checks the length
+//! and the CRC-32 (IEEE) of the whole payload before it is handed to the device.
+//!
+//! `export` asks the device for a payload instead: `config` (flag bit 0 includes secrets, bit 1
+//! the background image) or `keymap` (the effective keymap, flags unused). The device answers with its own `start`,
+//! `chunk` and `end` frames as notifications, without waiting for acks; they are told apart
+//! from the JSON replies by their first byte.
+//!
+//! The receiver is pure state; the BLE side lives in `bt_keyboard_mode::ControllerService`.
+
+use serde::Serialize;
+
+const FRAME_START: u8 = 0x01;
+const FRAME_CHUNK: u8 = 0x02;
+const FRAME_END: u8 = 0x03;
+const FRAME_ABORT: u8 = 0x04;
+const FRAME_EXPORT: u8 = 0x05;
+
+/// What a transfer carries, picked by the `kind` byte of the start frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferKind {
+    /// Boot background image (PNG/GIF/JPEG), `1`
+    Background,
+    /// Keymap JSON, handled like a write to the keymap characteristic, `2`
+    Keymap,
+    /// PEM CA certificate for the ASR server, `3`
+    Cert,
+    /// Whole configuration document (see `crate::backup`), `4`
+    Config,
+}
+
+impl TransferKind {
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            1 => Some(Self::Background),
+            2 => Some(Self::Keymap),
+            3 => Some(Self::Cert),
+            4 => Some(Self::Config),
+            _ => None,
+        }
+    }
+
+    /// Largest payload accepted for this kind
+    pub fn max_len(self) -> usize {
+        match self {
+            Self::Background => 1024 * 1024,
+            Self::Keymap => 16 * 1024,
+            Self::Cert => 8 * 1024,
+            // Base64 of a full-size background plus the settings
+            Self::Config => 1536 * 1024,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::Background => 1,
+            Self::Keymap => 2,
+            Self::Cert => 3,
+            Self::Config => 4,
+        }
+    }
+}
+
+/// Payload the host asked the device to send, from an `export` frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExportRequest {
+    pub kind: TransferKind,
+    pub secrets: bool,
+    pub background: bool,
+}
+
+impl ExportRequest {
+    /// `None` when the frame is not an export request
+    pub fn parse(frame: &[u8]) -> Option<Result<Self, TransferError>> {
+        let (&tag, body) = frame.split_first()?;
+        if tag != FRAME_EXPORT {
+            return None;
+        }
+        let [kind, flags] = body else {
+            return Some(Err(TransferError::BadFrame));
+        };
+        Some(match TransferKind::from_byte(*kind) {
+            Some(kind @ (TransferKind::Config | TransferKind::Keymap)) => Ok(Self {
+                kind,
+                secrets: flags & 1 != 0,
+                background: flags & 2 != 0,
+            }),
+            _ => Err(TransferError::UnknownKind),
+        })
+    }
+}
+
+/// Frames sending `data` to the host: `start`, chunks of at most `chunk_len` bytes, `end`
+pub fn encode(kind: TransferKind, data: &[u8], chunk_len: usize) -> Vec<Vec<u8>> {
+    let mut start = vec![FRAME_START, kind.to_byte()];
+    start.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    start.extend_from_slice(&crc32(data).to_le_bytes());
+    let mut frames = vec![start];
+    for (seq, part) in data.chunks(chunk_len.max(1)).enumerate() {
+        let mut f = vec![FRAME_CHUNK];
+        f.extend_from_slice(&(seq as u16).to_le_bytes());
+        f.extend_from_slice(part);
+        frames.push(f);
+    }
+    frames.push(vec![FRAME_END]);
+    frames
+}
+
+/// Why a frame was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferError {
+    /// Empty, truncated or unknown frame
+    BadFrame,
+    UnknownKind,
+    /// Announced length is 0 or above the kind's limit
+    TooLarge,
+    /// Chunk or end without a transfer in progress
+    NoTransfer,
+    /// Chunk would exceed the announced length
+    Overflow,
+    /// `end` arrived before all announced bytes
+    LengthMismatch,
+    CrcMismatch,
+    /// The device could not use the received payload
+    Rejected,
+}
+
+/// Notification answering one frame, e.g. `{"type":"transfer","status":"ack","seq":3}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Reply {
+    /// Start accepted; send chunks from `next` (0 unless resuming)
+    Ready {
+        next: u16,
+    },
+    Ack {
+        seq: u16,
+    },
+    /// Chunk out of order; send chunks from `next`
+    Nack {
+        next: u16,
+    },
+    Done {
+        kind: TransferKind,
+        len: usize,
+    },
+    Aborted,
+    Error {
+        reason: TransferError,
+    },
+}
+
+impl Reply {
+    pub fn to_json(&self) -> String {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        if let Some(obj) = value.as_object_mut() {
+            obj.insert("type".into(), "transfer".into());
+        }
+        value.to_string()
+    }
+}
+
+/// Payload of a transfer that passed its final check
+#[derive(Debug, PartialEq, Eq)]
+pub struct Completed {
+    pub kind: TransferKind,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Transfer {
+    kind: TransferKind,
+    len: usize,
+    crc: u32,
+    next: u16,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct Receiver {
+    current: Option<Transfer>,
+}
+
+impl Receiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed one written frame. Returns the reply to notify and, once `end` checks out,
+    /// the received payload.
+    pub fn handle(&mut self, frame: &[u8]) -> (Reply, Option<Completed>) {
+        let Some((&tag, body)) = frame.split_first() else {
+            return (Self::error(TransferError::BadFrame), None);
+        };
+        match tag {
+            FRAME_START => (self.start(body), None),
+            FRAME_CHUNK => (self.chunk(body), None),
+            FRAME_END => self.end(),
+            FRAME_ABORT => {
+                self.current = None;
+                (Reply::Aborted, None)
+            }
+            _ => (Self::error(TransferError::BadFrame), None),
+        }
+    }
+
+    fn error(reason: TransferError) -> Reply {
+        Reply::Error { reason }
+    }
+
+    fn start(&mut self, body: &[u8]) -> Reply {
+        if body.len() != 9 {
+            return Self::error(TransferError::BadFrame);
+        }
+        let Some(kind) = TransferKind::from_byte(body[0]) else {
+            return Self::error(TransferError::UnknownKind);
+        };
+        let len = u32::from_le_bytes([body[1], body[2], body[3], body[4]]) as usize;
+        let crc = u32::from_le_bytes([body[5], body[6], body[7], body[8]]);
+        if len == 0 || len > kind.max_len() {
+            return Self::error(TransferError::TooLarge);
+        }
+
+        if let Some(t) = &self.current {
+            if t.kind == kind && t.len == len && t.crc == crc {
+                return Reply::Ready { next: t.next };
+            }
+        }
+        self.current = Some(Transfer {
+            kind,
+            len,
+            crc,
+            next: 0,
+            data: Vec::with_capacity(len),
+        });
+        Reply::Ready { next: 0 }
+    }
+
+    fn chunk(&mut self, body: &[u8]) -> Reply {
+        if body.len() < 2 {
+            return Self::error(TransferError::BadFrame);
+        }
+        let Some(t) = self.current.as_mut() else {
+            return Self::error(TransferError::NoTransfer);
+        };
+        let seq = u16::from_le_bytes([body[0], body[1]]);
+        let data = &body[2..];
+
+        // The ack of the previous chunk was lost and the sender retried it: it is already stored
+        if seq == t.next.wrapping_sub(1) && !t.data.is_empty() {
+            return Reply::Ack { seq };
+        }
+        if seq != t.next {
+            return Reply::Nack { next: t.next };
+        }
+        if t.data.len() + data.len() > t.len {
+            return Self::error(TransferError::Overflow);
+        }
+        t.data.extend_from_slice(data);
+        t.next = t.next.wrapping_add(1);
+        Reply::Ack { seq }
+    }
+
+    fn end(&mut self) -> (Reply, Option<Completed>) {
+        let Some(t) = self.current.take() else {
+            return (Self::error(TransferError::NoTransfer), None);
+        };
+        if t.data.len() != t.len {
+            // Keep what arrived so the sender can resume the missing chunks
+            self.current = Some(t);
+            return (Self::error(TransferError::LengthMismatch), None);
+        }
+        if crc32(&t.data) != t.crc {
+            return (Self::error(TransferError::CrcMismatch), None);
+        }
+        (
+            Reply::Done {
+                kind: t.kind,
+                len: t.len,
+            },
+            Some(Completed {
+                kind: t.kind,
+                data: t.data,
+            }),
+        )
+    }
+}
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xEDB8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = crc32_table();
+
+/// CRC-32 (IEEE 802.3), the same as zlib's `crc32` and JS `CRC32.buf`
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc = CRC32_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start(kind: u8, data: &[u8]) -> Vec<u8> {
+        let mut f = vec![FRAME_START, kind];
+        f.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        f.extend_from_slice(&crc32(data).to_le_bytes());
+        f
+    }
+
+    fn chunk(seq: u16, data: &[u8]) -> Vec<u8> {
+        let mut f = vec![FRAME_CHUNK];
+        f.extend_from_slice(&seq.to_le_bytes());
+        f.extend_from_slice(data);
+        f
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn full_transfer() {
+        let payload = br#"{"ESC":{"type":"key","value":"ESC"}}"#;
+        let mut rx = Receiver::new();
+        assert_eq!(rx.handle(&start(2, payload)).0, Reply::Ready { next: 0 });
+        for (i, part) in payload.chunks(8).enumerate() {
+            let seq = i as u16;
+            assert_eq!(rx.handle(&chunk(seq, part)).0, Reply::Ack { seq });
+        }
+        let (reply, done) = rx.handle(&[FRAME_END]);
+        assert_eq!(
+            reply,
+            Reply::Done {
+                kind: TransferKind::Keymap,
+                len: payload.len()
+            }
+        );
+        assert_eq!(
+            done,
+            Some(Completed {
+                kind: TransferKind::Keymap,
+                data: payload.to_vec()
+            })
+        );
+        // The transfer is finished
+        assert_eq!(
+            rx.handle(&chunk(0, b"x")).0,
+            Reply::Error {
+                reason: TransferError::NoTransfer
+            }
+        );
+    }
+
+    #[test]
+    fn out_of_order_and_retried_chunks() {
+        let payload = b"abcdefghij";
+        let mut rx = Receiver::new();
+        rx.handle(&start(1, payload));
+        assert_eq!(rx.handle(&chunk(0, b"abcd")).0, Reply::Ack { seq: 0 });
+        // Lost ack: the retry is acked again but not stored twice
+        assert_eq!(rx.handle(&chunk(0, b"abcd")).0, Reply::Ack { seq: 0 });
+        // Skipped chunk: nack tells the sender where to resume
+        assert_eq!(rx.handle(&chunk(2, b"ij")).0, Reply::Nack { next: 1 });
+        assert_eq!(
+            rx.handle(&[FRAME_END]).0,
+            Reply::Error {
+                reason: TransferError::LengthMismatch
+            }
+        );
+        assert_eq!(rx.handle(&chunk(1, b"efgh")).0, Reply::Ack { seq: 1 });
+        assert_eq!(rx.handle(&chunk(2, b"ij")).0, Reply::Ack { seq: 2 });
+        let (_, done) = rx.handle(&[FRAME_END]);
+        assert_eq!(done.unwrap().data, payload.to_vec());
+    }
+
+    #[test]
+    fn resume_with_same_start() {
+        let payload = b"0123456789";
+        let mut rx = Receiver::new();
+        rx.handle(&start(3, payload));
+        rx.handle(&chunk(0, b"01234"));
+        assert_eq!(rx.handle(&start(3, payload)).0, Reply::Ready { next: 1 });
+        // A different payload restarts from scratch
+        assert_eq!(rx.handle(&start(3, b"other")).0, Reply::Ready { next: 0 });
+    }
+
+    #[test]
+    fn rejected_frames() {
+        let mut rx = Receiver::new();
+        let err = |reason| Reply::Error { reason };
+        assert_eq!(rx.handle(&[]).0, err(TransferError::BadFrame));
+        assert_eq!(rx.handle(&[0x7F]).0, err(TransferError::BadFrame));
+        assert_eq!(
+            rx.handle(&start(9, b"x")).0,
+            err(TransferError::UnknownKind)
+        );
+        assert_eq!(rx.handle(&start(2, b"")).0, err(TransferError::TooLarge));
+        let big = vec![0u8; TransferKind::Keymap.max_len() + 1];
+        assert_eq!(rx.handle(&start(2, &big)).0, err(TransferError::TooLarge));
+        assert_eq!(rx.handle(&[FRAME_END]).0, err(TransferError::NoTransfer));
+
+        rx.handle(&start(2, b"abc"));
+        assert_eq!(
+            rx.handle(&chunk(0, b"abcd")).0,
+            err(TransferError::Overflow)
+        );
+        assert_eq!(rx.handle(&[FRAME_ABORT]).0, Reply::Aborted);
+        assert_eq!(rx.handle(&chunk(0, b"a")).0, err(TransferError::NoTransfer));
+
+        let mut f = start(2, b"abc");
+        f[6] ^= 1; // corrupt the announced CRC
+        rx.handle(&f);
+        rx.handle(&chunk(0, b"abc"));
+        assert_eq!(rx.handle(&[FRAME_END]).0, err(TransferError::CrcMismatch));
+    }
+
+    #[test]
+    fn export_frames_round_trip() {
+        assert_eq!(ExportRequest::parse(&[FRAME_END]), None);
+        assert_eq!(
+            ExportRequest::parse(&[FRAME_EXPORT, 4, 3]),
+            Some(Ok(ExportRequest {
+                kind: TransferKind::Config,
+                secrets: true,
+                background: true,
+            }))
+        );
+        assert_eq!(
+            ExportRequest::parse(&[FRAME_EXPORT, 2, 0]),
+            Some(Ok(ExportRequest {
+                kind: TransferKind::Keymap,
+                secrets: false,
+                background: false,
+            }))
+        );
+        assert_eq!(
+            ExportRequest::parse(&[FRAME_EXPORT, 1, 0]),
+            Some(Err(TransferError::UnknownKind))
+        );
+        assert_eq!(
+            ExportRequest::parse(&[FRAME_EXPORT, 4]),
+            Some(Err(TransferError::BadFrame))
+        );
+
+        // What the device sends is accepted by the same receiver the host would run
+        let payload: Vec<u8> = (0..=255).collect();
+        let frames = encode(TransferKind::Config, &payload, 100);
+        assert_eq!(frames.len(), 5);
+        let mut rx = Receiver::new();
+        let mut done = None;
+        for f in &frames {
+            done = rx.handle(f).1.or(done);
+        }
+        assert_eq!(done.unwrap().data, payload);
+    }
+
+    #[test]
+    fn reply_json_shape() {
+        assert_eq!(
+            Reply::Nack { next: 4 }.to_json(),
+            r#"{"next":4,"status":"nack","type":"transfer"}"#
+        );
+        assert_eq!(
+            Reply::Error {
+                reason: TransferError::CrcMismatch
+            }
+            .to_json(),
+            r#"{"reason":"crc_mismatch","status":"error","type":"transfer"}"#
+        );
+        assert_eq!(
+            Reply::Done {
+                kind: TransferKind::Background,
+                len: 10
+            }
+            .to_json(),
+            r#"{"kind":"background","len":10,"status":"done","type":"transfer"}"#
+        );
+    }
+}
@@ -1,12 +1,15 @@
 //! 手写 UI:开机菜单 / Setting / 各模式外壳。
 //!
 //! 基于 embedded-graphics + u8g2 中文字体,直接画到 `lcd::FrameBuffer`。
-//! vibekeys 无触屏,菜单用 Next(btn4)切换选项、Accept(btn7)确认;子列表(WiFi/密码字符)
-//! 仍可用旋钮(pin16/17)双向滚动。
+//! vibekeys 无触屏,菜单用 Next(btn4)切换选项、Accept(btn7)确认;子列表(WiFi/字符轮)
+//! 仍可用旋钮(pin16/17)双向滚动。按键全部来自统一输入服务(`crate::input`)。
 
 use embedded_graphics::{
     image::GetPixel,
-    mono_font::{ascii::FONT_7X13_BOLD, MonoTextStyle},
+    mono_font::{
+        ascii::{FONT_5X8, FONT_7X13_BOLD},
+        MonoTextStyle,
+    },
     prelude::*,
     primitives::{PrimitiveStyle, Rectangle, StyledDrawable},
     text::{Alignment, Baseline, LineHeight, Text, TextStyleBuilder},
@@ -21,10 +24,10 @@ use u8g2_fonts::{
     U8g2TextStyle,
 };
 
+use crate::bt_keyboard_mode::KeysPin;
+use crate::input::{Input, InputEvent};
 use crate::lcd::{ColorFormat, DisplayTargetDrive, FrameBuffer};
 
-type Btn<'a> = &'a mut crate::AnyBtn;
-
 const LINE_H: u32 = 14;
 
 // ========== 绘制工具 ==========
@@ -134,54 +137,78 @@ pub fn display_jpeg(jpeg: &[u8]) -> anyhow::Result<()> {
 pub enum BootChoice {
     Keyboard,
     Remote,
+    Hybrid,
     Setting,
 }
 
-const BOOT_LABELS: [&str; 3] = ["Keyboard", "Remote", "Setting"];
-const BOOT_CHOICES: [BootChoice; 3] = [
+const BOOT_LABELS: [&str; 4] = ["Keyboard", "Remote", "Hybrid", "Setting"];
+const BOOT_CHOICES: [BootChoice; 4] = [
     BootChoice::Keyboard,
     BootChoice::Remote,
+    BootChoice::Hybrid,
     BootChoice::Setting,
 ];
 
-#[derive(Debug)]
-enum MenuEvt {
-    Next,
-    Accept,
-    Esc,
-}
+/// 自动进入默认模式前的倒计时秒数。
+const AUTO_BOOT_SECS: u32 = 3;
 
 /// 开机主菜单:Next 键正向切换选项、Accept 进入、Esc 逆向。返回选中的模式。
+/// 底部显示本机 BLE 名称,方便在主机的蓝牙列表里认出是哪一台。
+/// `auto` 为 Some 时焦点停在该项并倒计时,到时自动进入;期间任意按键取消倒计时。
 pub async fn boot_menu(
     target: &mut FrameBuffer,
-    accept: Btn<'_>,
-    esc: Btn<'_>,
-    next: Btn<'_>,
+    input: &mut Input,
+    ble_name: &str,
+    auto: Option<BootChoice>,
 ) -> BootChoice {
-    let mut focus: usize = 0;
+    let mut focus: usize = auto
+        .and_then(|c| BOOT_CHOICES.iter().position(|&b| b == c))
+        .unwrap_or(0);
+    let mut countdown = auto.map(|_| AUTO_BOOT_SECS);
     let width = target.bounding_box().size.width;
     let n = BOOT_LABELS.len();
 
     loop {
-        let _ = render_boot_menu(target, focus, width);
-
-        let evt = tokio::select! {
-            _ = next.wait_for_falling_edge() => MenuEvt::Next,
-            _ = accept.wait_for_falling_edge() => MenuEvt::Accept,
-            _ = esc.wait_for_falling_edge() => MenuEvt::Esc,
+        let hint = match countdown {
+            Some(secs) => format!("{} in {}s, any key to stop", BOOT_LABELS[focus], secs),
+            None => format!("BLE: {ble_name}"),
+        };
+        let _ = render_boot_menu(target, focus, width, &hint);
+
+        let evt = match countdown {
+            Some(secs) => {
+                match tokio::time::timeout(std::time::Duration::from_secs(1), input.next()).await {
+                    Ok(evt) => {
+                        countdown = None;
+                        evt
+                    }
+                    Err(_) if secs <= 1 => return BOOT_CHOICES[focus],
+                    Err(_) => {
+                        countdown = Some(secs - 1);
+                        continue;
+                    }
+                }
+            }
+            None => input.next().await,
         };
-        tokio::time::sleep(std::time::Duration::from_millis(50)).await; // 消抖
-
         match evt {
-            MenuEvt::Next if next.is_low() => focus = (focus + 1) % n,
-            MenuEvt::Accept if accept.is_low() => return BOOT_CHOICES[focus],
-            MenuEvt::Esc if esc.is_low() => focus = (focus + n - 1) % n,
-            _ => {}
+            Some(InputEvent::Press(KeysPin::NEXT)) => focus = (focus + 1) % n,
+            Some(InputEvent::Press(KeysPin::ACCEPT)) => return BOOT_CHOICES[focus],
+            Some(InputEvent::Press(KeysPin::ESC)) => focus = (focus + n - 1) % n,
+            Some(_) => {}
+            // 输入服务已退出:没法再选,直接进键盘模式(BLE 配置页仍可用)
+            None => return BootChoice::Keyboard,
         }
     }
 }
 
-fn render_boot_menu(target: &mut FrameBuffer, focus: usize, width: u32) -> anyhow::Result<()> {
+/// `footer` 画在选项下方:平时是本机 BLE 名称,自动进入时是倒计时提示。
+fn render_boot_menu(
+    target: &mut FrameBuffer,
+    focus: usize,
+    width: u32,
+    footer: &str,
+) -> anyhow::Result<()> {
     clear(target, ColorFormat::CSS_BLACK)?;
     draw_text(
         target,
@@ -220,13 +247,58 @@ fn render_boot_menu(target: &mut FrameBuffer, focus: usize, width: u32) -> anyho
             )?;
         }
     }
+    let name_y = start_y + (BOOT_LABELS.len() as i32) * (item_h as i32) + 6;
+    draw_text(
+        target,
+        footer,
+        Rectangle::new(Point::new(4, name_y), Size::new(width - 4, LINE_H + 2)),
+        ColorFormat::CSS_GRAY,
+        None,
+        HorizontalAlignment::Center,
+    )?;
     flush(target)
 }
 
 // ========== Setting 页面 ==========
 
-/// 密码字符轮:0-9 a-z A-Z。
-const CHARSET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+/// 字符轮:0-9 a-z A-Z,加上 URL 常用标点(MQTT/ASR 地址,也方便带符号的 WiFi 密码)。
+const CHARSET: &[u8] =
+    b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ:/.-_~?=&%@+#!*$,;";
+
+/// 设置主菜单条目数,顺序见 `render_setting_menu`。
+const SETTING_ITEMS: usize = 11;
+
+/// 字符轮编辑的文本配置项(WiFi 密码走 PassEditor)。
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum TextField {
+    ServerUrl,
+    AsrUri,
+    AsrModel,
+    AsrApiKey,
+}
+
+/// ASR 子菜单的条目顺序,与 `asr_fields` 返回的下标一致。
+const ASR_FIELDS: [TextField; 3] = [TextField::AsrUri, TextField::AsrModel, TextField::AsrApiKey];
+
+impl TextField {
+    fn title(self) -> &'static str {
+        match self {
+            TextField::ServerUrl => "MQTT server",
+            TextField::AsrUri => "ASR URI",
+            TextField::AsrModel => "ASR model",
+            TextField::AsrApiKey => "ASR API key",
+        }
+    }
+
+    /// server_url 加载时用 128 字节缓冲,其余按常见长度留余量。
+    fn max_len(self) -> usize {
+        match self {
+            TextField::ServerUrl | TextField::AsrUri => 127,
+            TextField::AsrModel => 63,
+            TextField::AsrApiKey => 200,
+        }
+    }
+}
 
 #[derive(Copy, Clone, Eq, PartialEq)]
 enum SettingState {
@@ -237,11 +309,18 @@ enum SettingState {
     ScanPicker,
     /// 字符轮编辑某条 cred 的密码。
     PassEditor,
+    /// ASR 服务字段列表(URI / model / API key)。
+    Asr,
+    /// 字符轮编辑一个文本配置项,Esc 保存。
+    FieldEditor(TextField),
+    /// 已配对主机(bond)列表 + 尾部 <Delete all> / 配对开关。
+    Bonds,
 }
 
 #[derive(Copy, Clone)]
 enum InputEvt {
-    Rotate,
+    /// 旋钮一格;true = 向下
+    Rotate(bool),
     Accept,
     Esc,
     Next,
@@ -251,43 +330,32 @@ enum InputEvt {
 pub enum SettingOutcome {
     Back,
     Ota,
+    /// SoftAP 配网,见 `crate::provision`
+    Provision,
     ClearConfig,
 }
 
-/// 等一个输入事件。旋钮方向在返回后用 rot_a/rot_b 电平判断。
+/// 等一个输入事件。
 /// Next(btn4)是主菜单切换选项的主力;旋钮在子列表(WiFi/密码字符)里仍可滚动。
-/// Backspace(btn5)目前只在密码输入态用于删除光标前的字符。
-async fn wait_input(
-    rot_a: Btn<'_>,
-    accept: Btn<'_>,
-    esc: Btn<'_>,
-    next: Btn<'_>,
-    backspace: Btn<'_>,
-) -> InputEvt {
+/// Backspace(btn5)目前只在密码输入态用于删除光标前的字符,长按按输入服务的设置连发。
+async fn wait_input(input: &mut Input) -> InputEvt {
     loop {
-        let evt = tokio::select! {
-            _ = rot_a.wait_for_any_edge() => InputEvt::Rotate,
-            _ = accept.wait_for_falling_edge() => InputEvt::Accept,
-            _ = esc.wait_for_falling_edge() => InputEvt::Esc,
-            _ = next.wait_for_falling_edge() => InputEvt::Next,
-            _ = backspace.wait_for_falling_edge() => InputEvt::Backspace,
-        };
-        // 旋钮(编码器):边沿即有效事件,方向由调用方读 rot_a/rot_b 电平判定,不消抖、不等。
-        if matches!(evt, InputEvt::Rotate) {
-            return evt;
-        }
-        tokio::time::sleep(std::time::Duration::from_millis(50)).await; // 消抖
-                                                                        // 消抖后再确认仍处于按下,否则视为抖动丢弃,重新等下一个事件(对应 boot_menu 的 `_ => {}`)。
-        let pressed = match evt {
-            InputEvt::Accept => accept.is_low(),
-            InputEvt::Esc => esc.is_low(),
-            InputEvt::Next => next.is_low(),
-            InputEvt::Backspace => backspace.is_low(),
-            InputEvt::Rotate => true,
+        let evt = match input.next().await {
+            Some(InputEvent::RotateUp) => InputEvt::Rotate(false),
+            Some(InputEvent::RotateDown) => InputEvt::Rotate(true),
+            Some(InputEvent::Press(KeysPin::ACCEPT)) => InputEvt::Accept,
+            Some(InputEvent::Press(KeysPin::ESC)) => InputEvt::Esc,
+            Some(InputEvent::Press(KeysPin::NEXT) | InputEvent::Repeat(KeysPin::NEXT)) => {
+                InputEvt::Next
+            }
+            Some(
+                InputEvent::Press(KeysPin::BACKSPACE) | InputEvent::Repeat(KeysPin::BACKSPACE),
+            ) => InputEvt::Backspace,
+            Some(_) => continue,
+            // 输入服务已退出:当作 Esc,让各层依次退回
+            None => InputEvt::Esc,
         };
-        if pressed {
-            return evt;
-        }
+        return evt;
     }
 }
 
@@ -301,22 +369,14 @@ fn rotate_index(focus: usize, len: usize, down: bool) -> usize {
     }
 }
 
-fn rot_down(rot_a: &crate::AnyBtn, rot_b: &crate::AnyBtn) -> bool {
-    rot_a.is_high() == rot_b.is_low()
-}
-
 pub async fn setting_page(
     target: &mut FrameBuffer,
     scan_list: &[String],
-    accept: Btn<'_>,
-    esc: Btn<'_>,
-    next: Btn<'_>,
-    rot_a: Btn<'_>,
-    rot_b: Btn<'_>,
-    backspace: Btn<'_>,
+    input: &mut Input,
     setting: &mut crate::bt_wifi_mode::Setting,
     nvs: &mut esp_idf_svc::nvs::EspDefaultNvs,
 ) -> SettingOutcome {
+    use crate::bt_keyboard_mode as kb;
     use crate::bt_wifi_mode::{Setting as BtSetting, WifiCred, MAX_WIFI_CREDS};
 
     let mut state = SettingState::Menu;
@@ -328,24 +388,77 @@ pub async fn setting_page(
     let mut pending_ssid: String = String::new();
     let mut password: String = String::new();
     let mut cur_char: usize = 0;
+    let mut bonds: Vec<esp32_nimble::BLEAddress> = Vec::new();
+    let mut bond_focus: usize = 0;
+    // ASR 字段 [uri, model, api_key],进 ASR 子菜单时从 NVS 载入。
+    let mut asr: [String; 3] = Default::default();
+    let mut asr_focus: usize = 0;
 
     loop {
         match state {
             SettingState::Menu => {
                 let _ = render_setting_menu(target, menu_focus, setting);
-                match wait_input(rot_a, accept, esc, next, backspace).await {
+                match wait_input(input).await {
                     // 主菜单改用 Next 键切换选项;滚轮在这里不再切换(避免误触/跳格)。
-                    InputEvt::Next => menu_focus = rotate_index(menu_focus, 3, true),
-                    InputEvt::Rotate => {}
+                    InputEvt::Next => menu_focus = rotate_index(menu_focus, SETTING_ITEMS, true),
+                    InputEvt::Rotate(_) => {}
                     InputEvt::Accept => match menu_focus {
                         0 => {
                             cred_focus =
                                 cred_focus.min(cred_entry_count(setting).saturating_sub(1));
                             state = SettingState::WifiCreds;
                         }
-                        1 => return SettingOutcome::Ota,
+                        1 => {
+                            password = setting.server_url.clone();
+                            cur_char = 0;
+                            state = SettingState::FieldEditor(TextField::ServerUrl);
+                        }
+                        2 => {
+                            asr = asr_fields(nvs);
+                            state = SettingState::Asr;
+                        }
+                        3 => {
+                            // 0 = PTT,1 = Toggle,见 `app::MicMode`
+                            setting.mic_model = if setting.mic_model == 0 { 1 } else { 0 };
+                            if let Err(e) = BtSetting::save_mic_model(nvs, setting.mic_model) {
+                                log::error!("Failed to save mic_model: {:?}", e);
+                            }
+                        }
+                        4 => {
+                            setting.prefer_builtin_asr = !setting.prefer_builtin_asr;
+                            if let Err(e) =
+                                BtSetting::save_prefer_builtin_asr(nvs, setting.prefer_builtin_asr)
+                            {
+                                log::error!("Failed to save prefer_builtin_asr: {:?}", e);
+                            }
+                        }
+                        5 => {
+                            setting.boot_mode = setting.boot_mode.next();
+                            if let Err(e) = BtSetting::save_boot_mode(nvs, setting.boot_mode) {
+                                log::error!("Failed to save boot_mode: {:?}", e);
+                            }
+                        }
+                        // 切到下一个 BLE 主机槽位,下次进键盘模式即用它广播。
+                        6 => {
+                            setting.host_slot =
+                                crate::bt_keyboard_mode::next_host_slot(setting.host_slot);
+                            if let Err(e) = BtSetting::save_host_slot(nvs, setting.host_slot) {
+                                log::error!("Failed to save host_slot: {:?}", e);
+                            }
+                        }
+                        7 => {
+                            // 读 bond 要启动 BLE 协议栈,离开本页时再关掉(见 kb::stop_ble)。
+                            bonds = kb::bonded_hosts().unwrap_or_else(|e| {
+                                log::error!("Failed to list bonds: {:?}", e);
+                                Vec::new()
+                            });
+                            bond_focus = 0;
+                            state = SettingState::Bonds;
+                        }
+                        8 => return SettingOutcome::Ota,
+                        9 => return SettingOutcome::Provision,
                         // 清空配置的实际动作(操作 nvs)交给 main,这里只回报意图。
-                        2 => return SettingOutcome::ClearConfig,
+                        10 => return SettingOutcome::ClearConfig,
                         _ => {}
                     },
                     // 每条 cred 的增删改都即时落盘,Esc 直接返回即可。
@@ -357,11 +470,9 @@ pub async fn setting_page(
                 let labels = cred_labels(setting);
                 let count = labels.len();
                 let _ = render_list(target, "WiFi (ESC=back BkSp=del)", &labels, cred_focus);
-                match wait_input(rot_a, accept, esc, next, backspace).await {
+                match wait_input(input).await {
                     InputEvt::Next => cred_focus = rotate_index(cred_focus, count, true),
-                    InputEvt::Rotate => {
-                        cred_focus = rotate_index(cred_focus, count, rot_down(rot_a, rot_b));
-                    }
+                    InputEvt::Rotate(down) => cred_focus = rotate_index(cred_focus, count, down),
                     InputEvt::Accept => {
                         if cred_focus >= setting.wifi_list.len() {
                             // <Add>:进扫描选择器挑一个 ssid。
@@ -394,11 +505,9 @@ pub async fn setting_page(
             SettingState::ScanPicker => {
                 let count = scan_list.len();
                 let _ = render_list(target, "Pick network (ESC=back)", scan_list, scan_focus);
-                match wait_input(rot_a, accept, esc, next, backspace).await {
+                match wait_input(input).await {
                     InputEvt::Next => scan_focus = rotate_index(scan_focus, count, true),
-                    InputEvt::Rotate => {
-                        scan_focus = rotate_index(scan_focus, count, rot_down(rot_a, rot_b));
-                    }
+                    InputEvt::Rotate(down) => scan_focus = rotate_index(scan_focus, count, down),
                     InputEvt::Accept => {
                         if count > 0 {
                             let picked = scan_list[scan_focus].clone();
@@ -418,10 +527,10 @@ pub async fn setting_page(
             }
             SettingState::PassEditor => {
                 let _ = render_password(target, &pending_ssid, &password, cur_char);
-                match wait_input(rot_a, accept, esc, next, backspace).await {
+                match wait_input(input).await {
                     InputEvt::Next => cur_char = rotate_index(cur_char, CHARSET.len(), true),
-                    InputEvt::Rotate => {
-                        cur_char = rotate_index(cur_char, CHARSET.len(), rot_down(rot_a, rot_b));
+                    InputEvt::Rotate(down) => {
+                        cur_char = rotate_index(cur_char, CHARSET.len(), down);
                     }
                     InputEvt::Accept => {
                         if password.len() < 32 {
@@ -443,6 +552,7 @@ pub async fn setting_page(
                                 setting.wifi_list.push(WifiCred {
                                     ssid: pending_ssid.clone(),
                                     pass: password.clone(),
+                                    ..Default::default()
                                 });
                             }
                             _ => {}
@@ -460,10 +570,191 @@ pub async fn setting_page(
                     }
                 }
             }
+            SettingState::Asr => {
+                let labels = asr_labels(&asr);
+                let _ = render_list(target, "ASR (ESC=back)", &labels, asr_focus);
+                match wait_input(input).await {
+                    InputEvt::Next => asr_focus = rotate_index(asr_focus, labels.len(), true),
+                    InputEvt::Rotate(down) => {
+                        asr_focus = rotate_index(asr_focus, labels.len(), down);
+                    }
+                    InputEvt::Accept => {
+                        password = asr[asr_focus].clone();
+                        cur_char = 0;
+                        state = SettingState::FieldEditor(ASR_FIELDS[asr_focus]);
+                    }
+                    InputEvt::Esc => state = SettingState::Menu,
+                    InputEvt::Backspace => {}
+                }
+            }
+            SettingState::FieldEditor(field) => {
+                let _ = render_password(target, field.title(), &password, cur_char);
+                match wait_input(input).await {
+                    InputEvt::Next => cur_char = rotate_index(cur_char, CHARSET.len(), true),
+                    InputEvt::Rotate(down) => {
+                        cur_char = rotate_index(cur_char, CHARSET.len(), down);
+                    }
+                    InputEvt::Accept => {
+                        if password.len() < field.max_len() {
+                            password.push(CHARSET[cur_char] as char);
+                        }
+                    }
+                    InputEvt::Backspace => {
+                        password.pop();
+                    }
+                    InputEvt::Esc => {
+                        // 提交并落盘;ASR 字段改完回子菜单,MQTT 地址回主菜单。
+                        let value = std::mem::take(&mut password);
+                        state = match field {
+                            TextField::ServerUrl => {
+                                if let Err(e) = BtSetting::save_server_url(nvs, &value) {
+                                    log::error!("Failed to save server_url: {:?}", e);
+                                }
+                                setting.server_url = value;
+                                SettingState::Menu
+                            }
+                            _ => {
+                                let i = ASR_FIELDS.iter().position(|f| *f == field).unwrap_or(0);
+                                asr[i] = value;
+                                if let Err(e) = save_asr_fields(nvs, &asr) {
+                                    log::error!("Failed to save asr_config: {:?}", e);
+                                }
+                                SettingState::Asr
+                            }
+                        };
+                    }
+                }
+            }
+            SettingState::Bonds => {
+                let labels = bond_labels(&bonds, setting);
+                let count = labels.len();
+                let _ = render_list(target, "Paired hosts", &labels, bond_focus);
+                match wait_input(input).await {
+                    InputEvt::Next => bond_focus = rotate_index(bond_focus, count, true),
+                    InputEvt::Rotate(down) => bond_focus = rotate_index(bond_focus, count, down),
+                    InputEvt::Accept if bond_focus == bonds.len() => {
+                        // <Delete all>
+                        if let Err(e) = kb::delete_all_bonds() {
+                            log::error!("Failed to delete bonds: {:?}", e);
+                        }
+                        bonds.clear();
+                        setting.host_peers.clear();
+                        if let Err(e) = BtSetting::save_host_peers(nvs, &setting.host_peers) {
+                            log::error!("Failed to save host_peers: {:?}", e);
+                        }
+                        bond_focus = 0;
+                    }
+                    InputEvt::Accept if bond_focus == bonds.len() + 1 => {
+                        setting.open_pairing = !setting.open_pairing;
+                        if let Err(e) = BtSetting::save_open_pairing(nvs, setting.open_pairing) {
+                            log::error!("Failed to save open_pairing: {:?}", e);
+                        }
+                    }
+                    InputEvt::Accept => {}
+                    InputEvt::Backspace => {
+                        // 删除当前 bond(对尾部两项无效),同时腾出它占的槽位。
+                        if bond_focus < bonds.len() {
+                            let addr = bonds.remove(bond_focus);
+                            if let Err(e) = kb::delete_bond(&addr) {
+                                log::error!("Failed to delete bond {}: {:?}", addr, e);
+                            }
+                            if kb::forget_peer(&mut setting.host_peers, &addr.to_string()) {
+                                if let Err(e) = BtSetting::save_host_peers(nvs, &setting.host_peers)
+                                {
+                                    log::error!("Failed to save host_peers: {:?}", e);
+                                }
+                            }
+                        }
+                    }
+                    InputEvt::Esc => {
+                        // 设置页只在开机菜单里跑,还没进任何模式:别让协议栈一直开着
+                        if let Err(e) = kb::stop_ble() {
+                            log::error!("Failed to stop BLE: {:?}", e);
+                        }
+                        state = SettingState::Menu;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Bonds 列表显示文本:每个已配对主机「地址 槽位」,尾部 <Delete all> 与配对开关。
+fn bond_labels(
+    bonds: &[esp32_nimble::BLEAddress],
+    setting: &crate::bt_wifi_mode::Setting,
+) -> Vec<String> {
+    let mut v: Vec<String> = bonds
+        .iter()
+        .map(|addr| {
+            let addr = addr.to_string();
+            let slot = match crate::bt_keyboard_mode::peer_slot(&setting.host_peers, &addr) {
+                Some(slot) => format!(" S{}", slot + 1),
+                None => String::new(),
+            };
+            format!("{addr}{slot}")
+        })
+        .collect();
+    v.push("<Delete all>".to_string());
+    v.push(format!(
+        "Pairing: {}",
+        if setting.open_pairing {
+            "open"
+        } else {
+            "closed"
         }
+    ));
+    v
+}
+
+/// 已存 ASR 配置拆成 [uri, model, api_key];没有配置时全空。
+fn asr_fields(nvs: &esp_idf_svc::nvs::EspDefaultNvs) -> [String; 3] {
+    match crate::audio::AsrConfig::load_from_nvs(nvs) {
+        Some(crate::audio::AsrConfig::Whisper {
+            uri,
+            api_key,
+            model,
+        }) => [uri, model, api_key],
+        None => Default::default(),
     }
 }
 
+fn save_asr_fields(
+    nvs: &mut esp_idf_svc::nvs::EspDefaultNvs,
+    fields: &[String; 3],
+) -> anyhow::Result<()> {
+    let [uri, model, api_key] = fields.clone();
+    crate::audio::AsrConfig::Whisper {
+        uri,
+        api_key,
+        model,
+    }
+    .save_to_nvs(nvs)
+}
+
+/// ASR 子菜单显示文本;API key 不显示明文,只标出是否已设置。
+fn asr_labels(asr: &[String; 3]) -> Vec<String> {
+    let or_none = |s: &str| {
+        if s.is_empty() {
+            "<none>".to_string()
+        } else {
+            s.to_string()
+        }
+    };
+    vec![
+        format!("URI: {}", or_none(&asr[0])),
+        format!("Model: {}", or_none(&asr[1])),
+        format!(
+            "API key: {}",
+            if asr[2].is_empty() {
+                "<none>"
+            } else {
+                crate::bt_wifi_mode::SECRET_MASK
+            }
+        ),
+    ]
+}
+
 /// WifiCreds 列表条目数(含尾部 <Add>,达到上限时没有 <Add>)。
 fn cred_entry_count(setting: &crate::bt_wifi_mode::Setting) -> usize {
     let n = setting.wifi_list.len();
@@ -500,14 +791,44 @@ fn render_setting_menu(
     )?;
     let items = [
         format!("WiFi networks ({})", setting.wifi_list.len()),
+        "MQTT server".to_string(),
+        "ASR service".to_string(),
+        format!(
+            "MIC mode: {}",
+            if setting.mic_model == 0 {
+                "PTT"
+            } else {
+                "Toggle"
+            }
+        ),
+        format!(
+            "Built-in ASR: {}",
+            if setting.prefer_builtin_asr {
+                "on"
+            } else {
+                "off"
+            }
+        ),
+        format!("Auto boot: {}", setting.boot_mode.label()),
+        format!(
+            "BLE host: {}/{}",
+            setting.host_slot + 1,
+            crate::bt_keyboard_mode::HOST_SLOTS
+        ),
+        "Paired hosts".to_string(),
         "OTA Update".to_string(),
+        "WiFi setup (hotspot)".to_string(),
         "Clear config".to_string(),
     ];
     let item_h = LINE_H + 4;
     let start_y: i32 = 24;
-    for (i, label) in items.iter().enumerate() {
+    // 条目多于一屏时跟 render_list 一样整页跟随焦点滚动
+    let height = target.bounding_box().size.height as i32;
+    let visible = ((height - start_y) / (item_h as i32)).max(1) as usize;
+    let start = focus.saturating_sub(visible.saturating_sub(1));
+    for (i, label) in items.iter().enumerate().skip(start) {
         let rect = Rectangle::new(
-            Point::new(0, start_y + (i as i32) * (item_h as i32)),
+            Point::new(0, start_y + ((i - start) as i32) * (item_h as i32)),
             Size::new(width, item_h),
         );
         if i == focus {
@@ -663,17 +984,23 @@ fn render_password(
         None,
         HorizontalAlignment::Left,
     )?;
+    // 长文本(URL 等)只显示放得下的末尾部分,光标始终在屏内。
+    let fit = ((width - 4) / 7).saturating_sub(1) as usize;
+    let shown: String = password
+        .chars()
+        .skip(password.chars().count().saturating_sub(fit))
+        .collect();
     draw_text(
         target,
-        password,
+        &shown,
         Rectangle::new(Point::new(4, 18), Size::new(width - 4, LINE_H + 2)),
         ColorFormat::CSS_WHITE,
         None,
         HorizontalAlignment::Left,
     )?;
-    // 插入点光标:量出密码文本像素宽,在末尾画一个块状光标,随输入/退格左右移动。
+    // 插入点光标:量出文本像素宽,在末尾画一个块状光标,随输入/退格左右移动。
     let text_w = Text::new(
-        password,
+        &shown,
         Point::zero(),
         MonoTextStyle::new(&FONT_7X13_BOLD, ColorFormat::CSS_WHITE),
     )
@@ -767,7 +1094,17 @@ pub fn draw_status_bar(
                 ColorFormat::CSS_GRAY
             },
         )?;
-        x += icon_w + gap;
+        x += icon_w;
+        // 当前 BLE 主机槽位(1 起)
+        let slot = crate::bt_keyboard_mode::HOST_SLOT.load(std::sync::atomic::Ordering::Relaxed);
+        Text::with_baseline(
+            &(slot + 1).to_string(),
+            Point::new(x as i32 + 1, icon_top),
+            MonoTextStyle::new(&FONT_5X8, ColorFormat::CSS_WHITE),
+            Baseline::Top,
+        )
+        .draw(target)?;
+        x += 6 + gap;
     }
     draw_icon(
         target,
@@ -809,6 +1146,174 @@ pub fn render_keyboard_view(
     flush(target)
 }
 
+/// 主机下发的 JSON 布局(见 `crate::widget`):标题 + 图标、彩色文字行、进度条、按键说明。
+/// 自上而下排版,进度条与按键说明固定在底部;行数放不下时多余的行不画。
+pub fn render_widget_view(
+    target: &mut FrameBuffer,
+    wifi_on: bool,
+    ble_on: bool,
+    layout: &crate::widget::Layout,
+) -> anyhow::Result<()> {
+    let bb = target.bounding_box();
+    let width = bb.size.width;
+    clear(target, ColorFormat::CSS_BLACK)?;
+    draw_status_bar(target, wifi_on, Some(ble_on))?;
+
+    let mut y = STATUS_H as i32 + 2;
+    if layout.title.is_some() || layout.icon.is_some() {
+        let mut x = 4;
+        if let Some(icon) = layout.icon {
+            draw_widget_icon(target, icon, Point::new(x + 5, y + LINE_H as i32 / 2))?;
+            x += 14;
+        }
+        if let Some(title) = &layout.title {
+            draw_text_cjk(
+                target,
+                title,
+                Rectangle::new(Point::new(x, y), Size::new(width - x as u32, LINE_H)),
+                ColorFormat::CSS_WHEAT,
+                None,
+                HorizontalAlignment::Left,
+            )?;
+        }
+        y += LINE_H as i32 + 2;
+    }
+
+    // 底部:按键说明一行,其上进度条一行。
+    let mut bottom = bb.size.height as i32;
+    if !layout.keys.is_empty() {
+        bottom -= LINE_H as i32 + 2;
+        let n = layout.keys.len() as u32;
+        let cell_w = width / n;
+        for (i, legend) in layout.keys.iter().enumerate() {
+            let cell = Rectangle::new(
+                Point::new((i as u32 * cell_w) as i32 + 1, bottom + 1),
+                Size::new(cell_w - 2, LINE_H + 1),
+            );
+            fill_rect(target, cell, ColorFormat::CSS_DARK_SLATE_GRAY)?;
+            draw_text_cjk(
+                target,
+                &format!("{} {}", legend.key, legend.label),
+                cell,
+                ColorFormat::CSS_WHITE,
+                None,
+                HorizontalAlignment::Center,
+            )?;
+        }
+    }
+    if let Some(progress) = &layout.progress {
+        bottom -= LINE_H as i32 + 2;
+        let bar = Rectangle::new(Point::new(4, bottom), Size::new(width - 8, LINE_H));
+        bar.draw_styled(
+            &PrimitiveStyle::with_stroke(ColorFormat::CSS_GRAY, 1),
+            target,
+        )?;
+        let filled = ((width - 10) as f32 * progress.fraction) as u32;
+        if filled > 0 {
+            fill_rect(
+                target,
+                Rectangle::new(Point::new(5, bottom + 1), Size::new(filled, LINE_H - 2)),
+                ColorFormat::CSS_DARK_CYAN,
+            )?;
+        }
+        let pct = (progress.fraction * 100.0).round() as u32;
+        let label = match &progress.label {
+            Some(l) => format!("{} {}%", l, pct),
+            None => format!("{}%", pct),
+        };
+        draw_text_cjk(
+            target,
+            &label,
+            bar,
+            ColorFormat::CSS_WHITE,
+            None,
+            HorizontalAlignment::Center,
+        )?;
+    }
+
+    for line in &layout.lines {
+        if y + LINE_H as i32 > bottom {
+            break;
+        }
+        draw_text_cjk(
+            target,
+            &line.text,
+            Rectangle::new(Point::new(4, y), Size::new(width - 8, LINE_H)),
+            widget_color(line.color),
+            None,
+            HorizontalAlignment::Left,
+        )?;
+        y += LINE_H as i32;
+    }
+    flush(target)
+}
+
+fn widget_color(c: crate::widget::Color) -> ColorFormat {
+    ColorFormat::new(c.0 >> 3, c.1 >> 2, c.2 >> 3)
+}
+
+/// 布局标题旁的状态图标,`center` 为中心点,约 10px 见方。用图元画,不依赖图标字体码位。
+fn draw_widget_icon(
+    target: &mut FrameBuffer,
+    icon: crate::widget::Icon,
+    center: Point,
+) -> anyhow::Result<()> {
+    use crate::widget::Icon;
+    use embedded_graphics::primitives::{Arc, Circle, Line, Polyline, Triangle};
+
+    let white = PrimitiveStyle::with_stroke(ColorFormat::CSS_WHITE, 2);
+    let disc = Circle::with_center(center, 11);
+    match icon {
+        Icon::Ok => {
+            disc.draw_styled(&PrimitiveStyle::with_fill(ColorFormat::CSS_GREEN), target)?;
+            Polyline::new(&[
+                center + Point::new(-3, 0),
+                center + Point::new(-1, 2),
+                center + Point::new(3, -2),
+            ])
+            .draw_styled(&white, target)?;
+        }
+        Icon::Error => {
+            disc.draw_styled(&PrimitiveStyle::with_fill(ColorFormat::CSS_RED), target)?;
+            Line::new(center + Point::new(-2, -2), center + Point::new(2, 2))
+                .draw_styled(&white, target)?;
+            Line::new(center + Point::new(-2, 2), center + Point::new(2, -2))
+                .draw_styled(&white, target)?;
+        }
+        Icon::Warning => {
+            Triangle::new(
+                center + Point::new(0, -5),
+                center + Point::new(-5, 4),
+                center + Point::new(5, 4),
+            )
+            .draw_styled(&PrimitiveStyle::with_fill(ColorFormat::CSS_GOLD), target)?;
+            Line::new(center + Point::new(0, -2), center + Point::new(0, 1)).draw_styled(
+                &PrimitiveStyle::with_stroke(ColorFormat::CSS_BLACK, 1),
+                target,
+            )?;
+            Pixel(center + Point::new(0, 3), ColorFormat::CSS_BLACK).draw(target)?;
+        }
+        Icon::Info => {
+            disc.draw_styled(
+                &PrimitiveStyle::with_fill(ColorFormat::CSS_ROYAL_BLUE),
+                target,
+            )?;
+            Line::new(center + Point::new(0, -1), center + Point::new(0, 3)).draw_styled(
+                &PrimitiveStyle::with_stroke(ColorFormat::CSS_WHITE, 1),
+                target,
+            )?;
+            Pixel(center + Point::new(0, -3), ColorFormat::CSS_WHITE).draw(target)?;
+        }
+        Icon::Busy => {
+            Arc::with_center(center, 10, 0.0.deg(), 270.0.deg()).draw_styled(
+                &PrimitiveStyle::with_stroke(ColorFormat::CSS_CYAN, 2),
+                target,
+            )?;
+        }
+    }
+    Ok(())
+}
+
 /// Remote 模式视图:stop 显示占位提示,working 显示动画占位。
 /// (working 时实际屏幕由 app::run 的 ui.handle_message 显示 vibetty 实时画面覆盖。)
 pub fn render_remote_view(target: &mut FrameBuffer, working: bool) -> anyhow::Result<()> {
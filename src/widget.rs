@@ -0,0 +1,264 @@
+//! Host-driven screen layouts for keyboard mode.
+//!
+//! A write to the display characteristic is either plain text (shown as before) or a JSON
+//! layout, so a host script can show build status or agent progress on the keypad:
+//!
+//! ```json
+//! {"title":"CI","icon":"busy",
+//!  "lines":["main @ 3f2a1c",{"text":"2 tests failed","color":"red"}],
+//!  "progress":{"value":42,"max":100,"label":"building"},
+//!  "keys":[{"key":"ACCEPT","label":"Retry"},{"key":"ESC","label":"Dismiss"}]}
+//! ```
+//!
+//! Every field is optional. `progress` may also be a bare percentage; colors are names from
+//! [`Color::named`] or `#rrggbb`. Drawing lives in `ui::render_widget_view`.
+
+use serde::{Deserialize, Deserializer};
+
+/// Most body lines kept; the rest are dropped
+pub const MAX_LINES: usize = 8;
+/// Most key legends kept
+pub const MAX_LEGENDS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color(pub u8, pub u8, pub u8);
+
+impl Color {
+    pub const WHITE: Color = Color(0xFF, 0xFF, 0xFF);
+
+    pub fn named(name: &str) -> Option<Self> {
+        let c = match name.to_ascii_lowercase().as_str() {
+            "white" => Self::WHITE,
+            "gray" | "grey" => Color(0x80, 0x80, 0x80),
+            "red" => Color(0xFF, 0x40, 0x40),
+            "green" => Color(0x40, 0xD0, 0x40),
+            "yellow" => Color(0xFF, 0xD7, 0x00),
+            "orange" => Color(0xFF, 0x8C, 0x00),
+            "blue" => Color(0x40, 0x80, 0xFF),
+            "cyan" => Color(0x00, 0xD0, 0xD0),
+            "magenta" => Color(0xFF, 0x40, 0xFF),
+            _ => return None,
+        };
+        Some(c)
+    }
+
+    /// A name or `#rrggbb`
+    pub fn parse(s: &str) -> Option<Self> {
+        if let Some(hex) = s.strip_prefix('#') {
+            if hex.len() != 6 {
+                return None;
+            }
+            let v = u32::from_str_radix(hex, 16).ok()?;
+            return Some(Color((v >> 16) as u8, (v >> 8) as u8, v as u8));
+        }
+        Self::named(s)
+    }
+}
+
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Color::parse(&s).ok_or_else(|| serde::de::Error::custom(format!("unknown color: {}", s)))
+    }
+}
+
+/// Status glyph drawn next to the title
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Icon {
+    Ok,
+    Error,
+    Warning,
+    Info,
+    Busy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub text: String,
+    pub color: Color,
+}
+
+impl<'de> Deserialize<'de> for Line {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Text(String),
+            Styled { text: String, color: Option<Color> },
+        }
+        Ok(match Raw::deserialize(deserializer)? {
+            Raw::Text(text) => Line {
+                text,
+                color: Color::WHITE,
+            },
+            Raw::Styled { text, color } => Line {
+                text,
+                color: color.unwrap_or(Color::WHITE),
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    /// 0.0 ..= 1.0
+    pub fraction: f32,
+    pub label: Option<String>,
+}
+
+impl<'de> Deserialize<'de> for Progress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Percent(f32),
+            Full {
+                value: f32,
+                #[serde(default = "default_max")]
+                max: f32,
+                label: Option<String>,
+            },
+        }
+        fn default_max() -> f32 {
+            100.0
+        }
+        let (value, max, label) = match Raw::deserialize(deserializer)? {
+            Raw::Percent(p) => (p, 100.0, None),
+            Raw::Full { value, max, label } => (value, max, label),
+        };
+        if max <= 0.0 {
+            return Err(serde::de::Error::custom("progress max must be positive"));
+        }
+        Ok(Progress {
+            fraction: (value / max).clamp(0.0, 1.0),
+            label,
+        })
+    }
+}
+
+/// Label for a physical key, e.g. `{"key":"ACCEPT","label":"Retry"}`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Legend {
+    pub key: String,
+    pub label: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Layout {
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub icon: Option<Icon>,
+    #[serde(default)]
+    pub lines: Vec<Line>,
+    #[serde(default)]
+    pub progress: Option<Progress>,
+    #[serde(default)]
+    pub keys: Vec<Legend>,
+}
+
+/// What a display write asks for
+#[derive(Debug, PartialEq)]
+pub enum DisplayContent {
+    Text(String),
+    Layout(Layout),
+}
+
+impl DisplayContent {
+    /// A JSON object is a layout; anything else, including JSON that does not parse as a
+    /// layout, is shown as text.
+    pub fn parse(data: &str) -> Self {
+        if data.trim_start().starts_with('{') {
+            match serde_json::from_str::<Layout>(data) {
+                Ok(mut layout) => {
+                    layout.lines.truncate(MAX_LINES);
+                    layout.keys.truncate(MAX_LEGENDS);
+                    return Self::Layout(layout);
+                }
+                Err(e) => log::warn!("Display layout rejected ({}), showing as text", e),
+            }
+        }
+        Self::Text(data.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_text_stays_text() {
+        assert_eq!(
+            DisplayContent::parse("hello"),
+            DisplayContent::Text("hello".into())
+        );
+        assert_eq!(
+            DisplayContent::parse(r#"{"lines":[1]}"#),
+            DisplayContent::Text(r#"{"lines":[1]}"#.into())
+        );
+    }
+
+    #[test]
+    fn full_layout() {
+        let json = r##"{"title":"CI","icon":"busy",
+            "lines":["main",{"text":"2 failed","color":"red"},{"text":"x","color":"#00ff80"}],
+            "progress":{"value":21,"max":42,"label":"building"},
+            "keys":[{"key":"ACCEPT","label":"Retry"}]}"##;
+        let DisplayContent::Layout(l) = DisplayContent::parse(json) else {
+            panic!("not a layout");
+        };
+        assert_eq!(l.title.as_deref(), Some("CI"));
+        assert_eq!(l.icon, Some(Icon::Busy));
+        assert_eq!(
+            l.lines,
+            vec![
+                Line {
+                    text: "main".into(),
+                    color: Color::WHITE
+                },
+                Line {
+                    text: "2 failed".into(),
+                    color: Color(0xFF, 0x40, 0x40)
+                },
+                Line {
+                    text: "x".into(),
+                    color: Color(0x00, 0xFF, 0x80)
+                },
+            ]
+        );
+        let p = l.progress.unwrap();
+        assert_eq!(p.fraction, 0.5);
+        assert_eq!(p.label.as_deref(), Some("building"));
+        assert_eq!(l.keys[0].label, "Retry");
+    }
+
+    #[test]
+    fn progress_forms_and_limits() {
+        let parse = |json: &str| match DisplayContent::parse(json) {
+            DisplayContent::Layout(l) => Some(l),
+            DisplayContent::Text(_) => None,
+        };
+        assert_eq!(
+            parse(r#"{"progress":25}"#)
+                .unwrap()
+                .progress
+                .unwrap()
+                .fraction,
+            0.25
+        );
+        assert_eq!(
+            parse(r#"{"progress":150}"#)
+                .unwrap()
+                .progress
+                .unwrap()
+                .fraction,
+            1.0
+        );
+        assert!(parse(r#"{"progress":{"value":1,"max":0}}"#).is_none());
+        assert!(parse(r#"{"lines":[{"text":"a","color":"mauve"}]}"#).is_none());
+
+        let many = format!(r#"{{"lines":{:?}}}"#, vec!["l"; 20]);
+        assert_eq!(parse(&many).unwrap().lines.len(), MAX_LINES);
+    }
+}
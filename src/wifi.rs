@@ -3,35 +3,48 @@ use esp_idf_svc::{
     wifi::{AuthMethod, BlockingWifi, EspWifi},
 };
 
+use crate::bt_wifi_mode::{StaticIp, WifiCred};
+
+/// STA netif 当前是否是静态 IP 的那一个(见 `set_sta_ip`)。
+static STATIC_NETIF: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
 pub fn connect(
     esp_wifi: &mut EspWifi<'static>,
-    ssid: &str,
-    pass: &str,
+    cred: &WifiCred,
     sysloop: EspSystemEventLoop,
 ) -> anyhow::Result<()> {
-    let mut auth_method = AuthMethod::WPA2Personal;
+    let ssid = cred.ssid.as_str();
     if ssid.is_empty() {
         anyhow::bail!("Missing WiFi name")
     }
-    if pass.is_empty() {
-        auth_method = AuthMethod::None;
+    let (auth_method, password) = if cred.eap.is_some() {
+        // 企业网的密码交给 EAP 客户端,不放进 STA 配置
+        (AuthMethod::WPA2Enterprise, "")
+    } else if cred.pass.is_empty() {
         log::info!("Wifi password is empty");
-    }
+        (AuthMethod::None, "")
+    } else {
+        (AuthMethod::WPA2Personal, cred.pass.as_str())
+    };
+
+    set_sta_ip(esp_wifi, cred.static_ip.as_ref())?;
 
     let mut wifi = BlockingWifi::wrap(esp_wifi, sysloop)?;
 
+    // 隐藏网络不用特别处理:驱动按 SSID 主动探测,不依赖 beacon
     wifi.set_configuration(&esp_idf_svc::wifi::Configuration::Client(
         esp_idf_svc::wifi::ClientConfiguration {
             ssid: ssid
                 .try_into()
-                .expect("Could not parse the given SSID into WiFi config"),
-            password: pass
+                .map_err(|_| anyhow::anyhow!("SSID too long: {:?}", ssid))?,
+            password: password
                 .try_into()
-                .expect("Could not parse the given password into WiFi config"),
+                .map_err(|_| anyhow::anyhow!("WiFi password too long"))?,
             auth_method,
             ..Default::default()
         },
     ))?;
+    set_enterprise(cred)?;
 
     wifi.start()?;
 
@@ -50,6 +63,127 @@ pub fn connect(
     Ok(())
 }
 
+/// 按凭据切换 STA netif:静态 IP 换一个固定地址的 netif,DHCP 换回默认的。
+/// 换 netif 前要先停驱动;两条都是 DHCP 时什么也不做。
+fn set_sta_ip(esp_wifi: &mut EspWifi<'static>, static_ip: Option<&StaticIp>) -> anyhow::Result<()> {
+    use esp_idf_svc::ipv4;
+    use esp_idf_svc::netif::{EspNetif, NetifConfiguration, NetifStack};
+    use std::sync::atomic::Ordering;
+
+    if static_ip.is_none() && !STATIC_NETIF.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    if esp_wifi.is_started()? {
+        esp_wifi.stop()?;
+    }
+    let netif = match static_ip {
+        Some(ip) => {
+            log::info!("Using static IP {}/{}", ip.ip, ip.prefix);
+            EspNetif::new_with_conf(&NetifConfiguration {
+                ip_configuration: Some(ipv4::Configuration::Client(
+                    ipv4::ClientConfiguration::Fixed(ipv4::ClientSettings {
+                        ip: ip.ip,
+                        subnet: ipv4::Subnet {
+                            gateway: ip.gateway,
+                            mask: ipv4::Mask(ip.prefix),
+                        },
+                        dns: Some(ip.dns.unwrap_or(ip.gateway)),
+                        secondary_dns: ip.dns2,
+                    }),
+                )),
+                ..NetifConfiguration::wifi_default_client()
+            })?
+        }
+        None => EspNetif::new(NetifStack::Sta)?,
+    };
+    esp_wifi.swap_netif_sta(netif)?;
+    STATIC_NETIF.store(static_ip.is_some(), Ordering::Relaxed);
+    Ok(())
+}
+
+/// 企业网凭据交给 EAP 客户端(PEAP/MSCHAPv2);其余网络关掉企业认证,免得上一条企业网的
+/// 设置残留。存了 CA 证书(`audio::ca_cert`)时用它校验认证服务器,没存时不校验。
+fn set_enterprise(cred: &WifiCred) -> anyhow::Result<()> {
+    use esp_idf_svc::sys;
+
+    let Some(eap) = &cred.eap else {
+        sys::esp!(unsafe { sys::esp_wifi_sta_enterprise_disable() })?;
+        return Ok(());
+    };
+    let username = if eap.username.is_empty() {
+        &eap.identity
+    } else {
+        &eap.username
+    };
+    unsafe {
+        sys::esp!(sys::esp_eap_client_set_identity(
+            eap.identity.as_ptr(),
+            eap.identity.len() as _,
+        ))?;
+        sys::esp!(sys::esp_eap_client_set_username(
+            username.as_ptr(),
+            username.len() as _,
+        ))?;
+        sys::esp!(sys::esp_eap_client_set_password(
+            cred.pass.as_ptr(),
+            cred.pass.len() as _,
+        ))?;
+        match crate::audio::ca_cert() {
+            Some(pem) => sys::esp!(sys::esp_eap_client_set_ca_cert(
+                pem.as_ptr(),
+                pem.len() as _,
+            ))?,
+            None => {
+                log::warn!("No CA certificate stored, not checking the EAP server");
+                sys::esp_eap_client_clear_ca_cert();
+            }
+        }
+        sys::esp!(sys::esp_wifi_sta_enterprise_enable())?;
+    }
+    log::info!("WPA2-Enterprise as {:?}", eap.identity);
+    Ok(())
+}
+
+/// 以 WPA2 热点(SoftAP)启动 WiFi,供配网页使用;返回设备在热点里的地址。
+/// 已启动(扫描 / 连过 STA)的话先停掉,退出时由调用方 `stop`。
+pub fn start_ap(
+    esp_wifi: &mut EspWifi<'static>,
+    ssid: &str,
+    password: &str,
+    sysloop: EspSystemEventLoop,
+) -> anyhow::Result<std::net::Ipv4Addr> {
+    let mut wifi = BlockingWifi::wrap(esp_wifi, sysloop)?;
+    if wifi.is_started()? {
+        wifi.stop()?;
+    }
+    wifi.set_configuration(&esp_idf_svc::wifi::Configuration::AccessPoint(
+        esp_idf_svc::wifi::AccessPointConfiguration {
+            ssid: ssid
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("SSID too long: {:?}", ssid))?,
+            auth_method: AuthMethod::WPA2Personal,
+            password: password
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("hotspot password too long"))?,
+            channel: 1,
+            max_connections: 4,
+            ..Default::default()
+        },
+    ))?;
+    wifi.start()?;
+    wifi.wait_netif_up()?;
+    let ip = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    log::info!("SoftAP {:?} up at {}", ssid, ip);
+    Ok(ip)
+}
+
+/// 当前连接的 AP 信号强度(dBm);未连接时为 None。
+pub fn sta_rssi() -> Option<i8> {
+    let mut info = esp_idf_svc::sys::wifi_ap_record_t::default();
+    let r = unsafe { esp_idf_svc::sys::esp_wifi_sta_get_ap_info(&mut info) };
+    (r == esp_idf_svc::sys::ESP_OK).then_some(info.rssi)
+}
+
 /// 扫描周围 WiFi,返回去重(保序)后的 ssid 列表。
 pub fn scan(
     esp_wifi: &mut EspWifi<'static>,
@@ -74,3 +208,87 @@ pub fn scan(
     }
     Ok(ssids)
 }
+
+/// 连接管理线程维护的 WiFi 链路状态。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkState {
+    pub connected: bool,
+    /// 当前(或正在重连)的网络
+    pub ssid: Option<String>,
+}
+
+static LINK: std::sync::Mutex<LinkState> = std::sync::Mutex::new(LinkState {
+    connected: false,
+    ssid: None,
+});
+
+/// 连上后每隔这么久查一次链路是否还在。
+const LINK_POLL: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// 当前链路状态(连接管理线程写,各模式读)。
+pub fn link_state() -> LinkState {
+    LINK.lock().unwrap().clone()
+}
+
+fn set_link(connected: bool, ssid: &str) {
+    *LINK.lock().unwrap() = LinkState {
+        connected,
+        ssid: Some(ssid.to_string()),
+    };
+}
+
+/// 启动 WiFi 连接管理线程,从此由它独占 `esp_wifi`:按 wifi_roam 的顺序与退避连
+/// wifi_list 里的网络,连上后轮询链路,掉线就重连 / 换下一个。不再扫描(二次 scan
+/// 会让驱动崩溃),`scan_list` 只用 boot 阶段的结果排序。
+pub fn spawn_manager(
+    mut esp_wifi: EspWifi<'static>,
+    sysloop: EspSystemEventLoop,
+    creds: Vec<WifiCred>,
+    scan_list: &[String],
+) -> anyhow::Result<()> {
+    let mut roamer = crate::wifi_roam::Roamer::new(creds, scan_list);
+    std::thread::Builder::new()
+        .name("wifi".to_string())
+        .stack_size(8 * 1024)
+        .spawn(move || loop {
+            let Some(attempt) = roamer.next_attempt() else {
+                log::warn!("No WiFi network configured, connection manager exiting");
+                return;
+            };
+            if !attempt.delay.is_zero() {
+                log::info!("Retrying WiFi in {:?}", attempt.delay);
+                std::thread::sleep(attempt.delay);
+            }
+            let ssid = attempt.cred.ssid.clone();
+            set_link(false, &ssid);
+            log::info!("Connecting to WiFi {}", ssid);
+            if let Err(e) = connect(&mut esp_wifi, &attempt.cred, sysloop.clone()) {
+                log::warn!("WiFi {} failed: {:?}", ssid, e);
+                let _ = esp_wifi.disconnect();
+                continue;
+            }
+            roamer.connected();
+            set_link(true, &ssid);
+            while esp_wifi.is_connected().unwrap_or(false) {
+                std::thread::sleep(LINK_POLL);
+            }
+            log::warn!("WiFi {} lost, reconnecting", ssid);
+            set_link(false, &ssid);
+            roamer.lost();
+        })?;
+    Ok(())
+}
+
+/// 等连接管理线程连上 WiFi,最多 `timeout`;返回是否已连上。
+pub fn wait_connected(timeout: std::time::Duration) -> bool {
+    let start = std::time::Instant::now();
+    loop {
+        if link_state().connected {
+            return true;
+        }
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,190 @@
+//! Reconnect and roaming policy of the WiFi connection manager (`wifi::spawn_manager`).
+//!
+//! The manager never scans again after boot: a second scan on the started driver crashes it
+//! (see `wifi::scan`). It connects to known networks by name instead, walking `wifi_list` in
+//! passes:
+//!
+//! - the first pass tries the networks the boot scan saw (and hidden ones, which no scan
+//!   shows), in `wifi_list` order, then the ones it missed (they may have come into range
+//!   since);
+//! - after a connection drops, the network that was just lost goes first again, since most
+//!   drops are short;
+//! - attempts within a pass run back to back; each pass that ends without a connection
+//!   waits longer before the next one ([`backoff`]), up to [`MAX_BACKOFF`].
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::bt_wifi_mode::WifiCred;
+
+/// Wait before the second pass; doubles with every failed pass
+pub const BASE_BACKOFF: Duration = Duration::from_secs(2);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Wait before the pass that follows `failed_passes` passes without a connection
+pub fn backoff(failed_passes: u32) -> Duration {
+    match failed_passes {
+        0 => Duration::ZERO,
+        n => BASE_BACKOFF
+            .saturating_mul(1 << (n - 1).min(16))
+            .min(MAX_BACKOFF),
+    }
+}
+
+/// One connection attempt: wait `delay`, then connect to `cred`
+#[derive(Debug, Clone)]
+pub struct Attempt {
+    pub cred: WifiCred,
+    pub delay: Duration,
+}
+
+pub struct Roamer {
+    creds: Vec<WifiCred>,
+    /// Boot-time preference: networks seen by the boot scan first
+    order: Vec<usize>,
+    /// Networks left in the current pass
+    queue: VecDeque<usize>,
+    in_pass: bool,
+    failed_passes: u32,
+    /// Network of the latest attempt
+    last: Option<usize>,
+    /// Network to try first in the next pass: the one connected last
+    preferred: Option<usize>,
+}
+
+impl Roamer {
+    pub fn new(creds: Vec<WifiCred>, scan_list: &[String]) -> Self {
+        let (mut order, missed): (Vec<usize>, Vec<usize>) =
+            (0..creds.len()).partition(|&i| creds[i].in_range(scan_list));
+        order.extend(missed);
+        Self {
+            creds,
+            order,
+            queue: VecDeque::new(),
+            in_pass: false,
+            failed_passes: 0,
+            last: None,
+            preferred: None,
+        }
+    }
+
+    /// Next network to try; `None` when `wifi_list` is empty
+    pub fn next_attempt(&mut self) -> Option<Attempt> {
+        if self.creds.is_empty() {
+            return None;
+        }
+        let mut delay = Duration::ZERO;
+        if self.queue.is_empty() {
+            if self.in_pass {
+                self.failed_passes += 1;
+                delay = backoff(self.failed_passes);
+            }
+            self.in_pass = true;
+            self.queue = self.preferred.into_iter().collect();
+            self.queue.extend(
+                self.order
+                    .iter()
+                    .copied()
+                    .filter(|&i| Some(i) != self.preferred),
+            );
+        }
+        let i = self.queue.pop_front()?;
+        self.last = Some(i);
+        Some(Attempt {
+            cred: self.creds[i].clone(),
+            delay,
+        })
+    }
+
+    /// The latest attempt connected
+    pub fn connected(&mut self) {
+        self.preferred = self.last;
+        self.failed_passes = 0;
+        self.queue.clear();
+        self.in_pass = false;
+    }
+
+    /// The connection dropped: start a new pass right away, lost network first
+    pub fn lost(&mut self) {
+        self.queue.clear();
+        self.in_pass = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn creds(names: &[&str]) -> Vec<WifiCred> {
+        names
+            .iter()
+            .map(|n| WifiCred {
+                ssid: n.to_string(),
+                pass: String::new(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn ssids(r: &mut Roamer, n: usize) -> Vec<(String, u64)> {
+        (0..n)
+            .map(|_| {
+                let a = r.next_attempt().unwrap();
+                (a.cred.ssid, a.delay.as_secs())
+            })
+            .collect()
+    }
+
+    fn pairs(list: &[(&str, u64)]) -> Vec<(String, u64)> {
+        list.iter().map(|(s, d)| (s.to_string(), *d)).collect()
+    }
+
+    #[test]
+    fn scanned_networks_go_first() {
+        let mut r = Roamer::new(creds(&["home", "office", "phone"]), &["phone".into()]);
+        assert_eq!(
+            ssids(&mut r, 4),
+            pairs(&[("phone", 0), ("home", 0), ("office", 0), ("phone", 2)])
+        );
+    }
+
+    #[test]
+    fn hidden_networks_count_as_scanned() {
+        let mut list = creds(&["home", "lab"]);
+        list[1].hidden = true;
+        let mut r = Roamer::new(list, &[]);
+        assert_eq!(ssids(&mut r, 2), pairs(&[("lab", 0), ("home", 0)]));
+    }
+
+    #[test]
+    fn backoff_grows_per_pass_and_resets() {
+        let mut r = Roamer::new(creds(&["a"]), &[]);
+        let delays: Vec<u64> = ssids(&mut r, 8).into_iter().map(|(_, d)| d).collect();
+        assert_eq!(delays, vec![0, 2, 4, 8, 16, 32, 60, 60]);
+        r.connected();
+        r.lost();
+        assert_eq!(ssids(&mut r, 2), pairs(&[("a", 0), ("a", 2)]));
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn lost_network_is_retried_first() {
+        let mut r = Roamer::new(
+            creds(&["home", "office"]),
+            &["home".into(), "office".into()],
+        );
+        ssids(&mut r, 2);
+        // connected to "office" on the second try
+        r.connected();
+        r.lost();
+        assert_eq!(
+            ssids(&mut r, 3),
+            pairs(&[("office", 0), ("home", 0), ("office", 2)])
+        );
+    }
+
+    #[test]
+    fn empty_list_has_nothing_to_try() {
+        assert!(Roamer::new(Vec::new(), &[]).next_attempt().is_none());
+    }
+}